@@ -0,0 +1,38 @@
+#ifndef PPA_H
+#define PPA_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define PPA_OK 0
+#define PPA_INVALID_ARGUMENT 1
+#define PPA_VERIFICATION_FAILED 2
+
+/*
+ * Verifies a Nitro attestation document against a JSON policy:
+ *
+ *   {"image_id": "<hex>", "module_id": "<regex>", "clock_skew": 300,
+ *    "expected_pcrs": {"0": "<hex>", "2": "<hex>"},
+ *    "min_vcpus": 2, "min_memory_mib": 4096, "root_cert_pem": "<pem>",
+ *    "limits": {"max_size": 32768, "max_depth": 8, "max_items": 64,
+ *               "max_cabundle": 8, "max_pcrs": 32,
+ *               "max_chain_depth": 6}}
+ *
+ * Only image_id is required. On return *out_report holds a JSON report on
+ * success or {"category": ..., "error": ...} on failure; release it with
+ * ppa_free_string.
+ */
+int ppa_verify(const uint8_t *doc_ptr, size_t doc_len, const char *policy_json,
+               char **out_report);
+
+void ppa_free_string(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* PPA_H */
@@ -0,0 +1,138 @@
+use clap::{Parser, Subcommand};
+use ppa::aead;
+use ppa::frame::{self, Padding};
+use ppa::message::{self, Envelope, Kind};
+use ppa::transport;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// address of the server <ip:port> or unix:<path>
+    #[clap(short, long, value_parser)]
+    ip_addr: String,
+
+    /// path to app public key file
+    #[arg(short, long)]
+    app: String,
+
+    /// path to admin private key file, or the auditor's for audit-log
+    #[arg(short, long)]
+    secret: String,
+
+    /// connect over RA-TLS, accepting only an enclave attested to run this image ID
+    #[arg(long)]
+    tls_image_id: Option<String>,
+
+    /// connect through a Noise_IK channel keyed by the app's public key, for apps run
+    /// with --noise
+    #[arg(long)]
+    noise: bool,
+
+    /// pad every frame to hide its size: none, padme or block:<bytes>; only hides sizes
+    /// over --tls or --noise, which encrypt the frame headers
+    #[arg(long, default_value_t = Padding::None)]
+    padding: Padding,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Retire the current loader key in favour of the pending one
+    FinalizeRotation {
+        /// path to the new loader public key file
+        #[arg(short, long)]
+        loader: String,
+    },
+
+    /// Delete a dataset together with every loader's contribution to it
+    DropDataset {
+        /// name of the dataset
+        #[arg(short, long)]
+        name: String,
+    },
+
+    /// Rotate the app's own key, accepting the old one until the grace window closes
+    RotateAppKey {
+        /// seconds the old app key stays accepted
+        #[arg(short, long, default_value_t = 86400)]
+        grace_secs: u64,
+    },
+
+    /// Print the log of accepted uploads, one JSON entry per line, with the auditor key
+    AuditLog,
+}
+
+fn read_key(path: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut key = [0u8; 32];
+    file.read_exact(&mut key)?;
+    Ok(key)
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    frame::set_padding(cli.padding);
+
+    println!("secret: {}, app: {}", cli.secret, cli.app);
+
+    let secret = read_key(&cli.secret)?;
+    let app = read_key(&cli.app)?;
+
+    let mut outbound = transport::dial(
+        &cli.ip_addr,
+        cli.tls_image_id.as_deref(),
+        cli.noise,
+        &secret,
+        &app,
+    )
+    .await?;
+
+    let envelope = match cli.command {
+        Command::FinalizeRotation { loader } => {
+            let loader = read_key(&loader)?;
+            Envelope::seal_to(Kind::FinalizeRotation, &secret, &app, 0, &loader)?.0
+        }
+        Command::DropDataset { name } => {
+            let seq = aead::next_sequence();
+            let name = name.as_bytes();
+            Envelope::seal_to(Kind::DropDataset, &secret, &app, seq, name)?.0
+        }
+        Command::RotateAppKey { grace_secs } => {
+            let seq = aead::next_sequence();
+            let grace = grace_secs.to_be_bytes();
+            Envelope::seal_to(Kind::RotateAppKey, &secret, &app, seq, &grace)?.0
+        }
+        Command::AuditLog => {
+            // Read page by page over the one connection until a page comes back empty
+            let mut from = 0u64;
+            loop {
+                let msg = from.to_le_bytes();
+                let (envelope, reply) = Envelope::seal_to(Kind::AuditLog, &secret, &app, 0, &msg)?;
+                let resp = message::request(&mut outbound, &envelope).await?;
+                let page = reply.decrypt(&resp, &envelope.reply_aad())?;
+                let page: serde_json::Value = serde_json::from_slice(&page)?;
+                let entries = page["entries"].as_array().ok_or("malformed audit log")?;
+                if entries.is_empty() {
+                    return Ok(());
+                }
+                for entry in entries {
+                    println!("{}", entry);
+                }
+                from = page["next"].as_u64().ok_or("malformed audit log")?;
+            }
+        }
+    };
+
+    let resp = message::request(&mut outbound, &envelope).await?;
+    let resp = String::from_utf8(resp)?;
+
+    println!("Response: {}", resp);
+
+    Ok(())
+}
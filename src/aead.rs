@@ -0,0 +1,300 @@
+use crate::locked::Locked;
+use aes_gcm::Aes256Gcm;
+use chacha20poly1305::{
+    aead::{Aead, KeyInit, Payload},
+    ChaCha20Poly1305,
+};
+use hmac::{Hmac, Mac};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use std::{env, fs};
+use zeroize::Zeroize;
+
+type HmacSha256 = Hmac<Sha256>;
+
+pub const NONCE_LEN: usize = 12;
+pub const COMMITMENT_LEN: usize = 32;
+pub const TAG_LEN: usize = 16;
+
+/// Length of a sealed message carrying `msg_len` bytes of plaintext.
+pub const fn sealed_len(msg_len: usize) -> usize {
+    NONCE_LEN + COMMITMENT_LEN + msg_len + TAG_LEN
+}
+
+/// Sequence number for a new loader message: nanoseconds since the unix epoch, or one
+/// above the last number handed out if the clock has stepped back since, so the app
+/// doesn't take later messages for replays. The last number is kept in
+/// `$PPA_SEQUENCE_FILE`, by default `.ppa-sequence` in the home directory; if it can't
+/// be read or written the clock alone decides.
+pub fn next_sequence() -> u64 {
+    next_sequence_in(&sequence_file())
+}
+
+fn next_sequence_in(path: &Path) -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64;
+    let last = fs::read_to_string(path)
+        .ok()
+        .and_then(|last| last.trim().parse::<u64>().ok());
+    let seq = last.map_or(now, |last| now.max(last.saturating_add(1)));
+    // Kept before it is used, so no later message can be handed the same number
+    let _ = fs::write(path, seq.to_string());
+    seq
+}
+
+fn sequence_file() -> PathBuf {
+    if let Some(path) = env::var_os("PPA_SEQUENCE_FILE") {
+        return path.into();
+    }
+    let home = env::var_os("HOME").or_else(|| env::var_os("USERPROFILE"));
+    let home = home.map(PathBuf::from).unwrap_or_default();
+    home.join(".ppa-sequence")
+}
+
+/// AEAD a session is sealed with, negotiated in the handshake. Both take 12-byte nonces
+/// and 16-byte tags, so sealed lengths don't depend on the suite.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Suite {
+    ChaCha20Poly1305 = 0,
+    Aes256Gcm = 1,
+}
+
+impl Suite {
+    pub fn from_u8(id: u8) -> Option<Suite> {
+        match id {
+            0 => Some(Suite::ChaCha20Poly1305),
+            1 => Some(Suite::Aes256Gcm),
+            _ => None,
+        }
+    }
+
+    /// Suites in this host's order of preference: AES-256-GCM first where the CPU has
+    /// AES instructions, as it is faster there, and ChaCha20-Poly1305 first elsewhere.
+    pub fn preferred() -> [Suite; 2] {
+        if aes_instructions() {
+            [Suite::Aes256Gcm, Suite::ChaCha20Poly1305]
+        } else {
+            [Suite::ChaCha20Poly1305, Suite::Aes256Gcm]
+        }
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn aes_instructions() -> bool {
+    std::arch::is_x86_feature_detected!("aes") && std::arch::is_x86_feature_detected!("pclmulqdq")
+}
+
+#[cfg(target_arch = "aarch64")]
+fn aes_instructions() -> bool {
+    std::arch::is_aarch64_feature_detected!("aes")
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn aes_instructions() -> bool {
+    false
+}
+
+/// ChaCha20-Poly1305 or AES-256-GCM wrapped in an HMAC-SHA256 key commitment.
+///
+/// Poly1305 and GHASH alone are not key-committing: a ciphertext can be crafted to
+/// decrypt validly under two different keys. Both the encryption key and the commitment key are derived
+/// from the shared secret, and the commitment is checked before decryption, so a sealed
+/// message only opens under the key it was produced with.
+///
+/// The keys are held in `Locked` memory, and the AEAD is keyed from them for each
+/// message rather than kept expanded, so no copy lives outside it.
+///
+/// Nonces are random unless the cipher is `sequenced`, as session ciphers are: each
+/// direction then numbers its messages, so nonces can't collide however many are sent
+/// and a message that isn't the next one from its sender doesn't open.
+///
+/// Wire format: `nonce (12) | commitment (32) | ciphertext`.
+pub struct CommittingCipher {
+    suite: Suite,
+    /// encryption key, then commitment key
+    keys: Locked<[u8; 64]>,
+    sequence: Option<Sequence>,
+}
+
+/// Counter nonces of a sequenced cipher: `direction (u32 BE) | counter (u64 BE)`, the
+/// initiator's direction 0 and the responder's 1.
+struct Sequence {
+    direction: u32,
+    /// counter of the next message sent
+    sent: AtomicU64,
+    /// counter of the next message expected from the peer
+    received: AtomicU64,
+}
+
+fn counter_nonce(direction: u32, counter: u64) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..4].copy_from_slice(&direction.to_be_bytes());
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+fn derive(shared: &[u8; 32], label: &[u8], out: &mut [u8]) {
+    let mut mac = HmacSha256::new_from_slice(shared).expect("hmac accepts any key length");
+    mac.update(label);
+    out.copy_from_slice(&mac.finalize().into_bytes());
+}
+
+impl CommittingCipher {
+    /// ChaCha20-Poly1305 cipher keyed by `shared`.
+    pub fn new(shared: [u8; 32]) -> Self {
+        Self::with_suite(shared, Suite::ChaCha20Poly1305)
+    }
+
+    /// Derives the keys from `shared`, wiping this copy of it.
+    pub fn with_suite(mut shared: [u8; 32], suite: Suite) -> Self {
+        let label: &[u8] = match suite {
+            Suite::ChaCha20Poly1305 => b"ppa-aead-enc",
+            Suite::Aes256Gcm => b"ppa-aead-enc-aes256gcm",
+        };
+        let mut keys = Locked::new([0u8; 64]);
+        derive(&shared, label, &mut keys[..32]);
+        derive(&shared, b"ppa-aead-commit", &mut keys[32..]);
+        shared.zeroize();
+        CommittingCipher {
+            suite,
+            keys,
+            sequence: None,
+        }
+    }
+
+    /// Switches to counter nonces for a cipher both sides derived from one session, each
+    /// direction counting from zero. `initiator` tells the two directions apart.
+    pub fn sequenced(mut self, initiator: bool) -> Self {
+        self.sequence = Some(Sequence {
+            direction: if initiator { 0 } else { 1 },
+            sent: AtomicU64::new(0),
+            received: AtomicU64::new(0),
+        });
+        self
+    }
+
+    fn commitment(&self, nonce: &[u8]) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.keys[32..]).expect("hmac accepts any key length");
+        mac.update(nonce);
+        mac
+    }
+
+    /// The AEAD keyed for one message. Both zeroize their expanded key when dropped.
+    fn chacha(&self) -> ChaCha20Poly1305 {
+        ChaCha20Poly1305::new_from_slice(&self.keys[..32]).expect("keys are 32 bytes")
+    }
+
+    fn aes(&self) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&self.keys[..32]).expect("keys are 32 bytes")
+    }
+
+    pub fn encrypt(&self, msg: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = match &self.sequence {
+            Some(sequence) => {
+                let counter = sequence.sent.fetch_add(1, Ordering::Relaxed);
+                counter_nonce(sequence.direction, counter)
+            }
+            None => {
+                let mut nonce = [0u8; NONCE_LEN];
+                OsRng.fill_bytes(&mut nonce);
+                nonce
+            }
+        };
+        let payload = Payload { msg, aad };
+        let ciphertext = match self.suite {
+            Suite::ChaCha20Poly1305 => self.chacha().encrypt(&nonce.into(), payload),
+            Suite::Aes256Gcm => self.aes().encrypt(&nonce.into(), payload),
+        }
+        .map_err(|e| "Encrypt failed: ".to_owned() + &e.to_string())?;
+
+        let mut buf = Vec::with_capacity(NONCE_LEN + COMMITMENT_LEN + ciphertext.len());
+        buf.extend_from_slice(&nonce);
+        buf.extend_from_slice(&self.commitment(&nonce).finalize().into_bytes());
+        buf.extend_from_slice(&ciphertext);
+        Ok(buf)
+    }
+
+    pub fn decrypt(&self, buf: &[u8], aad: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        if buf.len() < NONCE_LEN + COMMITMENT_LEN {
+            return Err("ciphertext too short".into());
+        }
+        let (nonce, rest) = buf.split_at(NONCE_LEN);
+        let (commitment, ciphertext) = rest.split_at(COMMITMENT_LEN);
+        let expected = self.sequence.as_ref().map(|sequence| {
+            let counter = sequence.received.load(Ordering::Relaxed);
+            (counter, counter_nonce(1 - sequence.direction, counter))
+        });
+        if let Some((_, expected)) = &expected {
+            if nonce != expected {
+                return Err("message out of sequence".into());
+            }
+        }
+
+        self.commitment(nonce)
+            .verify_slice(commitment)
+            .map_err(|_| "key commitment mismatch")?;
+
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        let msg = match self.suite {
+            Suite::ChaCha20Poly1305 => self.chacha().decrypt(nonce.into(), payload),
+            Suite::Aes256Gcm => self.aes().decrypt(nonce.into(), payload),
+        }
+        .map_err(|e| "Decrypt failed: ".to_owned() + &e.to_string())?;
+        // Only an authentic message moves the counter on
+        if let (Some(sequence), Some((counter, _))) = (&self.sequence, expected) {
+            sequence
+                .received
+                .compare_exchange(counter, counter + 1, Ordering::Relaxed, Ordering::Relaxed)
+                .map_err(|_| "message out of sequence")?;
+        }
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message::{aad, Kind};
+
+    #[test]
+    fn sequences_keep_increasing_when_the_clock_steps_back() {
+        let path = env::temp_dir().join(format!("ppa-sequence-{}", std::process::id()));
+        // A last number far ahead of the clock, as if it had since stepped back
+        let last = u64::MAX - 10;
+        fs::write(&path, last.to_string()).unwrap();
+        let first = next_sequence_in(&path);
+        let second = next_sequence_in(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(first, last + 1);
+        assert_eq!(second, last + 2);
+    }
+
+    #[test]
+    fn sequenced_messages_open_only_under_their_type_sender_and_number() {
+        let cipher = CommittingCipher::new([7; 32]);
+        let (sender, seq) = ([1; 32], 1_700_000_000_000_000_000);
+        let upload = aad(Kind::Upload, &sender, seq);
+        let buf = cipher.encrypt(b"vectors", &upload).unwrap();
+        assert_eq!(cipher.decrypt(&buf, &upload).unwrap(), b"vectors");
+        let moved = [
+            aad(Kind::Upload, &sender, seq + 1),
+            aad(Kind::Rotate, &sender, seq),
+            aad(Kind::Upload, &[2; 32], seq),
+        ];
+        for aad in moved {
+            assert!(cipher.decrypt(&buf, &aad).is_err());
+        }
+        let other = CommittingCipher::new([8; 32]);
+        assert!(other.decrypt(&buf, &upload).is_err());
+    }
+}
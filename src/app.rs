@@ -1,89 +1,3898 @@
-use chacha20poly1305::{
-    aead::{Aead, KeyInit, Payload},
-    ChaCha20Poly1305,
-};
 use clap::Parser;
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode, Uri};
+use ppa::aead::{CommittingCipher, Suite};
+use ppa::attestation::{self, Limits, Policy, AWS_ROOT_CERT};
+use ppa::frame::{self, AppError, ERR_AUTH, ERR_DECRYPT, ERR_REPLAY, ERR_REQUEST};
+use ppa::frame::{Padding, ERR_SCHEMA, ERR_SIZE, ERR_STANDBY};
+use ppa::hll;
+use ppa::hooks::Hooks;
+use ppa::hpke;
+use ppa::http::https_client;
+use ppa::keys::{self, Attest};
+use ppa::locked::Locked;
+use ppa::logging::{self, LogFormat};
+use ppa::merkle;
+use ppa::message::{Envelope, Kind, Status};
+use ppa::noise;
+use ppa::paillier::PublicKey;
+use ppa::pedersen;
+use ppa::range::{self, Proof};
+use ppa::ratls;
+use ppa::receipt::{AttestedResult, Receipt, ResultBinding, ResultClaims};
+use ppa::session::{self, Ephemeral, Hello};
+use ppa::shamir;
+use ppa::signing::{self, Scheme, Signer};
+use ppa::store::{self, Backend, Sealed, StateStore};
+use ppa::sum::{self, Bucket, Mode, Numeric, Op, Query, Schema, Value};
+use ppa::views::{self, Access, View};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use socket2::{SockRef, TcpKeepalive};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 use std::error::Error;
 use std::fs::File;
+use std::future::Future;
 use std::io::Read;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
+use std::net::SocketAddr;
+use std::ops::{Deref, RangeInclusive};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
-use x25519_dalek::x25519;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use tokio_rustls::TlsAcceptor;
+#[cfg(target_os = "linux")]
+use tokio_vsock::{VsockAddr, VsockListener};
+use tracing::{debug, info, warn, Instrument};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     /// ip address of the server <ip:port>
-    #[clap(short, long, value_parser)]
+    #[clap(short, long, value_parser, required_unless_present = "listen")]
+    ip_addr: Option<String>,
+
+    /// address to listen on as well: <vsock:CID:PORT> for serving inside a Nitro
+    /// enclave, or <unix:PATH> for a local socket, repeatable
+    #[arg(long, value_parser = parse_listen)]
+    listen: Vec<Listen>,
+
+    /// private key to load: a file path, env:<VAR>, secretsmanager:<secret-id> or
+    /// kms:<ciphertext file>, decrypted for this attested enclave when serving
+    /// --attestation-addr; without it a key is generated from NSM entropy at startup
+    /// and never leaves the enclave
+    #[arg(short, long)]
+    secret: Option<String>,
+
+    /// path to loader public key file, repeat for several data owners
+    #[arg(short, long, required_unless_present_any = ["loader_dir", "tenants"])]
+    loader: Vec<String>,
+
+    /// directory of loader public key files (*.pub), one per data owner
+    #[arg(long)]
+    loader_dir: Option<String>,
+
+    /// path to requester public key file, granted exact results for any query
+    #[arg(short, long, required_unless_present_any = ["view_policy", "tenants"])]
+    requester: Option<String>,
+
+    /// path to a JSON access-control list of requester keys with the operations,
+    /// datasets and result view each is granted; overrides the requester above if it
+    /// lists the same key
+    #[arg(long)]
+    view_policy: Option<String>,
+
+    /// path to a JSON file of tenants by name, each an isolated group of loader and
+    /// requester key files with optional min_contributors and max_datasets; a tenant's
+    /// datasets are invisible to every other client
+    #[arg(long)]
+    tenants: Option<String>,
+
+    /// path to admin public key file, enables admin messages such as finalizing rotations
+    #[arg(long)]
+    admin: Option<String>,
+
+    /// path to auditor public key file, which may read the log of accepted uploads
+    #[arg(long)]
+    auditor: Option<String>,
+
+    /// terminate TLS with a self-signed certificate carrying an NSM attestation of its key,
+    /// so clients verify the enclave in the TLS handshake
+    #[arg(long, requires = "attestation_addr")]
+    tls: bool,
+
+    /// require every connection to open with a Noise_IK handshake from a registered
+    /// loader, requester or admin key
+    #[arg(long)]
+    noise: bool,
+
+    /// pad every frame sent to hide its size: none, padme or block:<bytes>; only hides
+    /// sizes over --tls or --noise, which encrypt the frame headers
+    #[arg(long, default_value_t = Padding::None)]
+    padding: Padding,
+
+    /// maximum number of connections served concurrently
+    #[arg(long, default_value_t = 64)]
+    max_connections: usize,
+
+    /// seconds a client may take to send a message once it has started, or to finish a
+    /// TLS or Noise handshake
+    #[arg(long, default_value_t = 30)]
+    read_timeout: u64,
+
+    /// seconds a connection may wait for its first message, or sit between messages
+    #[arg(long, default_value_t = 60)]
+    idle_timeout: u64,
+
+    /// seconds a client may take to accept a response
+    #[arg(long, default_value_t = 30)]
+    write_timeout: u64,
+
+    /// largest message accepted, in KiB; larger ones are refused before they are read
+    #[arg(long, default_value_t = 1024, value_parser = clap::value_parser!(u32).range(1..=1024))]
+    max_message_kib: u32,
+
+    /// seconds a subscription waits for a new aggregate before it is answered as not
+    /// ready, for the requester to renew
+    #[arg(long, default_value_t = 300)]
+    subscribe_timeout: u64,
+
+    /// largest chunked upload a loader may stream on one connection, in MiB. At most
+    /// 1048576 (1 TiB)
+    #[arg(
+        long,
+        default_value_t = 1024,
+        value_parser = clap::value_parser!(u64).range(1..=1 << 20)
+    )]
+    max_upload_mib: u64,
+
+    /// memory every chunked upload in progress may hold together, in MiB; further
+    /// chunks wait for uploads to commit or be abandoned, and their connections aren't
+    /// read meanwhile. At most 1048576 (1 TiB)
+    #[arg(
+        long,
+        default_value_t = 4096,
+        value_parser = clap::value_parser!(u64).range(1..=1 << 20)
+    )]
+    max_pending_mib: u64,
+
+    /// distinct loaders that must have contributed to a dataset before any result over it
+    /// is released
+    #[arg(long, default_value_t = 1)]
+    min_contributors: usize,
+
+    /// vectors a histogram bucket must hold to be released; smaller buckets are left
+    /// out of the answer
+    #[arg(long, default_value_t = 10)]
+    min_bucket_count: u64,
+
+    /// dataset whose uploads are pairwise-masked by the loaders, answered only with sums
+    /// once every loader has contributed; repeatable
+    #[arg(long)]
+    masked_dataset: Vec<String>,
+
+    /// dataset whose uploads are Shamir shares, one of several instances each holding a
+    /// share of every value; answered with this instance's share of the sum. Repeatable
+    #[arg(long)]
+    shared_dataset: Vec<String>,
+
+    /// dataset whose uploads are HyperLogLog sketches of identifiers, merged and
+    /// answered only with the approximate count of distinct identifiers; repeatable
+    #[arg(long)]
+    sketch_dataset: Vec<String>,
+
+    /// encrypted dataset and the Paillier public key file its uploads are encrypted
+    /// under <name=path>; the app adds ciphertexts and never decrypts. Repeatable
+    #[arg(long)]
+    paillier: Vec<String>,
+
+    /// admissible values of a dataset <name=MIN..MAX>, inclusive and written in the
+    /// dataset's type; an upload holding any value outside is refused. Uploads to a
+    /// masked, shared or encrypted dataset carry a range proof instead. Repeatable
+    #[arg(long)]
+    value_range: Vec<String>,
+
+    /// named group of loaders whose contributions a query may aggregate alone
+    /// <name=LOADER,LOADER,...>; a result still needs --min-contributors of them.
+    /// Repeatable
+    #[arg(long)]
+    contributor_group: Vec<String>,
+
+    /// dataset run as a poll <name=CLOSE>, CLOSE in unix seconds: each loader casts one
+    /// ballot, a vector with a single 1, before it closes, and only the final tally is
+    /// answered after. Repeatable
+    #[arg(long)]
+    poll: Vec<String>,
+
+    /// seconds in-flight requests get to finish after SIGTERM/SIGINT before exiting
+    #[arg(long, default_value_t = 10)]
+    shutdown_timeout: u64,
+
+    /// console log format, levels are set with RUST_LOG
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+
+    /// seconds of idleness before TCP keepalive probes are sent
+    #[arg(long, default_value_t = 15)]
+    keepalive: u64,
+
+    /// persist datasets to this store, sealed under a key derived from the app secret,
+    /// and restore them at startup <file:DIR|relay:URL|s3:BUCKET[/PREFIX]>
+    #[arg(long, requires = "secret")]
+    store: Option<String>,
+
+    /// close an aggregation epoch every <EPOCH_SECS> seconds
+    #[arg(long)]
+    epoch_secs: Option<u64>,
+
+    /// webhook URL notified with the signed result bundle on each epoch close
+    #[arg(long, requires = "epoch_secs")]
+    hook: Vec<String>,
+
+    /// key signing query results and epoch bundles, loaded like --secret; a fresh key is
+    /// generated if not given. Its public half is published in attestations
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    /// signature scheme of the signing key <ed25519|p256|secp256k1>
+    #[arg(long, default_value = "ed25519")]
+    signing_scheme: Scheme,
+
+    /// refuse to serve unless the enclave's own attestation shows this image ID
+    #[arg(long)]
+    expected_image_id: Option<String>,
+
+    /// local attestation endpoint used for the startup self-check
+    #[arg(long, default_value = "http://127.0.0.1:1300/attestation/raw")]
+    attestation_endpoint: String,
+
+    /// serve /attestation/raw on this address <ip:port> with documents from the NSM
+    /// device that embed the app's public key, the self-check then uses the NSM too
+    #[arg(long, required_unless_present = "secret")]
+    attestation_addr: Option<SocketAddr>,
+
+    /// serve an unauthenticated /healthz on this address <ip:port> for liveness probes
+    #[arg(long)]
+    health_addr: Option<SocketAddr>,
+
+    /// serve Prometheus request metrics on this address <ip:port> at /metrics
+    #[arg(long)]
+    metrics_addr: Option<SocketAddr>,
+
+    /// stream the state to a standby replica at this address <ip:port> whenever it
+    /// changes, once each side has verified the other's attestation
+    #[arg(long, requires = "peer_image_id", conflicts_with = "replica_listen")]
+    replica: Option<String>,
+
+    /// stand by as the replica of a primary that connects on this address <ip:port>,
+    /// refusing requests until its stream ends; run with the primary's --secret so
+    /// clients can switch over
+    #[arg(long, requires_all = ["peer_image_id", "secret"])]
+    replica_listen: Option<SocketAddr>,
+
+    /// image ID the replication peer's attestation must show
+    #[arg(long)]
+    peer_image_id: Option<String>,
+}
+
+/// Extra address the app listens on, besides `--ip-addr`.
+#[derive(Clone, Debug)]
+enum Listen {
+    Vsock(u32, u32),
+    Unix(PathBuf),
+}
+
+fn parse_listen(s: &str) -> Result<Listen, String> {
+    if let Some(path) = s.strip_prefix("unix:") {
+        if path.is_empty() {
+            return Err("expected unix:<PATH>".into());
+        }
+        return Ok(Listen::Unix(PathBuf::from(path)));
+    }
+    let (cid, port) = s
+        .strip_prefix("vsock:")
+        .and_then(|addr| addr.split_once(':'))
+        .ok_or("expected vsock:<CID>:<PORT> or unix:<PATH>")?;
+    let cid = cid.parse().map_err(|e| format!("invalid cid: {}", e))?;
+    let port = port.parse().map_err(|e| format!("invalid port: {}", e))?;
+    Ok(Listen::Vsock(cid, port))
+}
+
+/// Loader key files from `--loader` and every `.pub` file in `--loader-dir`.
+fn loader_paths(files: &[String], dir: Option<&str>) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut paths = files.to_vec();
+    if let Some(dir) = dir {
+        let mut entries = Vec::new();
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_some_and(|ext| ext == "pub") {
+                entries.push(path.to_string_lossy().into_owned());
+            }
+        }
+        entries.sort();
+        paths.extend(entries);
+    }
+    Ok(paths)
+}
+
+/// One tenant in the `--tenants` file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TenantConfig {
+    /// loader public key files
+    #[serde(default)]
+    loaders: Vec<String>,
+    /// requester public key files, granted exact results over the tenant's datasets
+    #[serde(default)]
+    requesters: Vec<String>,
+    /// overrides --min-contributors for the tenant's datasets
+    min_contributors: Option<usize>,
+    /// most datasets the tenant may hold at once
+    max_datasets: Option<usize>,
+}
+
+/// Limits of a tenant. Its datasets are stored as `tenant/name`, and its clients only
+/// ever name datasets under that prefix.
+struct Tenant {
+    min_contributors: usize,
+    max_datasets: Option<usize>,
+}
+
+/// A data owner's identities accepted for uploads. During a rotation both the current
+/// and the pending key are accepted until an admin finalizes it.
+struct LoaderKeys {
+    /// file stem of the loader's public key file, identifying its contribution, under
+    /// its tenant's prefix for a tenant's loader
+    name: String,
+    tenant: Option<String>,
+    current_pub: [u8; 32],
+    pending: Option<[u8; 32]>,
+    /// sequence number of the last message accepted from the current key
+    current_seq: u64,
+    /// sequence number of the last message accepted from the pending key
+    pending_seq: u64,
+}
+
+impl LoaderKeys {
+    fn new(name: String, tenant: Option<String>, current_pub: [u8; 32]) -> Self {
+        LoaderKeys {
+            name,
+            tenant,
+            current_pub,
+            pending: None,
+            current_seq: 0,
+            pending_seq: 0,
+        }
+    }
+
+    /// Sequence number of the last message accepted from `key`, if it is one of this
+    /// loader's keys.
+    fn last_seq(&mut self, key: &[u8; 32]) -> Option<&mut u64> {
+        if same_key(key, &self.current_pub) {
+            return Some(&mut self.current_seq);
+        }
+        match &self.pending {
+            Some(pending_pub) if same_key(pending_pub, key) => Some(&mut self.pending_seq),
+            _ => None,
+        }
+    }
+
+    /// Whether `key` is one of this loader's keys.
+    fn owns(&self, key: &[u8; 32]) -> bool {
+        same_key(key, &self.current_pub) || self.pending.is_some_and(|p| same_key(&p, key))
+    }
+}
+
+/// Compares keys in constant time, so response timing doesn't tell how much of a
+/// forged key matched.
+fn same_key(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    a[..].ct_eq(&b[..]).into()
+}
+
+/// The app's own X25519 secrets. After an app key rotation the previous secret keeps
+/// opening messages until its grace window closes, so clients can move to the new
+/// public key without downtime.
+struct AppKeys {
+    current: Arc<Locked<[u8; 32]>>,
+    /// previous secret and when it retires
+    previous: Option<(Arc<Locked<[u8; 32]>>, Instant)>,
+}
+
+impl AppKeys {
+    fn new(secret: Arc<Locked<[u8; 32]>>) -> Self {
+        AppKeys {
+            current: secret,
+            previous: None,
+        }
+    }
+
+    /// Public key of the current secret, the one attestations carry.
+    fn public(&self) -> [u8; 32] {
+        x25519(**self.current, X25519_BASEPOINT_BYTES)
+    }
+
+    /// Makes `secret` the current key, keeping the old one for `grace`.
+    fn rotate(&mut self, secret: Locked<[u8; 32]>, grace: Duration) {
+        let old = std::mem::replace(&mut self.current, Arc::new(secret));
+        self.previous = Some((old, Instant::now() + grace));
+    }
+
+    /// Secrets messages may be sealed for, newest first. The previous secret is dropped,
+    /// and so zeroized, once its grace window has passed and no message still uses it.
+    fn secrets(&mut self) -> Vec<Arc<Locked<[u8; 32]>>> {
+        if matches!(&self.previous, Some((_, retire_at)) if Instant::now() >= *retire_at) {
+            self.previous = None;
+            info!("Previous app key retired");
+        }
+        std::iter::once(&self.current)
+            .chain(self.previous.as_ref().map(|(secret, _)| secret))
+            .cloned()
+            .collect()
+    }
+}
+
+/// Opens `buf`, sealed with HPKE from `peer`'s static key, under whichever app key it
+/// was sealed for. Returns the cipher any reply goes back under, which only the sender
+/// of this one message can open.
+fn open(
+    state: &State,
+    peer: &[u8; 32],
+    buf: &[u8],
+    aad: &[u8],
+) -> Option<(CommittingCipher, Zeroizing<Vec<u8>>)> {
+    let secrets = state.keys.lock().unwrap().secrets();
+    secrets.iter().find_map(|secret| {
+        let (msg, reply) = hpke::open(secret, peer, aad, buf).ok()?;
+        Some((reply, msg))
+    })
+}
+
+/// Cipher a request was opened with, which its reply is sealed under.
+enum ReplyCipher<'a> {
+    /// the connection's session
+    Session(&'a CommittingCipher),
+    /// the key exported from the request's HPKE context
+    Hpke(CommittingCipher),
+}
+
+impl Deref for ReplyCipher<'_> {
+    type Target = CommittingCipher;
+
+    fn deref(&self) -> &CommittingCipher {
+        match self {
+            ReplyCipher::Session(cipher) => cipher,
+            ReplyCipher::Hpke(cipher) => cipher,
+        }
+    }
+}
+
+/// Opens a request from `peer` under the connection's session if the peer opened one,
+/// and with HPKE otherwise.
+fn open_request<'a>(
+    state: &State,
+    session: Option<&'a Session>,
+    peer: &[u8; 32],
+    buf: &[u8],
+    aad: &[u8],
+) -> Option<(ReplyCipher<'a>, Zeroizing<Vec<u8>>)> {
+    match session.filter(|session| same_key(&session.peer, peer)) {
+        Some(session) => {
+            let msg = Zeroizing::new(session.cipher.decrypt(buf, aad).ok()?);
+            Some((ReplyCipher::Session(&session.cipher), msg))
+        }
+        None => {
+            let (cipher, msg) = open(state, peer, buf, aad)?;
+            Some((ReplyCipher::Hpke(cipher), msg))
+        }
+    }
+}
+
+/// Keys agreed with a loader or requester for one connection.
+struct Session {
+    /// static public key the peer authenticated the handshake with
+    peer: [u8; 32],
+    cipher: CommittingCipher,
+    /// chunked upload in progress on the connection
+    upload: Option<PendingUpload>,
+}
+
+/// Vectors of a chunked upload received so far. They only join the dataset on commit;
+/// an upload abandoned with its connection is zeroized.
+struct PendingUpload {
+    dataset: String,
+    /// schema declared by the first chunk, which the rest must match
+    schema: Option<Schema>,
+    /// TTL declared by the first chunk, which the rest must match
+    ttl: Option<u64>,
+    vectors: Vec<Vec<u64>>,
+    /// encoded size of the chunks received, counted against `--max-upload-mib`
+    size: usize,
+    /// hash of the sealed chunks received, in order, for the audit log
+    digest: Sha256,
+    /// share of `--max-pending-mib` the chunks hold, released with the upload
+    reserved: Option<OwnedSemaphorePermit>,
+}
+
+impl Drop for PendingUpload {
+    fn drop(&mut self) {
+        self.vectors.zeroize();
+    }
+}
+
+/// Answers a loader or requester handshake. The payload is the peer's ephemeral key,
+/// the AEAD suites it offers and, for a hybrid session, its ML-KEM-768 key, HPKE-sealed
+/// from its static key. The reply is the app's ephemeral key, the suite it chose and the
+/// ML-KEM ciphertext, sealed under the reply key. Returns the reply with the session it
+/// opens; the app's ephemeral secrets are gone once the session key is derived.
+fn handshake(state: &State, envelope: &Envelope) -> Result<(Vec<u8>, Session), Box<dyn Error>> {
+    let sender = envelope.sender()?;
+    if !state
+        .loaders
+        .lock()
+        .unwrap()
+        .iter()
+        .any(|loader| loader.owns(&sender))
+        && state.requesters.get(&sender).is_none()
+    {
+        return Err(Rejection::auth("unknown loader or requester key"));
+    }
+    let (cipher, hello) = open(state, &sender, &envelope.payload, &envelope.aad())
+        .ok_or_else(|| Rejection::decrypt("handshake failed to authenticate"))?;
+    let parsed = Hello::parse(&hello).map_err(|_| AppError::malformed("handshake"))?;
+    let suite = session::choose(parsed.offered)
+        .ok_or_else(|| AppError::new(ERR_REQUEST, "no supported AEAD suite offered"))?;
+    let (kem_ciphertext, kem_shared) = match parsed.kem_key {
+        Some(key) => {
+            let (ciphertext, shared) =
+                session::encapsulate(key).map_err(|_| AppError::malformed("handshake"))?;
+            (ciphertext, Some(shared))
+        }
+        None => (Vec::new(), None),
+    };
+    debug!(?suite, hybrid = kem_shared.is_some(), "Session opened");
+
+    let ephemeral = Ephemeral::generate();
+    let response = [&ephemeral.public[..], &[suite as u8], &kem_ciphertext].concat();
+    let reply = cipher.encrypt(&response, &session::reply_aad(envelope, parsed.ephemeral))?;
+    let transcript = session::transcript(&sender, &hello, &response);
+    let session = Session {
+        peer: sender,
+        cipher: ephemeral
+            .session(parsed.ephemeral, kem_shared.as_deref(), &transcript, suite)
+            .sequenced(false),
+        upload: None,
+    };
+    Ok((reply, session))
+}
+
+/// The connection's session, provided the envelope was sent with the loader key that
+/// opened it.
+fn loader_session<'a>(
+    session: Option<&'a Session>,
+    envelope: &Envelope,
+) -> Result<&'a Session, Box<dyn Error>> {
+    let session =
+        session.ok_or_else(|| Rejection::auth("loader messages need a handshake first"))?;
+    if !same_key(&envelope.sender()?, &session.peer) {
+        return Err(Rejection::auth("sender does not match the session"));
+    }
+    Ok(session)
+}
+
+/// Opens a loader payload sealed under the session as `[kind] | nonce`, accepting it
+/// only if the nonce is above the last one accepted from the loader's key, and returns
+/// the loader's name with the message. The nonce can't be bumped without breaking the
+/// seal.
+fn accept_loader(
+    loaders: &mut [LoaderKeys],
+    session: Option<&Session>,
+    envelope: &Envelope,
+) -> Result<(String, Zeroizing<Vec<u8>>), Box<dyn Error>> {
+    let session = loader_session(session, envelope)?;
+    let msg = session
+        .cipher
+        .decrypt(&envelope.payload, &envelope.aad())
+        .map(Zeroizing::new)
+        .map_err(|e| Rejection::decrypt(e.to_string()))?;
+    for loader in loaders.iter_mut() {
+        if let Some(last_seq) = loader.last_seq(&session.peer) {
+            if envelope.nonce <= *last_seq {
+                return Err(Rejection::replay("replayed or out-of-order loader message"));
+            }
+            *last_seq = envelope.nonce;
+            return Ok((loader.name.clone(), msg));
+        }
+    }
+    Err(Rejection::auth("unknown loader key"))
+}
+
+/// A request refused before any work was done, tagged with why for the metrics. Its
+/// message is what the client is told.
+#[derive(Debug)]
+struct Rejection {
+    category: &'static str,
+    reason: String,
+}
+
+impl Rejection {
+    /// The sender isn't allowed to send the message.
+    fn auth(reason: impl Into<String>) -> Box<dyn Error> {
+        Rejection::new("auth", reason)
+    }
+
+    /// The payload didn't authenticate under the expected key.
+    fn decrypt(reason: impl Into<String>) -> Box<dyn Error> {
+        Rejection::new("decrypt", reason)
+    }
+
+    /// The message's sequence number was already used.
+    fn replay(reason: impl Into<String>) -> Box<dyn Error> {
+        Rejection::new("replay", reason)
+    }
+
+    fn new(category: &'static str, reason: impl Into<String>) -> Box<dyn Error> {
+        Box::new(Rejection {
+            category,
+            reason: reason.into(),
+        })
+    }
+}
+
+impl std::fmt::Display for Rejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+impl Error for Rejection {}
+
+/// Upper bounds of the request latency buckets, in seconds
+const LATENCY_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0];
+
+#[derive(Default)]
+struct Histogram {
+    /// requests at or below each bucket's bound
+    buckets: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Request counters by message kind. Nothing about the data, datasets or senders is
+/// recorded, so exposing them doesn't weaken confidentiality.
+#[derive(Default)]
+struct Metrics {
+    requests: BTreeMap<&'static str, u64>,
+    failures: BTreeMap<&'static str, u64>,
+    /// failed requests that were refused, by `Rejection` category
+    rejections: BTreeMap<&'static str, u64>,
+    latency: BTreeMap<&'static str, Histogram>,
+}
+
+impl Metrics {
+    fn record(&mut self, msg_type: u8, elapsed: Duration, error: Option<&(dyn Error + 'static)>) {
+        let kind = match Kind::from_byte(msg_type) {
+            Some(kind) => kind.label(),
+            None => "unknown",
+        };
+        *self.requests.entry(kind).or_insert(0) += 1;
+        if let Some(error) = error {
+            *self.failures.entry(kind).or_insert(0) += 1;
+            if let Some(rejection) = error.downcast_ref::<Rejection>() {
+                *self.rejections.entry(rejection.category).or_insert(0) += 1;
+            }
+        }
+
+        let seconds = elapsed.as_secs_f64();
+        let histogram = self.latency.entry(kind).or_default();
+        for (bucket, bound) in histogram.buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        histogram.count += 1;
+        histogram.sum += seconds;
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP app_requests_total Requests by message kind.\n");
+        out.push_str("# TYPE app_requests_total counter\n");
+        for (kind, count) in &self.requests {
+            out.push_str(&format!(
+                "app_requests_total{{kind=\"{}\"}} {}\n",
+                kind, count
+            ));
+        }
+
+        out.push_str("# HELP app_failures_total Failed requests by message kind.\n");
+        out.push_str("# TYPE app_failures_total counter\n");
+        for (kind, count) in &self.failures {
+            out.push_str(&format!(
+                "app_failures_total{{kind=\"{}\"}} {}\n",
+                kind, count
+            ));
+        }
+
+        out.push_str("# HELP app_rejections_total Refused requests by reason.\n");
+        out.push_str("# TYPE app_rejections_total counter\n");
+        for category in ["auth", "decrypt", "replay"] {
+            let count = self.rejections.get(category).copied().unwrap_or(0);
+            out.push_str(&format!(
+                "app_rejections_total{{reason=\"{}\"}} {}\n",
+                category, count
+            ));
+        }
+
+        out.push_str(
+            "# HELP app_request_duration_seconds Request handling time by message kind.\n",
+        );
+        out.push_str("# TYPE app_request_duration_seconds histogram\n");
+        for (kind, histogram) in &self.latency {
+            for (bucket, bound) in histogram.buckets.iter().zip(LATENCY_BUCKETS) {
+                out.push_str(&format!(
+                    "app_request_duration_seconds_bucket{{kind=\"{}\",le=\"{}\"}} {}\n",
+                    kind, bound, bucket
+                ));
+            }
+            out.push_str(&format!(
+                "app_request_duration_seconds_bucket{{kind=\"{}\",le=\"+Inf\"}} {}\n",
+                kind, histogram.count
+            ));
+            out.push_str(&format!(
+                "app_request_duration_seconds_sum{{kind=\"{}\"}} {}\n",
+                kind, histogram.sum
+            ));
+            out.push_str(&format!(
+                "app_request_duration_seconds_count{{kind=\"{}\"}} {}\n",
+                kind, histogram.count
+            ));
+        }
+        out
+    }
+}
+
+struct State {
+    keys: Mutex<AppKeys>,
+    /// new app keys come from NSM entropy, as the startup key did
+    nsm: bool,
+    loaders: Mutex<Vec<LoaderKeys>>,
+    admin: Option<[u8; 32]>,
+    auditor: Option<[u8; 32]>,
+    /// every accepted upload, oldest first, never edited or truncated
+    audit: Mutex<Vec<AuditEntry>>,
+    /// SHA-256 of the sealed payload each current contribution was accepted as, by
+    /// dataset and loader name, the leaves of each dataset's Merkle tree
+    commitments: Mutex<BTreeMap<String, BTreeMap<String, merkle::Hash>>>,
+    /// blinding factor of each committed contribution's Pedersen commitment, by dataset
+    /// and loader name
+    blindings: Mutex<BTreeMap<String, BTreeMap<String, [u8; 32]>>>,
+    /// range-proven contributions to range-checked masked, shared and encrypted
+    /// datasets, by dataset and loader name
+    proofs: Mutex<BTreeMap<String, BTreeMap<String, Proven>>>,
+    /// idempotency keys of the latest uploads accepted from each loader, by loader
+    /// name, oldest first
+    idempotency: Mutex<BTreeMap<String, VecDeque<String>>>,
+    /// requester public key to what it may query
+    requesters: HashMap<[u8; 32], Access>,
+    /// tenants by name
+    tenants: HashMap<String, Tenant>,
+    /// tenant of each requester that belongs to one
+    requester_tenants: HashMap<[u8; 32], String>,
+    /// privacy budget each requester with a budget has spent
+    spent: Mutex<HashMap<[u8; 32], f64>>,
+    /// datasets by name, each holding the vectors most recently uploaded to it by each
+    /// loader, by loader name
+    data: Mutex<BTreeMap<String, BTreeMap<String, Vec<Vec<u64>>>>>,
+    /// schema of each dataset, declared by its first upload
+    schemas: Mutex<HashMap<String, Schema>>,
+    /// unix time each expiring contribution is removed at, by dataset and loader name
+    expiry: Mutex<BTreeMap<String, BTreeMap<String, u64>>>,
+    /// version of each dataset, bumped whenever its contributions change, which
+    /// subscriptions wait on
+    versions: watch::Sender<HashMap<String, u64>>,
+    subscribe_timeout: Duration,
+    /// sequence number of the last admin message that changes data
+    admin_seq: Mutex<u64>,
+    /// operator-owned dataset exercised by the canary binary, never mixed with real data
+    canary: Mutex<Vec<Vec<u64>>>,
+    read_timeout: Duration,
+    idle_timeout: Duration,
+    write_timeout: Duration,
+    /// largest frame payload accepted, in bytes
+    max_message: u32,
+    /// largest chunked upload, in bytes
+    max_upload: usize,
+    /// bytes chunked uploads in progress may still take, together
+    pending: Arc<Semaphore>,
+    min_contributors: usize,
+    min_bucket_count: u64,
+    /// datasets holding pairwise-masked uploads
+    masked: HashSet<String>,
+    /// datasets holding Shamir shares
+    shared: HashSet<String>,
+    /// datasets holding HyperLogLog sketches
+    sketched: HashSet<String>,
+    /// encrypted datasets by name, with the Paillier key their uploads are encrypted under
+    paillier: HashMap<String, PublicKey>,
+    /// inclusive bounds each range-checked dataset's values must lie within, as written
+    ranges: HashMap<String, (String, String)>,
+    /// unix time each poll closes at, by dataset name
+    polls: HashMap<String, u64>,
+    /// loader names of each contributor group queries may be restricted to
+    groups: HashMap<String, BTreeSet<String>>,
+    /// Paillier ciphertexts of each encrypted dataset, by loader name, one per position
+    encrypted: Mutex<BTreeMap<String, BTreeMap<String, Vec<Vec<u8>>>>>,
+    /// RA-TLS acceptor wrapping every connection
+    tls: Option<TlsAcceptor>,
+    /// connections are wrapped in a Noise channel
+    noise: bool,
+    /// signalled whenever persisted state changes, to the store and to a replica
+    dirty: watch::Sender<()>,
+    /// a replica waiting on its primary, refusing requests so its state stays the
+    /// primary's
+    standby: AtomicBool,
+    /// leadership term, replicated and raised when a standby takes over
+    term: AtomicU64,
+    /// end of the lease a primary with a replica serves under, renewed by the replica's
+    /// acknowledgements
+    lease: Option<Mutex<Instant>>,
+    /// set once a shutdown signal arrives, connections close after their current request
+    /// and waiting subscriptions are answered at once
+    shutting_down: watch::Sender<bool>,
+    metrics: Mutex<Metrics>,
+    /// signs query results and epoch bundles
+    signer: Box<dyn Signer + Send + Sync>,
+    /// query results carry an NSM attestation binding them
+    attest_results: bool,
+}
+
+/// Loaders that must have contributed to a dataset before a result over it is released,
+/// so it can't single one of them out. Masked datasets wait for every loader of the
+/// dataset's tenant, or every loader outside a tenant, since their masks only cancel then.
+fn needed(state: &State, name: &str) -> usize {
+    if state.masked.contains(name) {
+        let tenant = tenant_of(state, name).map(|(tenant, _)| tenant);
+        let loaders = state.loaders.lock().unwrap();
+        loaders
+            .iter()
+            .filter(|l| l.tenant.as_deref() == tenant)
+            .count()
+    } else {
+        tenant_of(state, name).map_or(state.min_contributors, |(_, t)| t.min_contributors)
+    }
+}
+
+/// Loaders that have contributed to a dataset.
+fn contributors(state: &State, name: &str) -> usize {
+    if state.paillier.contains_key(name) {
+        let encrypted = state.encrypted.lock().unwrap();
+        encrypted.get(name).map_or(0, BTreeMap::len)
+    } else {
+        let data = state.data.lock().unwrap();
+        data.get(name).map_or(0, BTreeMap::len)
+    }
+}
+
+/// Removes every contribution whose TTL has passed, zeroizing it.
+fn expire(state: &State) {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let mut expired = Vec::new();
+    for (dataset, loaders) in state.expiry.lock().unwrap().iter() {
+        for (loader, at) in loaders {
+            if *at <= now {
+                expired.push((dataset.clone(), loader.clone()));
+            }
+        }
+    }
+    for (dataset, loader) in expired {
+        if wipe(state, &dataset, Some(&loader)).is_ok() {
+            info!(%loader, %dataset, "Contribution expired");
+            state.dirty.send_replace(());
+        }
+    }
+}
+
+/// Sweeps out expired contributions every second, so they go even if nobody queries.
+async fn run_expiry(state: Arc<State>) {
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    loop {
+        ticker.tick().await;
+        // A standby's contributions expire with the primary's
+        if !state.standby.load(Ordering::Relaxed) {
+            expire(&state);
+        }
+    }
+}
+
+/// Marks a dataset's contributions changed, waking its subscriptions.
+fn bump(state: &State, name: &str) {
+    state
+        .versions
+        .send_modify(|versions| *versions.entry(name.to_owned()).or_default() += 1);
+}
+
+/// Dot product of the vectors the two loaders of a contributor group uploaded, one
+/// each, read as `numeric`. The requester learns nothing else of either vector, and
+/// gets nothing until the two are enough contributors for the dataset.
+fn dot(
+    state: &State,
+    name: &str,
+    numeric: Numeric,
+    members: Option<&BTreeSet<String>>,
+) -> Result<Value, Box<dyn Error>> {
+    let members = members
+        .filter(|members| members.len() == 2)
+        .ok_or("dot products need a contributor group of two loaders")?;
+    expire(state);
+    if members.len() < needed(state, name) {
+        return Err("too few contributors to release a result".into());
+    }
+    let data = state.data.lock().unwrap();
+    let contributions = data.get(name).ok_or("unknown dataset")?;
+    let vectors = members
+        .iter()
+        .map(|loader| {
+            let vectors = contributions.get(loader).map(Vec::as_slice);
+            match vectors {
+                Some([vector]) => Ok(vector),
+                Some(_) => Err("dot products need one vector from each loader"),
+                None => Err("too few contributors to release a result"),
+            }
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let [a, b] = vectors[..] else {
+        unreachable!("the group has two members");
+    };
+    if a.len() != b.len() {
+        return Err("vectors differ in length".into());
+    }
+    a.iter()
+        .zip(b)
+        .try_fold(0i128, |dot, (x, y)| {
+            let product = numeric.value(*x).checked_mul(numeric.value(*y))?;
+            dot.checked_add(product)
+        })
+        .map(Value::Int)
+        .ok_or_else(|| "dot product overflows".into())
+}
+
+/// Checks an upload to the poll `name`, closing at unix time `close`, is a ballot: one
+/// unsigned integer vector holding a single 1 and otherwise zeros, kept until the
+/// tally, cast while the poll is open.
+fn ballot(
+    name: &str,
+    close: u64,
+    schema: &Schema,
+    ttl: Option<u64>,
+    vectors: &[Vec<u64>],
+) -> Result<(), Box<dyn Error>> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    if now >= close {
+        return Err(format!("poll {} closed at {}", name, close).into());
+    }
+    let one_hot = |v: &Vec<u64>| v.iter().all(|x| *x <= 1) && v.iter().sum::<u64>() == 1;
+    let valid = matches!(vectors, [vector] if one_hot(vector));
+    if !valid || schema.numeric != Numeric::INTEGER || ttl.is_some() {
+        let reason = "a ballot is one integer vector holding a single 1, without a TTL";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    Ok(())
+}
+
+/// Admissible values of a range-checked dataset, read as `numeric`.
+fn value_range(
+    state: &State,
+    name: &str,
+    numeric: Numeric,
+) -> Result<Option<RangeInclusive<i128>>, Box<dyn Error>> {
+    let Some((min, max)) = state.ranges.get(name) else {
+        return Ok(None);
+    };
+    let bound = |b: &str| -> Result<i128, Box<dyn Error>> {
+        let reason = format!("range bound {} is not a {} value", b, numeric);
+        let bits = numeric
+            .parse(b)
+            .map_err(|_| AppError::new(ERR_SCHEMA, reason))?;
+        Ok(numeric.value(bits))
+    };
+    Ok(Some(bound(min)?..=bound(max)?))
+}
+
+/// Every loader's vectors in a dataset, combined into one computation. Refused until
+/// enough loaders have contributed.
+fn dataset(
+    state: &State,
+    name: &str,
+    group: Option<&BTreeSet<String>>,
+) -> Result<(Numeric, Vec<Vec<u64>>), Box<dyn Error>> {
+    // Nothing past its TTL is counted, even before the sweep gets to it
+    expire(state);
+    let needed = needed(state, name);
+    let data = state.data.lock().unwrap();
+    let mut contributions: Vec<_> = data.get(name).ok_or("unknown dataset")?.iter().collect();
+    if let Some(group) = group {
+        contributions.retain(|(loader, _)| group.contains(*loader));
+    }
+    if contributions.len() < needed {
+        return Err("too few contributors to release a result".into());
+    }
+    let numeric = state
+        .schemas
+        .lock()
+        .unwrap()
+        .get(name)
+        .map_or(Numeric::INTEGER, |schema| schema.numeric);
+    let vectors = contributions.into_iter().flat_map(|(_, vectors)| vectors);
+    Ok((numeric, vectors.cloned().collect()))
+}
+
+/// The tenant a stored dataset belongs to, by its `tenant/` prefix.
+fn tenant_of<'a>(state: &'a State, dataset: &str) -> Option<(&'a str, &'a Tenant)> {
+    let (tenant, _) = dataset.split_once('/')?;
+    state
+        .tenants
+        .get_key_value(tenant)
+        .map(|(name, tenant)| (name.as_str(), tenant))
+}
+
+/// Stored name of a dataset a client named: a tenant's clients reach only their own
+/// tenant's datasets, and nobody else can name one.
+fn qualify(state: &State, tenant: Option<&str>, name: String) -> Result<String, Box<dyn Error>> {
+    if !state.tenants.is_empty() && name.contains('/') {
+        return Err(Rejection::auth("dataset names may not contain '/'"));
+    }
+    Ok(match tenant {
+        Some(tenant) => format!("{}/{}", tenant, name),
+        None => name,
+    })
+}
+
+/// Tenant of the loader named `loader`, if it belongs to one.
+fn loader_tenant(state: &State, loader: &str) -> Option<String> {
+    state
+        .loaders
+        .lock()
+        .unwrap()
+        .iter()
+        .find(|l| l.name == loader)
+        .and_then(|l| l.tenant.clone())
+}
+
+/// Opens an admin payload, provided the envelope was sent with the admin key.
+fn admin(state: &State, envelope: &Envelope) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let admin = state
+        .admin
+        .ok_or_else(|| Rejection::auth("no admin key configured"))?;
+    if !same_key(&envelope.sender()?, &admin) {
+        return Err(Rejection::auth("not sent with the admin key"));
+    }
+    let (_, msg) = open(state, &admin, &envelope.payload, &envelope.aad())
+        .ok_or_else(|| Rejection::decrypt("admin message failed to authenticate"))?;
+    Ok(msg)
+}
+
+/// Accepts `seq` if it is above the last admin message that changed data.
+fn admin_sequence(state: &State, seq: u64) -> Result<(), Box<dyn Error>> {
+    let mut admin_seq = state.admin_seq.lock().unwrap();
+    if seq <= *admin_seq {
+        return Err(Rejection::replay("replayed or out-of-order admin message"));
+    }
+    *admin_seq = seq;
+    Ok(())
+}
+
+/// Starts a loader key rotation. The payload is `new_pub | seal_old(new_pub) |
+/// seal_new(new_pub)`, sent with the current key: it vouches for the new one and the
+/// new key proves possession of its secret. The nonce counts against the current key,
+/// as for uploads.
+fn begin_rotation(state: &State, envelope: &Envelope) -> Result<(), Box<dyn Error>> {
+    let sealed_key_len = hpke::sealed_len(32);
+    let payload = &envelope.payload;
+    if payload.len() != 32 + 2 * sealed_key_len {
+        return Err(AppError::malformed("rotation message"));
+    }
+    let (new_pub, rest) = payload.split_at(32);
+    let (by_old, by_new) = rest.split_at(sealed_key_len);
+    let seq = envelope.nonce;
+    let aad = envelope.aad();
+    let new_pub: [u8; 32] = new_pub.try_into()?;
+
+    let sender = envelope.sender()?;
+    let certified = |key: &[u8; 32], sealed: &[u8]| {
+        open(state, key, sealed, &aad).is_some_and(|(_, msg)| *msg == new_pub)
+    };
+    if !certified(&sender, by_old) || !certified(&new_pub, by_new) {
+        return Err(Rejection::decrypt("rotation cross-certification failed"));
+    }
+    let mut loaders = state.loaders.lock().unwrap();
+    let loader = loaders
+        .iter_mut()
+        .find(|loader| same_key(&loader.current_pub, &sender))
+        .ok_or_else(|| Rejection::auth("unknown loader key"))?;
+    if seq <= loader.current_seq {
+        return Err(Rejection::replay("replayed or out-of-order loader message"));
+    }
+    loader.current_seq = seq;
+    // Re-announcing the pending key keeps its counter, so its uploads stay unreplayable
+    if loader.pending != Some(new_pub) {
+        loader.pending_seq = 0;
+    }
+    loader.pending = Some(new_pub);
+    Ok(())
+}
+
+/// Retires a loader's current key in favour of its pending one. The payload is the
+/// pending public key sealed under the admin key.
+fn finalize_rotation(state: &State, envelope: &Envelope) -> Result<(), Box<dyn Error>> {
+    let new_pub = admin(state, envelope)?;
+
+    let mut loaders = state.loaders.lock().unwrap();
+    for loader in loaders.iter_mut() {
+        match loader.pending.take() {
+            Some(pending_pub) if pending_pub[..] == new_pub[..] => {
+                loader.current_pub = pending_pub;
+                loader.current_seq = loader.pending_seq;
+                loader.pending_seq = 0;
+                return Ok(());
+            }
+            pending => loader.pending = pending,
+        }
+    }
+    Err("no matching rotation pending".into())
+}
+
+/// Deletes a dataset with every loader's contribution to it. The payload is the name
+/// sealed under the admin key, sequenced so it can't be replayed to delete a later
+/// dataset of the same name.
+fn drop_dataset(state: &State, envelope: &Envelope) -> Result<(), Box<dyn Error>> {
+    let seq = envelope.nonce;
+    let name = String::from_utf8(admin(state, envelope)?.to_vec())
+        .map_err(|_| AppError::malformed("dataset name"))?;
+    admin_sequence(state, seq)?;
+
+    wipe(state, &name, None)?;
+    info!(dataset = %name, "Dataset dropped");
+    Ok(())
+}
+
+/// Removes a dataset, or only `loader`'s contribution to it, zeroizing the removed
+/// vectors. A dataset left without contributions goes with its last one.
+fn wipe(state: &State, name: &str, loader: Option<&str>) -> Result<(), Box<dyn Error>> {
+    if state.paillier.contains_key(name) {
+        remove(&mut state.encrypted.lock().unwrap(), name, loader)?;
+    } else {
+        let mut data = state.data.lock().unwrap();
+        remove(&mut data, name, loader)?;
+        if !data.contains_key(name) {
+            state.schemas.lock().unwrap().remove(name);
+        }
+    }
+    forget(&mut state.expiry.lock().unwrap(), name, loader);
+    forget(&mut state.commitments.lock().unwrap(), name, loader);
+    forget(&mut state.blindings.lock().unwrap(), name, loader);
+    forget(&mut state.proofs.lock().unwrap(), name, loader);
+    bump(state, name);
+    Ok(())
+}
+
+/// Drops what is kept about a dataset, or only about `loader`'s contribution to it.
+fn forget<T>(map: &mut BTreeMap<String, BTreeMap<String, T>>, name: &str, loader: Option<&str>) {
+    if let Some(loaders) = map.get_mut(name) {
+        match loader {
+            Some(loader) => {
+                loaders.remove(loader);
+            }
+            None => loaders.clear(),
+        }
+        if loaders.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+fn remove<T: Zeroize>(
+    data: &mut BTreeMap<String, BTreeMap<String, T>>,
+    name: &str,
+    loader: Option<&str>,
+) -> Result<(), Box<dyn Error>> {
+    let contributions = data.get_mut(name).ok_or("unknown dataset")?;
+    match loader {
+        Some(loader) => contributions
+            .remove(loader)
+            .ok_or("no contribution to wipe")?
+            .zeroize(),
+        None => contributions.values_mut().for_each(Zeroize::zeroize),
+    }
+    if loader.is_none() || contributions.is_empty() {
+        data.remove(name);
+    }
+    Ok(())
+}
+
+/// Rotates the app's own key. The payload is the grace window in seconds, a big-endian
+/// u64 sealed under the admin key and sequenced like other admin changes. Messages
+/// sealed for the old key are accepted until the window closes; returns the new public
+/// key, which attestations carry from then on.
+fn rotate_app_key(state: &State, envelope: &Envelope) -> Result<[u8; 32], Box<dyn Error>> {
+    let seq = envelope.nonce;
+    let grace = admin(state, envelope)?;
+    let grace: [u8; 8] = grace
+        .as_slice()
+        .try_into()
+        .map_err(|_| AppError::malformed("app key rotation"))?;
+    admin_sequence(state, seq)?;
+
+    let mut secret = Locked::<[u8; 32]>::default();
+    if state.nsm {
+        *secret = nsm_secret()?;
+    } else {
+        OsRng.fill_bytes(&mut secret[..]);
+    }
+    let mut keys = state.keys.lock().unwrap();
+    keys.rotate(secret, Duration::from_secs(u64::from_be_bytes(grace)));
+    Ok(keys.public())
+}
+
+/// Answers a result query with the view granted to the requester. The payload is
+/// `seal(dataset | mode | op | weights)`, where only weighted sums carry weights, and
+/// the result is sealed back to the requester as `op | receipt`, echoing the operation
+/// ahead of the JSON result receipt signed by the app's result key and, in an enclave,
+/// attested. A requester that opened a session seals both under it instead.
+fn answer_query(
+    state: &State,
+    session: Option<&Session>,
+    envelope: &Envelope,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Nothing is computed or released until the query authenticates under a registered
+    // requester key. Unknown keys and bad ciphertexts fail alike, so probing can't tell
+    // which keys are registered.
+    let aad = envelope.aad();
+    let (sender, access, (cipher, query)) = envelope
+        .sender()
+        .ok()
+        .and_then(|sender| {
+            let access = state.requesters.get(&sender)?;
+            let opened = open_request(state, session, &sender, &envelope.payload, &aad)?;
+            Some((sender, access, opened))
+        })
+        .ok_or_else(|| Rejection::auth("unauthenticated query"))?;
+    let view = &access.view;
+    let (requested, query) = sum::split_dataset(&query)?;
+    // The weights stay inside the enclave like the data they score
+    let Query {
+        mode,
+        op,
+        percentile,
+        group,
+        weights,
+    } = Query::decode(query)?;
+    // Noise is calibrated to how much one value can move a sum, and percentiles are
+    // drawn by a mechanism of their own
+    let noisable = matches!(op, Op::Sum | Op::Median | Op::Percentile);
+    if !noisable && !matches!(view, View::Exact) {
+        return Err(Rejection::auth(format!(
+            "{} is not permitted by the requester's view",
+            op
+        )));
+    }
+    // Checked before the dataset is looked up, so a denied requester can't even learn
+    // which datasets exist
+    access
+        .permits(&requested, op)
+        .map_err(|e| Rejection::auth(e.to_string()))?;
+    let tenant = state.requester_tenants.get(&sender).map(String::as_str);
+    let name = qualify(state, tenant, requested.clone())?;
+    let members = match &group {
+        Some(group) => Some(
+            state
+                .groups
+                .get(group)
+                .ok_or_else(|| format!("unknown contributor group {}", group))?,
+        ),
+        None => None,
+    };
+    // Masks and shares only add up over every loader, and ciphertexts aren't filtered
+    let opaque = state.paillier.contains_key(&name)
+        || state.masked.contains(&name)
+        || state.shared.contains(&name);
+    if members.is_some() && opaque {
+        return Err("contributor groups only apply to plaintext datasets".into());
+    }
+    // Sketches only answer how many distinct identifiers they hold
+    if state.sketched.contains(&name) != (op == Op::Distinct) {
+        return Err("sketch datasets answer distinct counts, and only they do".into());
+    }
+    // Nothing of a poll is released but its final tally, and only once it has closed
+    if let Some(close) = state.polls.get(&name) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        if now < *close {
+            return Err(format!("poll {} closes at {}", requested, close).into());
+        }
+        if op != Op::Sum || mode != Mode::Elementwise || members.is_some() {
+            return Err("polls only answer their tally, an elementwise sum".into());
+        }
+    }
+
+    let result = if op == Op::Histogram {
+        if opaque {
+            return Err("histograms are only answered over plaintext datasets".into());
+        }
+        let (numeric, data) = dataset(state, &name, members)?;
+        let (buckets, suppressed) = sum::histogram(numeric, &data, state.min_bucket_count)?;
+        let buckets: Vec<_> = buckets
+            .into_iter()
+            .map(|(bucket, Bucket { count, sums })| {
+                let sum = match mode {
+                    Mode::Total => serde_json::json!(numeric.format(sums.iter().sum())),
+                    Mode::Elementwise => {
+                        let sums: Vec<_> = sums.into_iter().map(|s| numeric.format(s)).collect();
+                        serde_json::json!(sums)
+                    }
+                };
+                serde_json::json!({
+                    "bucket": numeric.format(bucket),
+                    "count": count,
+                    "sum": sum,
+                })
+            })
+            .collect();
+        serde_json::json!({ "buckets": buckets, "suppressed": suppressed }).to_string()
+    } else if let Some(key) = state.paillier.get(&name) {
+        // Added as ciphertexts: the app never sees a plaintext, and only the holder of
+        // the private key can open the sum
+        if op != Op::Sum || view.epsilon().is_some() {
+            return Err("encrypted datasets only answer exact sums".into());
+        }
+        encrypted_sum(state, key, &name, mode)?
+    } else {
+        let (numeric, data) = dataset(state, &name, members)?;
+        let values = if state.masked.contains(&name) {
+            // Only the sum survives the masks
+            if op != Op::Sum {
+                return Err(format!("{} is not available on a masked dataset", op).into());
+            }
+            match mode {
+                Mode::Total => vec![Value::Int(total(state, &name, numeric, &data)?)],
+                Mode::Elementwise => {
+                    let sums: Vec<i128> = sum::wrapping_sums(&data)?
+                        .into_iter()
+                        .map(|sum| numeric.value(sum))
+                        .collect();
+                    open_masked(state, &name, mode, &sums)?;
+                    sums.into_iter().map(Value::Int).collect()
+                }
+            }
+        } else if state.shared.contains(&name) {
+            // The answer is this instance's share of the sum, which noise would corrupt
+            if op != Op::Sum || view.epsilon().is_some() {
+                return Err("shared datasets only answer exact sums".into());
+            }
+            match mode {
+                Mode::Total => vec![Value::Int(total(state, &name, numeric, &data)?)],
+                Mode::Elementwise => shamir::sums(&data)?
+                    .into_iter()
+                    .map(|sum| Value::Int(sum as i128))
+                    .collect(),
+            }
+        } else {
+            match (mode, op) {
+                (mode, Op::Median | Op::Percentile) => {
+                    let rank = percentile.unwrap_or(50);
+                    let bounds = value_range(state, &name, numeric)?
+                        .map(|range| (*range.start(), *range.end()));
+                    let samples = match mode {
+                        Mode::Total => {
+                            vec![data.iter().flatten().map(|v| numeric.value(*v)).collect()]
+                        }
+                        Mode::Elementwise => sum::positions(numeric, &data)?,
+                    };
+                    samples
+                        .into_iter()
+                        .map(|mut sample: Vec<i128>| {
+                            sample.sort_unstable();
+                            view.percentile(&sample, rank, bounds)
+                        })
+                        .collect::<Result<_, _>>()?
+                }
+                (Mode::Total, Op::Dot) => vec![dot(state, &name, numeric, members)?],
+                (Mode::Total, Op::Distinct) => {
+                    let estimate = hll::estimate(&hll::merge(&data)?)?;
+                    vec![Value::Int(estimate.round() as i128)]
+                }
+                (Mode::Elementwise, Op::Distinct) => {
+                    return Err("distinct counts are only answered in total".into());
+                }
+                (Mode::Elementwise, Op::Dot) => {
+                    return Err("dot products are only answered in total".into());
+                }
+                (Mode::Total, Op::Weighted) => {
+                    let sums = sum::weighted(numeric, &data, &weights)?;
+                    vec![sum::aggregate(Op::Sum, numeric, sums)?]
+                }
+                (Mode::Total, op) => {
+                    let values = data.iter().flatten().map(|v| numeric.value(*v));
+                    vec![sum::aggregate(op, numeric, values)?]
+                }
+                (Mode::Elementwise, Op::Weighted) => sum::weighted(numeric, &data, &weights)?
+                    .into_iter()
+                    .map(Value::Int)
+                    .collect(),
+                (Mode::Elementwise, op) => sum::elementwise(op, numeric, &data)?,
+            }
+        };
+        // Every noised value released costs epsilon, charged before any is rendered
+        if let Some(epsilon) = view.epsilon() {
+            spend(state, &sender, access, epsilon * values.len() as f64)?;
+        }
+        // Results come back in the dataset's declared type, except counts, and products
+        // carry both factors' decimal places
+        let numeric = match op {
+            Op::Count | Op::Distinct => Numeric::INTEGER,
+            Op::Dot => Numeric {
+                scale: numeric.scale * 2,
+                ..numeric
+            },
+            _ => numeric,
+        };
+        let render = |value: Value| match value {
+            Value::Int(v) => numeric.format(view.apply(v)),
+            Value::Float(v) => numeric.unscale(v).to_string(),
+        };
+        match mode {
+            Mode::Total => render(values[0]),
+            Mode::Elementwise => {
+                let values: Vec<String> = values.into_iter().map(render).collect();
+                format!("[{}]", values.join(", "))
+            }
+        }
+    };
+
+    // A sum recovered from shares or decrypted by the requester is checked there
+    let (range_commitments, range_openings) = range_sums(state, &name, mode)?.unzip();
+    let claims = ResultClaims {
+        dataset: requested,
+        op: op.to_string(),
+        elementwise: mode == Mode::Elementwise,
+        result,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        merkle_root: merkle_root(state, &name).map(hex::encode),
+        percentile,
+        group: group.clone(),
+        // Blinding factors open the whole dataset's commitments, not a group's
+        blinding: if mode == Mode::Total
+            && op == Op::Sum
+            && group.is_none()
+            && proves_sums(state, &name, view)
+        {
+            blinding(state, &name).map(|b| hex::encode(b.to_bytes()))
+        } else {
+            None
+        },
+        range_commitments,
+        range_openings,
+    };
+    let receipt = Receipt::for_result(state.signer.as_ref(), &claims);
+    // A fresh document per answer, so the receipt stands on its own without trusting
+    // the result key
+    let attestation = if state.attest_results {
+        let public = state.keys.lock().unwrap().public();
+        let binding = ResultBinding {
+            merkle_root: claims.merkle_root.clone(),
+            ..ResultBinding::new(&receipt)
+        };
+        let binding = serde_json::to_vec(&binding)?;
+        Some(hex::encode(nsm_attestation(&public, Some(binding))?))
+    } else {
+        None
+    };
+    let result = AttestedResult {
+        receipt,
+        attestation,
+    };
+    let mut response = Zeroizing::new(vec![op.to_byte()]);
+    response.extend(serde_json::to_vec(&result)?);
+    // Results use the reply AAD so a query can't be reflected back as a response
+    cipher.encrypt(&response, &envelope.reply_aad())
+}
+
+/// Answers a subscription once the dataset holds a new aggregate: its contributions
+/// changed since the version the requester last saw, and enough loaders have
+/// contributed for a result. The payload is `seal(dataset | version)`, the version
+/// little-endian and 0 for a first subscription, and the reply is a sealed JSON
+/// `{"dataset", "version", "ready"}`. One still waiting after `--subscribe-timeout`, or
+/// when the app shuts down, is answered as not ready, for the requester to renew.
+async fn subscribe(
+    state: &State,
+    session: Option<&Session>,
+    envelope: &Envelope,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Authenticated like a query, so subscribing can't probe for datasets either
+    let aad = envelope.aad();
+    let (sender, access, (cipher, msg)) = envelope
+        .sender()
+        .ok()
+        .and_then(|sender| {
+            let access = state.requesters.get(&sender)?;
+            let opened = open_request(state, session, &sender, &envelope.payload, &aad)?;
+            Some((sender, access, opened))
+        })
+        .ok_or_else(|| Rejection::auth("unauthenticated subscription"))?;
+    let (requested, since) = sum::split_dataset(&msg)?;
+    let since: [u8; 8] = since
+        .try_into()
+        .map_err(|_| AppError::malformed("subscription"))?;
+    let since = u64::from_le_bytes(since);
+    access
+        .permits_dataset(&requested)
+        .map_err(|e| Rejection::auth(e.to_string()))?;
+    let tenant = state.requester_tenants.get(&sender).map(String::as_str);
+    let name = qualify(state, tenant, requested.clone())?;
+
+    let mut versions = state.versions.subscribe();
+    let mut shutdown = state.shutting_down.subscribe();
+    let deadline = tokio::time::sleep(state.subscribe_timeout);
+    tokio::pin!(deadline);
+    let version = loop {
+        let version = versions.borrow_and_update().get(&name).copied();
+        if let Some(version) = version.filter(|v| *v > since) {
+            if contributors(state, &name) >= needed(state, &name) {
+                break Some(version);
+            }
+        }
+        tokio::select! {
+            changed = versions.changed() => {
+                if changed.is_err() {
+                    break None;
+                }
+            }
+            _ = shutdown.wait_for(|down| *down) => break None,
+            _ = &mut deadline => break None,
+        }
+    };
+    let notice = serde_json::json!({
+        "dataset": requested,
+        "version": version.unwrap_or(since),
+        "ready": version.is_some(),
+    });
+    cipher.encrypt(notice.to_string().as_bytes(), &envelope.reply_aad())
+}
+
+/// Charges `cost` against the requester's privacy budget, refusing the query if it
+/// would overrun. Spending is basic composition: the epsilons of every answer add up.
+fn spend(
+    state: &State,
+    requester: &[u8; 32],
+    access: &Access,
+    cost: f64,
+) -> Result<(), Box<dyn Error>> {
+    let Some(budget) = access.budget else {
+        return Ok(());
+    };
+    let mut spent = state.spent.lock().unwrap();
+    let spent = spent.entry(*requester).or_default();
+    if *spent + cost > budget {
+        return Err(Rejection::auth(format!(
+            "privacy budget exhausted: {:.3} of {} spent",
+            spent, budget
+        )));
+    }
+    *spent += cost;
+    state.dirty.send_replace(());
+    Ok(())
+}
+
+/// Homomorphic sum of an encrypted dataset, as hex ciphertexts: one for the total, or
+/// one per position rendered as a list.
+fn encrypted_sum(
+    state: &State,
+    key: &PublicKey,
+    name: &str,
+    mode: Mode,
+) -> Result<String, Box<dyn Error>> {
+    let needed = needed(state, name);
+    let encrypted = state.encrypted.lock().unwrap();
+    let contributions = encrypted.get(name).ok_or("unknown dataset")?;
+    if contributions.len() < needed {
+        return Err("too few contributors to release a result".into());
+    }
+    let width = contributions.values().next().map_or(0, Vec::len);
+    if contributions.values().any(|c| c.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    let mut sums = vec![key.zero()?; width];
+    for ciphertexts in contributions.values() {
+        for (sum, c) in sums.iter_mut().zip(ciphertexts) {
+            *sum = key.add(sum, c)?;
+        }
+    }
+    Ok(match mode {
+        Mode::Total => {
+            let total = sums
+                .iter()
+                .try_fold(key.zero()?, |total, sum| key.add(&total, sum))?;
+            hex::encode(total)
+        }
+        Mode::Elementwise => {
+            let sums: Vec<String> = sums.iter().map(hex::encode).collect();
+            format!("[{}]", sums.join(", "))
+        }
+    })
+}
+
+/// Stores a loader's Paillier ciphertexts for an encrypted dataset, one per position of
+/// a single vector, replacing its previous upload, and returns the dataset's name. The
+/// payload is `dataset | ciphertexts`, each ciphertext as long as the dataset key's
+/// `n^2`.
+fn contribute_encrypted(
+    state: &State,
+    loader: String,
+    tenant: Option<&str>,
+    msg: &[u8],
+    proven: Option<Proven>,
+) -> Result<String, Box<dyn Error>> {
+    let (name, ciphertexts) = sum::split_dataset(msg)?;
+    let name = qualify(state, tenant, name)?;
+    let key = state
+        .paillier
+        .get(&name)
+        .ok_or("not an encrypted dataset")?;
+    let len = key.ciphertext_len();
+    if ciphertexts.is_empty() || ciphertexts.len() % len != 0 {
+        return Err(AppError::malformed("encrypted upload"));
+    }
+    let ciphertexts: Vec<Vec<u8>> = ciphertexts.chunks(len).map(<[u8]>::to_vec).collect();
+    for c in &ciphertexts {
+        key.check(c)?;
+    }
+    let covered = proven.as_ref().map(|p| p.commitments.len());
+    if state.ranges.contains_key(&name) && covered != Some(ciphertexts.len()) {
+        let reason = "uploads to the dataset need a range proof covering every value";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    info!(%loader, dataset = %name, values = ciphertexts.len(), "Encrypted upload accepted");
+    state
+        .encrypted
+        .lock()
+        .unwrap()
+        .entry(name.clone())
+        .or_default()
+        .insert(loader.clone(), ciphertexts);
+    keep_proof(state, &name, &loader, proven);
+    bump(state, &name);
+    state.dirty.send_replace(());
+    Ok(name)
+}
+
+/// An accepted upload, as the audit log records it: who sent which sealed payload to
+/// which dataset and when, without anything of the plaintext.
+#[derive(Clone, Serialize, Deserialize)]
+struct AuditEntry {
+    loader: String,
+    /// hex public key the upload was sent with
+    key: String,
+    dataset: String,
+    /// hex SHA-256 of the sealed payload, or of every sealed chunk in order
+    ciphertext_sha256: String,
+    /// sequence number the upload was sent under
+    sequence: u64,
+    /// unix time the upload was accepted
+    timestamp: u64,
+}
+
+/// Merkle root over a dataset's current contributions, ordered by loader name.
+fn merkle_root(state: &State, name: &str) -> Option<merkle::Hash> {
+    let commitments = state.commitments.lock().unwrap();
+    let leaves: Vec<merkle::Hash> = commitments
+        .get(name)?
+        .iter()
+        .map(|(loader, digest)| merkle::leaf(loader, digest))
+        .collect();
+    Some(merkle::root(&leaves))
+}
+
+/// Whether exact sums of a dataset can be checked against its loaders' commitments.
+/// Noise, masks, shares and ciphertexts all change what is added from what was
+/// committed to.
+fn proves_sums(state: &State, name: &str, view: &View) -> bool {
+    matches!(view, View::Exact)
+        && !state.paillier.contains_key(name)
+        && !state.masked.contains(name)
+        && !state.shared.contains(name)
+}
+
+/// Sum of the blinding factors of a dataset's contributions, which opens the sum of
+/// their Pedersen commitments to the dataset's total. Only there when every current
+/// contribution was committed to.
+fn blinding(state: &State, name: &str) -> Option<Scalar> {
+    let data = state.data.lock().unwrap();
+    let blindings = state.blindings.lock().unwrap();
+    let (contributors, blindings) = (data.get(name)?, blindings.get(name)?);
+    if !contributors.keys().eq(blindings.keys()) {
+        return None;
+    }
+    blindings.values().map(|b| pedersen::opening(b).ok()).sum()
+}
+
+/// Splits the blinding factor off the front of a committed upload.
+fn take_blinding(msg: &[u8]) -> Result<(Scalar, &[u8]), Box<dyn Error>> {
+    if msg.len() < 32 {
+        return Err(AppError::malformed("committed upload"));
+    }
+    let (blinding, rest) = msg.split_at(32);
+    let blinding =
+        pedersen::opening(blinding).map_err(|e| AppError::new(ERR_SCHEMA, e.to_string()))?;
+    Ok((blinding, rest))
+}
+
+/// A loader's range-proven contribution to a masked, shared or encrypted dataset: its
+/// commitment to each value, in order, and what opens each once aggregated, the
+/// blinding factor masked, Shamir-shared or Paillier-encrypted like the value.
+#[derive(Clone, Serialize, Deserialize)]
+struct Proven {
+    commitments: Vec<pedersen::Commitment>,
+    openings: Vec<Vec<u8>>,
+    /// length of the contribution's vectors
+    width: usize,
+}
+
+/// Verifies the range proof `envelope` carries for its upload to the masked, shared or
+/// encrypted dataset `name`, which the loader asked for as `requested`, and checks each
+/// opening is a scalar or, for an encrypted dataset, a ciphertext under its key.
+fn proven(
+    state: &State,
+    envelope: &Envelope,
+    requested: &str,
+    name: &str,
+    numeric: Numeric,
+    proof: Proof,
+    openings: Vec<Vec<u8>>,
+) -> Result<Proven, Box<dyn Error>> {
+    let key = state.paillier.get(name);
+    let opaque = key.is_some() || state.masked.contains(name) || state.shared.contains(name);
+    let Some(range) = value_range(state, name, numeric)?.filter(|_| opaque) else {
+        let reason = "only range-checked masked, shared and encrypted datasets take proofs";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    };
+    proof
+        .verify(&envelope.sender, requested, &range)
+        .map_err(|e| AppError::new(ERR_SCHEMA, format!("range proof rejected: {}", e)))?;
+    for opening in &openings {
+        let valid = match key {
+            Some(key) => key.check(opening).is_ok(),
+            None => pedersen::opening(opening).is_ok(),
+        };
+        if !valid {
+            return Err(AppError::malformed("range proof opening"));
+        }
+    }
+    Ok(Proven {
+        width: proof.commitments.len(),
+        commitments: proof.commitments,
+        openings,
+    })
+}
+
+/// Keeps `loader`'s range-proven contribution, or forgets the previous one's proof.
+fn keep_proof(state: &State, name: &str, loader: &str, proven: Option<Proven>) {
+    let mut proofs = state.proofs.lock().unwrap();
+    match proven {
+        Some(proven) => {
+            let loaders = proofs.entry(name.to_owned()).or_default();
+            loaders.insert(loader.to_owned(), proven);
+        }
+        None => forget(&mut proofs, name, Some(loader)),
+    }
+}
+
+/// A range-checked dataset's commitments and openings gathered per value of a `mode`
+/// sum: one group for the total, or one per position.
+fn proven_sums(
+    state: &State,
+    name: &str,
+    mode: Mode,
+) -> Result<Vec<(Vec<pedersen::Commitment>, Vec<Vec<u8>>)>, Box<dyn Error>> {
+    let proofs = state.proofs.lock().unwrap();
+    let loaders = proofs.get(name).ok_or("no range proofs for the dataset")?;
+    let width = loaders.values().next().map_or(0, |p| p.width);
+    if loaders.values().any(|p| p.width != width) || width == 0 {
+        return Err("vectors differ in length".into());
+    }
+    let slots = match mode {
+        Mode::Total => 1,
+        Mode::Elementwise => width,
+    };
+    let mut sums = vec![(Vec::new(), Vec::new()); slots];
+    for proven in loaders.values() {
+        let values = proven.commitments.iter().zip(&proven.openings);
+        for (i, (commitment, opening)) in values.enumerate() {
+            let slot = match mode {
+                Mode::Total => 0,
+                Mode::Elementwise => i % width,
+            };
+            let (commitments, openings) = &mut sums[slot];
+            commitments.push(*commitment);
+            openings.push(opening.clone());
+        }
+    }
+    Ok(sums)
+}
+
+/// Checks the unmasked sums of a range-checked masked dataset, one for the total or one
+/// per position, open the sums of its loaders' range-proven commitments under the sums
+/// of their masked blinding factors. A loader that masked values other than those it
+/// proved in range is caught here, before the sum is released.
+fn open_masked(state: &State, name: &str, mode: Mode, sums: &[i128]) -> Result<(), Box<dyn Error>> {
+    if !state.ranges.contains_key(name) {
+        return Ok(());
+    }
+    let proven = proven_sums(state, name, mode)?;
+    if proven.len() != sums.len() {
+        return Err("range proofs don't cover the dataset".into());
+    }
+    for (sum, (commitments, openings)) in sums.iter().zip(proven) {
+        let blinding = openings
+            .iter()
+            .map(|o| pedersen::opening(o))
+            .sum::<Result<Scalar, _>>()?;
+        if !pedersen::verify(&commitments, *sum, &blinding) {
+            return Err("masked sum doesn't open the loaders' range-proven commitments".into());
+        }
+    }
+    Ok(())
+}
+
+/// For a range-checked shared or encrypted dataset, the hex sums of its commitments and
+/// of their openings per value of a `mode` sum, for the requester to check the sum it
+/// recovers against: this instance's share of the blinding factors' sum, or that sum
+/// encrypted. The app can't check these sums itself, since it never sees them.
+fn range_sums(
+    state: &State,
+    name: &str,
+    mode: Mode,
+) -> Result<Option<(Vec<String>, Vec<String>)>, Box<dyn Error>> {
+    let key = state.paillier.get(name);
+    if !state.ranges.contains_key(name) || (key.is_none() && !state.shared.contains(name)) {
+        return Ok(None);
+    }
+    let mut commitments = Vec::new();
+    let mut openings = Vec::new();
+    for (values, opened) in proven_sums(state, name, mode)? {
+        let sum = pedersen::sum(&values).ok_or("malformed commitment")?;
+        commitments.push(hex::encode(sum));
+        let opening = match key {
+            Some(key) => opened
+                .iter()
+                .try_fold(key.zero()?, |sum, o| key.add(&sum, o))?,
+            None => {
+                let share = opened
+                    .iter()
+                    .map(|o| pedersen::opening(o))
+                    .sum::<Result<Scalar, _>>()?;
+                share.to_bytes().to_vec()
+            }
+        };
+        openings.push(hex::encode(opening));
+    }
+    Ok(Some((commitments, openings)))
+}
+
+/// Proof that `loader`'s contribution is a leaf of the dataset's Merkle tree, as JSON
+/// for the loader to check against the root a receipt shows.
+fn inclusion(state: &State, name: &str, loader: &str) -> Result<String, Box<dyn Error>> {
+    let commitments = state.commitments.lock().unwrap();
+    let contributions = commitments.get(name).ok_or("unknown dataset")?;
+    let index = contributions
+        .keys()
+        .position(|l| l == loader)
+        .ok_or("no contribution to prove")?;
+    let leaves: Vec<merkle::Hash> = contributions
+        .iter()
+        .map(|(loader, digest)| merkle::leaf(loader, digest))
+        .collect();
+    let proof: Vec<String> = merkle::proof(&leaves, index)
+        .iter()
+        .map(hex::encode)
+        .collect();
+    let inclusion = serde_json::json!({
+        "loader": loader,
+        "ciphertext_sha256": hex::encode(contributions[loader]),
+        "index": index,
+        "count": leaves.len(),
+        "proof": proof,
+        "merkle_root": hex::encode(merkle::root(&leaves)),
+    });
+    Ok(inclusion.to_string())
+}
+
+/// Entries returned per audit log read
+const AUDIT_PAGE: usize = 1000;
+
+/// Idempotency keys remembered per loader
+const IDEMPOTENCY_KEYS: usize = 64;
+
+/// Whether `envelope` retries an upload already accepted from `loader`, carrying the
+/// same idempotency key.
+fn already_applied(state: &State, loader: &str, envelope: &Envelope) -> bool {
+    let Some(key) = &envelope.idempotency_key else {
+        return false;
+    };
+    let idempotency = state.idempotency.lock().unwrap();
+    idempotency
+        .get(loader)
+        .is_some_and(|keys| keys.contains(key))
+}
+
+/// Appends an accepted upload to the audit log, makes it its loader's leaf in the
+/// dataset's Merkle tree and remembers its idempotency key.
+fn audit(state: &State, envelope: &Envelope, loader: String, dataset: String, digest: &[u8]) {
+    if let Some(key) = &envelope.idempotency_key {
+        let mut idempotency = state.idempotency.lock().unwrap();
+        let keys = idempotency.entry(loader.clone()).or_default();
+        keys.push_back(key.clone());
+        if keys.len() > IDEMPOTENCY_KEYS {
+            keys.pop_front();
+        }
+    }
+    if let Ok(leaf) = digest.try_into() {
+        let mut commitments = state.commitments.lock().unwrap();
+        let leaves = commitments.entry(dataset.clone()).or_default();
+        leaves.insert(loader.clone(), leaf);
+    }
+    let entry = AuditEntry {
+        loader,
+        key: hex::encode(&envelope.sender),
+        dataset,
+        ciphertext_sha256: hex::encode(digest),
+        sequence: envelope.nonce,
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+    };
+    state.audit.lock().unwrap().push(entry);
+    state.dirty.send_replace(());
+}
+
+/// Answers an auditor with a page of the audit log. The payload is `seal(from)`, the
+/// index of the first entry as a little-endian u64, and the reply is sealed JSON
+/// `{"entries": [...], "next": index}`, with no entries once the log is read to the end.
+fn audit_log(state: &State, envelope: &Envelope) -> Result<Vec<u8>, Box<dyn Error>> {
+    let auditor = state
+        .auditor
+        .ok_or_else(|| Rejection::auth("no auditor key configured"))?;
+    if !same_key(&envelope.sender()?, &auditor) {
+        return Err(Rejection::auth("not sent with the auditor key"));
+    }
+    let (cipher, from) = open(state, &auditor, &envelope.payload, &envelope.aad())
+        .ok_or_else(|| Rejection::decrypt("audit log read failed to authenticate"))?;
+    let from: [u8; 8] = from
+        .as_slice()
+        .try_into()
+        .map_err(|_| AppError::malformed("audit log read"))?;
+    let from = usize::try_from(u64::from_le_bytes(from)).unwrap_or(usize::MAX);
+
+    let audit = state.audit.lock().unwrap();
+    let entries = audit.get(from..).unwrap_or_default();
+    let entries = &entries[..entries.len().min(AUDIT_PAGE)];
+    let page = serde_json::json!({
+        "entries": entries,
+        "next": from.saturating_add(entries.len()),
+    });
+    drop(audit);
+    cipher.encrypt(page.to_string().as_bytes(), &envelope.reply_aad())
+}
+
+/// Makes `vectors` the loader's contribution to a dataset, creating the dataset on
+/// first use with the schema the upload declares. Later uploads must declare the same
+/// type and labels. Each upload replaces only its own loader's contribution; the
+/// vectors it replaces are zeroized. With a TTL the contribution is removed that many
+/// seconds from now, otherwise it stays until replaced or wiped.
+fn contribute(
+    state: &State,
+    dataset: String,
+    loader: String,
+    schema: Schema,
+    ttl: Option<u64>,
+    vectors: Vec<Vec<u64>>,
+    proven: Option<Proven>,
+) -> Result<(), Box<dyn Error>> {
+    if state.paillier.contains_key(&dataset) {
+        return Err(AppError::new(ERR_SCHEMA, "dataset takes encrypted uploads"));
+    }
+    if state.shared.contains(&dataset) && vectors.iter().flatten().any(|v| *v >= shamir::P) {
+        return Err(AppError::new(ERR_SCHEMA, "malformed shares"));
+    }
+    // Shares are field elements, which a requester combines as integers
+    if state.shared.contains(&dataset) && schema.numeric != Numeric::INTEGER {
+        let reason = "shared datasets take unsigned integer uploads";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    if state.sketched.contains(&dataset) {
+        let sketch = match &vectors[..] {
+            [sketch] if schema.numeric == Numeric::INTEGER => hll::validate(sketch),
+            _ => Err("a sketch upload holds one integer vector of registers".into()),
+        };
+        sketch.map_err(|e| AppError::new(ERR_SCHEMA, e.to_string()))?;
+    }
+    // Masks cancel position by position, which only works for one vector per loader
+    if state.masked.contains(&dataset) && vectors.len() != 1 {
+        let reason = "masked uploads hold exactly one vector";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    let poll = state.polls.get(&dataset);
+    if let Some(close) = poll {
+        ballot(&dataset, *close, &schema, ttl, &vectors)?;
+    }
+    let opaque = state.masked.contains(&dataset) || state.shared.contains(&dataset);
+    let values = vectors.iter().map(Vec::len).sum();
+    let ranged = state.ranges.contains_key(&dataset);
+    // Masked values and shares say nothing of the values behind them, so their loaders
+    // prove the range instead
+    if opaque && ranged && proven.as_ref().map(|p| p.commitments.len()) != Some(values) {
+        let reason = "uploads to the dataset need a range proof covering every value";
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    // Checked on the decrypted values, so a loader can't poison a sum with absurd ones
+    if let Some(range) = value_range(state, &dataset, schema.numeric)?.filter(|_| !opaque) {
+        let numeric = schema.numeric;
+        let mut values = vectors.iter().flatten().map(|v| numeric.value(*v));
+        if !values.all(|v| range.contains(&v)) {
+            let (min, max) = (numeric.format(*range.start()), numeric.format(*range.end()));
+            let reason = format!("values must lie within {}..{}", min, max);
+            return Err(AppError::new(ERR_SCHEMA, reason));
+        }
+    }
+    let mut data = state.data.lock().unwrap();
+    // Under the data lock, so two connections can't both cast the loader's ballot
+    let voted = data.get(&dataset).is_some_and(|d| d.contains_key(&loader));
+    if poll.is_some() && voted {
+        let reason = format!("{} already voted in {}", loader, dataset);
+        return Err(Rejection::replay(reason));
+    }
+    let limit = tenant_of(state, &dataset).and_then(|(name, t)| Some((name, t.max_datasets?)));
+    if let Some((tenant, max)) = limit {
+        let prefix = format!("{}/", tenant);
+        let held = data.keys().filter(|name| name.starts_with(&prefix)).count();
+        if !data.contains_key(&dataset) && held >= max {
+            return Err(format!("tenant {} already holds {} datasets", tenant, max).into());
+        }
+    }
+    let mut schemas = state.schemas.lock().unwrap();
+    let declared = schemas.entry(dataset.clone()).or_insert(schema.clone());
+    if declared.numeric != schema.numeric {
+        let reason = format!(
+            "dataset holds {} values, not {}",
+            declared.numeric, schema.numeric
+        );
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    if declared.labels != schema.labels {
+        let reason = format!("dataset positions are labelled {:?}", declared.labels);
+        return Err(AppError::new(ERR_SCHEMA, reason));
+    }
+    drop(schemas);
+    let numeric = schema.numeric;
+    info!(%loader, %dataset, vectors = vectors.len(), %numeric, "Upload accepted");
+    let replaced = data
+        .entry(dataset.clone())
+        .or_default()
+        .insert(loader.clone(), vectors);
+    keep_proof(state, &dataset, &loader, proven);
+    // Set under the data lock, so a sweep can't take the new upload for the old one
+    let mut expiry = state.expiry.lock().unwrap();
+    let loaders = expiry.entry(dataset.clone()).or_default();
+    match ttl {
+        Some(ttl) => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            loaders.insert(loader, now.saturating_add(ttl));
+        }
+        None => {
+            loaders.remove(&loader);
+        }
+    }
+    if loaders.is_empty() {
+        expiry.remove(&dataset);
+    }
+    drop(expiry);
+    drop(data);
+    if let Some(mut replaced) = replaced {
+        replaced.zeroize();
+    }
+    // A new contribution is committed to afresh, or not at all
+    forget(
+        &mut state.blindings.lock().unwrap(),
+        &dataset,
+        Some(&loader),
+    );
+    bump(state, &dataset);
+    state.dirty.send_replace(());
+    Ok(())
+}
+
+/// Answers a single message with the response payload.
+fn respond(
+    state: &State,
+    session: &mut Option<Session>,
+    envelope: Envelope,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    debug!(kind = ?envelope.kind, sender = %hex::encode(&envelope.sender), "Message received");
+    match envelope.kind {
+        Kind::Handshake => {
+            let (reply, opened) = handshake(state, &envelope)?;
+            *session = Some(opened);
+            Ok(reply)
+        }
+        Kind::Upload => {
+            let (loader, values) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            if already_applied(state, &loader, &envelope) {
+                info!(%loader, "Retried upload acknowledged");
+                return Ok(b"Data write suceeded!".to_vec());
+            }
+            let (name, values) = sum::split_dataset(&values)?;
+            let name = qualify(state, loader_tenant(state, &loader).as_deref(), name)?;
+            let (schema, ttl, vectors) = sum::decode(values)?;
+            contribute(
+                state,
+                name.clone(),
+                loader.clone(),
+                schema,
+                ttl,
+                vectors,
+                None,
+            )?;
+            let digest = Sha256::digest(&envelope.payload);
+            audit(state, &envelope, loader, name, &digest);
+            Ok(b"Data write suceeded!".to_vec())
+        }
+        Kind::ProvenUpload => {
+            let (loader, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            if already_applied(state, &loader, &envelope) {
+                info!(%loader, "Retried upload acknowledged");
+                return Ok(b"Data write suceeded!".to_vec());
+            }
+            let (proof, openings, msg) = range::detach(&msg)?;
+            let (requested, values) = sum::split_dataset(msg)?;
+            let tenant = loader_tenant(state, &loader);
+            let tenant = tenant.as_deref();
+            let name = qualify(state, tenant, requested.clone())?;
+            // Ciphertexts are checked against the key, the rest decoded like any upload
+            let decoded = if state.paillier.contains_key(&name) {
+                None
+            } else {
+                Some(sum::decode(values)?)
+            };
+            let numeric = decoded
+                .as_ref()
+                .map_or(Numeric::INTEGER, |(schema, _, _)| schema.numeric);
+            let proven = proven(
+                state, &envelope, &requested, &name, numeric, proof, openings,
+            )?;
+            match decoded {
+                None => {
+                    contribute_encrypted(state, loader.clone(), tenant, msg, Some(proven))?;
+                }
+                Some((schema, ttl, vectors)) => {
+                    let width = vectors.first().map_or(0, Vec::len);
+                    let proven = Some(Proven { width, ..proven });
+                    contribute(
+                        state,
+                        name.clone(),
+                        loader.clone(),
+                        schema,
+                        ttl,
+                        vectors,
+                        proven,
+                    )?;
+                }
+            }
+            let digest = Sha256::digest(&envelope.payload);
+            audit(state, &envelope, loader, name, &digest);
+            Ok(b"Data write suceeded!".to_vec())
+        }
+        Kind::CommittedUpload => {
+            let (loader, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            if already_applied(state, &loader, &envelope) {
+                info!(%loader, "Retried upload acknowledged");
+                return Ok(b"Data write suceeded!".to_vec());
+            }
+            let (blinding, values) = take_blinding(&msg)?;
+            let (name, values) = sum::split_dataset(values)?;
+            let name = qualify(state, loader_tenant(state, &loader).as_deref(), name)?;
+            let (schema, ttl, vectors) = sum::decode(values)?;
+            contribute(
+                state,
+                name.clone(),
+                loader.clone(),
+                schema,
+                ttl,
+                vectors,
+                None,
+            )?;
+            let mut blindings = state.blindings.lock().unwrap();
+            let loaders = blindings.entry(name.clone()).or_default();
+            loaders.insert(loader.clone(), blinding.to_bytes());
+            drop(blindings);
+            let digest = Sha256::digest(&envelope.payload);
+            audit(state, &envelope, loader, name, &digest);
+            Ok(b"Data write suceeded!".to_vec())
+        }
+        Kind::EncryptedUpload => {
+            let (loader, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            if already_applied(state, &loader, &envelope) {
+                info!(%loader, "Retried upload acknowledged");
+                return Ok(b"Data write suceeded!".to_vec());
+            }
+            let tenant = loader_tenant(state, &loader);
+            let name = contribute_encrypted(state, loader.clone(), tenant.as_deref(), &msg, None)?;
+            let digest = Sha256::digest(&envelope.payload);
+            audit(state, &envelope, loader, name, &digest);
+            Ok(b"Data write suceeded!".to_vec())
+        }
+        Kind::UploadBegin => {
+            let (loader, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            let (name, _) = sum::split_dataset(&msg)?;
+            let name = qualify(state, loader_tenant(state, &loader).as_deref(), name)?;
+            info!(%loader, dataset = %name, "Chunked upload started");
+            // Beginning again discards an unfinished upload
+            let session = session
+                .as_mut()
+                .ok_or("loader messages need a handshake first")?;
+            session.upload = Some(PendingUpload {
+                dataset: name,
+                schema: None,
+                ttl: None,
+                vectors: Vec::new(),
+                size: 0,
+                digest: Sha256::new(),
+                reserved: None,
+            });
+            Ok(b"Upload started".to_vec())
+        }
+        Kind::UploadChunk => {
+            let (_, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            let session = session
+                .as_mut()
+                .ok_or("loader messages need a handshake first")?;
+            let upload = session.upload.as_mut().ok_or("no upload in progress")?;
+            upload.size += msg.len();
+            upload.digest.update(&envelope.payload);
+            if upload.size > state.max_upload {
+                session.upload = None;
+                return Err(AppError::new(ERR_SIZE, "upload exceeds --max-upload-mib"));
+            }
+            let (schema, ttl, mut vectors) = sum::decode(&msg)?;
+            if upload.schema.is_none() {
+                upload.ttl = ttl;
+            }
+            if *upload.schema.get_or_insert(schema.clone()) != schema || upload.ttl != ttl {
+                vectors.zeroize();
+                let reason = "chunk schema or TTL differs from earlier chunks";
+                return Err(AppError::new(ERR_SCHEMA, reason));
+            }
+            let width = upload.vectors.first().map(Vec::len);
+            if width.is_some_and(|width| vectors.iter().any(|v| v.len() != width)) {
+                vectors.zeroize();
+                let reason = "chunk vectors differ in length from earlier chunks";
+                return Err(AppError::new(ERR_SCHEMA, reason));
+            }
+            upload.vectors.append(&mut vectors);
+            Ok(b"Chunk accepted".to_vec())
+        }
+        Kind::UploadCommit => {
+            let (loader, msg) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            let count: [u8; 8] = msg
+                .as_slice()
+                .try_into()
+                .map_err(|_| AppError::malformed("upload commit"))?;
+            let mut upload = session
+                .as_mut()
+                .and_then(|session| session.upload.take())
+                .ok_or("no upload in progress")?;
+            if already_applied(state, &loader, &envelope) {
+                info!(%loader, "Retried upload acknowledged");
+                return Ok(b"Data write suceeded!".to_vec());
+            }
+            // Every chunk was acknowledged in order, but the count catches a loader that
+            // lost track of what it sent
+            if upload.vectors.len() as u64 != u64::from_le_bytes(count) {
+                let reason = "upload does not hold the committed number of vectors";
+                return Err(AppError::new(ERR_SIZE, reason));
+            }
+            let vectors = std::mem::take(&mut upload.vectors);
+            let schema = upload.schema.take().unwrap_or(Schema {
+                numeric: Numeric::INTEGER,
+                labels: Vec::new(),
+            });
+            let (dataset, ttl) = (upload.dataset.clone(), upload.ttl);
+            contribute(
+                state,
+                dataset.clone(),
+                loader.clone(),
+                schema,
+                ttl,
+                vectors,
+                None,
+            )?;
+            let digest = upload.digest.clone().finalize();
+            audit(state, &envelope, loader, dataset, &digest);
+            Ok(b"Data write suceeded!".to_vec())
+        }
+        Kind::Query => answer_query(state, session.as_ref(), &envelope),
+        Kind::Subscribe => Err("subscriptions are answered asynchronously".into()),
+        Kind::AuditLog => audit_log(state, &envelope),
+        Kind::Status => Ok(serde_json::to_vec(&Status {
+            term: state.term.load(Ordering::Relaxed),
+            leader: leading(state),
+        })?),
+        Kind::Inclusion => {
+            let (loader, name) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| AppError::malformed("dataset name"))?;
+            let name = qualify(state, loader_tenant(state, &loader).as_deref(), name)?;
+            let inclusion = inclusion(state, &name, &loader)?;
+            // Sealed to the session like a canary result
+            let cipher = &loader_session(session.as_ref(), &envelope)?.cipher;
+            cipher.encrypt(inclusion.as_bytes(), &envelope.reply_aad())
+        }
+        Kind::CanaryLoad => {
+            // Canary payloads use their own AAD so they can't be replayed as real uploads
+            let (_, values) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            *state.canary.lock().unwrap() = sum::decode(&values)?.2;
+            Ok(b"Canary write suceeded!".to_vec())
+        }
+        Kind::CanaryCompute => {
+            // Sealed to the session of the loader that asked, like query results, so
+            // the host never sees a computed output
+            let cipher = &loader_session(session.as_ref(), &envelope)?.cipher;
+            cipher
+                .decrypt(&envelope.payload, &envelope.aad())
+                .map_err(|e| Rejection::decrypt(e.to_string()))?;
+            let total = sum::total(Numeric::INTEGER, &state.canary.lock().unwrap())?;
+            let result = format!("Result: {}", total);
+            cipher.encrypt(result.as_bytes(), &envelope.reply_aad())
+        }
+        Kind::Rotate => {
+            begin_rotation(state, &envelope)?;
+            state.dirty.send_replace(());
+            Ok(b"Rotation pending".to_vec())
+        }
+        Kind::FinalizeRotation => {
+            finalize_rotation(state, &envelope)?;
+            state.dirty.send_replace(());
+            Ok(b"Rotation finalized".to_vec())
+        }
+        Kind::DropDataset => {
+            drop_dataset(state, &envelope)?;
+            state.dirty.send_replace(());
+            Ok(b"Dataset dropped".to_vec())
+        }
+        Kind::Wipe => {
+            // A loader can only wipe what it uploaded itself; whole datasets take the
+            // admin key
+            let (loader, name) = accept_loader(
+                &mut state.loaders.lock().unwrap(),
+                session.as_ref(),
+                &envelope,
+            )?;
+            let name = String::from_utf8(name.to_vec())
+                .map_err(|_| AppError::malformed("dataset name"))?;
+            let name = qualify(state, loader_tenant(state, &loader).as_deref(), name)?;
+            if state.polls.contains_key(&name) {
+                return Err("ballots can't be withdrawn".into());
+            }
+            wipe(state, &name, Some(&loader))?;
+            info!(%loader, dataset = %name, "Contribution wiped");
+            state.dirty.send_replace(());
+            Ok(b"Contribution wiped".to_vec())
+        }
+        Kind::RotateAppKey => {
+            let public = rotate_app_key(state, &envelope)?;
+            info!(public_key = %hex::encode(public), "App key rotated");
+            Ok(format!("App key rotated: {}", hex::encode(public)).into_bytes())
+        }
+    }
+}
+
+/// Takes `len` bytes of the pending upload budget for the chunk about to join the
+/// connection's upload, waiting up to `--read-timeout` for other uploads to release
+/// theirs. A connection without an upload in progress takes nothing.
+async fn reserve(
+    state: &State,
+    session: &mut Option<Session>,
+    len: usize,
+) -> Result<(), Box<dyn Error>> {
+    let Some(upload) = session.as_mut().and_then(|session| session.upload.as_mut()) else {
+        return Ok(());
+    };
+    // Frames are at most 1 MiB
+    let len = u32::try_from(len).map_err(|_| AppError::new(ERR_SIZE, "chunk too large"))?;
+    let acquire = state.pending.clone().acquire_many_owned(len);
+    let permit = tokio::time::timeout(state.read_timeout, acquire)
+        .await
+        .map_err(|_| AppError::new(ERR_SIZE, "uploads in progress exceed --max-pending-mib"))?
+        .map_err(|_| "pending upload budget closed")?;
+    match &mut upload.reserved {
+        Some(reserved) => reserved.merge(permit),
+        None => upload.reserved = Some(permit),
+    }
+    Ok(())
+}
+
+/// Serves framed messages on a connection until the client closes it. Each response
+/// is framed with the type of the message it answers; a message that fails is
+/// answered with an error frame and closes the connection.
+async fn handle<S: AsyncRead + AsyncWrite + Unpin>(
+    mut inbound: S,
+    state: &State,
+) -> Result<(), Box<dyn Error>> {
+    // Dropped with the connection, taking the session key with it
+    let mut session = None;
+    loop {
+        // A client that vanishes mid-frame, or never sends anything, would otherwise
+        // hold its buffer and permit forever
+        let (idle, timeout) = (state.idle_timeout, state.read_timeout);
+        let msg = frame::read_timed(&mut inbound, idle, timeout, state.max_message)
+            .await
+            .map_err(|e| error_body(e.as_ref()));
+        let (msg_type, payload) = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return Ok(()),
+            // An oversized message is refused with its code, then the connection closes
+            // with its payload unread
+            Err(e) if e.code == ERR_SIZE => {
+                send(&mut inbound, state, frame::ERROR, &serde_json::to_vec(&e)?).await?;
+                return Err(Box::new(e));
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+
+        let envelope = Envelope::decode(msg_type, &payload);
+        let correlation_id = envelope.as_ref().ok().and_then(|e| e.correlation_id);
+
+        // Errors become coded error bodies so nothing non-Send is held across the write
+        let started = Instant::now();
+        let result = match envelope {
+            // Only the leader of a replicated pair serves, so two never take writes at once
+            Ok(envelope) if envelope.kind != Kind::Status && !leading(state) => {
+                let term = state.term.load(Ordering::Relaxed);
+                let reason = format!("not the leader at term {}", term);
+                Err(AppError::new(ERR_STANDBY, reason))
+            }
+            // The one message that waits, for the dataset rather than the client
+            Ok(envelope) if envelope.kind == Kind::Subscribe => {
+                subscribe(state, session.as_ref(), &envelope).await
+            }
+            // A chunk waits for room in the pending upload budget before it's taken in,
+            // and the connection isn't read meanwhile, so loaders streaming faster than
+            // uploads commit are held back by their own sockets
+            Ok(envelope) if envelope.kind == Kind::UploadChunk => {
+                match reserve(state, &mut session, envelope.payload.len()).await {
+                    Ok(()) => respond(state, &mut session, envelope),
+                    Err(e) => Err(e),
+                }
+            }
+            envelope => envelope.and_then(|envelope| respond(state, &mut session, envelope)),
+        };
+        let result = {
+            let error = result.as_ref().err().map(|e| e.as_ref());
+            state
+                .metrics
+                .lock()
+                .unwrap()
+                .record(msg_type, started.elapsed(), error);
+            result.map_err(|e| AppError {
+                correlation_id,
+                ..error_body(e.as_ref())
+            })
+        };
+        let (response_type, response, error) = match result {
+            // The request's correlation ID heads its response
+            Ok(mut response) => {
+                if let Some(id) = correlation_id {
+                    response.splice(0..0, id.to_be_bytes());
+                }
+                (msg_type, response, None)
+            }
+            Err(e) => (frame::ERROR, serde_json::to_vec(&e)?, Some(e)),
+        };
+        send(&mut inbound, state, response_type, &response).await?;
+        if let Some(e) = error {
+            return Err(Box::new(e));
+        }
+        if *state.shutting_down.borrow() {
+            return Ok(());
+        }
+    }
+}
+
+/// Writes a response frame, giving up on a client that stops reading.
+async fn send<S: AsyncWrite + Unpin>(
+    outbound: &mut S,
+    state: &State,
+    msg_type: u8,
+    payload: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    tokio::time::timeout(
+        state.write_timeout,
+        frame::write(outbound, msg_type, payload),
+    )
+    .await
+    .map_err(|_| "write timed out")?
+}
+
+/// The error frame body for a failed request: its own code if it carries one, the
+/// code for a rejection's category, or `ERR_REQUEST`.
+fn error_body(error: &(dyn Error + 'static)) -> AppError {
+    if let Some(error) = error.downcast_ref::<AppError>() {
+        return error.clone();
+    }
+    let code = match error.downcast_ref::<Rejection>().map(|r| r.category) {
+        Some("auth") => ERR_AUTH,
+        Some("decrypt") => ERR_DECRYPT,
+        Some("replay") => ERR_REPLAY,
+        _ => ERR_REQUEST,
+    };
+    AppError {
+        code: code.to_owned(),
+        message: error.to_string(),
+        correlation_id: None,
+    }
+}
+
+/// Whether `key` belongs to a registered loader, requester or admin.
+fn known_key(state: &State, key: &[u8; 32]) -> bool {
+    state.requesters.contains_key(key)
+        || state.admin.is_some_and(|admin| same_key(&admin, key))
+        || state
+            .loaders
+            .lock()
+            .unwrap()
+            .iter()
+            .any(|loader| loader.owns(key))
+}
+
+/// Serves a connection, first terminating RA-TLS on it if the app is configured to.
+async fn serve_connection<S>(inbound: S, state: &State) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let Some(tls) = &state.tls else {
+        return serve_stream(inbound, state).await;
+    };
+    let inbound = tokio::time::timeout(state.read_timeout, tls.accept(inbound))
+        .await
+        .map_err(|_| "TLS handshake timed out")??;
+    serve_stream(inbound, state).await
+}
+
+/// Serves a connection, first opening a Noise channel on it if the app requires one.
+async fn serve_stream<S>(inbound: S, state: &State) -> Result<(), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    if !state.noise {
+        return handle(inbound, state).await;
+    }
+    let secrets = state.keys.lock().unwrap().secrets();
+    let (_, channel) = tokio::time::timeout(
+        state.read_timeout,
+        noise::accept(inbound, &secrets, |key| known_key(state, key)),
+    )
+    .await
+    .map_err(|_| "noise handshake timed out")??;
+    handle(channel, state).await
+}
+
+fn spawn_handler<S>(inbound: S, peer: String, state: Arc<State>, permit: OwnedSemaphorePermit)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    tokio::spawn(async move {
+        let span = tracing::info_span!("connection", %peer);
+        if let Err(e) = serve_connection(inbound, &state)
+            .instrument(span.clone())
+            .await
+        {
+            span.in_scope(|| warn!(error = %e, "Connection closed with an error"));
+        }
+        drop(permit);
+    });
+}
+
+async fn serve_tcp(
     ip_addr: String,
+    state: Arc<State>,
+    semaphore: Arc<Semaphore>,
+    keepalive: TcpKeepalive,
+) -> Result<(), Box<dyn Error>> {
+    info!("Listening on: {}", ip_addr);
 
-    /// path to private key file
-    #[arg(short, long)]
-    secret: String,
+    let listener = TcpListener::bind(ip_addr).await?;
 
-    /// path to loader public key file
-    #[arg(short, long)]
-    loader: String,
+    while let Ok((inbound, addr)) = listener.accept().await {
+        let permit = semaphore.clone().acquire_owned().await?;
+        if let Err(e) = SockRef::from(&inbound).set_tcp_keepalive(&keepalive) {
+            warn!(peer = %addr, error = %e, "Failed to enable keepalive");
+        }
+        spawn_handler(inbound, addr.to_string(), state.clone(), permit);
+    }
 
-    /// path to requester public key file
-    #[arg(short, long)]
-    requester: String,
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+async fn serve_vsock(
+    cid: u32,
+    port: u32,
+    state: Arc<State>,
+    semaphore: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error>> {
+    info!("Listening on: vsock:{}:{}", cid, port);
+
+    let listener = VsockListener::bind(VsockAddr::new(cid, port))?;
+
+    while let Ok((inbound, addr)) = listener.accept().await {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let peer = format!("vsock:{}:{}", addr.cid(), addr.port());
+        spawn_handler(inbound, peer, state.clone(), permit);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+async fn serve_vsock(
+    _cid: u32,
+    _port: u32,
+    _state: Arc<State>,
+    _semaphore: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error>> {
+    Err("vsock listeners are only supported on Linux".into())
+}
+
+/// Serves a Unix domain socket, for sidecars and local development. Access is
+/// governed by the socket file's permissions.
+#[cfg(unix)]
+async fn serve_unix(
+    path: PathBuf,
+    state: Arc<State>,
+    semaphore: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::net::UnixListener;
+
+    // A socket left behind by an earlier run would make the bind fail
+    if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let peer = format!("unix:{}", path.display());
+    info!("Listening on: {}", peer);
+
+    let listener = UnixListener::bind(&path)?;
+
+    while let Ok((inbound, _)) = listener.accept().await {
+        let permit = semaphore.clone().acquire_owned().await?;
+        spawn_handler(inbound, peer.clone(), state.clone(), permit);
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn serve_unix(
+    _path: PathBuf,
+    _state: Arc<State>,
+    _semaphore: Arc<Semaphore>,
+) -> Result<(), Box<dyn Error>> {
+    Err("unix socket listeners are only supported on Unix".into())
+}
+
+/// Store key of the persisted snapshot
+const SNAPSHOT_KEY: &str = "snapshot";
+
+/// App state that survives restarts when a store is configured.
+#[derive(Default, Serialize, Deserialize)]
+struct Snapshot {
+    data: BTreeMap<String, BTreeMap<String, Vec<Vec<u64>>>>,
+    /// sequence number of the last message accepted from each loader's current key, by
+    /// loader name, so replays stay rejected after a restart
+    loader_seqs: BTreeMap<String, u64>,
+    admin_seq: u64,
+    /// privacy budget spent by each requester, by hex public key, so a restart doesn't
+    /// refill it
+    #[serde(default)]
+    spent: BTreeMap<String, f64>,
+    /// Paillier ciphertexts of encrypted datasets
+    #[serde(default)]
+    encrypted: BTreeMap<String, BTreeMap<String, Vec<Vec<u8>>>>,
+    /// decimal places of each dataset's values, absent for integer datasets
+    #[serde(default)]
+    scales: BTreeMap<String, u8>,
+    /// datasets holding signed values
+    #[serde(default)]
+    signed: BTreeSet<String>,
+    /// position labels of each labelled dataset
+    #[serde(default)]
+    labels: BTreeMap<String, Vec<String>>,
+    /// unix time each expiring contribution is removed at, by dataset and loader name
+    #[serde(default)]
+    expiry: BTreeMap<String, BTreeMap<String, u64>>,
+    /// every accepted upload, so the audit log outlives restarts
+    #[serde(default)]
+    audit: Vec<AuditEntry>,
+    /// Merkle leaves of current contributions, by dataset and loader name
+    #[serde(default)]
+    commitments: BTreeMap<String, BTreeMap<String, merkle::Hash>>,
+    /// blinding factors of committed contributions, by dataset and loader name
+    #[serde(default)]
+    blindings: BTreeMap<String, BTreeMap<String, [u8; 32]>>,
+    /// range-proven contributions, by dataset and loader name
+    #[serde(default)]
+    proofs: BTreeMap<String, BTreeMap<String, Proven>>,
+    /// idempotency keys of the latest uploads from each loader, by loader name, so a
+    /// retry after a restart or failover isn't applied twice
+    #[serde(default)]
+    idempotency: BTreeMap<String, VecDeque<String>>,
+    /// leadership term, so a standby that took over stays ahead of its old primary
+    #[serde(default)]
+    term: u64,
+}
+
+/// Key the store is sealed under. It only depends on the app secret, so a restarted
+/// app holding the same secret can open what its predecessor wrote.
+fn store_key(secret: &[u8; 32]) -> [u8; 32] {
+    let mut key = [0u8; 32];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(b"ppa-store", &mut key)
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+/// What a store or replica keeps of the state.
+fn snapshot(state: &State) -> Snapshot {
+    let schemas = state.schemas.lock().unwrap().clone();
+    Snapshot {
+        data: state.data.lock().unwrap().clone(),
+        loader_seqs: state
+            .loaders
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|loader| (loader.name.clone(), loader.current_seq))
+            .collect(),
+        admin_seq: *state.admin_seq.lock().unwrap(),
+        spent: state
+            .spent
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, spent)| (hex::encode(key), *spent))
+            .collect(),
+        encrypted: state.encrypted.lock().unwrap().clone(),
+        expiry: state.expiry.lock().unwrap().clone(),
+        audit: state.audit.lock().unwrap().clone(),
+        commitments: state.commitments.lock().unwrap().clone(),
+        blindings: state.blindings.lock().unwrap().clone(),
+        proofs: state.proofs.lock().unwrap().clone(),
+        idempotency: state.idempotency.lock().unwrap().clone(),
+        term: state.term.load(Ordering::Relaxed),
+        scales: schemas
+            .iter()
+            .filter(|(_, schema)| schema.numeric.scale > 0)
+            .map(|(name, schema)| (name.clone(), schema.numeric.scale))
+            .collect(),
+        signed: schemas
+            .iter()
+            .filter(|(_, schema)| schema.numeric.signed)
+            .map(|(name, _)| name.clone())
+            .collect(),
+        labels: schemas
+            .iter()
+            .filter(|(_, schema)| !schema.labels.is_empty())
+            .map(|(name, schema)| (name.clone(), schema.labels.clone()))
+            .collect(),
+    }
+}
+
+/// Schema of each dataset in a snapshot.
+fn snapshot_schemas(snapshot: &Snapshot) -> Result<HashMap<String, Schema>, Box<dyn Error>> {
+    let mut schemas = HashMap::new();
+    for name in snapshot.data.keys() {
+        let scale = snapshot.scales.get(name).copied().unwrap_or(0);
+        let signed = snapshot.signed.contains(name);
+        let schema = Schema {
+            numeric: Numeric::new(signed, scale)?,
+            labels: snapshot.labels.get(name).cloned().unwrap_or_default(),
+        };
+        schemas.insert(name.clone(), schema);
+    }
+    Ok(schemas)
+}
+
+/// Privacy budget spent by each requester in a snapshot.
+fn snapshot_spent(snapshot: &Snapshot) -> Result<HashMap<[u8; 32], f64>, Box<dyn Error>> {
+    let mut spent = HashMap::new();
+    for (key, cost) in &snapshot.spent {
+        let key: [u8; 32] = hex::decode(key)?
+            .try_into()
+            .map_err(|_| "malformed requester key in snapshot")?;
+        spent.insert(key, *cost);
+    }
+    Ok(spent)
+}
+
+/// Replaces the state with a snapshot taken elsewhere, zeroizing the vectors it
+/// replaces, and wakes subscriptions to datasets whose contributions changed.
+fn restore(state: &State, snapshot: Snapshot) -> Result<(), Box<dyn Error>> {
+    let schemas = snapshot_schemas(&snapshot)?;
+    let spent = snapshot_spent(&snapshot)?;
+    for loader in state.loaders.lock().unwrap().iter_mut() {
+        loader.current_seq = snapshot.loader_seqs.get(&loader.name).copied().unwrap_or(0);
+    }
+    *state.admin_seq.lock().unwrap() = snapshot.admin_seq;
+    state.term.store(snapshot.term, Ordering::Relaxed);
+    *state.spent.lock().unwrap() = spent;
+
+    let mut data = state.data.lock().unwrap();
+    let mut replaced = std::mem::replace(&mut *data, snapshot.data);
+    *state.schemas.lock().unwrap() = schemas;
+    *state.expiry.lock().unwrap() = snapshot.expiry;
+    *state.encrypted.lock().unwrap() = snapshot.encrypted;
+    *state.audit.lock().unwrap() = snapshot.audit;
+    *state.commitments.lock().unwrap() = snapshot.commitments;
+    *state.blindings.lock().unwrap() = snapshot.blindings;
+    *state.proofs.lock().unwrap() = snapshot.proofs;
+    *state.idempotency.lock().unwrap() = snapshot.idempotency;
+    let changed: BTreeSet<String> = data
+        .keys()
+        .chain(replaced.keys())
+        .filter(|name| data.get(*name) != replaced.get(*name))
+        .cloned()
+        .collect();
+    drop(data);
+    for vectors in replaced.values_mut().flat_map(BTreeMap::values_mut) {
+        vectors.zeroize();
+    }
+    for name in changed {
+        bump(state, &name);
+    }
+    state.dirty.send_replace(());
+    Ok(())
+}
+
+async fn save(store: &Sealed<Backend>, state: &State) -> Result<(), Box<dyn Error>> {
+    let snapshot = snapshot(state);
+    store
+        .put(SNAPSHOT_KEY, &serde_cbor::to_vec(&snapshot)?)
+        .await
+}
+
+/// Saves a snapshot whenever the state changes. Changes made while a save is running
+/// are picked up by the next one.
+async fn persist(store: Arc<Sealed<Backend>>, state: Arc<State>) {
+    let mut dirty = state.dirty.subscribe();
+    while dirty.changed().await.is_ok() {
+        if let Err(e) = save(&store, &state).await {
+            warn!(error = %e, "Persisting state failed");
+        }
+    }
+}
+
+/// Frame types of the replication stream: an attestation document, part of a sealed
+/// snapshot, its last part and a sealed heartbeat from the primary, then the replica's
+/// sealed status and acknowledgements
+const REPLICA_ATTEST: u8 = 0;
+const REPLICA_PART: u8 = 1;
+const REPLICA_SNAPSHOT: u8 = 2;
+const REPLICA_HEARTBEAT: u8 = 3;
+const REPLICA_STATUS: u8 = 4;
+const REPLICA_ACK: u8 = 5;
+
+/// How often a primary with nothing new to send shows a replica it's alive
+const REPLICA_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long a primary keeps serving after sending a message its replica acknowledged.
+/// Shorter than `REPLICA_TIMEOUT`, so a primary cut off from its replica stops before
+/// the replica takes over.
+const REPLICA_LEASE: Duration = Duration::from_secs(10);
+
+/// Silence after which a replica takes its primary for lost
+const REPLICA_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// AAD of the `counter`th sealed message in one direction of a replication stream. The
+/// direction is bound too, so the replica's messages can't be reflected back to it.
+fn replica_aad(to_replica: bool, counter: u64) -> [u8; 9] {
+    let mut aad = [to_replica as u8; 9];
+    aad[1..].copy_from_slice(&counter.to_be_bytes());
+    aad
+}
+
+/// Whether the app serves requests: it isn't a standby, and a primary with a replica
+/// holds an unexpired lease.
+fn leading(state: &State) -> bool {
+    !state.standby.load(Ordering::Relaxed)
+        && state
+            .lease
+            .as_ref()
+            .is_none_or(|lease| Instant::now() < *lease.lock().unwrap())
+}
+
+/// Mutually attests a replication connection. Each side sends an NSM attestation
+/// document embedding a fresh X25519 key and checks the other's against `image_id`,
+/// the primary first, and the channel is keyed by the two ephemeral keys, so only the
+/// attested enclaves can open it.
+async fn attest_peer<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    image_id: &str,
+    primary: bool,
+) -> Result<CommittingCipher, Box<dyn Error>> {
+    let ephemeral = Ephemeral::generate();
+    let doc = nsm_attestation(&ephemeral.public, None)?;
+    if primary {
+        frame::write(conn, REPLICA_ATTEST, &doc).await?;
+    }
+    let peer_doc = match frame::read(conn).await? {
+        Some((REPLICA_ATTEST, peer_doc)) => peer_doc,
+        _ => return Err("replication peer sent no attestation".into()),
+    };
+    let policy = Policy {
+        image_id: image_id.to_owned(),
+        module_id: None,
+        clock_skew: 300,
+        expected_pcrs: BTreeMap::new(),
+        min_vcpus: None,
+        min_memory_mib: None,
+        limits: Limits::default(),
+    };
+    let report = attestation::verify(&peer_doc, AWS_ROOT_CERT, &policy)?;
+    let peer: [u8; 32] = report
+        .public_key
+        .as_slice()
+        .try_into()
+        .map_err(|_| "replication peer attested no X25519 key")?;
+    if !primary {
+        frame::write(conn, REPLICA_ATTEST, &doc).await?;
+    }
+    let (first, second) = match primary {
+        true => (ephemeral.public, peer),
+        false => (peer, ephemeral.public),
+    };
+    let transcript = [&b"ppa-replication"[..], &first, &second].concat();
+    let session = ephemeral.session(&peer, None, &transcript, Suite::ChaCha20Poly1305);
+    Ok(session.sequenced(primary))
+}
+
+/// Streams the state to the standby replica at `addr` whenever it changes, with
+/// heartbeats in between, reconnecting after failures. Every snapshot is whole, so a
+/// replica that missed some is caught up by the next.
+async fn replicate(addr: String, image_id: String, state: Arc<State>) {
+    let mut dirty = state.dirty.subscribe();
+    loop {
+        if let Err(e) = stream_to(&addr, &image_id, &state, &mut dirty).await {
+            warn!(replica = %addr, error = %e, "Replication failed, retrying");
+        }
+        tokio::time::sleep(REPLICA_HEARTBEAT_INTERVAL).await;
+    }
+}
+
+/// Reads the replica's next sealed message of type `kind`.
+async fn replica_reply(
+    conn: &mut tokio::net::TcpStream,
+    cipher: &CommittingCipher,
+    kind: u8,
+    counter: &mut u64,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let sealed = match frame::read(conn).await? {
+        Some((k, sealed)) if k == kind => sealed,
+        _ => return Err("replica closed the stream".into()),
+    };
+    let msg = cipher.decrypt(&sealed, &replica_aad(false, *counter))?;
+    *counter += 1;
+    Ok(msg)
+}
+
+async fn stream_to(
+    addr: &str,
+    image_id: &str,
+    state: &State,
+    dirty: &mut watch::Receiver<()>,
+) -> Result<(), Box<dyn Error>> {
+    let mut conn = tokio::net::TcpStream::connect(addr).await?;
+    let cipher = attest_peer(&mut conn, image_id, true).await?;
+    // Each sealed message is bound to its place in the stream, so none can be
+    // replayed, dropped or reordered
+    let (mut sent, mut received) = (0u64, 0u64);
+
+    // A replica that took over, or holds a newer term, has state this app lacks
+    let status = replica_reply(&mut conn, &cipher, REPLICA_STATUS, &mut received).await?;
+    if status.len() != 9 {
+        return Err("malformed replica status".into());
+    }
+    let term = u64::from_be_bytes(status[..8].try_into()?);
+    let own = state.term.load(Ordering::Relaxed);
+    if status[8] == 0 || term > own {
+        return Err(format!("fenced: the replica leads at term {}", term).into());
+    }
+    info!(replica = %addr, term = own, "Replica attested");
+
+    let mut send_snapshot = true;
+    loop {
+        let sent_at = Instant::now();
+        if send_snapshot {
+            let snapshot = Zeroizing::new(serde_cbor::to_vec(&snapshot(state))?);
+            let sealed = cipher.encrypt(&snapshot, &replica_aad(true, sent))?;
+            let mut parts = sealed.chunks(frame::MAX_LEN as usize).peekable();
+            while let Some(part) = parts.next() {
+                let kind = match parts.peek() {
+                    Some(_) => REPLICA_PART,
+                    None => REPLICA_SNAPSHOT,
+                };
+                frame::write(&mut conn, kind, part).await?;
+            }
+        } else {
+            let sealed = cipher.encrypt(b"", &replica_aad(true, sent))?;
+            frame::write(&mut conn, REPLICA_HEARTBEAT, &sealed).await?;
+        }
+        sent += 1;
+        let ack = replica_reply(&mut conn, &cipher, REPLICA_ACK, &mut received);
+        tokio::time::timeout(REPLICA_LEASE, ack)
+            .await
+            .map_err(|_| "replica stopped acknowledging")??;
+        // The replica had this message, so it won't take over for a while yet
+        if let Some(lease) = &state.lease {
+            *lease.lock().unwrap() = sent_at + REPLICA_LEASE;
+        }
+        send_snapshot = tokio::time::timeout(REPLICA_HEARTBEAT_INTERVAL, dirty.changed())
+            .await
+            .is_ok();
+    }
+}
+
+/// Takes the primary's stream on `addr`, replacing the state with every snapshot it
+/// sends. The app stands by, refusing requests, from startup and while an attested
+/// primary is connected. Once the stream ends or falls silent it takes over under the
+/// next term, and from then on tells any primary that connects that it's fenced.
+async fn serve_replica(
+    addr: SocketAddr,
+    image_id: String,
+    state: Arc<State>,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    info!(%addr, "Waiting for the primary");
+    loop {
+        let (mut conn, peer) = listener.accept().await?;
+        // Whoever connects can't hold the listener without attesting
+        let attested = attest_peer(&mut conn, &image_id, false);
+        let cipher = match tokio::time::timeout(state.read_timeout, attested).await {
+            Ok(Ok(cipher)) => cipher,
+            Ok(Err(e)) => {
+                warn!(%peer, error = %e, "Replication peer refused");
+                continue;
+            }
+            Err(_) => {
+                warn!(%peer, "Replication peer took too long to attest");
+                continue;
+            }
+        };
+        let mut sent = 0u64;
+        let standby = state.standby.load(Ordering::Relaxed);
+        let mut status = state.term.load(Ordering::Relaxed).to_be_bytes().to_vec();
+        status.push(standby as u8);
+        let sealed = cipher.encrypt(&status, &replica_aad(false, sent))?;
+        sent += 1;
+        if let Err(e) = frame::write(&mut conn, REPLICA_STATUS, &sealed).await {
+            warn!(%peer, error = %e, "Replication peer dropped");
+            continue;
+        }
+        if !standby {
+            warn!(%peer, "Fenced a stale primary");
+            continue;
+        }
+        info!(%peer, "Primary attested, standing by");
+        if let Err(e) = follow(&mut conn, &cipher, &state, sent).await {
+            warn!(%peer, error = %e, "Replication stream failed");
+        }
+        let term = state.term.fetch_add(1, Ordering::Relaxed) + 1;
+        state.standby.store(false, Ordering::Relaxed);
+        state.dirty.send_replace(());
+        warn!(%peer, term, "Primary lost, taking over");
+    }
+}
+
+async fn follow(
+    conn: &mut tokio::net::TcpStream,
+    cipher: &CommittingCipher,
+    state: &State,
+    mut sent: u64,
+) -> Result<(), Box<dyn Error>> {
+    let mut received = 0u64;
+    let mut sealed = Vec::new();
+    loop {
+        let msg = frame::read_timed(conn, REPLICA_TIMEOUT, state.read_timeout, frame::MAX_LEN);
+        let aad = replica_aad(true, received);
+        match msg.await? {
+            None => return Ok(()),
+            Some((REPLICA_PART, part)) => {
+                sealed.extend_from_slice(&part);
+                continue;
+            }
+            Some((REPLICA_SNAPSHOT, part)) => {
+                sealed.extend_from_slice(&part);
+                let opened = Zeroizing::new(cipher.decrypt(&std::mem::take(&mut sealed), &aad)?);
+                restore(state, serde_cbor::from_slice(&opened)?)?;
+                debug!(counter = received, "Snapshot replicated");
+            }
+            Some((REPLICA_HEARTBEAT, heartbeat)) => {
+                cipher.decrypt(&heartbeat, &aad)?;
+            }
+            Some((kind, _)) => return Err(format!("unexpected replication frame {}", kind).into()),
+        }
+        received += 1;
+        let ack = cipher.encrypt(b"", &replica_aad(false, sent))?;
+        sent += 1;
+        frame::write(conn, REPLICA_ACK, &ack).await?;
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() -> Result<(), Box<dyn Error>> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut terminate = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = terminate.recv() => {}
+            res = tokio::signal::ctrl_c() => res?,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+/// Signed notification that an epoch closed with the given total for each dataset:
+/// a number for integer datasets, a decimal string for fixed-point ones.
+///
+/// The signature covers the `payload` string exactly as sent.
+fn epoch_bundle(
+    signer: &dyn Signer,
+    epoch: u64,
+    results: &BTreeMap<String, serde_json::Value>,
+) -> String {
+    let closed_at = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let payload = serde_json::json!({
+        "epoch": epoch,
+        "closed_at": closed_at,
+        "results": results,
+    })
+    .to_string();
+    let signature = signer.sign(payload.as_bytes());
+
+    serde_json::json!({
+        "payload": payload,
+        "scheme": signer.scheme(),
+        "public_key": hex::encode(signer.public_key()),
+        "signature": hex::encode(signature),
+    })
+    .to_string()
+}
+
+/// Total of a dataset's values, mod 2^64 for masked datasets and in the share field for
+/// shared ones.
+fn total(
+    state: &State,
+    name: &str,
+    numeric: Numeric,
+    data: &[Vec<u64>],
+) -> Result<i128, Box<dyn Error>> {
+    if state.masked.contains(name) {
+        let sums = sum::wrapping_sums(data)?;
+        let total = numeric.value(sums.iter().fold(0, |a, v| a.wrapping_add(*v)));
+        open_masked(state, name, Mode::Total, &[total])?;
+        return Ok(total);
+    }
+    if state.shared.contains(name) {
+        let sums = shamir::sums(data)?;
+        return Ok(sums.iter().fold(0, |a, v| shamir::add(a, *v)) as i128);
+    }
+    sum::total(numeric, data)
+}
+
+async fn run_epochs(state: Arc<State>, hooks: Arc<Hooks>, period: Duration) {
+    let mut ticker = tokio::time::interval(period);
+    // The first tick completes immediately
+    ticker.tick().await;
+
+    let mut epoch = 0;
+    loop {
+        ticker.tick().await;
+        if state.standby.load(Ordering::Relaxed) {
+            continue;
+        }
+        epoch += 1;
+
+        let names: Vec<String> = state.data.lock().unwrap().keys().cloned().collect();
+        let mut results = BTreeMap::new();
+        for name in names {
+            // A tenant's results only go to its own requesters
+            if tenant_of(&state, &name).is_some() {
+                continue;
+            }
+            let result = dataset(&state, &name, None)
+                .and_then(|(numeric, data)| Ok((numeric, total(&state, &name, numeric, &data)?)));
+            match result {
+                // Totals are in range of the dataset's type, so they fit a JSON number
+                Ok((numeric, total)) if numeric.scale == 0 => {
+                    results.insert(name, serde_json::json!(total));
+                }
+                Ok((numeric, total)) => {
+                    results.insert(name, numeric.format(total).into());
+                }
+                Err(e) => warn!(epoch, dataset = %name, error = %e, "Dataset skipped"),
+            }
+        }
+        let bundle = epoch_bundle(state.signer.as_ref(), epoch, &results);
+        info!(epoch, "Epoch closed");
+
+        let hooks = hooks.clone();
+        tokio::spawn(async move { hooks.fire(epoch, bundle.as_bytes()).await });
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn nsm_request(
+    request: aws_nitro_enclaves_nsm_api::api::Request,
+) -> Result<aws_nitro_enclaves_nsm_api::api::Response, Box<dyn Error>> {
+    use aws_nitro_enclaves_nsm_api::api::Response as NsmResponse;
+    use aws_nitro_enclaves_nsm_api::driver::{nsm_exit, nsm_init, nsm_process_request};
+
+    let fd = nsm_init();
+    if fd < 0 {
+        return Err("failed to open the NSM device, not running in an enclave?".into());
+    }
+    let response = nsm_process_request(fd, request);
+    nsm_exit(fd);
+
+    match response {
+        NsmResponse::Error(code) => Err(format!("NSM request failed: {:?}", code).into()),
+        response => Ok(response),
+    }
+}
+
+/// Requests an attestation document embedding `public` from the Nitro Secure Module.
+#[cfg(target_os = "linux")]
+fn nsm_attestation(public: &[u8], user_data: Option<Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>> {
+    use aws_nitro_enclaves_nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
+
+    let request = NsmRequest::Attestation {
+        user_data: user_data.map(serde_bytes::ByteBuf::from),
+        nonce: None,
+        public_key: Some(serde_bytes::ByteBuf::from(public.to_vec())),
+    };
+    match nsm_request(request)? {
+        NsmResponse::Attestation { document } => Ok(document),
+        _ => Err("unexpected NSM response".into()),
+    }
+}
+
+/// Generates an X25519 secret from NSM entropy.
+#[cfg(target_os = "linux")]
+fn nsm_secret() -> Result<[u8; 32], Box<dyn Error>> {
+    use aws_nitro_enclaves_nsm_api::api::{Request as NsmRequest, Response as NsmResponse};
+
+    match nsm_request(NsmRequest::GetRandom)? {
+        NsmResponse::GetRandom { random } if random.len() >= 32 => {
+            let random = Zeroizing::new(random);
+            let mut secret = [0u8; 32];
+            secret.copy_from_slice(&random[..32]);
+            Ok(secret)
+        }
+        _ => Err("unexpected NSM response".into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nsm_attestation(_public: &[u8], _user_data: Option<Vec<u8>>) -> Result<Vec<u8>, Box<dyn Error>> {
+    Err("the NSM device is only available in Linux enclaves".into())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn nsm_secret() -> Result<[u8; 32], Box<dyn Error>> {
+    Err("the NSM device is only available in Linux enclaves, pass --secret".into())
+}
+
+/// Serves `/attestation/raw` like the Oyster attestation server, so verifiers can talk to
+/// the app directly. Each document carries the app's current public key, and its
+/// `user_data` names the result signing key.
+async fn serve_attestation(addr: SocketAddr, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let user_data = serde_json::to_vec(&serde_json::json!({
+        "result_key": hex::encode(state.signer.public_key()),
+        "result_scheme": state.signer.scheme(),
+    }))?;
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        let user_data = user_data.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let public = state.keys.lock().unwrap().public();
+                let user_data = user_data.clone();
+                async move {
+                    let resp = if req.uri().path() != "/attestation/raw" {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    } else {
+                        match nsm_attestation(&public, Some(user_data)) {
+                            Ok(doc) => Response::new(Body::from(doc)),
+                            Err(e) => Response::builder()
+                                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                                .body(Body::from(e.to_string()))
+                                .unwrap(),
+                        }
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    info!("Serving attestation on: {}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+/// Liveness report served on `/healthz`. It carries counts only, never dataset names,
+/// loader names or values.
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+    /// the app key and every configured client key are loaded; the listener only starts
+    /// once they are, so a report at all means they are
+    keys_loaded: bool,
+    loaders: usize,
+    requesters: usize,
+    datasets: usize,
+    /// loader uploads held across all datasets
+    contributions: usize,
+    /// a replica waiting on its primary, not serving requests
+    standby: bool,
+    /// leadership term of a replicated pair
+    term: u64,
+    /// serving requests: neither a standby nor a primary whose lease ran out
+    leader: bool,
+}
+
+fn health(state: &State) -> Health {
+    let loaders = state.loaders.lock().unwrap().len();
+    let data = state.data.lock().unwrap();
+    Health {
+        status: "ok",
+        keys_loaded: true,
+        loaders,
+        requesters: state.requesters.len(),
+        datasets: data.len(),
+        contributions: data.values().map(BTreeMap::len).sum(),
+        standby: state.standby.load(Ordering::Relaxed),
+        term: state.term.load(Ordering::Relaxed),
+        leader: leading(state),
+    }
+}
+
+/// Serves `/healthz` with the app's `Health` as JSON.
+async fn serve_health(addr: SocketAddr, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                async move {
+                    let resp = if req.uri().path() == "/healthz" {
+                        let body = serde_json::to_vec(&health(&state)).unwrap();
+                        Response::builder()
+                            .header("content-type", "application/json")
+                            .body(Body::from(body))
+                            .unwrap()
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    info!("Serving health on: {}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+/// Serves the request metrics in the Prometheus text format on `/metrics`.
+async fn serve_metrics(addr: SocketAddr, state: Arc<State>) -> Result<(), Box<dyn Error>> {
+    let make_svc = make_service_fn(move |_| {
+        let state = state.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let state = state.clone();
+                async move {
+                    let resp = if req.uri().path() == "/metrics" {
+                        Response::new(Body::from(state.metrics.lock().unwrap().render()))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+
+    info!("Serving metrics on: {}", addr);
+    Server::bind(&addr).serve(make_svc).await?;
+    Ok(())
+}
+
+/// Verifies the enclave's own attestation before any data is accepted, catching images
+/// that were mis-built or deployed with the wrong key. Without an endpoint the document
+/// comes straight from the NSM.
+async fn self_verify(
+    endpoint: Option<&str>,
+    expected_image_id: &str,
+    public: &[u8; 32],
+) -> Result<(), Box<dyn Error>> {
+    let doc = match endpoint {
+        Some(endpoint) => {
+            let res = https_client().get(endpoint.parse::<Uri>()?).await?;
+            hyper::body::to_bytes(res).await?.to_vec()
+        }
+        None => nsm_attestation(public, None)?,
+    };
+
+    let policy = Policy {
+        image_id: expected_image_id.to_owned(),
+        module_id: None,
+        clock_skew: 300,
+        expected_pcrs: BTreeMap::new(),
+        min_vcpus: None,
+        min_memory_mib: None,
+        limits: Limits::default(),
+    };
+    let report = attestation::verify(&doc, AWS_ROOT_CERT, &policy)?;
+    if report.public_key != public {
+        return Err("attested public key does not match the app secret".into());
+    }
+
+    info!(image_id = %report.image_id, "Self-attestation verified");
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    logging::init(cli.log_format);
+    frame::set_padding(cli.padding);
+
+    info!(
+        "secret: {}, loaders: {:?}, requester: {}",
+        cli.secret.as_deref().unwrap_or("<generated>"),
+        cli.loader,
+        cli.requester.as_deref().unwrap_or("<policy>")
+    );
 
-    println!(
-        "secret: {}, loader: {}, requester: {}",
-        cli.secret, cli.loader, cli.requester
+    // Inside an enclave KMS only releases keys to the attested image
+    let nsm_attest = |public_key: &[u8]| nsm_attestation(public_key, None);
+    let attest: Option<Attest> = cli.attestation_addr.map(|_| &nsm_attest as Attest);
+    let secret = Arc::new(match &cli.secret {
+        Some(spec) => keys::load(spec, attest).await?,
+        // Only the public key leaves the enclave, bound into its attestation
+        None => Locked::new(nsm_secret()?),
+    });
+    info!(
+        public_key = %hex::encode(x25519(**secret, X25519_BASEPOINT_BYTES)),
+        "App key ready"
     );
 
-    let mut file = File::open(cli.secret)?;
-    let mut secret = [0u8; 32];
-    file.read_exact(&mut secret)?;
+    let tenants: BTreeMap<String, TenantConfig> = match &cli.tenants {
+        Some(path) => serde_json::from_slice(&std::fs::read(path)?)?,
+        None => BTreeMap::new(),
+    };
+    if let Some(name) = tenants.keys().find(|t| t.is_empty() || t.contains('/')) {
+        return Err(format!("invalid tenant name {:?}", name).into());
+    }
+
+    let mut loaders = Vec::new();
+    let mut loader_pubs = Vec::new();
+    let paths = loader_paths(&cli.loader, cli.loader_dir.as_deref())?
+        .into_iter()
+        .map(|path| (None, path));
+    let tenant_paths = tenants.iter().flat_map(|(tenant, config)| {
+        config
+            .loaders
+            .iter()
+            .map(move |path| (Some(tenant.clone()), path.clone()))
+    });
+    for (tenant, path) in paths.chain(tenant_paths) {
+        let stem = Path::new(&path)
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or_else(|| format!("invalid loader key path {}", path))?;
+        // Named under its tenant, so two tenants' owners never share a contribution
+        let name = match &tenant {
+            Some(tenant) => format!("{}/{}", tenant, stem),
+            None => stem.to_owned(),
+        };
+        let mut file = File::open(&path)?;
+        let mut loader = [0; 32];
+        file.read_exact(&mut loader)?;
 
-    let mut file = File::open(cli.loader)?;
-    let mut loader = [0; 32];
-    file.read_exact(&mut loader)?;
+        // A shared name or key would merge two owners' contributions
+        if loaders.iter().any(|l: &LoaderKeys| l.name == name) || loader_pubs.contains(&loader) {
+            return Err(format!("duplicate loader {} ({})", name, path).into());
+        }
+        loader_pubs.push(loader);
+        loaders.push(LoaderKeys::new(name, tenant, loader));
+    }
+    if loaders.is_empty() {
+        return Err("no loader keys configured".into());
+    }
 
-    let mut file = File::open(cli.requester)?;
-    let mut requester = [0; 32];
-    file.read_exact(&mut requester)?;
+    let admin = match cli.admin {
+        Some(path) => {
+            let mut file = File::open(path)?;
+            let mut admin = [0u8; 32];
+            file.read_exact(&mut admin)?;
+            Some(admin)
+        }
+        None => None,
+    };
+    let auditor = match &cli.auditor {
+        Some(path) => {
+            let mut file = File::open(path)?;
+            let mut auditor = [0u8; 32];
+            file.read_exact(&mut auditor)?;
+            Some(auditor)
+        }
+        None => None,
+    };
 
-    let loader_shared = x25519(secret, loader);
-    let loader_cipher = ChaCha20Poly1305::new(&loader_shared.into());
+    if let Some(image_id) = &cli.expected_image_id {
+        let public = x25519(**secret, X25519_BASEPOINT_BYTES);
+        let endpoint = match cli.attestation_addr {
+            Some(_) => None,
+            None => Some(cli.attestation_endpoint.as_str()),
+        };
+        self_verify(endpoint, image_id, &public)
+            .await
+            .map_err(|e| format!("startup self-verification failed, refusing to serve: {}", e))?;
+    }
 
-    println!("Listening on: {}", cli.ip_addr);
+    let mut requesters = HashMap::new();
+    if let Some(path) = &cli.requester {
+        let mut file = File::open(path)?;
+        let mut requester = [0; 32];
+        file.read_exact(&mut requester)?;
+        requesters.insert(requester, Access::default());
+    }
+    if let Some(path) = &cli.view_policy {
+        for grant in views::read_policy(path)? {
+            let mut file = File::open(&grant.key)?;
+            let mut key = [0u8; 32];
+            file.read_exact(&mut key)?;
+            requesters.insert(key, grant.access);
+        }
+    }
+    let mut requester_tenants = HashMap::new();
+    for (tenant, config) in &tenants {
+        for path in &config.requesters {
+            let mut file = File::open(path)?;
+            let mut key = [0u8; 32];
+            file.read_exact(&mut key)?;
+            // A key in two tenants, or in one and outside it, would see both
+            if requesters.insert(key, Access::default()).is_some() {
+                return Err(format!("duplicate requester {}", path).into());
+            }
+            requester_tenants.insert(key, tenant.clone());
+        }
+    }
 
-    let listener = TcpListener::bind(cli.ip_addr).await?;
+    // One certificate for the app's lifetime, its key never leaves the enclave
+    let tls = if cli.tls {
+        let public = x25519(**secret, X25519_BASEPOINT_BYTES);
+        let config = ratls::server_config(|user_data| nsm_attestation(&public, Some(user_data)))?;
+        Some(TlsAcceptor::from(Arc::new(config)))
+    } else {
+        None
+    };
 
-    let mut data: Vec<u8> = vec![0, 0];
-    while let Ok((inbound, _)) = listener.accept().await {
-        let mut buf: Vec<u8> = Vec::with_capacity(1000);
-        let (mut ri, mut wi) = tokio::io::split(inbound);
-        let len = ri.read_to_end(&mut buf).await?;
-
-        if buf[0] == 0 {
-            data = loader_cipher
-                .decrypt(
-                    buf[1..13].into(),
-                    Payload {
-                        msg: &buf[13..len],
-                        aad: &[0],
-                    },
-                )
-                .map_err(|e| "Decrypt failed: ".to_owned() + &e.to_string())?;
-            wi.write_all(b"Data write suceeded!").await?;
-        } else if buf[0] == 1 {
-            let sum = data[0] + data[1];
-            wi.write_all(b"Result: ").await?;
-            wi.write_all(sum.to_string().as_bytes()).await?;
-        } else {
-            wi.write_all(b"Unknown msg").await?;
+    let store = match &cli.store {
+        Some(spec) => Some(Arc::new(store::open(spec, store_key(&secret))?)),
+        None => None,
+    };
+    let mut snapshot = Snapshot::default();
+    if let Some(store) = &store {
+        if let Some(blob) = store.get(SNAPSHOT_KEY).await? {
+            snapshot = serde_cbor::from_slice(&blob)?;
+            info!(datasets = snapshot.data.len(), "State restored");
+        }
+    }
+    for loader in loaders.iter_mut() {
+        loader.current_seq = snapshot.loader_seqs.get(&loader.name).copied().unwrap_or(0);
+    }
+    let mut paillier = HashMap::new();
+    for spec in &cli.paillier {
+        let (name, path) = spec.split_once('=').ok_or("--paillier takes <name=path>")?;
+        let key = PublicKey::from_bytes(&std::fs::read(path)?)?;
+        paillier.insert(name.to_owned(), key);
+    }
+
+    let mut ranges = HashMap::new();
+    for spec in &cli.value_range {
+        let (name, range) = spec
+            .split_once('=')
+            .and_then(|(name, range)| Some((name, range.split_once("..")?)))
+            .ok_or("--value-range takes <name=MIN..MAX>")?;
+        ranges.insert(name.to_owned(), (range.0.to_owned(), range.1.to_owned()));
+    }
+
+    let mut polls = HashMap::new();
+    for spec in &cli.poll {
+        let (name, close) = spec
+            .split_once('=')
+            .and_then(|(name, close)| Some((name, close.parse::<u64>().ok()?)))
+            .ok_or("--poll takes <name=CLOSE>, CLOSE in unix seconds")?;
+        // A ballot has to be read to be checked and counted
+        let mut opaque = cli.masked_dataset.iter().chain(&cli.shared_dataset);
+        if opaque.any(|d| d == name) || paillier.contains_key(name) {
+            return Err(format!("dataset {} can't be a poll", name).into());
+        }
+        polls.insert(name.to_owned(), close);
+    }
+    // Sketches merge by maximum, which means nothing for masks, shares or ballots
+    for name in &cli.sketch_dataset {
+        let mut other = cli.masked_dataset.iter().chain(&cli.shared_dataset);
+        if other.any(|d| d == name) || paillier.contains_key(name) || polls.contains_key(name) {
+            return Err(format!("dataset {} can't hold sketches", name).into());
+        }
+    }
+
+    let mut groups = HashMap::new();
+    for spec in &cli.contributor_group {
+        let (name, members) = spec
+            .split_once('=')
+            .ok_or("--contributor-group takes <name=LOADER,LOADER,...>")?;
+        let members: BTreeSet<String> = members.split(',').map(str::to_owned).collect();
+        let known = |member: &String| loaders.iter().any(|loader| &loader.name == member);
+        if let Some(unknown) = members.iter().find(|member| !known(member)) {
+            return Err(format!("group {} names unknown loader {}", name, unknown).into());
+        }
+        groups.insert(name.to_owned(), members);
+    }
+
+    let schemas = snapshot_schemas(&snapshot)?;
+    let spent = snapshot_spent(&snapshot)?;
+    let pending = usize::try_from(cli.max_pending_mib << 20)
+        .map_err(|_| "--max-pending-mib exceeds the address space")?;
+
+    let signing_secret = match &cli.signing_key {
+        Some(spec) => keys::load(spec, attest).await?,
+        None => Locked::new(signing::generate_secret()),
+    };
+    let signer = signing::signer(cli.signing_scheme, &signing_secret)?;
+    info!(
+        scheme = %signer.scheme(),
+        public_key = %hex::encode(signer.public_key()),
+        "Result signing key ready"
+    );
+
+    let max_upload = usize::try_from(cli.max_upload_mib << 20)
+        .map_err(|_| "--max-upload-mib exceeds the address space")?;
+
+    let state = Arc::new(State {
+        keys: Mutex::new(AppKeys::new(secret.clone())),
+        nsm: cli.secret.is_none(),
+        loaders: Mutex::new(loaders),
+        admin,
+        auditor,
+        audit: Mutex::new(snapshot.audit),
+        commitments: Mutex::new(snapshot.commitments),
+        blindings: Mutex::new(snapshot.blindings),
+        proofs: Mutex::new(snapshot.proofs),
+        idempotency: Mutex::new(snapshot.idempotency),
+        requesters,
+        tenants: tenants
+            .into_iter()
+            .map(|(name, config)| {
+                let tenant = Tenant {
+                    min_contributors: config.min_contributors.unwrap_or(cli.min_contributors),
+                    max_datasets: config.max_datasets,
+                };
+                (name, tenant)
+            })
+            .collect(),
+        requester_tenants,
+        spent: Mutex::new(spent),
+        data: Mutex::new(snapshot.data),
+        schemas: Mutex::new(schemas),
+        expiry: Mutex::new(snapshot.expiry),
+        versions: watch::channel(HashMap::new()).0,
+        subscribe_timeout: Duration::from_secs(cli.subscribe_timeout),
+        admin_seq: Mutex::new(snapshot.admin_seq),
+        canary: Mutex::new(Vec::new()),
+        read_timeout: Duration::from_secs(cli.read_timeout),
+        idle_timeout: Duration::from_secs(cli.idle_timeout),
+        write_timeout: Duration::from_secs(cli.write_timeout),
+        max_message: cli.max_message_kib * 1024,
+        max_upload,
+        pending: Arc::new(Semaphore::new(pending)),
+        min_contributors: cli.min_contributors,
+        min_bucket_count: cli.min_bucket_count,
+        masked: cli.masked_dataset.iter().cloned().collect(),
+        shared: cli.shared_dataset.iter().cloned().collect(),
+        sketched: cli.sketch_dataset.iter().cloned().collect(),
+        paillier,
+        ranges,
+        polls,
+        groups,
+        encrypted: Mutex::new(snapshot.encrypted),
+        tls,
+        noise: cli.noise,
+        dirty: watch::channel(()).0,
+        standby: AtomicBool::new(cli.replica_listen.is_some()),
+        term: AtomicU64::new(snapshot.term),
+        lease: cli.replica.as_ref().map(|_| Mutex::new(Instant::now())),
+        shutting_down: watch::channel(false).0,
+        metrics: Mutex::new(Metrics::default()),
+        signer,
+        attest_results: cli.attestation_addr.is_some(),
+    });
+
+    if let Some(store) = &store {
+        tokio::spawn(persist(store.clone(), state.clone()));
+    }
+    tokio::spawn(run_expiry(state.clone()));
+    if let (Some(addr), Some(image_id)) = (&cli.replica, &cli.peer_image_id) {
+        tokio::spawn(replicate(addr.clone(), image_id.clone(), state.clone()));
+    }
+
+    if let Some(epoch_secs) = cli.epoch_secs {
+        let hooks = Arc::new(Hooks::new(cli.hook.clone()));
+        tokio::spawn(run_epochs(
+            state.clone(),
+            hooks,
+            Duration::from_secs(epoch_secs),
+        ));
+    }
+
+    let keepalive = TcpKeepalive::new()
+        .with_time(Duration::from_secs(cli.keepalive))
+        .with_interval(Duration::from_secs(cli.keepalive));
+    let semaphore = Arc::new(Semaphore::new(cli.max_connections));
+
+    let mut listeners: Vec<Pin<Box<dyn Future<Output = Result<(), Box<dyn Error>>>>>> = Vec::new();
+    if let Some(ip_addr) = cli.ip_addr {
+        listeners.push(Box::pin(serve_tcp(
+            ip_addr,
+            state.clone(),
+            semaphore.clone(),
+            keepalive,
+        )));
+    }
+    if let Some(addr) = cli.attestation_addr {
+        listeners.push(Box::pin(serve_attestation(addr, state.clone())));
+    }
+    if let Some(addr) = cli.health_addr {
+        listeners.push(Box::pin(serve_health(addr, state.clone())));
+    }
+    if let Some(addr) = cli.metrics_addr {
+        listeners.push(Box::pin(serve_metrics(addr, state.clone())));
+    }
+    if let (Some(addr), Some(image_id)) = (cli.replica_listen, cli.peer_image_id.clone()) {
+        listeners.push(Box::pin(serve_replica(addr, image_id, state.clone())));
+    }
+    // The state holds the key from here on, zeroized when it's dropped
+    drop(secret);
+    for listen in cli.listen {
+        listeners.push(match listen {
+            Listen::Vsock(cid, port) => {
+                Box::pin(serve_vsock(cid, port, state.clone(), semaphore.clone()))
+            }
+            Listen::Unix(path) => Box::pin(serve_unix(path, state.clone(), semaphore.clone())),
+        });
+    }
+    tokio::select! {
+        res = futures::future::try_join_all(listeners) => {
+            res?;
         }
+        res = shutdown_signal() => res?,
     }
 
+    // Dropping the listeners stopped new connections; every in-flight connection holds a
+    // permit, so all permits are back once they have drained
+    info!("Shutting down, draining connections");
+    state.shutting_down.send_replace(true);
+    let permits = u32::try_from(cli.max_connections)?;
+    let deadline = Duration::from_secs(cli.shutdown_timeout);
+    match tokio::time::timeout(deadline, semaphore.acquire_many(permits)).await {
+        Ok(_) => info!("All connections drained"),
+        Err(_) => warn!("Shutdown deadline passed, dropping remaining connections"),
+    }
+    if let Some(store) = &store {
+        save(store, &state).await?;
+        info!("State saved");
+    }
+
+    // Returning drops the runtime's tasks and with them the last references to the
+    // state, zeroizing the key material it holds
     Ok(())
-}
\ No newline at end of file
+}
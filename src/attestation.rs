@@ -0,0 +1,1018 @@
+use aws_nitro_enclaves_cose::{crypto::Openssl, crypto::SigningPublicKey, CoseSign1};
+use openssl::asn1::Asn1Time;
+use openssl::x509::{X509VerifyResult, X509};
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_cbor::{self, value, value::Value};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::time::Instant;
+use subtle::ConstantTimeEq;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::ExtendedKeyUsage;
+use x509_parser::prelude::FromDer;
+
+/// AWS Nitro Enclaves root certificate, used when no other root is configured
+pub const AWS_ROOT_CERT: &[u8] = include_bytes!("../aws.cert");
+
+/// What an attestation document must satisfy to be accepted.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Policy {
+    /// Expected image ID (hex-encoded), empty to accept any image and only report it
+    pub image_id: String,
+
+    /// Expected module ID, matched as a regex against the whole value
+    #[serde(default)]
+    pub module_id: Option<String>,
+
+    /// Seconds of clock skew tolerated when checking certificate validity periods
+    #[serde(default = "default_clock_skew")]
+    pub clock_skew: i64,
+
+    /// Expected values of individual PCRs (hex-encoded) by index, checked in addition to
+    /// the image ID and used to pinpoint which PCR caused a mismatch
+    #[serde(default)]
+    pub expected_pcrs: BTreeMap<u64, String>,
+
+    /// Minimum vCPUs the enclave must report in its user_data
+    #[serde(default)]
+    pub min_vcpus: Option<u64>,
+
+    /// Minimum memory in MiB the enclave must report in its user_data
+    #[serde(default)]
+    pub min_memory_mib: Option<u64>,
+
+    /// Structural limits enforced before the document is parsed
+    #[serde(default)]
+    pub limits: Limits,
+}
+
+/// Bounds on an attestation document's shape, so a malicious endpoint can't trigger
+/// pathological memory or stack usage while it is parsed.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default)]
+pub struct Limits {
+    /// Maximum encoded document size in bytes
+    pub max_size: usize,
+    /// Maximum nesting depth of CBOR arrays and maps
+    pub max_depth: usize,
+    /// Maximum number of entries in any single CBOR array or map
+    pub max_items: u64,
+    /// Maximum number of intermediate certificates in the cabundle
+    pub max_cabundle: usize,
+    /// Maximum number of entries in the PCR map
+    pub max_pcrs: usize,
+    /// Maximum number of certificates in the chain, leaf and root included
+    pub max_chain_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        // Real Nitro documents are ~5 KiB, 4 levels deep, with 4 cabundle entries and
+        // 16 or 32 PCRs
+        Limits {
+            max_size: 32 * 1024,
+            max_depth: 8,
+            max_items: 64,
+            max_cabundle: 8,
+            max_pcrs: 32,
+            max_chain_depth: 6,
+        }
+    }
+}
+
+/// Enclave resources, reported by convention as a JSON object in the attestation
+/// document's `user_data`, e.g. `{"vcpus": 2, "memory_mib": 4096}`.
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct Resources {
+    #[serde(default)]
+    pub vcpus: Option<u64>,
+    #[serde(default)]
+    pub memory_mib: Option<u64>,
+}
+
+fn default_clock_skew() -> i64 {
+    300
+}
+
+fn get_all_certs(cert: X509, cabundle: Vec<Value>) -> Result<Vec<X509>, Box<dyn Error>> {
+    let mut all_certs = Vec::new();
+    all_certs.push(cert);
+    for cert in cabundle {
+        let intermediate_certificate = match cert {
+            Value::Bytes(b) => b,
+            _ => return Err("cabundle entry is not a byte string".into()),
+        };
+        let intermediate_certificate = X509::from_der(&intermediate_certificate)?;
+        all_certs.push(intermediate_certificate);
+    }
+    Ok(all_certs)
+}
+
+fn eku_purposes(eku: &ExtendedKeyUsage) -> Vec<String> {
+    let known = [
+        (eku.server_auth, "serverAuth"),
+        (eku.client_auth, "clientAuth"),
+        (eku.code_signing, "codeSigning"),
+        (eku.email_protection, "emailProtection"),
+        (eku.time_stamping, "timeStamping"),
+        (eku.ocsp_signing, "OCSPSigning"),
+    ];
+    let mut purposes: Vec<String> = known
+        .iter()
+        .filter(|(set, _)| *set)
+        .map(|(_, name)| name.to_string())
+        .collect();
+    purposes.extend(eku.other.iter().map(|oid| oid.to_id_string()));
+    purposes
+}
+
+/// Checks the extensions that make a signed chain well-formed: only CAs issue, within
+/// their path length, key usages allow what each certificate is used for, and no
+/// issuer narrows the extended key usage below what it issued. `certs` runs from the
+/// leaf to the root.
+fn check_extensions(certs: &[X509], max_chain_depth: usize) -> Result<(), Box<dyn Error>> {
+    if certs.len() > max_chain_depth {
+        return Err(format!(
+            "certificate chain too deep: {} certificates, limit {}",
+            certs.len(),
+            max_chain_depth
+        )
+        .into());
+    }
+
+    let ders = certs
+        .iter()
+        .map(|cert| cert.to_der())
+        .collect::<Result<Vec<_>, _>>()?;
+    let mut parsed = Vec::with_capacity(ders.len());
+    for der in &ders {
+        let (_, cert) = X509Certificate::from_der(der)?;
+        parsed.push(cert);
+    }
+
+    for (i, cert) in parsed.iter().enumerate() {
+        let constraints = cert.basic_constraints()?.map(|ext| ext.value);
+        let key_usage = cert.key_usage()?.map(|ext| ext.value);
+
+        if i == 0 {
+            if constraints.is_some_and(|bc| bc.ca) {
+                return Err("leaf certificate is a CA".into());
+            }
+            if key_usage.is_some_and(|ku| !ku.digital_signature()) {
+                return Err("leaf certificate key usage excludes digitalSignature".into());
+            }
+            continue;
+        }
+
+        let Some(constraints) = constraints.filter(|bc| bc.ca) else {
+            return Err(format!("issuer certificate {} is not a CA", i).into());
+        };
+        // Intermediate CAs between this issuer and the leaf
+        if let Some(path_len) = constraints.path_len_constraint {
+            if (i - 1) as u64 > path_len as u64 {
+                return Err(format!(
+                    "issuer certificate {} path length {} exceeded by {} intermediates",
+                    i,
+                    path_len,
+                    i - 1
+                )
+                .into());
+            }
+        }
+        if key_usage.is_some_and(|ku| !ku.key_cert_sign()) {
+            return Err(format!("issuer certificate {} key usage excludes keyCertSign", i).into());
+        }
+
+        if let Some(issuer_eku) = cert.extended_key_usage()?.map(|ext| ext.value) {
+            if issuer_eku.any {
+                continue;
+            }
+            let allowed = eku_purposes(issuer_eku);
+            for below in &parsed[..i] {
+                let Some(eku) = below.extended_key_usage()?.map(|ext| ext.value) else {
+                    continue;
+                };
+                if let Some(purpose) = eku_purposes(eku).iter().find(|p| !allowed.contains(p)) {
+                    return Err(format!(
+                        "issuer certificate {} does not allow extended key usage {}",
+                        i, purpose
+                    )
+                    .into());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn verify_cert_chain(
+    cert: X509,
+    cabundle: Vec<Value>,
+    root_cert_pem: Vec<u8>,
+    attestation_time: i64,
+    clock_skew: i64,
+    max_chain_depth: usize,
+    trace: &mut Trace,
+) -> Result<(), Box<dyn Error>> {
+    let certs = get_all_certs(cert, cabundle)?;
+    trace.step("extensions", &certs[0].to_der()?, || {
+        check_extensions(&certs, max_chain_depth)
+    })?;
+    // Use attestation timestamp for validation, not current system time, allowing for
+    // documents produced right at certificate issuance or expiry
+    let earliest = Asn1Time::from_unix(attestation_time - clock_skew)?;
+    let latest = Asn1Time::from_unix(attestation_time + clock_skew)?;
+    let mut i = 0;
+    while i < certs.len() - 1 {
+        trace.step(&format!("chain_link{}", i), &certs[i].to_der()?, || {
+            let pubkey = certs[i + 1].public_key()?;
+            let x = certs[i].verify(&pubkey)?;
+            if !x {
+                return Err("signature verification failed".into());
+            }
+            let x = certs[i + 1].issued(&certs[i]);
+            if x != X509VerifyResult::OK {
+                return Err("certificate issuer and subject verification failed".into());
+            }
+            if certs[i].not_after() < earliest || certs[i].not_before() > latest {
+                return Err("certificate timestamp expired/not valid".into());
+            }
+            Ok(())
+        })?;
+        i += 1;
+    }
+    trace.step("root", &root_cert_pem, || {
+        // Several roots may be trusted at once, e.g. during a root rotation
+        let roots = X509::stack_from_pem(&root_cert_pem)?;
+        if !roots.iter().any(|root| root == certs.last().unwrap()) {
+            return Err("root certificate mismatch".into());
+        }
+        Ok(())
+    })
+}
+
+fn compute_image_id(pcr0: &[u8], pcr1: &[u8], pcr2: &[u8], pcr16: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+
+    // Bitflags: PCR 0, 1, 2, 16
+    let bitflags: u32 = (1 << 0) | (1 << 1) | (1 << 2) | (1 << 16);
+    hasher.update(&bitflags.to_be_bytes());
+
+    // PCR values (48 bytes each)
+    hasher.update(pcr0);
+    hasher.update(pcr1);
+    hasher.update(pcr2);
+    hasher.update(pcr16);
+
+    hex::encode(hasher.finalize())
+}
+
+fn extract_pcr(
+    pcrs_map: &mut BTreeMap<Value, Value>,
+    index: u64,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let pcr = pcrs_map
+        .remove(&value::to_value(index).unwrap())
+        .ok_or(Box::<dyn Error>::from(format!("pcr{} not found", index)))?;
+    match pcr {
+        Value::Bytes(b) => Ok(b),
+        _ => Err(format!("pcr{} is not bytes", index).into()),
+    }
+}
+
+fn extract_pcr_optional(pcrs_map: &mut BTreeMap<Value, Value>, index: u64) -> Vec<u8> {
+    match pcrs_map.remove(&value::to_value(index).unwrap()) {
+        Some(Value::Bytes(b)) => b,
+        _ => vec![0u8; 48], // Default to zeros if not present
+    }
+}
+
+/// The facts established by a successful verification.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    #[serde(serialize_with = "hex::serde::serialize")]
+    pub public_key: Vec<u8>,
+    pub image_id: String,
+    pub module_id: String,
+    pub resources: Option<Resources>,
+    /// attestation timestamp in milliseconds since the unix epoch
+    pub timestamp: i64,
+}
+
+/// A verification failure tagged with the step that failed, so callers can count and
+/// report failures by category.
+#[derive(Debug)]
+pub struct VerifyError {
+    pub category: &'static str,
+    pub error: Box<dyn Error>,
+}
+
+impl VerifyError {
+    pub fn category<E: Into<Box<dyn Error>>>(category: &'static str) -> impl Fn(E) -> VerifyError {
+        move |e| VerifyError {
+            category,
+            error: e.into(),
+        }
+    }
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.category, self.error)
+    }
+}
+
+impl Error for VerifyError {}
+
+pub const FAILURE_CATEGORIES: [&str; 9] = [
+    "fetch",
+    "policy",
+    "document",
+    "image_id",
+    "module_id",
+    "resources",
+    "signature",
+    "certificate_chain",
+    "output",
+];
+
+/// Record of every step a verification performed, for audit logs.
+#[derive(Debug, Default, Serialize)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TraceStep {
+    pub step: String,
+    /// hex-encoded SHA-256 of the bytes the step examined
+    pub input_sha256: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub micros: u64,
+}
+
+impl Trace {
+    /// Runs `f` as the step `name` over `input`, recording its outcome and duration.
+    fn step<T>(
+        &mut self,
+        name: &str,
+        input: &[u8],
+        f: impl FnOnce() -> Result<T, Box<dyn Error>>,
+    ) -> Result<T, Box<dyn Error>> {
+        let started = Instant::now();
+        let result = f();
+        self.steps.push(TraceStep {
+            step: name.to_owned(),
+            input_sha256: hex::encode(Sha256::digest(input)),
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|e| e.to_string()),
+            micros: started.elapsed().as_micros() as u64,
+        });
+        result
+    }
+}
+
+/// Reads a CBOR item head, returning the major type and argument.
+fn read_cbor_head(buf: &[u8], pos: &mut usize) -> Result<(u8, u64), Box<dyn Error>> {
+    let initial = *buf.get(*pos).ok_or("truncated cbor")?;
+    *pos += 1;
+    let major = initial >> 5;
+    let len = match initial & 0x1f {
+        info @ 0..=23 => return Ok((major, info as u64)),
+        24 => 1,
+        25 => 2,
+        26 => 4,
+        27 => 8,
+        31 => return Err("indefinite-length cbor items are not supported".into()),
+        _ => return Err("malformed cbor item head".into()),
+    };
+    let bytes = buf.get(*pos..*pos + len).ok_or("truncated cbor")?;
+    *pos += len;
+    Ok((major, bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64)))
+}
+
+/// Walks the CBOR structure of `buf` without allocating per item, checking nesting depth
+/// and container sizes against `limits` before any real parsing happens.
+fn check_cbor(buf: &[u8], limits: &Limits) -> Result<(), Box<dyn Error>> {
+    if buf.len() > limits.max_size {
+        return Err(format!(
+            "document too large: {} bytes, limit {}",
+            buf.len(),
+            limits.max_size
+        )
+        .into());
+    }
+
+    let mut pos = 0;
+    // Items still expected at each open nesting level, starting with the single root
+    let mut pending: Vec<u64> = vec![1];
+    while let Some(remaining) = pending.last_mut() {
+        if *remaining == 0 {
+            pending.pop();
+            continue;
+        }
+        *remaining -= 1;
+
+        let (major, arg) = read_cbor_head(buf, &mut pos)?;
+        match major {
+            // Byte and text strings
+            2 | 3 => {
+                let len = usize::try_from(arg)?;
+                if len > buf.len() - pos {
+                    return Err("truncated cbor".into());
+                }
+                pos += len;
+            }
+            // Arrays and maps
+            4 | 5 => {
+                if arg > limits.max_items {
+                    return Err(format!(
+                        "cbor container too large: {} entries, limit {}",
+                        arg, limits.max_items
+                    )
+                    .into());
+                }
+                if pending.len() > limits.max_depth {
+                    return Err(format!("cbor nested deeper than {}", limits.max_depth).into());
+                }
+                pending.push(if major == 5 { arg * 2 } else { arg });
+            }
+            // A tag wraps exactly one following item
+            6 => *remaining += 1,
+            // Integers and simple values/floats carry everything in the head
+            _ => {}
+        }
+    }
+
+    if pos != buf.len() {
+        return Err("trailing bytes after cbor document".into());
+    }
+    Ok(())
+}
+
+/// Checks the fields read as DER certificates or keys hold byte strings, so well-formed
+/// CBOR with the wrong value types is refused before anything is verified.
+fn check_types(attestation_doc: &BTreeMap<Value, Value>) -> Result<(), Box<dyn Error>> {
+    let field = |name: &str| attestation_doc.get(&value::to_value(name).unwrap());
+    if let Some(certificate) = field("certificate") {
+        if !matches!(certificate, Value::Bytes(_)) {
+            return Err("certificate is not a byte string".into());
+        }
+    }
+    if let Some(cabundle) = field("cabundle") {
+        let Value::Array(cabundle) = cabundle else {
+            return Err("cabundle is not an array".into());
+        };
+        if cabundle.iter().any(|cert| !matches!(cert, Value::Bytes(_))) {
+            return Err("cabundle entry is not a byte string".into());
+        }
+    }
+    // Optional in the NSM format, so null is left for `extract_public_key` to refuse
+    if let Some(public_key) = field("public_key") {
+        if !matches!(public_key, Value::Bytes(_) | Value::Null) {
+            return Err("public key is not a byte string".into());
+        }
+    }
+    Ok(())
+}
+
+fn parse_document(
+    attestation_doc_cbor: &[u8],
+    limits: &Limits,
+) -> Result<(CoseSign1, BTreeMap<Value, Value>), Box<dyn Error>> {
+    check_cbor(attestation_doc_cbor, limits)?;
+    let cosesign1 = CoseSign1::from_bytes(attestation_doc_cbor)?;
+    let payload = cosesign1.get_payload::<Openssl>(None as Option<&dyn SigningPublicKey>)?;
+    check_cbor(&payload, limits)?;
+    let attestation_doc: BTreeMap<Value, Value> =
+        value::from_value(serde_cbor::from_slice::<Value>(&payload)?)?;
+    check_types(&attestation_doc)?;
+    Ok((cosesign1, attestation_doc))
+}
+
+/// Fields of an attestation document, decoded without checking anything but its shape.
+#[derive(Debug, Default)]
+pub struct Document {
+    pub module_id: Option<String>,
+    pub digest: Option<String>,
+    /// milliseconds since the unix epoch
+    pub timestamp: Option<i64>,
+    pub pcrs: BTreeMap<u64, Vec<u8>>,
+    pub certificate: Option<Vec<u8>>,
+    pub cabundle: Vec<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+    pub user_data: Option<Vec<u8>>,
+    pub nonce: Option<Vec<u8>>,
+}
+
+/// Decodes an attestation document for inspection. Nothing is verified, use `verify`
+/// before trusting any field.
+pub fn decode(attestation_doc_cbor: &[u8], limits: &Limits) -> Result<Document, Box<dyn Error>> {
+    let (_, attestation_doc) = parse_document(attestation_doc_cbor, limits)?;
+
+    let mut document = Document::default();
+    for (key, value) in attestation_doc {
+        let Value::Text(key) = key else { continue };
+        match (key.as_str(), value) {
+            ("module_id", Value::Text(t)) => document.module_id = Some(t),
+            ("digest", Value::Text(t)) => document.digest = Some(t),
+            ("timestamp", Value::Integer(i)) => document.timestamp = Some(i.try_into()?),
+            ("pcrs", Value::Map(pcrs)) => {
+                for (index, pcr) in pcrs {
+                    if let (Value::Integer(index), Value::Bytes(pcr)) = (index, pcr) {
+                        document.pcrs.insert(index.try_into()?, pcr);
+                    }
+                }
+            }
+            ("certificate", Value::Bytes(b)) => document.certificate = Some(b),
+            ("cabundle", Value::Array(certs)) => {
+                for cert in certs {
+                    if let Value::Bytes(cert) = cert {
+                        document.cabundle.push(cert);
+                    }
+                }
+            }
+            ("public_key", Value::Bytes(b)) => document.public_key = Some(b),
+            ("user_data", Value::Bytes(b)) => document.user_data = Some(b),
+            ("nonce", Value::Bytes(b)) => document.nonce = Some(b),
+            _ => {}
+        }
+    }
+    Ok(document)
+}
+
+/// What each PCR covers, for mismatch reports
+fn pcr_label(index: u64) -> &'static str {
+    match index {
+        0 => " (enclave image)",
+        1 => " (kernel and bootstrap)",
+        2 => " (application)",
+        _ => "",
+    }
+}
+
+/// Explains an image_id or PCR mismatch PCR by PCR, since two differing hashes alone
+/// don't say what changed.
+fn pcr_mismatch_report(
+    expected_image_id: &str,
+    computed_image_id: &str,
+    used: &[(u64, &Vec<u8>)],
+    others: &BTreeMap<u64, Vec<u8>>,
+    pcr16_present: bool,
+    expected_pcrs: &BTreeMap<u64, String>,
+) -> String {
+    let mut report = if computed_image_id == expected_image_id || expected_image_id.is_empty() {
+        format!("pcr mismatch with image_id {}", computed_image_id)
+    } else {
+        format!(
+            "image_id mismatch: expected {}, got {}",
+            expected_image_id, computed_image_id
+        )
+    };
+
+    let mut indexes: Vec<u64> = used.iter().map(|(index, _)| *index).collect();
+    let extra: Vec<u64> = expected_pcrs
+        .keys()
+        .filter(|index| !indexes.contains(index))
+        .copied()
+        .collect();
+    indexes.extend(extra);
+    for index in indexes {
+        let actual = match used.iter().find(|(i, _)| *i == index) {
+            Some((16, _)) if !pcr16_present => "absent, zeros used".to_owned(),
+            Some((_, pcr)) => hex::encode(pcr),
+            None => others
+                .get(&index)
+                .map(hex::encode)
+                .unwrap_or_else(|| "absent".to_owned()),
+        };
+        report.push_str(&format!("\n  pcr{}{}: {}", index, pcr_label(index), actual));
+        if let Some(expected) = expected_pcrs.get(&index) {
+            if !expected.eq_ignore_ascii_case(&actual) {
+                report.push_str(&format!("\n    DIFFERS, expected {}", expected));
+            }
+        }
+    }
+
+    if !pcr16_present {
+        report.push_str(
+            "\n  hint: the document has no PCR16, so zeros were used; check the expected \
+             image_id was computed the same way",
+        );
+    } else if let [(_, pcr0), (_, pcr1), (_, pcr2), _] = used {
+        if compute_image_id(pcr0, pcr1, pcr2, &[0u8; 48]) == expected_image_id {
+            report.push_str(
+                "\n  hint: the expected image_id matches with PCR16 zeroed, it was likely \
+                 computed without the PCR16 the enclave reports",
+            );
+        }
+    }
+    report
+}
+
+fn check_image_id(
+    attestation_doc: &mut BTreeMap<Value, Value>,
+    policy: &Policy,
+    trace: &mut Trace,
+) -> Result<String, Box<dyn Error>> {
+    // Extract PCRs
+    let document_pcrs_arr = attestation_doc
+        .remove(&value::to_value("pcrs").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "pcrs key not found in attestation doc",
+        ))?;
+    let mut document_pcrs_arr: BTreeMap<Value, Value> = value::from_value(document_pcrs_arr)?;
+    if document_pcrs_arr.len() > policy.limits.max_pcrs {
+        return Err(format!(
+            "too many pcrs: {}, limit {}",
+            document_pcrs_arr.len(),
+            policy.limits.max_pcrs
+        )
+        .into());
+    }
+
+    let pcr16_present = document_pcrs_arr.contains_key(&value::to_value(16u64).unwrap());
+    let pcr0 = extract_pcr(&mut document_pcrs_arr, 0)?;
+    let pcr1 = extract_pcr(&mut document_pcrs_arr, 1)?;
+    let pcr2 = extract_pcr(&mut document_pcrs_arr, 2)?;
+    let pcr16 = extract_pcr_optional(&mut document_pcrs_arr, 16);
+    let used = [(0, &pcr0), (1, &pcr1), (2, &pcr2), (16, &pcr16)];
+    for (index, pcr) in used {
+        trace.step(&format!("pcr{}", index), pcr, || Ok(()))?;
+    }
+
+    // Remaining PCRs are only needed when the policy pins them
+    let mut others = BTreeMap::new();
+    for index in policy.expected_pcrs.keys() {
+        if let Some(Value::Bytes(pcr)) = document_pcrs_arr.remove(&value::to_value(*index)?) {
+            others.insert(*index, pcr);
+        }
+    }
+
+    // Compute and verify image_id
+    let computed_image_id = compute_image_id(&pcr0, &pcr1, &pcr2, &pcr16);
+    let expected_image_id = policy.image_id.as_bytes();
+    let image_id_ok = expected_image_id.is_empty()
+        || bool::from(computed_image_id.as_bytes().ct_eq(expected_image_id));
+    let pcrs_ok = policy.expected_pcrs.iter().all(|(index, expected)| {
+        let actual = match used.iter().find(|(i, _)| i == index) {
+            Some((16, _)) if !pcr16_present => None,
+            Some((_, pcr)) => Some(*pcr),
+            None => others.get(index),
+        };
+        actual.is_some_and(|pcr| {
+            let expected = expected.to_ascii_lowercase();
+            bool::from(hex::encode(pcr).as_bytes().ct_eq(expected.as_bytes()))
+        })
+    });
+
+    let concatenated = [
+        pcr0.as_slice(),
+        pcr1.as_slice(),
+        pcr2.as_slice(),
+        pcr16.as_slice(),
+    ]
+    .concat();
+    trace.step("image_id", &concatenated, || {
+        if !image_id_ok || !pcrs_ok {
+            return Err(pcr_mismatch_report(
+                &policy.image_id,
+                &computed_image_id,
+                &used,
+                &others,
+                pcr16_present,
+                &policy.expected_pcrs,
+            )
+            .into());
+        }
+        Ok(())
+    })?;
+
+    Ok(computed_image_id)
+}
+
+fn check_signature(
+    cosesign1: &CoseSign1,
+    attestation_doc: &mut BTreeMap<Value, Value>,
+) -> Result<X509, Box<dyn Error>> {
+    let enclave_certificate = attestation_doc
+        .remove(&value::to_value("certificate").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "certificate key not found in attestation doc",
+        ))?;
+    let enclave_certificate = match enclave_certificate {
+        Value::Bytes(b) => b,
+        _ => return Err("certificate is not a byte string".into()),
+    };
+    let enclave_certificate = X509::from_der(&enclave_certificate)?;
+    let pub_key = enclave_certificate.public_key()?;
+    let verify_result = cosesign1.verify_signature::<Openssl>(&pub_key)?;
+
+    if !verify_result {
+        return Err("cose signature verification failed".into());
+    }
+
+    Ok(enclave_certificate)
+}
+
+fn check_module_id(
+    attestation_doc: &mut BTreeMap<Value, Value>,
+    expected_module_id: Option<&Regex>,
+) -> Result<String, Box<dyn Error>> {
+    let module_id = attestation_doc
+        .remove(&value::to_value("module_id").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "module_id not found in attestation doc",
+        ))?;
+    let module_id = match module_id {
+        Value::Text(t) => t,
+        _ => return Err("module_id is not a string".into()),
+    };
+
+    if let Some(expected) = expected_module_id {
+        if !expected.is_match(&module_id) {
+            return Err(format!(
+                "module_id mismatch: expected {}, got {}",
+                expected, module_id
+            )
+            .into());
+        }
+    }
+
+    Ok(module_id)
+}
+
+fn check_resources(
+    attestation_doc: &mut BTreeMap<Value, Value>,
+    policy: &Policy,
+) -> Result<Option<Resources>, Box<dyn Error>> {
+    let resources = match attestation_doc.remove(&value::to_value("user_data").unwrap()) {
+        Some(Value::Bytes(b)) => serde_json::from_slice::<Resources>(&b).ok(),
+        _ => None,
+    };
+
+    let required = [
+        (
+            "vcpus",
+            policy.min_vcpus,
+            resources.as_ref().and_then(|r| r.vcpus),
+        ),
+        (
+            "memory_mib",
+            policy.min_memory_mib,
+            resources.as_ref().and_then(|r| r.memory_mib),
+        ),
+    ];
+    for (name, min, actual) in required {
+        let Some(min) = min else { continue };
+        match actual {
+            Some(actual) if actual >= min => {}
+            Some(actual) => {
+                return Err(
+                    format!("{} below minimum: required {}, got {}", name, min, actual).into(),
+                )
+            }
+            None => return Err(format!("{} not reported in user_data", name).into()),
+        }
+    }
+
+    Ok(resources)
+}
+
+fn extract_timestamp(attestation_doc: &mut BTreeMap<Value, Value>) -> Result<i64, Box<dyn Error>> {
+    // Extract timestamp from attestation doc (in milliseconds)
+    let timestamp = attestation_doc
+        .remove(&value::to_value("timestamp").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "timestamp not found in attestation doc",
+        ))?;
+    let timestamp: i64 = match timestamp {
+        Value::Integer(i) => i.try_into()?,
+        _ => return Err("timestamp is not an integer".into()),
+    };
+    Ok(timestamp)
+}
+
+fn check_cert_chain(
+    attestation_doc: &mut BTreeMap<Value, Value>,
+    enclave_certificate: X509,
+    root_cert_pem: Vec<u8>,
+    timestamp: i64,
+    clock_skew: i64,
+    limits: &Limits,
+    trace: &mut Trace,
+) -> Result<(), Box<dyn Error>> {
+    let cabundle = attestation_doc
+        .remove(&value::to_value("cabundle").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "cabundle key not found in attestation doc",
+        ))?;
+
+    let mut cabundle: Vec<Value> = value::from_value(cabundle)?;
+    if cabundle.len() > limits.max_cabundle {
+        return Err(format!(
+            "cabundle too long: {} certificates, limit {}",
+            cabundle.len(),
+            limits.max_cabundle
+        )
+        .into());
+    }
+    cabundle.reverse();
+
+    // Pass timestamp in seconds (AWS Nitro uses milliseconds)
+    verify_cert_chain(
+        enclave_certificate,
+        cabundle,
+        root_cert_pem,
+        timestamp / 1000,
+        clock_skew,
+        limits.max_chain_depth,
+        trace,
+    )
+}
+
+fn extract_public_key(
+    attestation_doc: &mut BTreeMap<Value, Value>,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let public_key = attestation_doc
+        .remove(&value::to_value("public_key").unwrap())
+        .ok_or(Box::<dyn Error>::from(
+            "public key not found in attestation doc",
+        ))?;
+    let public_key = match public_key {
+        Value::Bytes(b) => b,
+        _ => return Err("public key is not a byte string".into()),
+    };
+    Ok(public_key)
+}
+
+/// Verifies an attestation document against `policy`. `root_cert_pem` may hold several
+/// PEM certificates, the chain is accepted if it ends at any of them.
+pub fn verify(
+    attestation_doc_cbor: &[u8],
+    root_cert_pem: &[u8],
+    policy: &Policy,
+) -> Result<Report, VerifyError> {
+    verify_traced(
+        attestation_doc_cbor,
+        root_cert_pem,
+        policy,
+        &mut Trace::default(),
+    )
+}
+
+/// Like `verify`, additionally recording each step into `trace`.
+pub fn verify_traced(
+    attestation_doc_cbor: &[u8],
+    root_cert_pem: &[u8],
+    policy: &Policy,
+    trace: &mut Trace,
+) -> Result<Report, VerifyError> {
+    let expected_module_id = policy
+        .module_id
+        .as_ref()
+        .map(|pattern| Regex::new(&format!("^(?:{})$", pattern)))
+        .transpose()
+        .map_err(VerifyError::category("policy"))?;
+
+    let (cosesign1, mut attestation_doc) = trace
+        .step("cose_parse", attestation_doc_cbor, || {
+            parse_document(attestation_doc_cbor, &policy.limits)
+        })
+        .map_err(VerifyError::category("document"))?;
+
+    let image_id = check_image_id(&mut attestation_doc, policy, trace)
+        .map_err(VerifyError::category("image_id"))?;
+
+    let module_id = trace
+        .step("module_id", attestation_doc_cbor, || {
+            check_module_id(&mut attestation_doc, expected_module_id.as_ref())
+        })
+        .map_err(VerifyError::category("module_id"))?;
+
+    let resources = trace
+        .step("resources", attestation_doc_cbor, || {
+            check_resources(&mut attestation_doc, policy)
+        })
+        .map_err(VerifyError::category("resources"))?;
+
+    // Verify COSE signature
+    let enclave_certificate = trace
+        .step("signature", attestation_doc_cbor, || {
+            check_signature(&cosesign1, &mut attestation_doc)
+        })
+        .map_err(VerifyError::category("signature"))?;
+
+    let timestamp =
+        extract_timestamp(&mut attestation_doc).map_err(VerifyError::category("document"))?;
+
+    // Verify certificate chain
+    check_cert_chain(
+        &mut attestation_doc,
+        enclave_certificate,
+        root_cert_pem.to_vec(),
+        timestamp,
+        policy.clock_skew,
+        &policy.limits,
+        trace,
+    )
+    .map_err(VerifyError::category("certificate_chain"))?;
+
+    // Extract public key
+    let public_key =
+        extract_public_key(&mut attestation_doc).map_err(VerifyError::category("document"))?;
+
+    Ok(Report {
+        public_key,
+        image_id,
+        module_id,
+        resources,
+        timestamp,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Untagged COSE_Sign1 around `payload` with empty headers and a dummy signature,
+    /// which parsing never checks.
+    fn cose(payload: &[u8]) -> Vec<u8> {
+        let protected = serde_cbor::to_vec(&Value::Map(BTreeMap::new())).unwrap();
+        serde_cbor::to_vec(&Value::Array(vec![
+            Value::Bytes(protected),
+            Value::Map(BTreeMap::new()),
+            Value::Bytes(payload.to_vec()),
+            Value::Bytes(vec![0; 96]),
+        ]))
+        .unwrap()
+    }
+
+    /// Attestation payload with every field of the type NSM gives it, but `field` set
+    /// to `value`.
+    fn payload(field: &str, value: Value) -> Vec<u8> {
+        let mut doc = BTreeMap::new();
+        let mut set = |name: &str, value: Value| {
+            doc.insert(value::to_value(name).unwrap(), value);
+        };
+        set("module_id", Value::Text("i-0123456789abcdef0-enc0".into()));
+        set("digest", Value::Text("SHA384".into()));
+        set("timestamp", Value::Integer(0));
+        set("pcrs", Value::Map(BTreeMap::new()));
+        set("certificate", Value::Bytes(vec![0x30]));
+        set("cabundle", Value::Array(vec![Value::Bytes(vec![0x30])]));
+        set("public_key", Value::Bytes(vec![0; 32]));
+        set(field, value);
+        serde_cbor::to_vec(&Value::Map(doc)).unwrap()
+    }
+
+    #[test]
+    fn parses_well_typed_documents() {
+        let doc = cose(&payload("user_data", Value::Null));
+        assert!(parse_document(&doc, &Limits::default()).is_ok());
+    }
+
+    #[test]
+    fn rejects_type_confused_documents() {
+        let confused = [
+            ("certificate", Value::Integer(0)),
+            ("cabundle", Value::Integer(0)),
+            ("cabundle", Value::Array(vec![Value::Integer(0)])),
+            ("public_key", Value::Text("key".into())),
+        ];
+        for (field, value) in confused {
+            let doc = cose(&payload(field, value));
+            let parsed = parse_document(&doc, &Limits::default());
+            assert!(parsed.is_err(), "{}", field);
+        }
+    }
+
+    #[test]
+    fn rejects_oversized_documents() {
+        let limits = Limits::default();
+        let large = Value::Bytes(vec![0; limits.max_size]);
+        let doc = cose(&payload("user_data", large));
+        assert!(parse_document(&doc, &limits).is_err());
+
+        let wide = Value::Array(vec![Value::Null; limits.max_items as usize + 1]);
+        let doc = cose(&payload("user_data", wide));
+        assert!(parse_document(&doc, &limits).is_err());
+    }
+
+    #[test]
+    fn rejects_deeply_nested_documents() {
+        let limits = Limits::default();
+        let nested = (0..=limits.max_depth).fold(Value::Null, |inner, _| Value::Array(vec![inner]));
+        let doc = cose(&payload("user_data", nested));
+        assert!(parse_document(&doc, &limits).is_err());
+
+        // Nested far past the parser's own recursion limit, without a payload around it
+        let mut doc = vec![0x81; 10_000];
+        doc.push(0xf6);
+        assert!(parse_document(&doc, &limits).is_err());
+    }
+}
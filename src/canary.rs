@@ -0,0 +1,99 @@
+use clap::Parser;
+use ppa::aead;
+use ppa::frame::{self, Padding};
+use ppa::message::{self, Envelope, Kind};
+use ppa::session;
+use ppa::sum;
+use ppa::transport;
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Fixed canary dataset and the result the app must produce for it
+const CANARY: [u64; 2] = [7, 35];
+const EXPECTED: u64 = 42;
+
+#[derive(Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// address of the server <ip:port> or unix:<path>
+    #[clap(short, long, value_parser)]
+    ip_addr: String,
+
+    /// path to app public key file
+    #[arg(short, long)]
+    app: String,
+
+    /// path to loader private key file
+    #[arg(short, long)]
+    secret: String,
+
+    /// connect over RA-TLS, accepting only an enclave attested to run this image ID
+    #[arg(long)]
+    tls_image_id: Option<String>,
+
+    /// connect through a Noise_IK channel keyed by the app's public key, for apps run
+    /// with --noise
+    #[arg(long)]
+    noise: bool,
+
+    /// pad every frame to hide its size: none, padme or block:<bytes>; only hides sizes
+    /// over --tls or --noise, which encrypt the frame headers
+    #[arg(long, default_value_t = Padding::None)]
+    padding: Padding,
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    frame::set_padding(cli.padding);
+
+    println!("secret: {}, app: {}", cli.secret, cli.app);
+
+    let mut file = File::open(cli.secret)?;
+    let mut secret = [0u8; 32];
+    file.read_exact(&mut secret)?;
+
+    let mut file = File::open(cli.app)?;
+    let mut app = [0u8; 32];
+    file.read_exact(&mut app)?;
+
+    let public = x25519(secret, X25519_BASEPOINT_BYTES);
+
+    // Both messages share one connection and its session
+    let mut conn = transport::dial(
+        &cli.ip_addr,
+        cli.tls_image_id.as_deref(),
+        cli.noise,
+        &secret,
+        &app,
+    )
+    .await?;
+    let session = session::connect(&mut conn, &secret, &app, false).await?;
+
+    let seq = aead::next_sequence();
+    let schema = sum::Schema {
+        numeric: sum::Numeric::INTEGER,
+        labels: Vec::new(),
+    };
+    let canary = sum::encode(&schema, None, &[CANARY.to_vec()])?;
+    let envelope = Envelope::seal(Kind::CanaryLoad, &public, seq, &session, &canary)?;
+    let resp = message::request(&mut conn, &envelope).await?;
+    println!("load: {}", String::from_utf8(resp)?);
+
+    // The result comes back sealed to our session
+    let envelope = Envelope::seal(Kind::CanaryCompute, &public, 0, &session, &[])?;
+    let resp = message::request(&mut conn, &envelope).await?;
+    let resp = String::from_utf8(session.decrypt(&resp, &envelope.reply_aad())?)?;
+    println!("compute: {}", resp);
+
+    let expected = format!("Result: {}", EXPECTED);
+    if resp != expected {
+        return Err(format!("canary failed: expected \"{}\", got \"{}\"", expected, resp).into());
+    }
+
+    println!("Canary passed!");
+
+    Ok(())
+}
@@ -0,0 +1,116 @@
+//! C ABI for attestation verification, enabled with the `ffi` feature.
+//!
+//! See `include/ppa.h` for the corresponding declarations.
+
+use crate::attestation::{self, Policy, AWS_ROOT_CERT};
+use serde::Deserialize;
+use std::ffi::{c_char, c_int, CStr, CString};
+use std::panic;
+use std::ptr;
+
+pub const PPA_OK: c_int = 0;
+pub const PPA_INVALID_ARGUMENT: c_int = 1;
+pub const PPA_VERIFICATION_FAILED: c_int = 2;
+
+#[derive(Deserialize)]
+struct FfiPolicy {
+    #[serde(flatten)]
+    policy: Policy,
+
+    /// PEM root certificate, defaults to the AWS Nitro Enclaves root
+    #[serde(default)]
+    root_cert_pem: Option<String>,
+}
+
+fn error_json(category: &str, error: &str) -> String {
+    serde_json::json!({ "category": category, "error": error }).to_string()
+}
+
+fn run(doc: &[u8], policy_json: &CStr) -> (c_int, String) {
+    let policy: FfiPolicy = match policy_json
+        .to_str()
+        .map_err(|e| e.to_string())
+        .and_then(|json| serde_json::from_str(json).map_err(|e| e.to_string()))
+    {
+        Ok(policy) => policy,
+        Err(e) => return (PPA_INVALID_ARGUMENT, error_json("policy", &e)),
+    };
+
+    let root_cert = policy
+        .root_cert_pem
+        .as_ref()
+        .map(|pem| pem.as_bytes())
+        .unwrap_or(AWS_ROOT_CERT);
+
+    match attestation::verify(doc, root_cert, &policy.policy) {
+        Ok(report) => match serde_json::to_string(&report) {
+            Ok(json) => (PPA_OK, json),
+            Err(e) => (
+                PPA_VERIFICATION_FAILED,
+                error_json("output", &e.to_string()),
+            ),
+        },
+        Err(e) => (
+            PPA_VERIFICATION_FAILED,
+            error_json(e.category, &e.error.to_string()),
+        ),
+    }
+}
+
+/// Verifies an attestation document against a JSON policy.
+///
+/// On return `*out_report` points to a NUL-terminated JSON string: the verification
+/// report on success, or `{"category": ..., "error": ...}` on failure. It must be
+/// released with `ppa_free_string`. A panic while verifying is caught and reported as
+/// a failure, never unwound into the caller.
+///
+/// # Safety
+///
+/// `doc_ptr` must point to `doc_len` readable bytes, `policy_json` must be a valid
+/// NUL-terminated string and `out_report` must be a valid pointer to write to.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ppa_verify(
+    doc_ptr: *const u8,
+    doc_len: usize,
+    policy_json: *const c_char,
+    out_report: *mut *mut c_char,
+) -> c_int {
+    panic::catch_unwind(|| {
+        if out_report.is_null() {
+            return PPA_INVALID_ARGUMENT;
+        }
+        unsafe { *out_report = ptr::null_mut() };
+        if doc_ptr.is_null() || policy_json.is_null() {
+            return PPA_INVALID_ARGUMENT;
+        }
+
+        let doc = unsafe { std::slice::from_raw_parts(doc_ptr, doc_len) };
+        let policy_json = unsafe { CStr::from_ptr(policy_json) };
+
+        let (code, report) = run(doc, policy_json);
+        // serde_json escapes control characters, so the output never contains a NUL
+        let report = CString::new(report).expect("json contains no NUL bytes");
+        unsafe { *out_report = report.into_raw() };
+        code
+    })
+    .unwrap_or_else(|_| {
+        // Only reached past the null check on `out_report`
+        let report = error_json("internal", "verification panicked");
+        let report = CString::new(report).expect("json contains no NUL bytes");
+        unsafe { *out_report = report.into_raw() };
+        PPA_VERIFICATION_FAILED
+    })
+}
+
+/// Releases a string returned by `ppa_verify`.
+///
+/// # Safety
+///
+/// `s` must be null or a pointer previously returned through `ppa_verify` that has not
+/// been freed yet.
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn ppa_free_string(s: *mut c_char) {
+    if !s.is_null() {
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
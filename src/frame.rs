@@ -0,0 +1,267 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Largest payload sent or accepted in a single frame; the app may accept less
+pub const MAX_LEN: u32 = 1 << 20;
+
+/// Message type of a response carrying an `AppError` instead of a result
+pub const ERROR: u8 = 0xfe;
+
+/// Message type of a padded frame, whose payload is `type | length (u32 BE) | payload |
+/// zeros`: the frame it hides, followed by padding
+pub const PADDED: u8 = 0xfd;
+
+/// The sender isn't allowed to send the message
+pub const ERR_AUTH: &str = "ERR_AUTH";
+/// The payload didn't authenticate under the expected key
+pub const ERR_DECRYPT: &str = "ERR_DECRYPT";
+/// The message's sequence number was already used
+pub const ERR_REPLAY: &str = "ERR_REPLAY";
+/// A decrypted payload doesn't match its declared schema
+pub const ERR_SCHEMA: &str = "ERR_SCHEMA";
+/// A payload can't be parsed: truncated, trailing bytes or an unknown value
+pub const ERR_MALFORMED: &str = "ERR_MALFORMED";
+/// A payload is shorter or longer than it declares, or over a size limit
+pub const ERR_SIZE: &str = "ERR_SIZE";
+/// The instance doesn't lead its replicated pair; the request goes to the leader
+pub const ERR_STANDBY: &str = "ERR_STANDBY";
+/// Any other failure
+pub const ERR_REQUEST: &str = "ERR_REQUEST";
+
+/// Body of an error frame, as JSON: a stable code clients can match on and a message
+/// for people.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct AppError {
+    pub code: String,
+    pub message: String,
+    /// correlation ID of the request that failed, when it carried one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<u64>,
+}
+
+impl AppError {
+    pub fn new(code: &str, message: impl Into<String>) -> Box<dyn Error> {
+        Box::new(AppError {
+            code: code.to_owned(),
+            message: message.into(),
+            correlation_id: None,
+        })
+    }
+
+    /// An `ERR_MALFORMED` error for a payload that isn't a valid `what`.
+    pub fn malformed(what: &str) -> Box<dyn Error> {
+        AppError::new(ERR_MALFORMED, format!("malformed {}", what))
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl Error for AppError {}
+
+/// How frames are padded before they're written, so their lengths don't give away the
+/// size of what they carry.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Padding {
+    #[default]
+    None,
+    /// up to a multiple of this many bytes
+    Block(u32),
+    /// Padmé: keep the exponent and the top bits of the length, zero the rest. Costs at
+    /// most 12% and leaks O(log log n) bits of the length
+    Padme,
+}
+
+impl Padding {
+    /// Length a frame of `len` bytes is padded to.
+    pub fn padded_len(self, len: usize) -> usize {
+        match self {
+            Padding::None => len,
+            Padding::Block(block) => len.next_multiple_of(block as usize),
+            Padding::Padme if len < 2 => len,
+            Padding::Padme => {
+                let exponent = len.ilog2();
+                let kept = exponent.ilog2() + 1;
+                let mask = (1usize << (exponent - kept)) - 1;
+                (len + mask) & !mask
+            }
+        }
+    }
+}
+
+impl fmt::Display for Padding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Padding::None => f.write_str("none"),
+            Padding::Block(block) => write!(f, "block:{}", block),
+            Padding::Padme => f.write_str("padme"),
+        }
+    }
+}
+
+impl FromStr for Padding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Padding::None),
+            "padme" => Ok(Padding::Padme),
+            _ => s
+                .strip_prefix("block:")
+                .and_then(|block| block.parse().ok())
+                .filter(|block| *block > 0)
+                .map(Padding::Block)
+                .ok_or_else(|| {
+                    format!(
+                        "unknown padding {:?}, expected none, padme or block:<bytes>",
+                        s
+                    )
+                }),
+        }
+    }
+}
+
+static PADDING: OnceLock<Padding> = OnceLock::new();
+
+/// Sets how every frame this process writes is padded. Only the first call counts.
+pub fn set_padding(padding: Padding) {
+    let _ = PADDING.set(padding);
+}
+
+/// Writes one frame: the payload length as a big-endian u32, the message type, then
+/// the payload. With padding set, the frame is wrapped in a `PADDED` frame unless that
+/// would take it over `MAX_LEN`.
+pub async fn write<W: AsyncWrite + Unpin>(
+    w: &mut W,
+    msg_type: u8,
+    payload: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_LEN)
+        .ok_or("frame too large")?;
+
+    let mut buf = Vec::with_capacity(5 + payload.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.push(msg_type);
+    buf.extend_from_slice(payload);
+    let padding = PADDING.get().copied().unwrap_or_default();
+    let padded = padding.padded_len(buf.len());
+    if padding != Padding::None && padded <= MAX_LEN as usize {
+        buf.resize(padded, 0);
+        let mut wrapped = Vec::with_capacity(5 + padded);
+        wrapped.extend_from_slice(&(padded as u32).to_be_bytes());
+        wrapped.push(PADDED);
+        wrapped.extend_from_slice(&buf);
+        buf = wrapped;
+    }
+    w.write_all(&buf).await?;
+    w.flush().await?;
+    Ok(())
+}
+
+/// Reads one frame and returns its message type and payload, or `None` if the peer
+/// closed the connection cleanly between frames.
+pub async fn read<R: AsyncRead + Unpin>(
+    r: &mut R,
+) -> Result<Option<(u8, Vec<u8>)>, Box<dyn Error>> {
+    let mut first = [0u8; 1];
+    if r.read(&mut first).await? == 0 {
+        return Ok(None);
+    }
+    read_rest(r, first[0], MAX_LEN).await.map(Some)
+}
+
+/// Like `read`, but fails if no frame starts within `idle`, or if a frame that started
+/// hasn't fully arrived within `timeout`, so a client trickling bytes can't hold the
+/// connection open. Frames over `max_len` fail with `ERR_SIZE` before their payload is
+/// read.
+pub async fn read_timed<R: AsyncRead + Unpin>(
+    r: &mut R,
+    idle: Duration,
+    timeout: Duration,
+    max_len: u32,
+) -> Result<Option<(u8, Vec<u8>)>, Box<dyn Error>> {
+    let mut first = [0u8; 1];
+    let n = tokio::time::timeout(idle, r.read(&mut first))
+        .await
+        .map_err(|_| "connection idle too long")??;
+    if n == 0 {
+        return Ok(None);
+    }
+    tokio::time::timeout(timeout, read_rest(r, first[0], max_len))
+        .await
+        .map_err(|_| "read timed out")?
+        .map(Some)
+}
+
+/// Reads the rest of a frame whose first byte has arrived, unwrapping it if padded.
+async fn read_rest<R: AsyncRead + Unpin>(
+    r: &mut R,
+    first: u8,
+    max_len: u32,
+) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    let (msg_type, mut payload) = read_frame(r, first, max_len).await?;
+    if msg_type != PADDED {
+        return Ok((msg_type, payload));
+    }
+    // A padded frame hides exactly one frame, never another padded one
+    let (header, inner) = payload
+        .split_first_chunk::<5>()
+        .ok_or_else(|| AppError::malformed("padded frame"))?;
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let inner_type = header[4];
+    if inner_type == PADDED || len > inner.len() {
+        return Err(AppError::malformed("padded frame"));
+    }
+    payload.truncate(5 + len);
+    payload.drain(..5);
+    Ok((inner_type, payload))
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(
+    r: &mut R,
+    first: u8,
+    max_len: u32,
+) -> Result<(u8, Vec<u8>), Box<dyn Error>> {
+    let mut header = [first, 0, 0, 0, 0];
+    r.read_exact(&mut header[1..]).await?;
+
+    let len = u32::from_be_bytes([header[0], header[1], header[2], header[3]]);
+    // Checked before anything is allocated, so a client can't make the reader buffer
+    // more than the limit
+    if len > max_len {
+        let reason = format!("frame of {} bytes exceeds {} byte limit", len, max_len);
+        return Err(AppError::new(ERR_SIZE, reason));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    r.read_exact(&mut payload).await?;
+    Ok((header[4], payload))
+}
+
+/// Sends one message and waits for its response payload, turning error responses
+/// into errors. Callers can downcast them to `AppError` for the code.
+pub async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    msg_type: u8,
+    payload: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    write(conn, msg_type, payload).await?;
+    match read(conn).await? {
+        Some((ERROR, msg)) => {
+            let error: AppError = serde_json::from_slice(&msg)?;
+            Err(Box::new(error))
+        }
+        Some((_, resp)) => Ok(resp),
+        None => Err("connection closed before a response".into()),
+    }
+}
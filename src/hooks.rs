@@ -0,0 +1,95 @@
+use crate::http::{https_client, HttpsClient};
+use hyper::{Body, Method, Request};
+use std::error::Error;
+use std::sync::Mutex;
+use std::time::Duration;
+
+const MAX_ATTEMPTS: u32 = 5;
+
+/// Slack-compatible payload used when no template is configured
+pub const DEFAULT_TEMPLATE: &str = r#"{"text": "[{{event}}] {{endpoint}}: {{detail}}"}"#;
+
+/// Replaces each `{{name}}` in `template` with its value, escaped for use inside a JSON
+/// string.
+pub fn render(template: &str, fields: &[(&str, &str)]) -> String {
+    let mut out = template.to_owned();
+    for (name, value) in fields {
+        let escaped = serde_json::to_string(value).unwrap();
+        out = out.replace(&format!("{{{{{}}}}}", name), &escaped[1..escaped.len() - 1]);
+    }
+    out
+}
+
+/// Webhooks notified once per closed epoch, or of arbitrary events through `send`.
+///
+/// Each epoch is delivered at most once per process, and every request carries an
+/// `Idempotency-Key` so receivers can drop duplicates caused by retries.
+pub struct Hooks {
+    urls: Vec<String>,
+    client: HttpsClient,
+    last_fired: Mutex<Option<u64>>,
+}
+
+impl Hooks {
+    pub fn new(urls: Vec<String>) -> Self {
+        Hooks {
+            urls,
+            client: https_client(),
+            last_fired: Mutex::new(None),
+        }
+    }
+
+    async fn post(&self, url: &str, key: &str, body: &[u8]) -> Result<(), Box<dyn Error>> {
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri(url)
+            .header("content-type", "application/json")
+            .header("idempotency-key", key)
+            .body(Body::from(body.to_vec()))?;
+        let res = self.client.request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("hook returned {}", res.status()).into());
+        }
+        Ok(())
+    }
+
+    /// Delivers `body` for `epoch` to every hook. Epochs at or below the last fired one
+    /// are ignored.
+    pub async fn fire(&self, epoch: u64, body: &[u8]) {
+        {
+            let mut last_fired = self.last_fired.lock().unwrap();
+            if last_fired.is_some_and(|last| epoch <= last) {
+                return;
+            }
+            *last_fired = Some(epoch);
+        }
+
+        self.send(&epoch.to_string(), body).await
+    }
+
+    /// Delivers `body` to every hook under the idempotency `key`, retrying failures with
+    /// exponential backoff.
+    pub async fn send(&self, key: &str, body: &[u8]) {
+        for url in &self.urls {
+            let mut backoff = Duration::from_secs(1);
+            for attempt in 1..=MAX_ATTEMPTS {
+                let err = match self.post(url, key, body).await {
+                    Ok(()) => break,
+                    Err(e) => e.to_string(),
+                };
+                tracing::warn!(
+                    "hook {} key {} attempt {}/{} failed: {}",
+                    url,
+                    key,
+                    attempt,
+                    MAX_ATTEMPTS,
+                    err
+                );
+                if attempt < MAX_ATTEMPTS {
+                    tokio::time::sleep(backoff).await;
+                    backoff *= 2;
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,93 @@
+use crate::aead::CommittingCipher;
+use ::hpke::aead::ChaCha20Poly1305;
+use ::hpke::kdf::HkdfSha256;
+use ::hpke::kem::X25519HkdfSha256;
+use ::hpke::{Deserializable, HpkeError, OpModeR, OpModeS, Serializable};
+use rand_core::OsRng;
+use std::error::Error;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroizing;
+
+type Kem = X25519HkdfSha256;
+type PrivateKey = <Kem as ::hpke::Kem>::PrivateKey;
+type PublicKey = <Kem as ::hpke::Kem>::PublicKey;
+type EncappedKey = <Kem as ::hpke::Kem>::EncappedKey;
+
+/// HPKE `info`, binding every context to this protocol and version
+const INFO: &[u8] = b"ppa-hpke-v1";
+
+/// Exporter label of the key replies are sealed under
+const REPLY_LABEL: &[u8] = b"ppa-hpke-reply";
+
+/// Length of the encapsulated key heading every sealed message
+pub const ENC_LEN: usize = 32;
+
+/// Length of a sealed message carrying `msg_len` bytes of plaintext.
+pub const fn sealed_len(msg_len: usize) -> usize {
+    ENC_LEN + msg_len + 16
+}
+
+fn hpke_error(e: HpkeError) -> Box<dyn Error> {
+    format!("HPKE failed: {}", e).into()
+}
+
+fn private_key(secret: &[u8; 32]) -> Result<PrivateKey, Box<dyn Error>> {
+    PrivateKey::from_bytes(secret).map_err(hpke_error)
+}
+
+fn public_key(public: &[u8; 32]) -> Result<PublicKey, Box<dyn Error>> {
+    PublicKey::from_bytes(public).map_err(hpke_error)
+}
+
+/// Seals `msg` to `recipient` with single-shot HPKE in auth mode (RFC 9180, X25519,
+/// HKDF-SHA256, ChaCha20Poly1305), authenticated by the sender's static `secret`.
+/// Returns `enc | ciphertext` and the cipher the reply comes back under, keyed by an
+/// export of the HPKE context so only this sender can open it.
+pub fn seal(
+    secret: &[u8; 32],
+    recipient: &[u8; 32],
+    aad: &[u8],
+    msg: &[u8],
+) -> Result<(Vec<u8>, CommittingCipher), Box<dyn Error>> {
+    let public = public_key(&x25519(*secret, X25519_BASEPOINT_BYTES))?;
+    let mode = OpModeS::Auth((private_key(secret)?, public));
+    let (enc, mut context) = ::hpke::setup_sender::<ChaCha20Poly1305, HkdfSha256, Kem, _>(
+        &mode,
+        &public_key(recipient)?,
+        INFO,
+        &mut OsRng,
+    )
+    .map_err(hpke_error)?;
+    let mut sealed = enc.to_bytes().to_vec();
+    sealed.extend(context.seal(msg, aad).map_err(hpke_error)?);
+    let mut key = [0u8; 32];
+    context.export(REPLY_LABEL, &mut key).map_err(hpke_error)?;
+    Ok((sealed, CommittingCipher::new(key)))
+}
+
+/// Opens a message sealed by `seal` from the static key `sender` to `secret`. Returns
+/// the plaintext and the cipher to seal the reply under.
+pub fn open(
+    secret: &[u8; 32],
+    sender: &[u8; 32],
+    aad: &[u8],
+    sealed: &[u8],
+) -> Result<(Zeroizing<Vec<u8>>, CommittingCipher), Box<dyn Error>> {
+    if sealed.len() < sealed_len(0) {
+        return Err("ciphertext too short".into());
+    }
+    let (enc, ciphertext) = sealed.split_at(ENC_LEN);
+    let enc = EncappedKey::from_bytes(enc).map_err(hpke_error)?;
+    let mode = OpModeR::Auth(public_key(sender)?);
+    let mut context = ::hpke::setup_receiver::<ChaCha20Poly1305, HkdfSha256, Kem>(
+        &mode,
+        &private_key(secret)?,
+        &enc,
+        INFO,
+    )
+    .map_err(hpke_error)?;
+    let msg = Zeroizing::new(context.open(ciphertext, aad).map_err(hpke_error)?);
+    let mut key = [0u8; 32];
+    context.export(REPLY_LABEL, &mut key).map_err(hpke_error)?;
+    Ok((msg, CommittingCipher::new(key)))
+}
@@ -0,0 +1,47 @@
+use hyper::client::HttpConnector;
+use hyper::{Body, Client, Uri};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
+use std::error::Error;
+
+pub type HttpsClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+fn env_proxy(names: [&str; 2]) -> Option<Uri> {
+    names
+        .iter()
+        .find_map(|name| std::env::var(name).ok())
+        .filter(|proxy| !proxy.is_empty())
+        .and_then(|proxy| match proxy.parse::<Uri>() {
+            Ok(uri) => Some(uri),
+            Err(e) => {
+                tracing::warn!("ignoring invalid proxy {}: {}", proxy, e);
+                None
+            }
+        })
+}
+
+/// Client that speaks both plain HTTP and HTTPS.
+///
+/// `proxy` routes every request through the given proxy. Without it, the usual
+/// `HTTPS_PROXY`/`HTTP_PROXY` environment variables (or their lowercase forms) are
+/// honoured for HTTPS and HTTP destinations respectively.
+pub fn proxied_client(proxy: Option<&str>) -> Result<HttpsClient, Box<dyn Error>> {
+    let mut connector = ProxyConnector::new(HttpsConnector::new())?;
+    match proxy {
+        Some(proxy) => connector.add_proxy(Proxy::new(Intercept::All, proxy.parse::<Uri>()?)),
+        None => {
+            if let Some(uri) = env_proxy(["HTTPS_PROXY", "https_proxy"]) {
+                connector.add_proxy(Proxy::new(Intercept::Https, uri));
+            }
+            if let Some(uri) = env_proxy(["HTTP_PROXY", "http_proxy"]) {
+                connector.add_proxy(Proxy::new(Intercept::Http, uri));
+            }
+        }
+    }
+    Ok(Client::builder().build::<_, Body>(connector))
+}
+
+/// Client configured from the environment, see `proxied_client`.
+pub fn https_client() -> HttpsClient {
+    proxied_client(None).expect("tls connector initialization failed")
+}
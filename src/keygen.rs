@@ -1,9 +1,12 @@
 use clap::Parser;
+use ppa::paillier;
+use ppa::signing::{self, Scheme};
 use rand_core::OsRng;
 use std::error::Error;
-use std::fs::File;
+use std::fs::{File, OpenOptions};
 use std::io::Write;
 use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
@@ -15,6 +18,27 @@ struct Cli {
     /// path to public key file
     #[arg(short, long)]
     public: String,
+
+    /// generate a signing key of this scheme instead of an X25519 key <ed25519|p256|secp256k1>
+    #[arg(long)]
+    signing: Option<Scheme>,
+
+    /// generate a Paillier key with a modulus of this many bits instead, for encrypted
+    /// datasets
+    #[arg(long, conflicts_with = "signing")]
+    paillier: Option<i32>,
+}
+
+/// Creates the secret key file readable by the owner only where the platform supports it
+fn create_secret_file(path: &str) -> std::io::Result<File> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    options.open(path)
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
@@ -22,14 +46,28 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     println!("private key: {}, public key: {}", cli.secret, cli.public);
 
-    let secret = StaticSecret::new(OsRng);
-    let public = PublicKey::from(&secret);
+    let (secret, public) = match (cli.signing, cli.paillier) {
+        (Some(scheme), _) => {
+            let secret = signing::generate_secret();
+            let public = signing::signer(scheme, &secret)?.public_key();
+            (Zeroizing::new(secret.to_vec()), public)
+        }
+        (None, Some(bits)) => {
+            let key = paillier::PrivateKey::generate(bits)?;
+            (key.to_bytes(), key.public().to_bytes())
+        }
+        (None, None) => {
+            let secret = StaticSecret::new(OsRng);
+            let public = PublicKey::from(&secret).to_bytes().to_vec();
+            (Zeroizing::new(secret.to_bytes().to_vec()), public)
+        }
+    };
 
-    let mut file = File::create(cli.secret)?;
-    file.write_all(&secret.to_bytes())?;
+    let mut file = create_secret_file(&cli.secret)?;
+    file.write_all(&secret)?;
 
     let mut file = File::create(cli.public)?;
-    file.write_all(&public.to_bytes())?;
+    file.write_all(&public)?;
 
     println!("Generation successful!");
 
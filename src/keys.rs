@@ -0,0 +1,134 @@
+use crate::http::https_client;
+use crate::locked::Locked;
+use crate::store::AwsCredentials;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use hyper::Method;
+use openssl::cms::CmsContentInfo;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use zeroize::Zeroizing;
+
+/// Produces an attestation document embedding the given DER public key.
+pub type Attest<'a> = &'a dyn Fn(&[u8]) -> Result<Vec<u8>, Box<dyn Error>>;
+
+/// Loads a 32-byte private key from a spec:
+///
+/// - `<path>` or `file:<path>`: the raw key in a file
+/// - `env:<VAR>`: the key as hex in an environment variable
+/// - `secretsmanager:<secret-id>`: an AWS Secrets Manager secret holding the key as hex
+///   (`SecretString`) or raw bytes (`SecretBinary`)
+/// - `kms:<path>`: a file holding an AWS KMS ciphertext blob of the key
+///
+/// With `attest`, KMS answers only to the attested enclave: the key comes back encrypted
+/// to a throwaway RSA key whose public half is bound into the attestation, so it never
+/// crosses the host in the clear. AWS credentials and region come from the environment.
+/// The key is returned in locked memory.
+pub async fn load(
+    spec: &str,
+    attest: Option<Attest<'_>>,
+) -> Result<Locked<[u8; 32]>, Box<dyn Error>> {
+    let key = match spec.split_once(':') {
+        Some(("env", var)) => {
+            let hex_key =
+                Zeroizing::new(std::env::var(var).map_err(|_| format!("{} is not set", var))?);
+            Zeroizing::new(hex::decode(hex_key.trim())?)
+        }
+        Some(("secretsmanager", id)) => secrets_manager(id).await?,
+        Some(("kms", path)) => kms_decrypt(&std::fs::read(path)?, attest).await?,
+        Some(("file", path)) => return read_file(path),
+        _ => return read_file(spec),
+    };
+    if key.len() != 32 {
+        return Err(format!("{}: private keys are 32 bytes", spec).into());
+    }
+    let mut locked = Locked::<[u8; 32]>::default();
+    locked.copy_from_slice(&key);
+    Ok(locked)
+}
+
+fn read_file(path: &str) -> Result<Locked<[u8; 32]>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut key = Locked::<[u8; 32]>::default();
+    file.read_exact(&mut key[..])?;
+    Ok(key)
+}
+
+/// Calls an AWS JSON API action such as `TrentService.Decrypt`.
+async fn aws_json(service: &str, target: &str, body: Value) -> Result<Value, Box<dyn Error>> {
+    let credentials = AwsCredentials::from_env()?;
+    let host = format!("{}.{}.amazonaws.com", service, credentials.region);
+    let headers = [
+        ("content-type", "application/x-amz-json-1.1".to_owned()),
+        ("x-amz-target", target.to_owned()),
+    ];
+    let req = credentials.signed_request(
+        service,
+        Method::POST,
+        &host,
+        "/",
+        &headers,
+        serde_json::to_vec(&body)?,
+    )?;
+    let res = https_client().request(req).await?;
+    let status = res.status();
+    let body = hyper::body::to_bytes(res).await?;
+    if !status.is_success() {
+        return Err(format!(
+            "{} failed: {} {}",
+            target,
+            status,
+            String::from_utf8_lossy(&body)
+        )
+        .into());
+    }
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn secrets_manager(id: &str) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let res = aws_json(
+        "secretsmanager",
+        "secretsmanager.GetSecretValue",
+        json!({ "SecretId": id }),
+    )
+    .await?;
+    if let Some(text) = res["SecretString"].as_str() {
+        return Ok(Zeroizing::new(hex::decode(text.trim())?));
+    }
+    let binary = res["SecretBinary"]
+        .as_str()
+        .ok_or("secret holds neither a string nor binary")?;
+    Ok(Zeroizing::new(BASE64.decode(binary)?))
+}
+
+async fn kms_decrypt(
+    blob: &[u8],
+    attest: Option<Attest<'_>>,
+) -> Result<Zeroizing<Vec<u8>>, Box<dyn Error>> {
+    let mut request = json!({ "CiphertextBlob": BASE64.encode(blob) });
+    let Some(attest) = attest else {
+        let res = aws_json("kms", "TrentService.Decrypt", request).await?;
+        let plaintext = res["Plaintext"]
+            .as_str()
+            .ok_or("KMS returned no plaintext")?;
+        return Ok(Zeroizing::new(BASE64.decode(plaintext)?));
+    };
+
+    // KMS checks the attestation against the key policy before encrypting to this key
+    let recipient = PKey::from_rsa(Rsa::generate(2048)?)?;
+    let doc = attest(&recipient.public_key_to_der()?)?;
+    request["Recipient"] = json!({
+        "KeyEncryptionAlgorithm": "RSAES_OAEP_SHA_256",
+        "AttestationDocument": BASE64.encode(doc),
+    });
+    let res = aws_json("kms", "TrentService.Decrypt", request).await?;
+    let enveloped = res["CiphertextForRecipient"]
+        .as_str()
+        .ok_or("KMS returned no ciphertext for the enclave")?;
+    let mut cms = CmsContentInfo::from_der(&BASE64.decode(enveloped)?)?;
+    Ok(Zeroizing::new(cms.decrypt_without_cert_check(&recipient)?))
+}
@@ -0,0 +1,31 @@
+pub mod aead;
+pub mod attestation;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod frame;
+pub mod hll;
+pub mod hooks;
+pub mod hpke;
+pub mod http;
+pub mod keys;
+pub mod locked;
+pub mod logging;
+pub mod mask;
+pub mod merkle;
+pub mod message;
+pub mod noise;
+pub mod onchain;
+pub mod paillier;
+pub mod pedersen;
+#[cfg(feature = "python")]
+pub mod python;
+pub mod range;
+pub mod ratls;
+pub mod receipt;
+pub mod session;
+pub mod shamir;
+pub mod signing;
+pub mod store;
+pub mod sum;
+pub mod transport;
+pub mod views;
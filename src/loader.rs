@@ -1,72 +1,588 @@
-use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
-    ChaCha20Poly1305,
-};
 use clap::Parser;
+use curve25519_dalek::scalar::Scalar;
+use ppa::aead::{self, CommittingCipher};
+use ppa::frame::{self, Padding};
+use ppa::hll;
+use ppa::hpke;
+use ppa::keys;
+use ppa::logging::{self, LogFormat};
+use ppa::mask;
+use ppa::merkle;
+use ppa::message::{self, Envelope, Kind};
+use ppa::paillier::PublicKey;
+use ppa::pedersen;
+use ppa::range;
+use ppa::session;
+use ppa::shamir;
+use ppa::sum::{self, Numeric, Schema};
+use ppa::transport;
+use serde::Deserialize;
 use std::error::Error;
 use std::fs::File;
-use std::io::Read;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use x25519_dalek::x25519;
+use std::io::{BufRead, BufReader, Read};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tracing::info;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::{Zeroize, Zeroizing};
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// ip address of the server <ip:port>
+    /// address of the server <ip:port> or unix:<path>
     #[clap(short, long, value_parser)]
     ip_addr: String,
 
+    /// address of a standby replica of the app <ip:port>, repeatable; the connection
+    /// goes to whichever instance leads
+    #[arg(long, conflicts_with = "share")]
+    standby: Vec<String>,
+
     /// path to app public key file
     #[arg(short, long)]
     app: String,
 
-    /// path to private key file
+    /// private key to load: a file path, env:<VAR>, secretsmanager:<secret-id> or
+    /// kms:<ciphertext file>
     #[arg(short, long)]
     secret: String,
+
+    /// rotate to this private key, loaded like --secret, instead of uploading data
+    #[arg(long)]
+    rotate_to: Option<String>,
+
+    /// wipe this loader's contribution to the dataset instead of uploading data
+    #[arg(long, conflicts_with = "rotate_to")]
+    wipe: bool,
+
+    /// check that this loader's contribution is in the dataset's Merkle tree instead of
+    /// uploading data
+    #[arg(long, conflicts_with_all = ["rotate_to", "wipe"])]
+    prove: bool,
+
+    /// hex Merkle root to check the contribution against, as shown in a result receipt;
+    /// the app's current root if not given
+    #[arg(long, requires = "prove")]
+    merkle_root: Option<String>,
+
+    /// commit to the upload's total with a Pedersen commitment, logged for requesters
+    /// to check exact sums against; the blinding factor goes to the app with the data
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "rotate_to", "wipe", "prove", "input", "mask_peers", "paillier", "share"
+        ]
+    )]
+    commit: bool,
+
+    /// prove in zero knowledge that every value lies within <MIN..MAX>, written in the
+    /// values' type, for a masked, shared or encrypted dataset with that --value-range
+    #[arg(
+        long,
+        conflicts_with_all = ["rotate_to", "wipe", "prove", "input", "commit", "sketch"]
+    )]
+    range_proof: Option<String>,
+
+    /// dataset to upload to
+    #[arg(long, default_value = sum::DEFAULT_DATASET)]
+    dataset: String,
+
+    /// stream the vectors in this file, one comma-separated vector per line, as a
+    /// chunked upload instead of a single message
+    #[arg(long, conflicts_with_all = ["rotate_to", "wipe", "vector"])]
+    input: Option<String>,
+
+    /// comma-separated values to upload, repeat for several vectors of the same
+    /// length, defaults to 12,43
+    #[arg(long)]
+    vector: Vec<String>,
+
+    /// upload a HyperLogLog sketch of the identifiers in this file, one per line, to a
+    /// sketch dataset; the identifiers never leave the loader
+    #[arg(
+        long,
+        conflicts_with_all = [
+            "rotate_to", "wipe", "prove", "input", "vector", "mask_peers", "paillier", "share"
+        ]
+    )]
+    sketch: Option<String>,
+
+    /// index bits of the sketch, for 2^bits registers; more registers are more precise
+    #[arg(long, default_value_t = 12, value_parser = clap::value_parser!(u32).range(4..=16))]
+    sketch_precision: u32,
+
+    /// decimal places of the values, which are uploaded as fixed-point; 0 uploads
+    /// integers
+    #[arg(long, default_value_t = 0, conflicts_with_all = ["paillier", "share"])]
+    scale: u8,
+
+    /// upload the values as signed i64, so they may be negative
+    #[arg(long, conflicts_with_all = ["paillier", "share"])]
+    signed: bool,
+
+    /// comma-separated name of each vector position, which the app checks against the
+    /// labels the dataset was created with
+    #[arg(long, value_delimiter = ',', conflicts_with = "paillier")]
+    labels: Vec<String>,
+
+    /// seconds until the app removes this contribution, which is kept until replaced
+    /// or wiped if not given
+    #[arg(long, conflicts_with = "paillier")]
+    ttl: Option<u64>,
+
+    /// comma-separated public key files of every loader in a masked aggregation, this
+    /// one's included; the upload is pairwise-masked so the app only learns the sum
+    #[arg(
+        long,
+        value_delimiter = ',',
+        requires = "round",
+        conflicts_with_all = ["rotate_to", "wipe", "input"]
+    )]
+    mask_peers: Vec<String>,
+
+    /// encrypt the vector under this Paillier public key file, for an encrypted dataset
+    /// whose sum the app computes without decrypting
+    #[arg(long, conflicts_with_all = ["rotate_to", "wipe", "input", "mask_peers"])]
+    paillier: Option<String>,
+
+    /// a further app instance <ip:port=app public key file> to send a Shamir share of
+    /// every value to; --ip-addr and --app are the first instance. Repeatable
+    #[arg(
+        long,
+        requires = "threshold",
+        conflicts_with_all = ["rotate_to", "wipe", "input", "mask_peers", "paillier"]
+    )]
+    share: Vec<String>,
+
+    /// instances whose shares are needed to recover a sum
+    #[arg(long, requires = "share")]
+    threshold: Option<usize>,
+
+    /// masking round, agreed by all peers and never reused for a dataset
+    #[arg(long, requires = "mask_peers")]
+    round: Option<u64>,
+
+    /// connect over RA-TLS, accepting only an enclave attested to run this image ID
+    #[arg(long)]
+    tls_image_id: Option<String>,
+
+    /// connect through a Noise_IK channel keyed by the app's public key, for apps run
+    /// with --noise
+    #[arg(long)]
+    noise: bool,
+
+    /// pad every frame to hide its size: none, padme or block:<bytes>; only hides sizes
+    /// over --tls or --noise, which encrypt the frame headers
+    #[arg(long, default_value_t = Padding::None)]
+    padding: Padding,
+
+    /// key naming this upload, e.g. a UUID; running again with the same key after a
+    /// failure is acknowledged without the upload being applied twice
+    #[arg(long, conflicts_with_all = ["rotate_to", "wipe", "prove"])]
+    idempotency_key: Option<String>,
+
+    /// derive the session key from a hybrid ML-KEM-768 + X25519 key exchange, so
+    /// recorded uploads stay sealed against a future quantum computer
+    #[arg(long)]
+    pq: bool,
+
+    /// console log format, levels are set with RUST_LOG
+    #[arg(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+}
+
+/// The app's proof that this loader's contribution is a leaf of a dataset's Merkle tree.
+#[derive(Deserialize)]
+struct Inclusion {
+    loader: String,
+    ciphertext_sha256: String,
+    index: usize,
+    count: usize,
+    proof: Vec<String>,
+    merkle_root: String,
+}
+
+impl Inclusion {
+    /// Checks the proof against a hex root, returning the root.
+    fn verify(&self, root: &str) -> Result<merkle::Hash, Box<dyn Error>> {
+        let hash = |hex: &str| -> Result<merkle::Hash, Box<dyn Error>> {
+            hex::decode(hex)?
+                .try_into()
+                .map_err(|_| "hashes must be 32 bytes".into())
+        };
+        let root = hash(root)?;
+        let leaf = merkle::leaf(&self.loader, &hash(&self.ciphertext_sha256)?);
+        let proof = self
+            .proof
+            .iter()
+            .map(|h| hash(h))
+            .collect::<Result<Vec<_>, _>>()?;
+        if !merkle::verify(&leaf, self.index, self.count, &proof, &root) {
+            return Err("contribution is not included under the Merkle root".into());
+        }
+        Ok(root)
+    }
+}
+
+fn parse_vector(numeric: Numeric, s: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+    s.split(',')
+        .map(|v| numeric.parse(v.trim()))
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("invalid vector {:?}: {}", s, e).into())
+}
+
+/// The `--vector` values, defaulting to 12,43.
+fn parse_vectors(numeric: Numeric, vectors: &[String]) -> Result<Vec<Vec<u64>>, Box<dyn Error>> {
+    if vectors.is_empty() {
+        return Ok(vec![parse_vector(numeric, "12,43")?]);
+    }
+    vectors.iter().map(|v| parse_vector(numeric, v)).collect()
+}
+
+/// Splits every value into `n` Shamir shares, returning each instance's vectors.
+fn shamir_split(
+    vectors: &[Vec<u64>],
+    threshold: usize,
+    n: usize,
+) -> Result<Zeroizing<Vec<Vec<Vec<u64>>>>, Box<dyn Error>> {
+    let mut shares = Zeroizing::new(vec![Vec::new(); n]);
+    for vector in vectors {
+        for instance in shares.iter_mut() {
+            instance.push(Vec::with_capacity(vector.len()));
+        }
+        for value in vector {
+            for (instance, share) in shares.iter_mut().zip(shamir::split(*value, threshold, n)?) {
+                instance.last_mut().unwrap().push(share);
+            }
+        }
+    }
+    Ok(shares)
+}
+
+/// Proves every value of `vectors` lies within `spec`, <MIN..MAX> written in `numeric`,
+/// for this loader's upload to `dataset`. Returns the proof and the blinding factor of
+/// each value's commitment, in order.
+fn prove_range(
+    spec: &str,
+    numeric: Numeric,
+    public: &[u8; 32],
+    dataset: &str,
+    vectors: &[Vec<u64>],
+) -> Result<(range::Proof, Vec<Scalar>), Box<dyn Error>> {
+    let (min, max) = spec
+        .split_once("..")
+        .ok_or("--range-proof takes <MIN..MAX>")?;
+    let range = numeric.value(numeric.parse(min)?)..=numeric.value(numeric.parse(max)?);
+    let values: Vec<i128> = vectors
+        .iter()
+        .flatten()
+        .map(|v| numeric.value(*v))
+        .collect();
+    range::prove(public, dataset, &values, &range)
+}
+
+fn read_public(path: &str) -> Result<[u8; 32], Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut key = [0u8; 32];
+    file.read_exact(&mut key)?;
+    Ok(key)
+}
+
+/// Values sent per chunk, keeping each chunk well under the frame limit
+const CHUNK_VALUES: usize = 64 * 1024;
+
+/// Seals `msg` under the session and sends it, returning the app's response.
+async fn send_sealed<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    session: &CommittingCipher,
+    public: &[u8; 32],
+    kind: Kind,
+    seq: u64,
+    msg: &[u8],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let envelope = Envelope::seal(kind, public, seq, session, msg)?;
+    message::request(conn, &envelope).await
+}
+
+/// Streams the vectors in `path` to `dataset` as begin and chunk messages, so the file
+/// never has to fit in one message or in memory. Returns the sequence number of the
+/// last chunk and the number of vectors sent, for the commit.
+async fn upload_file<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    session: &CommittingCipher,
+    public: &[u8; 32],
+    dataset: &str,
+    schema: &Schema,
+    ttl: Option<u64>,
+    path: &str,
+) -> Result<(u64, u64), Box<dyn Error>> {
+    // Each message takes the next sequence number after the previous one
+    let mut seq = aead::next_sequence();
+    let begin = sum::with_dataset(dataset, &[])?;
+    send_sealed(conn, session, public, Kind::UploadBegin, seq, &begin).await?;
+
+    let mut chunk: Vec<Vec<u64>> = Vec::new();
+    let mut total = 0u64;
+    let mut lines = BufReader::new(File::open(path)?).lines();
+    loop {
+        let line = lines.next().transpose()?;
+        if let Some(line) = line.as_deref().filter(|line| !line.trim().is_empty()) {
+            chunk.push(parse_vector(schema.numeric, line)?);
+        }
+        let values: usize = chunk.iter().map(Vec::len).sum();
+        if !chunk.is_empty() && (values >= CHUNK_VALUES || line.is_none()) {
+            seq += 1;
+            let msg = Zeroizing::new(sum::encode(schema, ttl, &chunk)?);
+            send_sealed(conn, session, public, Kind::UploadChunk, seq, &msg).await?;
+            total += chunk.len() as u64;
+            chunk.zeroize();
+        }
+        if line.is_none() {
+            break;
+        }
+    }
+    info!("Streamed {} vectors", total);
+    Ok((seq, total))
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    logging::init(cli.log_format);
+    frame::set_padding(cli.padding);
 
-    println!("secret: {}, app: {}", cli.secret, cli.app);
+    info!("secret: {}, app: {}", cli.secret, cli.app);
 
-    let mut file = File::open(cli.secret)?;
-    let mut secret = [0u8; 32];
-    file.read_exact(&mut secret)?;
+    let secret = keys::load(&cli.secret, None).await?;
 
     let mut file = File::open(cli.app)?;
     let mut app = [0u8; 32];
     file.read_exact(&mut app)?;
 
-    let app_shared = x25519(secret, app);
-    let app_cipher = ChaCha20Poly1305::new(&app_shared.into());
-
-    let msg = [12, 43];
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let buf = app_cipher
-        .encrypt(
-            &nonce,
-            Payload {
-                msg: &msg,
-                aad: &[0],
-            },
+    let public = x25519(*secret, X25519_BASEPOINT_BYTES);
+    let numeric = Numeric::new(cli.signed, cli.scale)?;
+    let schema = Schema {
+        numeric,
+        labels: cli.labels.clone(),
+    };
+
+    if let Some(threshold) = cli.threshold {
+        let mut instances = vec![(cli.ip_addr.clone(), app)];
+        for spec in &cli.share {
+            let (addr, path) = spec
+                .split_once('=')
+                .ok_or("--share takes <ip:port=app public key file>")?;
+            instances.push((addr.to_owned(), read_public(path)?));
+        }
+        let vectors = Zeroizing::new(parse_vectors(numeric, &cli.vector)?);
+        let shares = shamir_split(&vectors, threshold, instances.len())?;
+        // One proof for every instance, each getting a share of every blinding factor
+        let proven = match &cli.range_proof {
+            Some(spec) => {
+                let (proof, blindings) =
+                    prove_range(spec, numeric, &public, &cli.dataset, &vectors)?;
+                let mut openings = vec![Vec::new(); instances.len()];
+                for blinding in &blindings {
+                    let shares = shamir::split_scalar(blinding, threshold, instances.len())?;
+                    for (instance, share) in openings.iter_mut().zip(shares) {
+                        instance.push(share.to_bytes().to_vec());
+                    }
+                }
+                Some((proof, openings))
+            }
+            None => None,
+        };
+
+        // One sequence number for every instance, each tracks its own
+        let seq = aead::next_sequence();
+        for (i, ((addr, app), vectors)) in instances.iter().zip(shares.iter()).enumerate() {
+            let tls_image_id = cli.tls_image_id.as_deref();
+            let mut conn = transport::dial(addr, tls_image_id, cli.noise, &secret, app).await?;
+            let session = session::connect(&mut conn, &secret, app, cli.pq).await?;
+            let encoded = Zeroizing::new(sum::encode(&schema, cli.ttl, vectors)?);
+            let msg = Zeroizing::new(sum::with_dataset(&cli.dataset, &encoded)?);
+            let (kind, msg) = match &proven {
+                Some((proof, openings)) => {
+                    let attached = range::attach(proof, &openings[i], &msg);
+                    (Kind::ProvenUpload, Zeroizing::new(attached))
+                }
+                None => (Kind::Upload, msg),
+            };
+            let envelope = Envelope::new(kind, &public, seq, Vec::new())
+                .with_idempotency_key(cli.idempotency_key.clone())
+                .sealed(&session, &msg)?;
+            let resp = message::request(&mut conn, &envelope).await?;
+            info!(instance = %addr, "Response: {}", String::from_utf8(resp)?);
+        }
+        return Ok(());
+    }
+
+    let addrs = [vec![cli.ip_addr.clone()], cli.standby.clone()].concat();
+    let mut outbound = transport::dial_leader(
+        &addrs,
+        cli.tls_image_id.as_deref(),
+        cli.noise,
+        &secret,
+        &app,
+    )
+    .await?;
+
+    if let Some(path) = &cli.input {
+        let session = session::connect(&mut outbound, &secret, &app, cli.pq).await?;
+        let (seq, total) = upload_file(
+            &mut outbound,
+            &session,
+            &public,
+            &cli.dataset,
+            &schema,
+            cli.ttl,
+            path,
         )
-        .unwrap();
+        .await?;
+        let envelope = Envelope::new(Kind::UploadCommit, &public, seq + 1, Vec::new())
+            .with_idempotency_key(cli.idempotency_key)
+            .sealed(&session, &total.to_le_bytes())?;
+        let resp = message::request(&mut outbound, &envelope).await?;
+        info!("Response: {}", String::from_utf8(resp)?);
+        return Ok(());
+    }
+
+    // Binds the message to this point in time so the app rejects replays of it
+    let seq = aead::next_sequence();
+
+    if cli.prove {
+        let session = session::connect(&mut outbound, &secret, &app, cli.pq).await?;
+        let dataset = cli.dataset.as_bytes();
+        let envelope = Envelope::seal(Kind::Inclusion, &public, seq, &session, dataset)?;
+        let resp = message::request(&mut outbound, &envelope).await?;
+        let resp = session.decrypt(&resp, &envelope.reply_aad())?;
+        let inclusion: Inclusion = serde_json::from_slice(&resp)?;
+        let root = cli.merkle_root.as_deref().unwrap_or(&inclusion.merkle_root);
+        let root = inclusion.verify(root)?;
+        info!(
+            root = %hex::encode(root),
+            ciphertext_sha256 = %inclusion.ciphertext_sha256,
+            "Contribution included"
+        );
+        return Ok(());
+    }
+
+    let envelope = match cli.rotate_to {
+        Some(spec) => {
+            let new_secret = keys::load(&spec, None).await?;
+            let new_public = x25519(*new_secret, X25519_BASEPOINT_BYTES);
 
-    let outbound = TcpStream::connect(cli.ip_addr).await?;
-    let (mut ro, mut wo) = tokio::io::split(outbound);
-    wo.write_u8(0).await?;
-    wo.write_all(nonce.as_slice()).await?;
-    wo.write_all(buf.as_slice()).await?;
-    wo.shutdown().await?;
+            // The old key vouches for the new one, the new key proves possession
+            let aad = message::aad(Kind::Rotate, &public, seq);
+            let mut buf = new_public.to_vec();
+            buf.extend(hpke::seal(&secret, &app, &aad, &new_public)?.0);
+            buf.extend(hpke::seal(&new_secret, &app, &aad, &new_public)?.0);
+            Envelope::new(Kind::Rotate, &public, seq, buf)
+        }
+        None if cli.paillier.is_some() => {
+            let key = PublicKey::from_bytes(&std::fs::read(cli.paillier.as_ref().unwrap())?)?;
+            let [vector] = &parse_vectors(numeric, &cli.vector)?[..] else {
+                return Err("encrypted uploads hold exactly one vector".into());
+            };
+            let mut ciphertexts = Vec::new();
+            for value in vector {
+                ciphertexts.extend(key.encrypt(*value)?);
+            }
+            let msg = sum::with_dataset(&cli.dataset, &ciphertexts)?;
+            let (kind, msg) = match &cli.range_proof {
+                Some(spec) => {
+                    let vectors = std::slice::from_ref(vector);
+                    let (proof, blindings) =
+                        prove_range(spec, numeric, &public, &cli.dataset, vectors)?;
+                    // Encrypted like the values, so only the requester learns their sum
+                    let openings = blindings
+                        .iter()
+                        .map(|blinding| {
+                            let mut be = blinding.to_bytes();
+                            be.reverse();
+                            key.encrypt_bytes(&be)
+                        })
+                        .collect::<Result<Vec<_>, _>>()?;
+                    (Kind::ProvenUpload, range::attach(&proof, &openings, &msg))
+                }
+                None => (Kind::EncryptedUpload, msg),
+            };
+            let session = session::connect(&mut outbound, &secret, &app, cli.pq).await?;
+            Envelope::new(kind, &public, seq, Vec::new())
+                .with_idempotency_key(cli.idempotency_key)
+                .sealed(&session, &msg)?
+        }
+        None if cli.wipe => {
+            let session = session::connect(&mut outbound, &secret, &app, cli.pq).await?;
+            let dataset = cli.dataset.as_bytes();
+            Envelope::seal(Kind::Wipe, &public, seq, &session, dataset)?
+        }
+        None => {
+            let mut vectors = match &cli.sketch {
+                Some(path) => {
+                    let ids = BufReader::new(File::open(path)?).lines();
+                    let ids = ids.collect::<Result<Vec<_>, _>>()?;
+                    let ids = ids.iter().map(|id| id.trim()).filter(|id| !id.is_empty());
+                    vec![hll::sketch(ids, cli.sketch_precision)?]
+                }
+                None => parse_vectors(numeric, &cli.vector)?,
+            };
+            if cli.range_proof.is_some() && cli.round.is_none() {
+                return Err("--range-proof is for masked, shared or encrypted uploads".into());
+            }
+            let mut proven = None;
+            if let Some(round) = cli.round {
+                // Masks cancel position by position, so every loader sends one vector
+                let [vector] = &mut vectors[..] else {
+                    return Err("masked uploads hold exactly one vector".into());
+                };
+                let peers = cli
+                    .mask_peers
+                    .iter()
+                    .map(|path| read_public(path))
+                    .collect::<Result<Vec<_>, _>>()?;
+                if let Some(spec) = &cli.range_proof {
+                    // Proven on the values, with the blinding factors masked like them
+                    let vectors = std::slice::from_ref(&*vector);
+                    let (proof, mut blindings) =
+                        prove_range(spec, numeric, &public, &cli.dataset, vectors)?;
+                    let dataset = &cli.dataset;
+                    mask::apply_blindings(&secret, &public, &peers, dataset, round, &mut blindings);
+                    let openings: Vec<Vec<u8>> =
+                        blindings.iter().map(|b| b.to_bytes().to_vec()).collect();
+                    proven = Some((proof, openings));
+                }
+                mask::apply(&secret, &public, &peers, &cli.dataset, round, vector);
+            }
+            // Data goes under a key that is forgotten once the connection closes
+            let session = session::connect(&mut outbound, &secret, &app, cli.pq).await?;
+            let vectors = Zeroizing::new(vectors);
+            let encoded = Zeroizing::new(sum::encode(&schema, cli.ttl, &vectors)?);
+            let msg = Zeroizing::new(sum::with_dataset(&cli.dataset, &encoded)?);
+            let (kind, msg) = if let Some((proof, openings)) = &proven {
+                let attached = range::attach(proof, openings, &msg);
+                (Kind::ProvenUpload, Zeroizing::new(attached))
+            } else if cli.commit {
+                let total = vectors.iter().flatten().map(|v| numeric.value(*v)).sum();
+                let blinding = pedersen::blinding();
+                let commitment = pedersen::commit(total, &blinding);
+                // Published by the loader, not the app, so the app can't swap it
+                info!(commitment = %hex::encode(commitment), "Contribution committed");
+                let mut committed = Zeroizing::new(blinding.to_bytes().to_vec());
+                committed.extend_from_slice(&msg);
+                (Kind::CommittedUpload, committed)
+            } else {
+                (Kind::Upload, msg)
+            };
+            Envelope::new(kind, &public, seq, Vec::new())
+                .with_idempotency_key(cli.idempotency_key)
+                .sealed(&session, &msg)?
+        }
+    };
 
-    let mut resp = String::with_capacity(1000);
-    ro.read_to_string(&mut resp).await?;
+    let resp = message::request(&mut outbound, &envelope).await?;
+    let resp = String::from_utf8(resp)?;
 
-    println!("Repsonse: {}", resp);
+    info!("Response: {}", resp);
 
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,96 @@
+use std::fmt;
+use std::ops::{Deref, DerefMut};
+#[cfg(feature = "mlock")]
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+/// Heap home for key material. With the `mlock` feature it lives on pages of its own
+/// that are locked with `mlock` and excluded from core dumps with
+/// `madvise(MADV_DONTDUMP)`, so it never reaches swap or a dump, and that are zeroed
+/// when it's dropped. Without the feature, or if the pages can't be locked (e.g. over
+/// `RLIMIT_MEMLOCK`), it's an ordinary box. Either way it's zeroized when dropped.
+///
+/// Moving a value in leaves the original where it was, so build secrets in place
+/// with `Locked::default` where the copy matters.
+pub struct Locked<T: Zeroize>(Storage<T>);
+
+enum Storage<T> {
+    #[cfg(feature = "mlock")]
+    Locked(NonNull<T>),
+    Heap(Box<T>),
+}
+
+// The pointer is owned like a box's
+unsafe impl<T: Zeroize + Send> Send for Locked<T> {}
+unsafe impl<T: Zeroize + Sync> Sync for Locked<T> {}
+
+impl<T: Zeroize> Locked<T> {
+    pub fn new(value: T) -> Self {
+        #[cfg(feature = "mlock")]
+        {
+            // Guarded, locked and undumpable pages, freed zeroed
+            match unsafe { memsec::malloc::<T>() } {
+                Some(ptr) => {
+                    unsafe { ptr.as_ptr().write(value) };
+                    return Locked(Storage::Locked(ptr));
+                }
+                None => tracing::warn!("Could not lock memory for key material"),
+            }
+        }
+        Locked(Storage::Heap(Box::new(value)))
+    }
+}
+
+impl<T: Zeroize + Default> Default for Locked<T> {
+    fn default() -> Self {
+        Locked::new(T::default())
+    }
+}
+
+impl<T: Zeroize + Clone> Clone for Locked<T> {
+    fn clone(&self) -> Self {
+        Locked::new((**self).clone())
+    }
+}
+
+impl<T: Zeroize> Deref for Locked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match &self.0 {
+            #[cfg(feature = "mlock")]
+            Storage::Locked(ptr) => unsafe { ptr.as_ref() },
+            Storage::Heap(value) => value,
+        }
+    }
+}
+
+impl<T: Zeroize> DerefMut for Locked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        match &mut self.0 {
+            #[cfg(feature = "mlock")]
+            Storage::Locked(ptr) => unsafe { ptr.as_mut() },
+            Storage::Heap(value) => value,
+        }
+    }
+}
+
+impl<T: Zeroize> Drop for Locked<T> {
+    fn drop(&mut self) {
+        (**self).zeroize();
+        #[cfg(feature = "mlock")]
+        if let Storage::Locked(ptr) = self.0 {
+            unsafe {
+                std::ptr::drop_in_place(ptr.as_ptr());
+                memsec::free(ptr);
+            }
+        }
+    }
+}
+
+/// Never prints the contents.
+impl<T: Zeroize> fmt::Debug for Locked<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Locked(..)")
+    }
+}
@@ -0,0 +1,194 @@
+use curve25519_dalek::scalar::Scalar;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use x25519_dalek::x25519;
+use zeroize::Zeroizing;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// HKDF info prefix for pairwise mask keys
+const INFO: &[u8] = b"ppa-mask-v1";
+
+/// HKDF info prefix for pairwise keys masking blinding factors
+const BLINDING_INFO: &[u8] = b"ppa-mask-blinding-v1";
+
+/// Adds pairwise masks to `values` in place, wrapping mod 2^64. Each pair of loaders
+/// derives the same mask stream from their X25519 shared secret, `dataset` and `round`;
+/// the loader with the lower public key adds it and the other subtracts it, so the masks
+/// cancel in the sum once every loader in `peers` has contributed. `peers` may include
+/// the loader's own key, which is skipped.
+///
+/// A round must never be reused for a dataset: two uploads under the same masks leak
+/// the difference of their values.
+pub fn apply(
+    secret: &[u8; 32],
+    public: &[u8; 32],
+    peers: &[[u8; 32]],
+    dataset: &str,
+    round: u64,
+    values: &mut [u64],
+) {
+    for peer in peers.iter().filter(|peer| *peer != public) {
+        let shared = Zeroizing::new(x25519(*secret, *peer));
+        let stream = stream(&shared, dataset, round, values.len());
+        for (value, mask) in values.iter_mut().zip(stream.iter()) {
+            *value = if public < peer {
+                value.wrapping_add(*mask)
+            } else {
+                value.wrapping_sub(*mask)
+            };
+        }
+    }
+}
+
+/// Adds pairwise masks to the blinding factors of a range-proven masked upload, one per
+/// value, the same way `apply` masks the values: the app learns only their sum, which
+/// opens the sum of the loaders' commitments once every loader has contributed.
+pub fn apply_blindings(
+    secret: &[u8; 32],
+    public: &[u8; 32],
+    peers: &[[u8; 32]],
+    dataset: &str,
+    round: u64,
+    blindings: &mut [Scalar],
+) {
+    for peer in peers.iter().filter(|peer| *peer != public) {
+        let shared = Zeroizing::new(x25519(*secret, *peer));
+        let key = key(&shared, BLINDING_INFO, dataset, round);
+        for (index, blinding) in (0u64..).zip(blindings.iter_mut()) {
+            // Two blocks per scalar, reduced without bias
+            let mut wide = Zeroizing::new([0u8; 64]);
+            for (half, block) in wide.chunks_exact_mut(32).zip([2 * index, 2 * index + 1]) {
+                let mut mac =
+                    HmacSha256::new_from_slice(&key[..]).expect("HMAC takes any key size");
+                mac.update(&block.to_be_bytes());
+                half.copy_from_slice(&mac.finalize().into_bytes());
+            }
+            let mask = Scalar::from_bytes_mod_order_wide(&wide);
+            *blinding = if public < peer {
+                *blinding + mask
+            } else {
+                *blinding - mask
+            };
+        }
+    }
+}
+
+/// A pair's key for `info`, the dataset and round, from their shared secret.
+fn key(shared: &[u8; 32], info: &[u8], dataset: &str, round: u64) -> Zeroizing<[u8; 32]> {
+    let mut info = info.to_vec();
+    info.extend_from_slice(&round.to_be_bytes());
+    info.extend_from_slice(dataset.as_bytes());
+    let mut key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, shared)
+        .expand(&info, &mut key[..])
+        .expect("32 bytes is a valid HKDF output length");
+    key
+}
+
+/// `len` mask values from a pair's shared secret: HMAC-SHA256 under a key derived for
+/// the dataset and round, over a block counter, four values per block.
+fn stream(shared: &[u8; 32], dataset: &str, round: u64, len: usize) -> Zeroizing<Vec<u64>> {
+    let key = key(shared, INFO, dataset, round);
+
+    let mut stream = Zeroizing::new(Vec::with_capacity(len));
+    let mut block = 0u64;
+    while stream.len() < len {
+        let mut mac = HmacSha256::new_from_slice(&key[..]).expect("HMAC takes any key size");
+        mac.update(&block.to_be_bytes());
+        let bytes = mac.finalize().into_bytes();
+        stream.extend(
+            bytes
+                .chunks_exact(8)
+                .map(|chunk| u64::from_le_bytes(chunk.try_into().unwrap())),
+        );
+        block += 1;
+    }
+    stream.truncate(len);
+    stream
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x25519_dalek::X25519_BASEPOINT_BYTES;
+
+    /// Each loader's values after masking against every loader in `keys`.
+    fn masked(keys: &[([u8; 32], [u8; 32])], values: &[Vec<u64>]) -> Vec<Vec<u64>> {
+        let peers: Vec<[u8; 32]> = keys.iter().map(|(_, public)| *public).collect();
+        keys.iter()
+            .zip(values)
+            .map(|((secret, public), values)| {
+                let mut values = values.clone();
+                apply(secret, public, &peers, "dataset", 7, &mut values);
+                values
+            })
+            .collect()
+    }
+
+    fn sum(vectors: &[Vec<u64>]) -> Vec<u64> {
+        let mut sum = vec![0u64; vectors[0].len()];
+        for vector in vectors {
+            for (total, value) in sum.iter_mut().zip(vector) {
+                *total = total.wrapping_add(*value);
+            }
+        }
+        sum
+    }
+
+    fn loaders(count: u8) -> Vec<([u8; 32], [u8; 32])> {
+        (1..=count)
+            .map(|i| {
+                let secret = [i; 32];
+                (secret, x25519(secret, X25519_BASEPOINT_BYTES))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn masks_cancel_across_every_loader() {
+        let keys = loaders(4);
+        let values: Vec<Vec<u64>> = (0..4u64).map(|i| vec![i, 10 * i, 100 * i, 5, 0]).collect();
+        let masked = masked(&keys, &values);
+        assert_ne!(masked, values);
+        assert_eq!(sum(&masked), sum(&values));
+    }
+
+    #[test]
+    fn masks_do_not_cancel_when_a_loader_drops_out() {
+        let keys = loaders(4);
+        let values: Vec<Vec<u64>> = (0..4u64).map(|i| vec![i, 10 * i, 100 * i, 5, 0]).collect();
+        let masked = masked(&keys, &values);
+        assert_ne!(sum(&masked[..3]), sum(&values[..3]));
+    }
+
+    #[test]
+    fn masks_differ_between_rounds_and_datasets() {
+        let keys = loaders(2);
+        let peers = [keys[0].1, keys[1].1];
+        let (secret, public) = &keys[0];
+        let mut first = vec![0u64; 8];
+        let mut other_round = vec![0u64; 8];
+        let mut other_dataset = vec![0u64; 8];
+        apply(secret, public, &peers, "dataset", 1, &mut first);
+        apply(secret, public, &peers, "dataset", 2, &mut other_round);
+        apply(secret, public, &peers, "other", 1, &mut other_dataset);
+        assert_ne!(first, other_round);
+        assert_ne!(first, other_dataset);
+    }
+
+    #[test]
+    fn blinding_masks_cancel_across_every_loader() {
+        let keys = loaders(3);
+        let peers: Vec<[u8; 32]> = keys.iter().map(|(_, public)| *public).collect();
+        let blindings: Vec<Scalar> = (1..=3u64).map(Scalar::from).collect();
+        let mut masked = Scalar::ZERO;
+        for ((secret, public), blinding) in keys.iter().zip(&blindings) {
+            let mut blinding = [*blinding];
+            apply_blindings(secret, public, &peers, "dataset", 7, &mut blinding);
+            masked += blinding[0];
+        }
+        assert_eq!(masked, Scalar::from(6u64));
+    }
+}
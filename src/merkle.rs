@@ -0,0 +1,127 @@
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// SHA-256 of a leaf or node
+pub type Hash = [u8; 32];
+
+/// Leaf committing to one loader's contribution: its name and the SHA-256 of the
+/// sealed payload it was accepted as.
+pub fn leaf(loader: &str, ciphertext_sha256: &[u8]) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([0]);
+    hasher.update((loader.len() as u32).to_le_bytes());
+    hasher.update(loader.as_bytes());
+    hasher.update(ciphertext_sha256);
+    hasher.finalize().into()
+}
+
+fn node(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+/// Largest power of two below `n`, for `n > 1`.
+fn split(n: usize) -> usize {
+    1 << (usize::BITS - 1 - (n - 1).leading_zeros())
+}
+
+/// Root of the Merkle tree over `leaves` in order, shaped like RFC 6962: leaves and
+/// inner nodes hash under different prefixes, so a node can't be passed off as a leaf,
+/// and a tree of `n` leaves splits at the largest power of two below `n`. An empty
+/// tree's root is the hash of nothing.
+pub fn root(leaves: &[Hash]) -> Hash {
+    match leaves.len() {
+        0 => Sha256::digest(b"").into(),
+        1 => leaves[0],
+        n => {
+            let k = split(n);
+            node(&root(&leaves[..k]), &root(&leaves[k..]))
+        }
+    }
+}
+
+/// Siblings on the path from `leaves[index]` to the root, nearest first.
+pub fn proof(leaves: &[Hash], index: usize) -> Vec<Hash> {
+    if leaves.len() <= 1 {
+        return Vec::new();
+    }
+    let k = split(leaves.len());
+    let (mut path, sibling) = if index < k {
+        (proof(&leaves[..k], index), root(&leaves[k..]))
+    } else {
+        (proof(&leaves[k..], index - k), root(&leaves[..k]))
+    };
+    path.push(sibling);
+    path
+}
+
+/// Whether `proof` shows `leaf` at `index` in a tree of `count` leaves with `root`.
+pub fn verify(leaf: &Hash, index: usize, count: usize, proof: &[Hash], root: &Hash) -> bool {
+    fn walk(leaf: &Hash, index: usize, count: usize, proof: &[Hash]) -> Option<Hash> {
+        if count == 1 {
+            return proof.is_empty().then_some(*leaf);
+        }
+        let (sibling, rest) = proof.split_last()?;
+        let k = split(count);
+        Some(if index < k {
+            node(&walk(leaf, index, k, rest)?, sibling)
+        } else {
+            node(sibling, &walk(leaf, index - k, count - k, rest)?)
+        })
+    }
+    index < count
+        && walk(leaf, index, count, proof)
+            .is_some_and(|computed| computed[..].ct_eq(&root[..]).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn leaves(count: usize) -> Vec<Hash> {
+        (0..count)
+            .map(|i| leaf(&format!("loader-{}", i), &Sha256::digest([i as u8])))
+            .collect()
+    }
+
+    #[test]
+    fn every_leaf_proves_against_the_root() {
+        for count in 1..=9 {
+            let leaves = leaves(count);
+            let root = root(&leaves);
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = proof(&leaves, index);
+                assert!(verify(leaf, index, count, &proof, &root));
+            }
+        }
+    }
+
+    #[test]
+    fn rejects_a_tampered_sibling_or_the_wrong_position() {
+        let leaves = leaves(5);
+        let root = root(&leaves);
+        let proof = proof(&leaves, 2);
+        let mut tampered = proof.clone();
+        tampered[0][0] ^= 1;
+        assert!(!verify(&leaves[2], 2, 5, &tampered, &root));
+        assert!(!verify(&leaves[2], 3, 5, &proof, &root));
+        assert!(!verify(&leaves[3], 2, 5, &proof, &root));
+        assert!(!verify(&leaves[2], 5, 5, &proof, &root));
+    }
+
+    #[test]
+    fn the_root_commits_to_every_leaf_and_their_order() {
+        let leaves = leaves(6);
+        let root = root(&leaves);
+        let mut swapped = leaves.clone();
+        swapped.swap(1, 4);
+        assert_ne!(super::root(&swapped), root);
+        assert_ne!(super::root(&leaves[..5]), root);
+        let mut changed = leaves.clone();
+        changed[5] = leaf("loader-5", &Sha256::digest(b"another payload"));
+        assert_ne!(super::root(&changed), root);
+    }
+}
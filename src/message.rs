@@ -0,0 +1,318 @@
+use crate::aead::CommittingCipher;
+use crate::frame::{self, AppError, ERR_MALFORMED};
+use crate::hpke;
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Envelope format version, bumped on incompatible changes
+pub const VERSION: u8 = 1;
+
+/// What a message asks the app to do.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Kind {
+    /// Loader upload of vectors to a dataset
+    Upload,
+    /// Requester query for an aggregate of a dataset
+    Query,
+    /// Loader upload of the canary dataset
+    CanaryLoad,
+    /// Loader request for the canary result
+    CanaryCompute,
+    /// Loader announcement of its next key
+    Rotate,
+    /// Admin approval of a pending loader key
+    FinalizeRotation,
+    /// Admin deletion of a dataset
+    DropDataset,
+    /// Loader handshake opening a session for the rest of the connection
+    Handshake,
+    /// Admin rotation of the app's own key
+    RotateAppKey,
+    /// Loader deletion of its contribution to a dataset
+    Wipe,
+    /// Loader start of a chunked upload to a dataset
+    UploadBegin,
+    /// One chunk of vectors of a chunked upload
+    UploadChunk,
+    /// Loader completion of a chunked upload, replacing its contribution
+    UploadCommit,
+    /// Loader upload of Paillier ciphertexts to an encrypted dataset
+    EncryptedUpload,
+    /// Requester subscription, answered once a dataset holds a new aggregate
+    Subscribe,
+    /// Auditor read of the log of accepted uploads
+    AuditLog,
+    /// Loader request for proof that its contribution is in a dataset's Merkle tree
+    Inclusion,
+    /// Loader upload to a masked, shared or encrypted dataset with a range proof
+    ProvenUpload,
+    /// Loader upload of vectors with the blinding factor of its Pedersen commitment
+    CommittedUpload,
+    /// Unauthenticated probe for which instance of a replicated pair leads
+    Status,
+}
+
+impl Kind {
+    pub const ALL: [Kind; 20] = [
+        Kind::Upload,
+        Kind::Query,
+        Kind::CanaryLoad,
+        Kind::CanaryCompute,
+        Kind::Rotate,
+        Kind::FinalizeRotation,
+        Kind::DropDataset,
+        Kind::Handshake,
+        Kind::RotateAppKey,
+        Kind::Wipe,
+        Kind::UploadBegin,
+        Kind::UploadChunk,
+        Kind::UploadCommit,
+        Kind::EncryptedUpload,
+        Kind::Subscribe,
+        Kind::AuditLog,
+        Kind::Inclusion,
+        Kind::ProvenUpload,
+        Kind::CommittedUpload,
+        Kind::Status,
+    ];
+
+    /// Name used in logs and metrics.
+    pub fn label(self) -> &'static str {
+        match self {
+            Kind::Upload => "upload",
+            Kind::Query => "query",
+            Kind::CanaryLoad => "canary_load",
+            Kind::CanaryCompute => "canary_compute",
+            Kind::Rotate => "rotate",
+            Kind::FinalizeRotation => "finalize_rotation",
+            Kind::DropDataset => "drop_dataset",
+            Kind::Handshake => "handshake",
+            Kind::RotateAppKey => "rotate_app_key",
+            Kind::Wipe => "wipe",
+            Kind::UploadBegin => "upload_begin",
+            Kind::UploadChunk => "upload_chunk",
+            Kind::UploadCommit => "upload_commit",
+            Kind::EncryptedUpload => "encrypted_upload",
+            Kind::Subscribe => "subscribe",
+            Kind::AuditLog => "audit_log",
+            Kind::Inclusion => "inclusion",
+            Kind::ProvenUpload => "proven_upload",
+            Kind::CommittedUpload => "committed_upload",
+            Kind::Status => "status",
+        }
+    }
+
+    /// The kind sent with frame type `byte`, if any.
+    pub fn from_byte(byte: u8) -> Option<Kind> {
+        Kind::ALL.into_iter().find(|kind| kind.to_byte() == byte)
+    }
+
+    /// Frame type the message is sent with, also bound into its AAD.
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Kind::Upload => 0,
+            Kind::Query => 1,
+            Kind::CanaryLoad => 2,
+            Kind::CanaryCompute => 3,
+            Kind::Rotate => 4,
+            Kind::FinalizeRotation => 5,
+            Kind::DropDataset => 6,
+            Kind::Handshake => 7,
+            Kind::RotateAppKey => 8,
+            Kind::Wipe => 9,
+            Kind::UploadBegin => 10,
+            Kind::UploadChunk => 11,
+            Kind::UploadCommit => 12,
+            Kind::EncryptedUpload => 13,
+            Kind::Subscribe => 14,
+            Kind::AuditLog => 15,
+            Kind::Inclusion => 16,
+            Kind::ProvenUpload => 17,
+            Kind::CommittedUpload => 18,
+            Kind::Status => 19,
+        }
+    }
+}
+
+/// The app's answer to a status probe, as JSON.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Status {
+    /// leadership term, raised each time a standby takes over from its primary
+    pub term: u64,
+    /// the instance serves requests: not a standby, and not a primary that has lost its
+    /// replica's acknowledgements
+    pub leader: bool,
+}
+
+/// Longest idempotency key an envelope may carry, in bytes
+pub const MAX_IDEMPOTENCY_KEY: usize = 64;
+
+/// Trailing byte of a reply's AAD, so a reply can't be reflected back as a request
+const REPLY: u8 = 0xff;
+
+/// AAD a payload is sealed with: `version | type | sender | nonce`, the nonce big-endian.
+/// Binding the whole header means a payload can't be replayed as another message type,
+/// claimed by another sender or moved to another sequence number.
+pub fn aad(kind: Kind, sender: &[u8; 32], nonce: u64) -> Vec<u8> {
+    let mut aad = vec![VERSION, kind.to_byte()];
+    aad.extend_from_slice(sender);
+    aad.extend_from_slice(&nonce.to_be_bytes());
+    aad
+}
+
+/// Every message sent to the app.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub version: u8,
+    pub kind: Kind,
+    /// X25519 public key of the sender, which selects the key the payload is sealed under
+    #[serde(with = "serde_bytes")]
+    pub sender: Vec<u8>,
+    /// sequence number bound into the payload's AAD, zero for unsequenced messages
+    pub nonce: u64,
+    #[serde(with = "serde_bytes")]
+    pub payload: Vec<u8>,
+    /// ID the app echoes in its response, to match responses to requests on a
+    /// connection kept open for several. Not part of the AAD: it only pairs frames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub correlation_id: Option<u64>,
+    /// key the sender picked for an upload, so a retry carrying it again is
+    /// acknowledged without being applied twice. Part of the AAD when present, so it
+    /// can't be added to or stripped from a sealed upload.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub idempotency_key: Option<String>,
+}
+
+impl Envelope {
+    pub fn new(kind: Kind, sender: &[u8; 32], nonce: u64, payload: Vec<u8>) -> Self {
+        Envelope {
+            version: VERSION,
+            kind,
+            sender: sender.to_vec(),
+            nonce,
+            payload,
+            correlation_id: None,
+            idempotency_key: None,
+        }
+    }
+
+    /// Tags the envelope with an idempotency key, before its payload is sealed.
+    pub fn with_idempotency_key(self, key: Option<String>) -> Self {
+        Envelope {
+            idempotency_key: key,
+            ..self
+        }
+    }
+
+    /// Seals `msg` under `cipher` as the payload, with the envelope's AAD.
+    pub fn sealed(mut self, cipher: &CommittingCipher, msg: &[u8]) -> Result<Self, Box<dyn Error>> {
+        self.payload = cipher.encrypt(msg, &self.aad())?;
+        Ok(self)
+    }
+
+    /// Seals `msg` under `cipher` with the AAD of the envelope that carries it.
+    pub fn seal(
+        kind: Kind,
+        sender: &[u8; 32],
+        nonce: u64,
+        cipher: &CommittingCipher,
+        msg: &[u8],
+    ) -> Result<Self, Box<dyn Error>> {
+        Envelope::new(kind, sender, nonce, Vec::new()).sealed(cipher, msg)
+    }
+
+    /// Seals `msg` to the app's static key `app` with HPKE, authenticated by the
+    /// sender's static `secret`, with the AAD of the envelope that carries it. Returns
+    /// the cipher the app's reply comes back under with the envelope.
+    pub fn seal_to(
+        kind: Kind,
+        secret: &[u8; 32],
+        app: &[u8; 32],
+        nonce: u64,
+        msg: &[u8],
+    ) -> Result<(Self, CommittingCipher), Box<dyn Error>> {
+        let sender = x25519(*secret, X25519_BASEPOINT_BYTES);
+        let (payload, reply) = hpke::seal(secret, app, &aad(kind, &sender, nonce), msg)?;
+        Ok((Envelope::new(kind, &sender, nonce, payload), reply))
+    }
+
+    /// AAD the payload is sealed with, followed by `len | idempotency key` if the
+    /// envelope carries one.
+    pub fn aad(&self) -> Vec<u8> {
+        let mut aad = vec![self.version, self.kind.to_byte()];
+        aad.extend_from_slice(&self.sender);
+        aad.extend_from_slice(&self.nonce.to_be_bytes());
+        if let Some(key) = &self.idempotency_key {
+            aad.push(key.len() as u8);
+            aad.extend_from_slice(key.as_bytes());
+        }
+        aad
+    }
+
+    /// AAD of the app's reply to this envelope.
+    pub fn reply_aad(&self) -> Vec<u8> {
+        let mut aad = self.aad();
+        aad.push(REPLY);
+        aad
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(serde_cbor::to_vec(self)?)
+    }
+
+    /// Decodes the envelope of a message received as a frame of type `msg_type`.
+    pub fn decode(msg_type: u8, buf: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let envelope: Envelope = serde_cbor::from_slice(buf)
+            .map_err(|e| AppError::new(ERR_MALFORMED, format!("malformed envelope: {}", e)))?;
+        if envelope.version != VERSION {
+            let reason = format!("unsupported envelope version {}", envelope.version);
+            return Err(AppError::new(ERR_MALFORMED, reason));
+        }
+        if envelope.kind.to_byte() != msg_type {
+            let reason = "frame type does not match the envelope";
+            return Err(AppError::new(ERR_MALFORMED, reason));
+        }
+        if matches!(&envelope.idempotency_key, Some(key) if key.len() > MAX_IDEMPOTENCY_KEY) {
+            let reason = format!("idempotency key is over {} bytes", MAX_IDEMPOTENCY_KEY);
+            return Err(AppError::new(ERR_MALFORMED, reason));
+        }
+        Ok(envelope)
+    }
+
+    /// The sender's public key.
+    pub fn sender(&self) -> Result<[u8; 32], Box<dyn Error>> {
+        self.sender
+            .as_slice()
+            .try_into()
+            .map_err(|_| AppError::malformed("sender"))
+    }
+}
+
+/// Sends an envelope under a fresh correlation ID and waits for its response payload,
+/// refusing a response that echoes another ID.
+pub async fn request<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    envelope: &Envelope,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let id = OsRng.next_u64();
+    let tagged = Envelope {
+        correlation_id: Some(id),
+        ..envelope.clone()
+    };
+    let resp = match frame::request(conn, envelope.kind.to_byte(), &tagged.encode()?).await {
+        Ok(resp) => resp,
+        Err(e) => match e.downcast_ref::<AppError>() {
+            Some(error) if matches!(error.correlation_id, Some(echoed) if echoed != id) => {
+                return Err("error response is for another request".into())
+            }
+            _ => return Err(e),
+        },
+    };
+    match resp.get(..8) {
+        Some(echoed) if echoed == id.to_be_bytes() => Ok(resp[8..].to_vec()),
+        _ => Err("response is for another request".into()),
+    }
+}
@@ -0,0 +1,177 @@
+use crate::locked::Locked;
+use snow::{Builder, TransportState};
+use std::error::Error;
+use std::io;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// Handshake pattern: the client knows the app's attested static key up front and
+/// sends its own static key in the first message, so the channel is mutually
+/// authenticated after a single round trip.
+pub const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_SHA256";
+
+/// Largest Noise message, including its 16-byte tag
+const MAX_MSG_LEN: usize = 65535;
+const TAG_LEN: usize = 16;
+
+/// Writes one Noise message prefixed with its length as a big-endian u16.
+async fn send<W: AsyncWrite + Unpin>(w: &mut W, msg: &[u8]) -> io::Result<()> {
+    let len =
+        u16::try_from(msg.len()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    w.write_all(&len.to_be_bytes()).await?;
+    w.write_all(msg).await?;
+    w.flush().await
+}
+
+/// Reads one length-prefixed Noise message, or `None` if the peer closed the
+/// connection between messages.
+async fn recv<R: AsyncRead + Unpin>(r: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 2];
+    match r.read_exact(&mut len).await {
+        Ok(_) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let mut msg = vec![0u8; u16::from_be_bytes(len) as usize];
+    r.read_exact(&mut msg).await?;
+    Ok(Some(msg))
+}
+
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+/// Decrypts messages from the network onto the plaintext side until the peer closes.
+async fn inbound<R, W>(
+    mut net: R,
+    mut plain: W,
+    transport: &Mutex<TransportState>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    while let Some(msg) = recv(&mut net).await? {
+        let len = transport
+            .lock()
+            .unwrap()
+            .read_message(&msg, &mut buf)
+            .map_err(noise_error)?;
+        plain.write_all(&buf[..len]).await?;
+    }
+    plain.shutdown().await
+}
+
+/// Encrypts bytes from the plaintext side onto the network until it closes.
+async fn outbound<R, W>(
+    mut plain: R,
+    mut net: W,
+    transport: &Mutex<TransportState>,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut chunk = vec![0u8; MAX_MSG_LEN - TAG_LEN];
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    loop {
+        let n = plain.read(&mut chunk).await?;
+        if n == 0 {
+            return net.shutdown().await;
+        }
+        let len = transport
+            .lock()
+            .unwrap()
+            .write_message(&chunk[..n], &mut buf)
+            .map_err(noise_error)?;
+        send(&mut net, &buf[..len]).await?;
+    }
+}
+
+/// Relays between `stream` and the returned in-memory stream, encrypting and
+/// decrypting with the Noise transport, so callers keep reading and writing frames.
+fn channel<S>(stream: S, transport: TransportState) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (local, remote) = tokio::io::duplex(MAX_MSG_LEN);
+    tokio::spawn(async move {
+        let transport = Mutex::new(transport);
+        let (net_r, net_w) = tokio::io::split(stream);
+        let (plain_r, plain_w) = tokio::io::split(remote);
+        if let Err(e) = tokio::try_join!(
+            inbound(net_r, plain_w, &transport),
+            outbound(plain_r, net_w, &transport)
+        ) {
+            tracing::debug!("noise channel: {}", e);
+        }
+    });
+    local
+}
+
+/// Runs the client side of the handshake with the app whose static public key is `app`.
+pub async fn connect<S>(
+    mut stream: S,
+    secret: &[u8; 32],
+    app: &[u8; 32],
+) -> Result<DuplexStream, Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut handshake = Builder::new(PATTERN.parse()?)
+        .local_private_key(secret)
+        .remote_public_key(app)
+        .build_initiator()?;
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+
+    let len = handshake.write_message(&[], &mut buf)?;
+    send(&mut stream, &buf[..len]).await?;
+    let reply = recv(&mut stream)
+        .await?
+        .ok_or("connection closed during the noise handshake")?;
+    handshake.read_message(&reply, &mut buf)?;
+
+    Ok(channel(stream, handshake.into_transport_mode()?))
+}
+
+/// Runs the app side of the handshake, refusing clients whose static key isn't
+/// `authorized`. The client may have addressed any of `secrets`, as it does while an
+/// app key rotation is in its grace window. Returns the client's static public key with
+/// the channel.
+pub async fn accept<S>(
+    mut stream: S,
+    secrets: &[Arc<Locked<[u8; 32]>>],
+    authorized: impl Fn(&[u8; 32]) -> bool,
+) -> Result<([u8; 32], DuplexStream), Box<dyn Error>>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let mut buf = vec![0u8; MAX_MSG_LEN];
+    let hello = recv(&mut stream)
+        .await?
+        .ok_or("connection closed during the noise handshake")?;
+    let mut handshake = None;
+    for secret in secrets {
+        let mut candidate = Builder::new(PATTERN.parse()?)
+            .local_private_key(&secret[..])
+            .build_responder()?;
+        if candidate.read_message(&hello, &mut buf).is_ok() {
+            handshake = Some(candidate);
+            break;
+        }
+    }
+    let mut handshake = handshake.ok_or("noise handshake for an unknown app key")?;
+    let client: [u8; 32] = handshake
+        .get_remote_static()
+        .and_then(|key| key.try_into().ok())
+        .ok_or("noise handshake carried no client key")?;
+    if !authorized(&client) {
+        return Err("noise handshake from an unknown key".into());
+    }
+
+    let len = handshake.write_message(&[], &mut buf)?;
+    send(&mut stream, &buf[..len]).await?;
+
+    Ok((client, channel(stream, handshake.into_transport_mode()?)))
+}
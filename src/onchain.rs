@@ -0,0 +1,121 @@
+use crate::attestation::{self, Limits};
+use openssl::bn::BigNumContext;
+use openssl::ec::PointConversionForm;
+use openssl::x509::X509;
+use serde_cbor::Value;
+use sha3::{Digest, Keccak256};
+use std::error::Error;
+
+/// Solidity signature of the verifier function the calldata targets:
+/// `verifyAttestation(signature, leafPublicKey, protectedHeader, payload, pcrs,
+/// timestampMs, enclavePublicKey)`. The protected header and payload let the contract
+/// rebuild the COSE `Sig_structure` the leaf key signed.
+pub const FUNCTION: &str = "verifyAttestation(bytes,bytes,bytes,bytes,bytes[],uint256,bytes)";
+
+/// PCRs passed on-chain, in order
+pub const PCRS: [u64; 4] = [0, 1, 2, 16];
+
+/// ABI values used by the attestation calldata.
+pub enum Token {
+    Uint(u128),
+    Bytes(Vec<u8>),
+    BytesArray(Vec<Vec<u8>>),
+}
+
+fn word(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+fn encode_bytes(data: &[u8], out: &mut Vec<u8>) {
+    out.extend_from_slice(&word(data.len() as u128));
+    out.extend_from_slice(data);
+    out.resize(out.len() + (32 - data.len() % 32) % 32, 0);
+}
+
+/// ABI-encodes `tokens` as a tuple: static heads, with dynamic values replaced by
+/// offsets into the tail that follows.
+pub fn encode(tokens: &[Token]) -> Vec<u8> {
+    let head_len = 32 * tokens.len();
+    let mut head = Vec::with_capacity(head_len);
+    let mut tail = Vec::new();
+    for token in tokens {
+        match token {
+            Token::Uint(value) => head.extend_from_slice(&word(*value)),
+            Token::Bytes(data) => {
+                head.extend_from_slice(&word((head_len + tail.len()) as u128));
+                encode_bytes(data, &mut tail);
+            }
+            Token::BytesArray(items) => {
+                head.extend_from_slice(&word((head_len + tail.len()) as u128));
+                tail.extend_from_slice(&word(items.len() as u128));
+                let items: Vec<Token> = items.iter().cloned().map(Token::Bytes).collect();
+                tail.extend(encode(&items));
+            }
+        }
+    }
+    head.extend(tail);
+    head
+}
+
+pub fn selector(signature: &str) -> [u8; 4] {
+    let hash = Keccak256::digest(signature.as_bytes());
+    [hash[0], hash[1], hash[2], hash[3]]
+}
+
+/// Splits a COSE_Sign1 into its protected header, payload and signature.
+fn cose_parts(doc: &[u8]) -> Result<(Vec<u8>, Vec<u8>, Vec<u8>), Box<dyn Error>> {
+    // Skip the optional COSE_Sign1 tag (18)
+    let doc = doc.strip_prefix(&[0xd2u8]).unwrap_or(doc);
+    let parts: Vec<Value> = serde_cbor::from_slice(doc)?;
+    match parts.as_slice() {
+        [Value::Bytes(protected), _, Value::Bytes(payload), Value::Bytes(signature)] => {
+            Ok((protected.clone(), payload.clone(), signature.clone()))
+        }
+        _ => Err("malformed COSE_Sign1".into()),
+    }
+}
+
+/// Calldata for `FUNCTION` carrying an attestation document. The document must have
+/// been verified already, this only reformats it.
+pub fn attestation_calldata(doc: &[u8], limits: &Limits) -> Result<Vec<u8>, Box<dyn Error>> {
+    let decoded = attestation::decode(doc, limits)?;
+    let (protected, payload, signature) = cose_parts(doc)?;
+
+    let leaf = X509::from_der(
+        decoded
+            .certificate
+            .as_deref()
+            .ok_or("certificate not found in attestation doc")?,
+    )?;
+    let leaf_key = leaf.public_key()?.ec_key()?;
+    let mut ctx = BigNumContext::new()?;
+    let leaf_key = leaf_key.public_key().to_bytes(
+        leaf_key.group(),
+        PointConversionForm::UNCOMPRESSED,
+        &mut ctx,
+    )?;
+
+    // Missing PCRs are zero, matching how the image ID is computed
+    let pcrs = PCRS
+        .iter()
+        .map(|index| decoded.pcrs.get(index).cloned().unwrap_or(vec![0u8; 48]))
+        .collect();
+    let timestamp = decoded
+        .timestamp
+        .ok_or("timestamp not found in attestation doc")?;
+
+    let tokens = [
+        Token::Bytes(signature),
+        Token::Bytes(leaf_key),
+        Token::Bytes(protected),
+        Token::Bytes(payload),
+        Token::BytesArray(pcrs),
+        Token::Uint(u128::try_from(timestamp)?),
+        Token::Bytes(decoded.public_key.unwrap_or_default()),
+    ];
+    let mut calldata = selector(FUNCTION).to_vec();
+    calldata.extend(encode(&tokens));
+    Ok(calldata)
+}
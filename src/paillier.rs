@@ -0,0 +1,261 @@
+use openssl::bn::{BigNum, BigNumContext, BigNumRef};
+use std::error::Error;
+use zeroize::Zeroizing;
+
+/// Smallest modulus accepted, in bits
+const MIN_BITS: i32 = 2048;
+
+/// Paillier public key with generator `g = n + 1`. Ciphertexts are big-endian integers
+/// below `n^2`, padded to its length.
+pub struct PublicKey {
+    n: BigNum,
+    n2: BigNum,
+}
+
+impl PublicKey {
+    /// Reads a key stored as the big-endian modulus.
+    pub fn from_bytes(buf: &[u8]) -> Result<PublicKey, Box<dyn Error>> {
+        let n = BigNum::from_slice(buf)?;
+        if n.num_bits() < MIN_BITS || !n.is_bit_set(0) {
+            let reason = format!(
+                "paillier modulus must be odd and at least {} bits",
+                MIN_BITS
+            );
+            return Err(reason.into());
+        }
+        PublicKey::new(n)
+    }
+
+    fn new(n: BigNum) -> Result<PublicKey, Box<dyn Error>> {
+        let mut n2 = BigNum::new()?;
+        n2.sqr(&n, &mut BigNumContext::new()?)?;
+        Ok(PublicKey { n, n2 })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.n.to_vec()
+    }
+
+    /// Length of every ciphertext under this key.
+    pub fn ciphertext_len(&self) -> usize {
+        self.n2.num_bytes() as usize
+    }
+
+    /// Encrypts `m` as `(1 + n)^m r^n = (1 + m n) r^n mod n^2` with a fresh random `r`.
+    pub fn encrypt(&self, m: u64) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.encrypt_bytes(&m.to_be_bytes())
+    }
+
+    /// Encrypts the big-endian integer `m`, which must be below `n`.
+    pub fn encrypt_bytes(&self, m: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let m = BigNum::from_slice(m)?;
+        if m >= self.n {
+            return Err("paillier plaintext exceeds the modulus".into());
+        }
+        let mut ctx = BigNumContext::new()?;
+        let mut r = BigNum::new()?;
+        while r.num_bits() == 0 {
+            self.n.rand_range(&mut r)?;
+        }
+        let mut rn = BigNum::new()?;
+        rn.mod_exp(&r, &self.n, &self.n2, &mut ctx)?;
+        let mut gm = BigNum::new()?;
+        gm.checked_mul(&m, &self.n, &mut ctx)?;
+        gm.add_word(1)?;
+        let mut c = BigNum::new()?;
+        c.mod_mul(&gm, &rn, &self.n2, &mut ctx)?;
+        self.encode(&c)
+    }
+
+    /// Ciphertext of the sum of the plaintexts of `a` and `b`: their product mod `n^2`.
+    pub fn add(&self, a: &[u8], b: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut c = BigNum::new()?;
+        c.mod_mul(
+            &self.decode(a)?,
+            &self.decode(b)?,
+            &self.n2,
+            &mut BigNumContext::new()?,
+        )?;
+        self.encode(&c)
+    }
+
+    /// Encryption of zero with `r = 1`, the identity of `add`.
+    pub fn zero(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.encode(&BigNum::from_u32(1)?)
+    }
+
+    /// Checks that `buf` is a well-formed ciphertext under this key.
+    pub fn check(&self, buf: &[u8]) -> Result<(), Box<dyn Error>> {
+        self.decode(buf).map(|_| ())
+    }
+
+    fn encode(&self, c: &BigNumRef) -> Result<Vec<u8>, Box<dyn Error>> {
+        Ok(c.to_vec_padded(self.ciphertext_len() as i32)?)
+    }
+
+    fn decode(&self, buf: &[u8]) -> Result<BigNum, Box<dyn Error>> {
+        let c = BigNum::from_slice(buf)?;
+        if buf.len() != self.ciphertext_len() || c >= self.n2 || c.num_bits() == 0 {
+            return Err("malformed paillier ciphertext".into());
+        }
+        Ok(c)
+    }
+}
+
+/// Paillier private key, stored as `u16 len(p) | p | q` with the primes big-endian.
+pub struct PrivateKey {
+    public: PublicKey,
+    p: BigNum,
+    q: BigNum,
+    /// `phi(n) = (p - 1)(q - 1)`, which stands in for the Carmichael function
+    phi: BigNum,
+    /// `phi^-1 mod n`
+    mu: BigNum,
+}
+
+impl PrivateKey {
+    /// Generates a key with a modulus of `bits` bits.
+    pub fn generate(bits: i32) -> Result<PrivateKey, Box<dyn Error>> {
+        if bits < MIN_BITS {
+            return Err(format!("paillier keys need at least {} bits", MIN_BITS).into());
+        }
+        loop {
+            let mut p = BigNum::new()?;
+            let mut q = BigNum::new()?;
+            p.generate_prime(bits / 2, false, None, None)?;
+            q.generate_prime(bits / 2, false, None, None)?;
+            if p != q {
+                return PrivateKey::from_primes(p, q);
+            }
+        }
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Result<PrivateKey, Box<dyn Error>> {
+        let [a, b, rest @ ..] = buf else {
+            return Err("malformed paillier private key".into());
+        };
+        let p_len = u16::from_be_bytes([*a, *b]) as usize;
+        if rest.len() <= p_len {
+            return Err("malformed paillier private key".into());
+        }
+        let (p, q) = rest.split_at(p_len);
+        PrivateKey::from_primes(BigNum::from_slice(p)?, BigNum::from_slice(q)?)
+    }
+
+    fn from_primes(p: BigNum, q: BigNum) -> Result<PrivateKey, Box<dyn Error>> {
+        let mut ctx = BigNumContext::new()?;
+        let mut n = BigNum::new()?;
+        n.checked_mul(&p, &q, &mut ctx)?;
+        let mut p1 = p.to_owned()?;
+        p1.sub_word(1)?;
+        let mut q1 = q.to_owned()?;
+        q1.sub_word(1)?;
+        let mut phi = BigNum::new()?;
+        phi.checked_mul(&p1, &q1, &mut ctx)?;
+        let mut mu = BigNum::new()?;
+        mu.mod_inverse(&phi, &n, &mut ctx)?;
+        Ok(PrivateKey {
+            public: PublicKey::new(n)?,
+            p,
+            q,
+            phi,
+            mu,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let p = Zeroizing::new(self.p.to_vec());
+        let mut buf = Zeroizing::new((p.len() as u16).to_be_bytes().to_vec());
+        buf.extend_from_slice(&p);
+        buf.extend_from_slice(&Zeroizing::new(self.q.to_vec()));
+        buf
+    }
+
+    pub fn public(&self) -> &PublicKey {
+        &self.public
+    }
+
+    /// Decrypts `c` as `L(c^phi mod n^2) mu mod n`, where `L(u) = (u - 1) / n`.
+    pub fn decrypt(&self, c: &[u8]) -> Result<u64, Box<dyn Error>> {
+        let bytes = self.decrypt_bytes(c)?;
+        if bytes.len() > 8 {
+            return Err("paillier plaintext exceeds u64".into());
+        }
+        let mut buf = [0u8; 8];
+        buf[8 - bytes.len()..].copy_from_slice(&bytes);
+        Ok(u64::from_be_bytes(buf))
+    }
+
+    /// Decrypts `c` to a big-endian integer below `n`, without leading zeros.
+    pub fn decrypt_bytes(&self, c: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let PublicKey { n, n2 } = &self.public;
+        let mut ctx = BigNumContext::new()?;
+        let mut u = BigNum::new()?;
+        u.mod_exp(&self.public.decode(c)?, &self.phi, n2, &mut ctx)?;
+        u.sub_word(1)?;
+        let mut l = BigNum::new()?;
+        l.checked_div(&u, n, &mut ctx)?;
+        let mut m = BigNum::new()?;
+        m.mod_mul(&l, &self.mu, n, &mut ctx)?;
+        Ok(m.to_vec())
+    }
+}
+
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        for secret in [&mut self.p, &mut self.q, &mut self.phi, &mut self.mu] {
+            secret.clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sums_without_decrypting() {
+        let key = PrivateKey::generate(MIN_BITS).unwrap();
+        let public = key.public();
+        let values = [3, 0, u32::MAX as u64, 41];
+        let sum = values
+            .iter()
+            .try_fold(public.zero().unwrap(), |sum, value| {
+                public.add(&sum, &public.encrypt(*value)?)
+            })
+            .unwrap();
+        assert_eq!(key.decrypt(&sum).unwrap(), values.iter().sum::<u64>());
+        assert_eq!(key.decrypt(&public.zero().unwrap()).unwrap(), 0);
+        // Fresh randomness, so equal values don't show
+        assert_ne!(public.encrypt(7).unwrap(), public.encrypt(7).unwrap());
+    }
+
+    #[test]
+    fn rejects_ciphertexts_out_of_range() {
+        let key = PrivateKey::generate(MIN_BITS).unwrap();
+        let public = key.public();
+        let len = public.ciphertext_len();
+        let valid = public.encrypt(1).unwrap();
+        assert!(public.check(&valid).is_ok());
+        let invalid = [
+            vec![0; len],
+            public.n2.to_vec_padded(len as i32).unwrap(),
+            vec![0xff; len],
+            valid[1..].to_vec(),
+            [&[0][..], &valid].concat(),
+        ];
+        for c in invalid {
+            assert!(public.check(&c).is_err());
+            assert!(public.add(&valid, &c).is_err());
+            assert!(key.decrypt(&c).is_err());
+        }
+    }
+
+    #[test]
+    fn rejects_small_or_even_moduli() {
+        assert!(PublicKey::from_bytes(&[0xff; 256]).is_ok());
+        assert!(PublicKey::from_bytes(&[0xff; 128]).is_err());
+        assert!(PublicKey::from_bytes(&[0xfe; 256]).is_err());
+        assert!(PrivateKey::generate(MIN_BITS / 2).is_err());
+    }
+}
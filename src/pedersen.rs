@@ -0,0 +1,67 @@
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use rand_core::{OsRng, RngCore};
+use sha2::Sha512;
+use std::error::Error;
+
+/// Compressed Ristretto point committing to a value
+pub type Commitment = [u8; 32];
+
+/// Second generator, hashed to the curve so nobody knows its discrete log relative to
+/// the basepoint. Knowing it would let the app open a commitment to any value.
+pub(crate) fn h() -> RistrettoPoint {
+    RistrettoPoint::hash_from_bytes::<Sha512>(b"ppa-pedersen-h")
+}
+
+/// `value` as a scalar, negative values wrapping modulo the group order.
+pub(crate) fn scalar(value: i128) -> Scalar {
+    let magnitude = Scalar::from(value.unsigned_abs());
+    if value < 0 {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/// Fresh blinding factor, uniform modulo the group order.
+pub fn blinding() -> Scalar {
+    let mut wide = [0u8; 64];
+    OsRng.fill_bytes(&mut wide);
+    Scalar::from_bytes_mod_order_wide(&wide)
+}
+
+/// Reads a blinding factor, which must be in canonical form.
+pub fn opening(bytes: &[u8]) -> Result<Scalar, Box<dyn Error>> {
+    let bytes: [u8; 32] = bytes
+        .try_into()
+        .map_err(|_| "blinding factors are 32 bytes")?;
+    Option::<Scalar>::from(Scalar::from_canonical_bytes(bytes))
+        .ok_or_else(|| "blinding factor is not canonical".into())
+}
+
+/// Pedersen commitment `value·G + blinding·H`. It hides `value`, and binds whoever
+/// made it to `value` unless they can break discrete logs.
+pub fn commit(value: i128, blinding: &Scalar) -> Commitment {
+    (RISTRETTO_BASEPOINT_POINT * scalar(value) + h() * blinding)
+        .compress()
+        .to_bytes()
+}
+
+/// Sum of `commitments`, a commitment to the sum of their values under the sum of
+/// their blinding factors. `None` if any isn't a valid point.
+pub fn sum(commitments: &[Commitment]) -> Option<Commitment> {
+    let points: Option<Vec<RistrettoPoint>> = commitments
+        .iter()
+        .map(|c| CompressedRistretto(*c).decompress())
+        .collect();
+    let sum: RistrettoPoint = points?.into_iter().sum();
+    Some(sum.compress().to_bytes())
+}
+
+/// Whether `commitments` add up to a commitment to `total` under `blinding`, the sum
+/// of their blinding factors. Commitments are additive, so this shows `total` is the
+/// sum of the committed values without opening any one of them.
+pub fn verify(commitments: &[Commitment], total: i128, blinding: &Scalar) -> bool {
+    sum(commitments).is_some_and(|sum| sum == commit(total, blinding))
+}
@@ -0,0 +1,70 @@
+//! Python bindings for attestation verification, enabled with the `python` feature.
+//!
+//! Build with `maturin build --features python` and use as:
+//!
+//! ```python
+//! import ppa
+//! report = ppa.verify(doc, image_id)
+//! ```
+
+use crate::attestation::{self, Limits, Policy, AWS_ROOT_CERT};
+use pyo3::create_exception;
+use pyo3::exceptions::PyException;
+use pyo3::prelude::*;
+use pyo3::types::{PyBytes, PyDict};
+use std::collections::BTreeMap;
+
+create_exception!(ppa, VerificationError, PyException);
+
+/// Verifies a raw attestation document and returns the report as a dict.
+///
+/// Raises `VerificationError` with a `<category>: <reason>` message on failure.
+#[pyfunction]
+#[pyo3(signature = (doc, image_id, root_cert=None, module_id=None, clock_skew=300))]
+fn verify<'py>(
+    py: Python<'py>,
+    doc: &[u8],
+    image_id: &str,
+    root_cert: Option<&[u8]>,
+    module_id: Option<String>,
+    clock_skew: i64,
+) -> PyResult<Bound<'py, PyDict>> {
+    let policy = Policy {
+        image_id: image_id.to_owned(),
+        module_id,
+        clock_skew,
+        expected_pcrs: BTreeMap::new(),
+        min_vcpus: None,
+        min_memory_mib: None,
+        limits: Limits::default(),
+    };
+
+    let report = attestation::verify(doc, root_cert.unwrap_or(AWS_ROOT_CERT), &policy)
+        .map_err(|e| VerificationError::new_err(e.to_string()))?;
+
+    let dict = PyDict::new_bound(py);
+    dict.set_item("public_key", PyBytes::new_bound(py, &report.public_key))?;
+    dict.set_item("image_id", report.image_id)?;
+    dict.set_item("module_id", report.module_id)?;
+    dict.set_item("timestamp", report.timestamp)?;
+    match report.resources {
+        Some(resources) => {
+            let r = PyDict::new_bound(py);
+            r.set_item("vcpus", resources.vcpus)?;
+            r.set_item("memory_mib", resources.memory_mib)?;
+            dict.set_item("resources", r)?;
+        }
+        None => dict.set_item("resources", py.None())?,
+    }
+    Ok(dict)
+}
+
+#[pymodule]
+fn ppa(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_function(wrap_pyfunction!(verify, m)?)?;
+    m.add(
+        "VerificationError",
+        m.py().get_type_bound::<VerificationError>(),
+    )?;
+    Ok(())
+}
@@ -0,0 +1,285 @@
+use crate::pedersen::{self, Commitment};
+use bulletproofs::{BulletproofGens, PedersenGens, RangeProof};
+use curve25519_dalek::constants::RISTRETTO_BASEPOINT_POINT;
+use curve25519_dalek::ristretto::{CompressedRistretto, RistrettoPoint};
+use curve25519_dalek::scalar::Scalar;
+use curve25519_dalek::traits::Identity;
+use merlin::Transcript;
+use std::error::Error;
+use std::ops::RangeInclusive;
+
+/// Bits each value's distance from a bound is proven to fit in
+const BITS: usize = 64;
+
+/// Most values one proof covers; the generators it needs grow with the count
+pub const MAX_VALUES: usize = 4096;
+
+/// Bulletproofs that every value committed to lies within a range: one aggregated proof
+/// that each `value - min` fits in 64 bits and one that each `max - value` does. Both
+/// distances add up to `max - min`, far below the group order, so neither can wrap
+/// around to hide a value outside the range.
+pub struct Proof {
+    /// Pedersen commitment to each value, as `pedersen::commit` makes them
+    pub commitments: Vec<Commitment>,
+    lower: RangeProof,
+    upper: RangeProof,
+}
+
+/// Generators for `m` values, with `pedersen`'s so the commitments are the same.
+fn generators(m: usize) -> (PedersenGens, BulletproofGens) {
+    let pedersen = PedersenGens {
+        B: RISTRETTO_BASEPOINT_POINT,
+        B_blinding: pedersen::h(),
+    };
+    (pedersen, BulletproofGens::new(BITS, m))
+}
+
+/// Transcript binding a proof to the loader making it, the dataset and the range, so
+/// it can't be replayed by another loader or against a wider range.
+fn transcript(
+    label: &'static [u8],
+    loader: &[u8],
+    dataset: &str,
+    range: &RangeInclusive<i128>,
+) -> Transcript {
+    let mut transcript = Transcript::new(b"ppa-range-v1");
+    transcript.append_message(b"bound", label);
+    transcript.append_message(b"loader", loader);
+    transcript.append_message(b"dataset", dataset.as_bytes());
+    transcript.append_message(b"min", &range.start().to_le_bytes());
+    transcript.append_message(b"max", &range.end().to_le_bytes());
+    transcript
+}
+
+/// Proves that every one of `values` lies within `range`, for `loader`'s upload to
+/// `dataset`. Returns the proof and the blinding factor of each value's commitment.
+pub fn prove(
+    loader: &[u8],
+    dataset: &str,
+    values: &[i128],
+    range: &RangeInclusive<i128>,
+) -> Result<(Proof, Vec<Scalar>), Box<dyn Error>> {
+    if values.is_empty() || values.len() > MAX_VALUES {
+        return Err(format!("a range proof covers 1 to {} values", MAX_VALUES).into());
+    }
+    let distance = |a: i128, b: i128| -> Result<u64, Box<dyn Error>> {
+        u64::try_from(a - b).map_err(|_| "a value lies outside the range".into())
+    };
+    let m = values.len().next_power_of_two();
+    let mut below = Vec::with_capacity(m);
+    let mut above = Vec::with_capacity(m);
+    for value in values {
+        below.push(distance(*value, *range.start())?);
+        above.push(distance(*range.end(), *value)?);
+    }
+    let blindings: Vec<Scalar> = values.iter().map(|_| pedersen::blinding()).collect();
+    // Aggregation takes a power of two of values; zeros with zero blinding commit to
+    // the identity, which the verifier pads with too
+    below.resize(m, 0);
+    above.resize(m, 0);
+    let mut lower_blindings = blindings.clone();
+    lower_blindings.resize(m, Scalar::ZERO);
+    let upper_blindings: Vec<Scalar> = lower_blindings.iter().map(|b| -b).collect();
+
+    let (pc_gens, bp_gens) = generators(m);
+    let (lower, _) = RangeProof::prove_multiple(
+        &bp_gens,
+        &pc_gens,
+        &mut transcript(b"lower", loader, dataset, range),
+        &below,
+        &lower_blindings,
+        BITS,
+    )?;
+    let (upper, _) = RangeProof::prove_multiple(
+        &bp_gens,
+        &pc_gens,
+        &mut transcript(b"upper", loader, dataset, range),
+        &above,
+        &upper_blindings,
+        BITS,
+    )?;
+    let commitments = values
+        .iter()
+        .zip(&blindings)
+        .map(|(value, blinding)| pedersen::commit(*value, blinding))
+        .collect();
+    let proof = Proof {
+        commitments,
+        lower,
+        upper,
+    };
+    Ok((proof, blindings))
+}
+
+impl Proof {
+    /// Checks the proof shows every committed value of `loader`'s upload to `dataset`
+    /// lies within `range`. `value·G + r·H` lies there when `(value - min)·G + r·H` and
+    /// `(max - value)·G - r·H` both commit to 64-bit values.
+    pub fn verify(
+        &self,
+        loader: &[u8],
+        dataset: &str,
+        range: &RangeInclusive<i128>,
+    ) -> Result<(), Box<dyn Error>> {
+        let count = self.commitments.len();
+        if count == 0 || count > MAX_VALUES {
+            return Err(format!("a range proof covers 1 to {} values", MAX_VALUES).into());
+        }
+        let points = self
+            .commitments
+            .iter()
+            .map(|c| CompressedRistretto(*c).decompress())
+            .collect::<Option<Vec<_>>>()
+            .ok_or("malformed commitment")?;
+        let min = RISTRETTO_BASEPOINT_POINT * pedersen::scalar(*range.start());
+        let max = RISTRETTO_BASEPOINT_POINT * pedersen::scalar(*range.end());
+        let m = count.next_power_of_two();
+        let padded = |points: Vec<RistrettoPoint>| {
+            let mut compressed: Vec<_> = points.iter().map(RistrettoPoint::compress).collect();
+            compressed.resize(m, RistrettoPoint::identity().compress());
+            compressed
+        };
+        let below = padded(points.iter().map(|p| p - min).collect());
+        let above = padded(points.iter().map(|p| max - p).collect());
+
+        let (pc_gens, bp_gens) = generators(m);
+        self.lower.verify_multiple(
+            &bp_gens,
+            &pc_gens,
+            &mut transcript(b"lower", loader, dataset, range),
+            &below,
+            BITS,
+        )?;
+        self.upper.verify_multiple(
+            &bp_gens,
+            &pc_gens,
+            &mut transcript(b"upper", loader, dataset, range),
+            &above,
+            BITS,
+        )?;
+        Ok(())
+    }
+}
+
+/// Prefixes `msg` with `proof` and, for each committed value, what opens its
+/// commitment once aggregated: `count (u32 LE) | commitments | len (u32 LE) | lower |
+/// len | upper`, then each opening as `len (u32 LE) | opening`.
+pub fn attach(proof: &Proof, openings: &[Vec<u8>], msg: &[u8]) -> Vec<u8> {
+    let mut buf = (proof.commitments.len() as u32).to_le_bytes().to_vec();
+    for commitment in &proof.commitments {
+        buf.extend_from_slice(commitment);
+    }
+    for part in [proof.lower.to_bytes(), proof.upper.to_bytes()]
+        .iter()
+        .chain(openings)
+    {
+        buf.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        buf.extend_from_slice(part);
+    }
+    buf.extend_from_slice(msg);
+    buf
+}
+
+/// Splits the proof and openings `attach` added off the front of `buf`, returning the
+/// rest.
+pub fn detach(buf: &[u8]) -> Result<(Proof, Vec<Vec<u8>>, &[u8]), Box<dyn Error>> {
+    let mut rest = buf;
+    let count = read_len(&mut rest)?;
+    if count == 0 || count > MAX_VALUES {
+        return Err(format!("a range proof covers 1 to {} values", MAX_VALUES).into());
+    }
+    let commitments: Vec<Commitment> = take(&mut rest, count * 32)?
+        .chunks_exact(32)
+        .map(|c| c.try_into().unwrap())
+        .collect();
+    let lower = RangeProof::from_bytes(read(&mut rest)?)?;
+    let upper = RangeProof::from_bytes(read(&mut rest)?)?;
+    let openings: Vec<Vec<u8>> = (0..count)
+        .map(|_| read(&mut rest).map(<[u8]>::to_vec))
+        .collect::<Result<_, _>>()?;
+    let proof = Proof {
+        commitments,
+        lower,
+        upper,
+    };
+    Ok((proof, openings, rest))
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+    if buf.len() < len {
+        return Err("truncated range proof".into());
+    }
+    let (head, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(head)
+}
+
+fn read_len(buf: &mut &[u8]) -> Result<usize, Box<dyn Error>> {
+    let len = take(buf, 4)?.try_into().unwrap();
+    Ok(u32::from_le_bytes(len) as usize)
+}
+
+fn read<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8], Box<dyn Error>> {
+    let len = read_len(buf)?;
+    take(buf, len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOADER: &[u8] = &[7; 32];
+
+    #[test]
+    fn proves_values_within_the_range() {
+        let range = -5..=1000;
+        let values = [-5, 0, 17, 999, 1000];
+        let (proof, blindings) = prove(LOADER, "trial-a", &values, &range).unwrap();
+        proof.verify(LOADER, "trial-a", &range).unwrap();
+        for ((commitment, value), blinding) in proof.commitments.iter().zip(values).zip(blindings) {
+            assert_eq!(*commitment, pedersen::commit(value, &blinding));
+        }
+    }
+
+    #[test]
+    fn refuses_values_outside_the_range() {
+        let range = 0..=1000;
+        assert!(prove(LOADER, "trial-a", &[3, -1], &range).is_err());
+        assert!(prove(LOADER, "trial-a", &[1001, 3], &range).is_err());
+        assert!(prove(LOADER, "trial-a", &[], &range).is_err());
+    }
+
+    #[test]
+    fn rejects_proofs_for_another_range_loader_or_dataset() {
+        let (proof, _) = prove(LOADER, "trial-a", &[0, 2000], &(0..=2000)).unwrap();
+        assert!(proof.verify(LOADER, "trial-a", &(0..=1000)).is_err());
+        assert!(proof.verify(LOADER, "trial-a", &(1..=2000)).is_err());
+        assert!(proof.verify(&[8; 32], "trial-a", &(0..=2000)).is_err());
+        assert!(proof.verify(LOADER, "trial-b", &(0..=2000)).is_err());
+    }
+
+    #[test]
+    fn rejects_tampered_commitments() {
+        let range = 0..=1000;
+        let (mut proof, blindings) = prove(LOADER, "trial-a", &[10, 20, 30], &range).unwrap();
+        // A commitment to an out-of-range value under the same blinding factor
+        proof.commitments[1] = pedersen::commit(5000, &blindings[1]);
+        assert!(proof.verify(LOADER, "trial-a", &range).is_err());
+        proof.commitments[1][0] ^= 1;
+        assert!(proof.verify(LOADER, "trial-a", &range).is_err());
+    }
+
+    #[test]
+    fn attached_proofs_survive_the_round_trip() {
+        let range = 0..=1000;
+        let (proof, _) = prove(LOADER, "trial-a", &[1, 2, 3], &range).unwrap();
+        let openings = vec![vec![1], vec![2, 2], vec![3, 3, 3]];
+        let buf = attach(&proof, &openings, b"payload");
+        let (detached, opened, rest) = detach(&buf).unwrap();
+        assert_eq!(detached.commitments, proof.commitments);
+        assert_eq!(opened, openings);
+        assert_eq!(rest, b"payload");
+        detached.verify(LOADER, "trial-a", &range).unwrap();
+        assert!(detach(&buf[..buf.len() - 20]).is_err());
+    }
+}
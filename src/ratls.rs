@@ -0,0 +1,176 @@
+use crate::attestation::{self, Limits, Policy, AWS_ROOT_CERT};
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::sync::Arc;
+use subtle::ConstantTimeEq;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::TlsConnector;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+/// Private OID of the certificate extension carrying the attestation document
+pub const ATTESTATION_OID: &[u64] = &[1, 3, 6, 1, 4, 1, 58888, 1, 1];
+
+/// Name the self-signed certificate is issued to
+pub const SERVER_NAME: &str = "ppa-app";
+
+/// JSON `user_data` of the embedded attestation, binding the certificate's key to the
+/// enclave.
+#[derive(Serialize, Deserialize)]
+pub struct Binding {
+    /// SHA-256 of the certificate's DER SubjectPublicKeyInfo
+    #[serde(with = "hex::serde")]
+    pub tls_spki_sha256: Vec<u8>,
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+/// TLS server config with a fresh self-signed certificate. `attest` is handed the
+/// `user_data` binding the certificate's key and returns the attestation document the
+/// certificate then carries, so clients check both in the TLS handshake.
+pub fn server_config(
+    attest: impl FnOnce(Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>>,
+) -> Result<ServerConfig, Box<dyn Error>> {
+    let key_pair = rcgen::KeyPair::generate()?;
+    let binding = Binding {
+        tls_spki_sha256: Sha256::digest(key_pair.public_key_der()).to_vec(),
+    };
+    let doc = attest(serde_json::to_vec(&binding)?)?;
+
+    let mut params = rcgen::CertificateParams::new(vec![SERVER_NAME.to_owned()])?;
+    params
+        .custom_extensions
+        .push(rcgen::CustomExtension::from_oid_content(
+            ATTESTATION_OID,
+            doc,
+        ));
+    let cert = params.self_signed(&key_pair)?;
+    let key = PrivateKeyDer::Pkcs8(key_pair.serialize_der().into());
+
+    Ok(ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(vec![cert.der().clone()], key)?)
+}
+
+/// Accepts the app's certificate only if it carries a valid attestation for the
+/// expected image that binds the certificate's key.
+#[derive(Debug)]
+struct AttestedServer {
+    policy: Policy,
+    provider: Arc<CryptoProvider>,
+}
+
+impl AttestedServer {
+    fn check(&self, cert: &CertificateDer<'_>) -> Result<(), Box<dyn Error>> {
+        let (_, cert) = X509Certificate::from_der(cert.as_ref())?;
+        let oid = ATTESTATION_OID
+            .iter()
+            .map(u64::to_string)
+            .collect::<Vec<_>>()
+            .join(".");
+        let doc = cert
+            .extensions()
+            .iter()
+            .find(|ext| ext.oid.to_id_string() == oid)
+            .ok_or("certificate carries no attestation")?
+            .value;
+
+        attestation::verify(doc, AWS_ROOT_CERT, &self.policy)?;
+        let user_data = attestation::decode(doc, &self.policy.limits)?
+            .user_data
+            .ok_or("attestation carries no key binding")?;
+        let binding: Binding = serde_json::from_slice(&user_data)?;
+        let spki_sha256 = Sha256::digest(cert.public_key().raw);
+        if !bool::from(binding.tls_spki_sha256.ct_eq(spki_sha256.as_slice())) {
+            return Err("certificate key is not the attested one".into());
+        }
+        Ok(())
+    }
+}
+
+impl ServerCertVerifier for AttestedServer {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        self.check(end_entity)
+            .map_err(|e| rustls::Error::General(format!("attestation: {}", e)))?;
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+/// Opens TLS to the app, accepting it only if its certificate is attested by an enclave
+/// running `image_id`.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    stream: S,
+    image_id: &str,
+) -> Result<TlsStream<S>, Box<dyn Error>> {
+    let verifier = AttestedServer {
+        policy: Policy {
+            image_id: image_id.to_owned(),
+            module_id: None,
+            clock_skew: 300,
+            expected_pcrs: BTreeMap::new(),
+            min_vcpus: None,
+            min_memory_mib: None,
+            limits: Limits::default(),
+        },
+        provider: provider(),
+    };
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+    let connector = TlsConnector::from(Arc::new(config));
+    Ok(connector
+        .connect(ServerName::try_from(SERVER_NAME)?, stream)
+        .await?)
+}
@@ -0,0 +1,185 @@
+use crate::attestation::{self, Policy, Report};
+use crate::signing::{self, Scheme, Signer};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use subtle::ConstantTimeEq;
+
+/// What a receipt asserts: the enclave running `image_id` holds `public_key`, as
+/// verified at `verified_at`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Claims {
+    pub image_id: String,
+    pub module_id: String,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    /// attestation timestamp in milliseconds since the unix epoch
+    pub timestamp: i64,
+    /// verification time in seconds since the unix epoch
+    pub verified_at: u64,
+}
+
+/// What a result receipt asserts: the app answered `op` over `dataset` with `result`
+/// at `timestamp`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ResultClaims {
+    pub dataset: String,
+    pub op: String,
+    /// the operation was applied per position rather than to all values
+    pub elementwise: bool,
+    /// rank of a percentile, from 0 to 100
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub percentile: Option<u8>,
+    /// contributor group whose contributions alone were aggregated
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<String>,
+    /// the result as returned to the requester, after the requester's view
+    pub result: String,
+    /// signing time in seconds since the unix epoch
+    pub timestamp: u64,
+    /// hex root of the Merkle tree over the dataset's contributions when it was queried
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+    /// hex sum of the blinding factors of every contribution's Pedersen commitment, for
+    /// exact sums of datasets whose loaders all committed; see `pedersen::verify`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub blinding: Option<String>,
+    /// hex sums of the range-proven commitments to a shared or encrypted dataset's
+    /// values, one per result value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_commitments: Option<Vec<String>>,
+    /// what opens each of `range_commitments` once the result is recovered: this
+    /// instance's hex share of the blinding factors' sum, or that sum Paillier-encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub range_openings: Option<Vec<String>>,
+}
+
+/// JSON `user_data` of a result attestation, binding a result receipt to the enclave.
+#[derive(Serialize, Deserialize)]
+pub struct ResultBinding {
+    /// SHA-256 of the receipt's payload, which holds the query and its result
+    #[serde(with = "hex::serde")]
+    pub result_sha256: Vec<u8>,
+    /// the receipt's Merkle root, repeated so the document shows it directly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub merkle_root: Option<String>,
+}
+
+impl ResultBinding {
+    pub fn new(receipt: &Receipt) -> ResultBinding {
+        ResultBinding {
+            result_sha256: Sha256::digest(receipt.payload.as_bytes()).to_vec(),
+            merkle_root: None,
+        }
+    }
+}
+
+/// A result receipt as returned to requesters, with an attestation document that ties
+/// it to the enclave image independently of the result key.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct AttestedResult {
+    #[serde(flatten)]
+    pub receipt: Receipt,
+    /// hex-encoded attestation document whose `user_data` is a `ResultBinding`, present
+    /// when the app runs in an enclave
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub attestation: Option<String>,
+}
+
+impl AttestedResult {
+    /// Verifies the attestation against `policy` and checks that it binds this receipt.
+    /// The receipt's signature is checked separately, with `Receipt::verify_result`.
+    pub fn verify_attestation(
+        &self,
+        root_cert_pem: &[u8],
+        policy: &Policy,
+    ) -> Result<Report, Box<dyn Error>> {
+        let doc = hex::decode(
+            self.attestation
+                .as_deref()
+                .ok_or("result carries no attestation")?,
+        )?;
+        let report = attestation::verify(&doc, root_cert_pem, policy)?;
+        let user_data = attestation::decode(&doc, &policy.limits)?
+            .user_data
+            .ok_or("attestation carries no result binding")?;
+        let binding: ResultBinding = serde_json::from_slice(&user_data)?;
+        let expected = ResultBinding::new(&self.receipt).result_sha256;
+        if !bool::from(binding.result_sha256.ct_eq(&expected)) {
+            return Err("attestation is for a different result".into());
+        }
+        Ok(report)
+    }
+}
+
+/// Verification result signed by a verifier, checkable without re-running attestation.
+///
+/// The signature covers the `payload` string exactly as stored, which is the JSON
+/// encoding of the `Claims`.
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Receipt {
+    pub payload: String,
+    pub scheme: Scheme,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+}
+
+impl Receipt {
+    pub fn issue(signer: &dyn Signer, report: &Report, verified_at: u64) -> Receipt {
+        let claims = Claims {
+            image_id: report.image_id.clone(),
+            module_id: report.module_id.clone(),
+            public_key: report.public_key.clone(),
+            timestamp: report.timestamp,
+            verified_at,
+        };
+        Receipt::sign(signer, &claims)
+    }
+
+    /// Receipt for a query result, signed by the app's result key so the requester can
+    /// show a third party which enclave produced the answer.
+    pub fn for_result(signer: &dyn Signer, claims: &ResultClaims) -> Receipt {
+        Receipt::sign(signer, claims)
+    }
+
+    fn sign<T: Serialize>(signer: &dyn Signer, claims: &T) -> Receipt {
+        let payload = serde_json::to_string(claims).expect("claims always serialize");
+        let signature = signer.sign(payload.as_bytes());
+        Receipt {
+            payload,
+            scheme: signer.scheme(),
+            public_key: signer.public_key(),
+            signature,
+        }
+    }
+
+    /// Checks the signature and returns the claims. `trusted` pins the verifier key;
+    /// without it the receipt only proves it was signed by the key it carries.
+    pub fn verify(&self, trusted: Option<&[u8]>) -> Result<Claims, Box<dyn Error>> {
+        self.check(trusted)?;
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+
+    /// Checks the signature of a result receipt and returns its claims. `trusted` pins
+    /// the app's result key, as published in its attestation.
+    pub fn verify_result(&self, trusted: Option<&[u8]>) -> Result<ResultClaims, Box<dyn Error>> {
+        self.check(trusted)?;
+        Ok(serde_json::from_str(&self.payload)?)
+    }
+
+    fn check(&self, trusted: Option<&[u8]>) -> Result<(), Box<dyn Error>> {
+        if let Some(trusted) = trusted {
+            if !bool::from(trusted.ct_eq(&self.public_key)) {
+                return Err("receipt signed by an untrusted key".into());
+            }
+        }
+        signing::verify(
+            self.scheme,
+            &self.public_key,
+            self.payload.as_bytes(),
+            &self.signature,
+        )
+    }
+}
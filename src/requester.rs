@@ -1,23 +1,36 @@
-use chacha20poly1305::{
-    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
-    ChaCha20Poly1305,
-};
 use clap::Parser;
+use curve25519_dalek::scalar::Scalar;
+use ppa::aead::CommittingCipher;
+use ppa::attestation::{Limits, Policy, AWS_ROOT_CERT};
+use ppa::frame::{self, Padding};
+use ppa::message::{self, Envelope, Kind};
+use ppa::paillier::PrivateKey;
+use ppa::pedersen;
+use ppa::receipt::{AttestedResult, ResultClaims};
+use ppa::session;
+use ppa::shamir;
+use ppa::sum::{self, Mode, Op, Query};
+use ppa::transport::{self, Stream};
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fs::File;
 use std::io::Read;
-use tokio::io::AsyncReadExt;
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
-use x25519_dalek::x25519;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroizing;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// ip address of the server <ip:port>
+    /// address of the server <ip:port> or unix:<path>
     #[clap(short, long, value_parser)]
     ip_addr: String,
 
+    /// address of a standby replica of the app <ip:port>, repeatable; the connection
+    /// goes to whichever instance leads
+    #[arg(long, conflicts_with = "share")]
+    standby: Vec<String>,
+
     /// path to app public key file
     #[arg(short, long)]
     app: String,
@@ -25,48 +38,490 @@ struct Cli {
     /// path to private key file
     #[arg(short, long)]
     secret: String,
+
+    /// dataset to query; repeat to query several over one connection
+    #[arg(long, default_value = sum::DEFAULT_DATASET)]
+    dataset: Vec<String>,
+
+    /// aggregate operation <sum|mean|min|max|count|weighted|variance|stddev|median|
+    /// percentile|dot|histogram|distinct>
+    #[arg(long, default_value = "sum")]
+    op: Op,
+
+    /// rank of the percentile --op percentile answers, from 0 to 100
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100))]
+    percentile: Option<u8>,
+
+    /// comma-separated per-position weights for --op weighted, sent encrypted
+    #[arg(long, value_delimiter = ',')]
+    weights: Vec<u64>,
+
+    /// apply the operation per position of the uploaded vectors instead of to all values
+    #[arg(long)]
+    elementwise: bool,
+
+    /// aggregate only the contributions of this contributor group, as the app's
+    /// --contributor-group defines it
+    #[arg(long, conflicts_with = "commitments")]
+    group: Option<String>,
+
+    /// connect over RA-TLS, accepting only an enclave attested to run this image ID
+    #[arg(long)]
+    tls_image_id: Option<String>,
+
+    /// connect through a Noise_IK channel keyed by the app's public key, for apps run
+    /// with --noise
+    #[arg(long)]
+    noise: bool,
+
+    /// pad every frame to hide its size: none, padme or block:<bytes>; only hides sizes
+    /// over --tls or --noise, which encrypt the frame headers
+    #[arg(long, default_value_t = Padding::None)]
+    padding: Padding,
+
+    /// open a session with a hybrid ML-KEM-768 + X25519 key exchange first and seal
+    /// queries and results under it, so recorded traffic stays sealed against a future
+    /// quantum computer
+    #[arg(long)]
+    pq: bool,
+
+    /// hex-encoded result key the app must sign with, as published in its attestation
+    #[arg(long)]
+    result_key: Option<String>,
+
+    /// write the signed result receipt to this file, to show others what the app answered
+    #[arg(long)]
+    receipt: Option<String>,
+
+    /// Paillier private key file to decrypt the result of an encrypted dataset with
+    #[arg(long)]
+    paillier_key: Option<String>,
+
+    /// require the result's attestation to show an enclave running this image ID
+    #[arg(long)]
+    result_image_id: Option<String>,
+
+    /// a further app instance <ip:port=app public key file> holding shares of a shared
+    /// dataset, in the order the loaders listed them; --ip-addr and --app are the first
+    #[arg(long, requires = "threshold", conflicts_with_all = ["receipt", "paillier_key"])]
+    share: Vec<String>,
+
+    /// instances whose shares are needed to recover a sum
+    #[arg(long, requires = "share")]
+    threshold: Option<usize>,
+
+    /// keep the connection open and query the dataset again whenever the app reports a
+    /// new aggregate, until interrupted
+    #[arg(long, conflicts_with = "share")]
+    subscribe: bool,
+
+    /// file of the loaders' hex Pedersen commitments, one per line; the sum must open
+    /// their sum, so the app can't have dropped, added or altered a contribution
+    #[arg(long, conflicts_with_all = ["elementwise", "share", "paillier_key"])]
+    commitments: Option<String>,
+}
+
+/// The app's answer to a subscription.
+#[derive(Deserialize)]
+struct Notice {
+    version: u64,
+    /// false when the subscription timed out before a new aggregate, to be renewed
+    ready: bool,
+}
+
+/// An app instance and how requests to it are sealed: under a session opened with a
+/// hybrid handshake, or with HPKE to its key.
+struct Instance<'a> {
+    app: &'a [u8; 32],
+    secret: &'a [u8; 32],
+    session: Option<CommittingCipher>,
+}
+
+impl<'a> Instance<'a> {
+    /// Opens a hybrid ML-KEM-768 + X25519 session over `outbound` when `pq` is set.
+    async fn open(
+        outbound: &mut Box<dyn Stream>,
+        app: &'a [u8; 32],
+        secret: &'a [u8; 32],
+        pq: bool,
+    ) -> Result<Instance<'a>, Box<dyn Error>> {
+        let session = if pq {
+            Some(session::connect(outbound, secret, app, true).await?)
+        } else {
+            None
+        };
+        Ok(Instance {
+            app,
+            secret,
+            session,
+        })
+    }
+
+    /// Sends `msg` and returns the app's decrypted response.
+    async fn request(
+        &self,
+        outbound: &mut Box<dyn Stream>,
+        kind: Kind,
+        msg: &[u8],
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        match &self.session {
+            Some(session) => {
+                let public = x25519(*self.secret, X25519_BASEPOINT_BYTES);
+                let envelope = Envelope::seal(kind, &public, 0, session, msg)?;
+                let resp = message::request(outbound, &envelope).await?;
+                session.decrypt(&resp, &envelope.reply_aad())
+            }
+            None => {
+                let (envelope, reply) = Envelope::seal_to(kind, self.secret, self.app, 0, msg)?;
+                let resp = message::request(outbound, &envelope).await?;
+                reply.decrypt(&resp, &envelope.reply_aad())
+            }
+        }
+    }
+}
+
+/// Waits for an aggregate of `dataset` newer than `version`.
+async fn wait(
+    outbound: &mut Box<dyn Stream>,
+    instance: &Instance<'_>,
+    dataset: &str,
+    version: u64,
+) -> Result<Notice, Box<dyn Error>> {
+    let msg = sum::with_dataset(dataset, &version.to_le_bytes())?;
+    let resp = instance.request(outbound, Kind::Subscribe, &msg).await?;
+    Ok(serde_json::from_slice(&resp)?)
+}
+
+/// Queries `dataset` and prints its verified result.
+async fn report(
+    cli: &Cli,
+    outbound: &mut Box<dyn Stream>,
+    instance: &Instance<'_>,
+    dataset: &str,
+    query: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    let (result, claims) = ask(cli, outbound, instance, dataset, query).await?;
+    let receipt = &result.receipt;
+    if let Some(path) = &cli.receipt {
+        std::fs::write(path, serde_json::to_vec_pretty(&result)?)?;
+    }
+
+    let result = match &cli.paillier_key {
+        Some(path) => {
+            let key = std::fs::read(path)?;
+            let result = decrypt(&key, &claims.result)?;
+            let proven = (&claims.range_commitments, &claims.range_openings);
+            if let (Some(commitments), Some(openings)) = proven {
+                let blindings = decrypt_blindings(&key, openings)?;
+                check_range(commitments, &parse_result(&result)?, &blindings)?;
+                println!("Sum opens the loaders' range-proven commitments");
+            }
+            result
+        }
+        None => claims.result,
+    };
+    if cli.dataset.len() > 1 || cli.subscribe {
+        println!("Dataset {}:", dataset);
+    }
+    println!("Repsonse: Result: {}", result);
+    println!(
+        "Signed by {} key {}",
+        receipt.scheme,
+        hex::encode(&receipt.public_key)
+    );
+    Ok(())
+}
+
+/// Sends the query for `dataset` over a connection to one app instance and returns its
+/// verified result receipt.
+async fn ask(
+    cli: &Cli,
+    outbound: &mut Box<dyn Stream>,
+    instance: &Instance<'_>,
+    dataset: &str,
+    query: &[u8],
+) -> Result<(AttestedResult, ResultClaims), Box<dyn Error>> {
+    // The app looks up our view by the public key the envelope carries
+    let query = sum::with_dataset(dataset, query)?;
+    let resp = instance.request(outbound, Kind::Query, &query).await?;
+    let (&op, resp) = resp.split_first().ok_or("empty response")?;
+    if Op::from_byte(op)? != cli.op {
+        return Err("response is for a different operation".into());
+    }
+
+    let result: AttestedResult = serde_json::from_slice(resp)?;
+    let trusted = cli.result_key.as_deref().map(hex::decode).transpose()?;
+    let claims = result.receipt.verify_result(trusted.as_deref())?;
+    if claims.dataset != dataset
+        || claims.op != cli.op.to_string()
+        || claims.percentile != cli.percentile
+        || claims.group != cli.group
+    {
+        return Err("receipt is for a different query".into());
+    }
+    if let Some(image_id) = &cli.result_image_id {
+        let policy = Policy {
+            image_id: image_id.clone(),
+            module_id: None,
+            clock_skew: 300,
+            expected_pcrs: BTreeMap::new(),
+            min_vcpus: None,
+            min_memory_mib: None,
+            limits: Limits::default(),
+        };
+        let report = result.verify_attestation(AWS_ROOT_CERT, &policy)?;
+        println!("Attested by image {}", report.image_id);
+    }
+    if let Some(path) = &cli.commitments {
+        check_commitments(path, &claims)?;
+        println!("Sum opens the loaders' commitments");
+    }
+    Ok((result, claims))
+}
+
+/// Checks that a sum is the total of the contributions committed to in `path`, with the
+/// blinding factor the receipt opens their sum with.
+fn check_commitments(path: &str, claims: &ResultClaims) -> Result<(), Box<dyn Error>> {
+    let commitments = std::fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(|line| -> Result<pedersen::Commitment, Box<dyn Error>> {
+            hex::decode(line)?
+                .try_into()
+                .map_err(|_| "commitments must be 32 bytes".into())
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let blinding = claims
+        .blinding
+        .as_deref()
+        .ok_or("result carries no blinding factor to check commitments with")?;
+    let blinding = pedersen::opening(&hex::decode(blinding)?)?;
+    // Rendered in the dataset's type, while the commitments are over its units
+    let total: i128 = claims.result.replace('.', "").parse()?;
+    if !pedersen::verify(&commitments, total, &blinding) {
+        return Err("result is not the sum of the committed contributions".into());
+    }
+    Ok(())
+}
+
+/// Checks the sums of a shared or encrypted dataset, once recovered, are the sums of
+/// the values the loaders proved in range: each opens the sum of their commitments the
+/// app reported under the matching sum of blinding factors.
+fn check_range(
+    commitments: &[String],
+    sums: &[u64],
+    blindings: &[Scalar],
+) -> Result<(), Box<dyn Error>> {
+    if commitments.len() != sums.len() || blindings.len() != sums.len() {
+        return Err("range-proven commitments don't match the result".into());
+    }
+    for ((commitment, sum), blinding) in commitments.iter().zip(sums).zip(blindings) {
+        let commitment: pedersen::Commitment = hex::decode(commitment)?
+            .try_into()
+            .map_err(|_| "commitments must be 32 bytes")?;
+        if !pedersen::verify(&[commitment], *sum as i128, blinding) {
+            return Err("result is not the sum of the range-proven contributions".into());
+        }
+    }
+    Ok(())
+}
+
+/// Decrypts hex Paillier ciphertexts of sums of blinding factors, reduced to scalars.
+fn decrypt_blindings(key: &[u8], openings: &[String]) -> Result<Vec<Scalar>, Box<dyn Error>> {
+    let key = PrivateKey::from_bytes(&Zeroizing::new(key.to_vec()))?;
+    openings
+        .iter()
+        .map(|opening| -> Result<Scalar, Box<dyn Error>> {
+            let sum = key.decrypt_bytes(&hex::decode(opening)?)?;
+            if sum.len() > 64 {
+                return Err("blinding factor sum exceeds 512 bits".into());
+            }
+            // Big-endian from Paillier, little-endian for the scalar
+            let mut wide = [0u8; 64];
+            for (byte, b) in wide.iter_mut().zip(sum.iter().rev()) {
+                *byte = *b;
+            }
+            Ok(Scalar::from_bytes_mod_order_wide(&wide))
+        })
+        .collect()
+}
+
+/// Values of a result, one or a `[a, b]` list.
+fn parse_result(result: &str) -> Result<Vec<u64>, Box<dyn Error>> {
+    let list = result
+        .strip_prefix('[')
+        .and_then(|r| r.strip_suffix(']'))
+        .unwrap_or(result);
+    Ok(list
+        .split(',')
+        .map(|v| v.trim().parse())
+        .collect::<Result<_, _>>()?)
+}
+
+/// Decrypts a result of hex Paillier ciphertexts, one or a `[a, b]` list, keeping its shape.
+fn decrypt(key: &[u8], result: &str) -> Result<String, Box<dyn Error>> {
+    let key = PrivateKey::from_bytes(&Zeroizing::new(key.to_vec()))?;
+    let decrypt = |c: &str| -> Result<String, Box<dyn Error>> {
+        Ok(key.decrypt(&hex::decode(c.trim())?)?.to_string())
+    };
+    match result.strip_prefix('[').and_then(|r| r.strip_suffix(']')) {
+        Some(list) => {
+            let values: Vec<String> = list.split(',').map(decrypt).collect::<Result<_, _>>()?;
+            Ok(format!("[{}]", values.join(", ")))
+        }
+        None => decrypt(result),
+    }
 }
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let cli = Cli::parse();
+    frame::set_padding(cli.padding);
 
     println!("secret: {}, app: {}", cli.secret, cli.app);
 
-    let mut file = File::open(cli.secret)?;
+    let mut file = File::open(&cli.secret)?;
     let mut secret = [0; 32];
     file.read_exact(&mut secret)?;
 
-    let mut file = File::open(cli.app)?;
+    let mut file = File::open(&cli.app)?;
     let mut app = [0; 32];
     file.read_exact(&mut app)?;
 
-    let app_shared = x25519(secret, app);
-    let app_cipher = ChaCha20Poly1305::new(&app_shared.into());
-
-    let msg = [12, 43];
-    let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
-    let buf = app_cipher
-        .encrypt(
-            &nonce,
-            Payload {
-                msg: &msg,
-                aad: &[0],
-            },
-        )
-        .unwrap();
-
-    let outbound = TcpStream::connect(cli.ip_addr).await?;
-    let (mut ro, mut wo) = tokio::io::split(outbound);
-    wo.write_u8(1).await?;
-    wo.write_all(nonce.as_slice()).await?;
-    wo.write_all(buf.as_slice()).await?;
-    wo.shutdown().await?;
-
-    let mut resp = String::with_capacity(1000);
-    ro.read_to_string(&mut resp).await?;
-
-    println!("Repsonse: {}", resp);
+    let mode = if cli.elementwise {
+        Mode::Elementwise
+    } else {
+        Mode::Total
+    };
+    if (cli.op == Op::Weighted) == cli.weights.is_empty() {
+        return Err("--weights is required with --op weighted and only allowed with it".into());
+    }
+    let query = Query {
+        mode,
+        op: cli.op,
+        percentile: cli.percentile,
+        group: cli.group.clone(),
+        weights: cli.weights.clone(),
+    }
+    .encode()?;
+    if cli.op == Op::Dot && cli.group.is_none() {
+        return Err("--op dot takes the --group of the two loaders".into());
+    }
+    if cli.dataset.len() > 1 && (cli.threshold.is_some() || cli.receipt.is_some() || cli.subscribe)
+    {
+        return Err("--share, --receipt and --subscribe take a single --dataset".into());
+    }
+    if cli.commitments.is_some() && (cli.op != Op::Sum || cli.dataset.len() > 1) {
+        return Err("--commitments checks the sum of a single --dataset".into());
+    }
+
+    if let Some(threshold) = cli.threshold {
+        let mut instances = vec![(cli.ip_addr.clone(), app)];
+        for spec in &cli.share {
+            let (addr, path) = spec
+                .split_once('=')
+                .ok_or("--share takes <ip:port=app public key file>")?;
+            let mut app = [0; 32];
+            File::open(path)?.read_exact(&mut app)?;
+            instances.push((addr.to_owned(), app));
+        }
+        // Any `threshold` instances will do, so unreachable ones are skipped
+        let mut shares = Vec::new();
+        for (x, (addr, app)) in (1u64..).zip(&instances) {
+            if shares.len() == threshold {
+                break;
+            }
+            let asked = async {
+                let mut outbound =
+                    transport::dial(addr, cli.tls_image_id.as_deref(), cli.noise, &secret, app)
+                        .await?;
+                let instance = Instance::open(&mut outbound, app, &secret, cli.pq).await?;
+                ask(&cli, &mut outbound, &instance, &cli.dataset[0], &query).await
+            };
+            match asked.await {
+                Ok((_, claims)) => shares.push((x, parse_result(&claims.result)?, claims)),
+                Err(e) => println!("instance {} failed: {}", addr, e),
+            }
+        }
+        if shares.len() < threshold {
+            return Err(format!("only {} of {} shares answered", shares.len(), threshold).into());
+        }
+        let width = shares[0].1.len();
+        if shares.iter().any(|(_, values, _)| values.len() != width) {
+            return Err("instances disagree on the result length".into());
+        }
+        let sums = (0..width)
+            .map(|i| {
+                let points: Vec<(u64, u64)> = shares.iter().map(|(x, v, _)| (*x, v[i])).collect();
+                shamir::combine(&points)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        // Range-checked datasets send every instance the same commitments and each a
+        // share of the blinding factors
+        let proven: Option<Vec<(u64, &Vec<String>, &Vec<String>)>> = shares
+            .iter()
+            .map(|(x, _, claims)| {
+                let commitments = claims.range_commitments.as_ref()?;
+                Some((*x, commitments, claims.range_openings.as_ref()?))
+            })
+            .collect();
+        if let Some(proven) = proven {
+            let commitments = proven[0].1;
+            if proven.iter().any(|(_, c, _)| *c != commitments) {
+                return Err("instances disagree on the range-proven commitments".into());
+            }
+            let blindings = (0..commitments.len())
+                .map(|i| -> Result<Scalar, Box<dyn Error>> {
+                    let points = proven
+                        .iter()
+                        .map(
+                            |(x, _, openings)| -> Result<(u64, Scalar), Box<dyn Error>> {
+                                let share =
+                                    openings.get(i).ok_or("missing blinding factor share")?;
+                                Ok((*x, pedersen::opening(&hex::decode(share)?)?))
+                            },
+                        )
+                        .collect::<Result<Vec<_>, _>>()?;
+                    shamir::combine_scalars(&points)
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            check_range(commitments, &sums, &blindings)?;
+            println!("Sum opens the loaders' range-proven commitments");
+        }
+        let sums: Vec<String> = sums.iter().map(u64::to_string).collect();
+        match mode {
+            Mode::Total => println!("Repsonse: Result: {}", sums.join(", ")),
+            Mode::Elementwise => println!("Repsonse: Result: [{}]", sums.join(", ")),
+        }
+        return Ok(());
+    }
+
+    // Every dataset is queried over the one connection
+    let addrs = [vec![cli.ip_addr.clone()], cli.standby.clone()].concat();
+    let mut outbound = transport::dial_leader(
+        &addrs,
+        cli.tls_image_id.as_deref(),
+        cli.noise,
+        &secret,
+        &app,
+    )
+    .await?;
+    let instance = Instance::open(&mut outbound, &app, &secret, cli.pq).await?;
+    if cli.subscribe {
+        let dataset = &cli.dataset[0];
+        let mut version = 0;
+        loop {
+            let notice = wait(&mut outbound, &instance, dataset, version).await?;
+            if notice.ready {
+                version = notice.version;
+                report(&cli, &mut outbound, &instance, dataset, &query).await?;
+            }
+        }
+    }
+    for dataset in &cli.dataset {
+        report(&cli, &mut outbound, &instance, dataset, &query).await?;
+    }
 
     Ok(())
 }
\ No newline at end of file
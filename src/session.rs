@@ -0,0 +1,192 @@
+use crate::aead::{CommittingCipher, Suite};
+use crate::message::{self, Envelope, Kind};
+use hkdf::Hkdf;
+use ml_kem::kem::{Decapsulate, Encapsulate};
+use ml_kem::{Ciphertext, EncodedSizeUser, KemCore, MlKem768};
+use rand_core::{OsRng, RngCore};
+use sha2::Sha256;
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+use zeroize::Zeroizing;
+
+/// HKDF info for session keys
+const INFO: &[u8] = b"ppa-session-v1";
+
+type KemEncapsulationKey = <MlKem768 as KemCore>::EncapsulationKey;
+type KemDecapsulationKey = <MlKem768 as KemCore>::DecapsulationKey;
+
+/// Length of an ML-KEM-768 encapsulation key
+pub const KEM_KEY_LEN: usize = 1184;
+
+/// Length of an ML-KEM-768 ciphertext
+pub const KEM_CIPHERTEXT_LEN: usize = 1088;
+
+/// AAD of the app's half of the handshake, which binds it to the peer's ephemeral key
+/// so an old reply can't be replayed into a new handshake.
+pub fn reply_aad(hello: &Envelope, peer_ephemeral: &[u8; 32]) -> Vec<u8> {
+    let mut aad = hello.reply_aad();
+    aad.extend_from_slice(peer_ephemeral);
+    aad
+}
+
+/// Handshake transcript the session key is bound to: the peer's static key and both
+/// halves of the handshake as sent. It covers the suites offered and chosen and the
+/// ML-KEM key and ciphertext, so stripping an offer or the hybrid exchange to force a
+/// weaker session leaves the two sides with different keys.
+pub fn transcript(peer: &[u8; 32], hello: &[u8], reply: &[u8]) -> Vec<u8> {
+    [&peer[..], &(hello.len() as u16).to_be_bytes(), hello, reply].concat()
+}
+
+/// A peer's half of the handshake, `ephemeral key (32) | suite count (1) | suite ids
+/// (1 byte each) | ML-KEM-768 encapsulation key (1184, hybrid only)`, with the suites
+/// in the peer's order of preference. Ids this build doesn't know are skipped.
+pub struct Hello<'a> {
+    pub ephemeral: &'a [u8; 32],
+    pub offered: &'a [u8],
+    pub kem_key: Option<&'a [u8]>,
+}
+
+impl<'a> Hello<'a> {
+    pub fn parse(buf: &'a [u8]) -> Result<Self, Box<dyn Error>> {
+        let (ephemeral, rest) = buf.split_at_checked(32).ok_or("malformed handshake")?;
+        let (count, rest) = rest.split_first().ok_or("malformed handshake")?;
+        let (offered, kem_key) = rest
+            .split_at_checked(*count as usize)
+            .ok_or("malformed handshake")?;
+        let kem_key = match kem_key.len() {
+            0 => None,
+            KEM_KEY_LEN => Some(kem_key),
+            _ => return Err("malformed handshake".into()),
+        };
+        Ok(Hello {
+            ephemeral: ephemeral.try_into().unwrap(),
+            offered,
+            kem_key,
+        })
+    }
+}
+
+/// The first of this host's preferred suites that the peer offered.
+pub fn choose(offered: &[u8]) -> Option<Suite> {
+    Suite::preferred()
+        .into_iter()
+        .find(|suite| offered.contains(&(*suite as u8)))
+}
+
+/// ML-KEM-768 key pair used for a single hybrid handshake, discarded with it.
+pub struct KemPair {
+    secret: KemDecapsulationKey,
+    pub public: Vec<u8>,
+}
+
+impl KemPair {
+    pub fn generate() -> Self {
+        let (secret, public) = MlKem768::generate(&mut OsRng);
+        KemPair {
+            secret,
+            public: public.as_bytes().to_vec(),
+        }
+    }
+
+    /// The secret the peer encapsulated in `ciphertext`.
+    pub fn decapsulate(self, ciphertext: &[u8]) -> Result<Zeroizing<[u8; 32]>, Box<dyn Error>> {
+        let ciphertext = Ciphertext::<MlKem768>::try_from(ciphertext)
+            .map_err(|_| "malformed ML-KEM ciphertext")?;
+        let shared = self
+            .secret
+            .decapsulate(&ciphertext)
+            .map_err(|_| "ML-KEM decapsulation failed")?;
+        Ok(Zeroizing::new(shared.into()))
+    }
+}
+
+/// Encapsulates a fresh secret to a peer's ML-KEM-768 key, returning the ciphertext to
+/// send it and the secret.
+pub fn encapsulate(public: &[u8]) -> Result<(Vec<u8>, Zeroizing<[u8; 32]>), Box<dyn Error>> {
+    let public = public.try_into().map_err(|_| "malformed ML-KEM key")?;
+    let (ciphertext, shared) = KemEncapsulationKey::from_bytes(&public)
+        .encapsulate(&mut OsRng)
+        .map_err(|_| "ML-KEM encapsulation failed")?;
+    Ok((ciphertext.to_vec(), Zeroizing::new(shared.into())))
+}
+
+/// X25519 key pair used for a single handshake. The secret is zeroized when the pair is
+/// consumed, so past sessions can't be decrypted even if the static keys leak.
+pub struct Ephemeral {
+    secret: Zeroizing<[u8; 32]>,
+    pub public: [u8; 32],
+}
+
+impl Ephemeral {
+    pub fn generate() -> Self {
+        let mut secret = Zeroizing::new([0u8; 32]);
+        OsRng.fill_bytes(&mut secret[..]);
+        let public = x25519(*secret, X25519_BASEPOINT_BYTES);
+        Ephemeral { secret, public }
+    }
+
+    /// Derives the session cipher for `suite` with the peer's ephemeral key and, in a
+    /// hybrid handshake, the ML-KEM secret. Either secret alone keeps the key hidden,
+    /// so a session recorded now stays sealed unless both X25519 and ML-KEM fall.
+    pub fn session(
+        self,
+        peer: &[u8; 32],
+        kem_shared: Option<&[u8; 32]>,
+        transcript: &[u8],
+        suite: Suite,
+    ) -> CommittingCipher {
+        let mut shared = Zeroizing::new(x25519(*self.secret, *peer).to_vec());
+        if let Some(kem_shared) = kem_shared {
+            shared.extend_from_slice(kem_shared);
+        }
+        let mut key = Zeroizing::new([0u8; 32]);
+        Hkdf::<Sha256>::new(Some(transcript), &shared[..])
+            .expand(INFO, &mut key[..])
+            .expect("32 bytes is a valid HKDF output length");
+        CommittingCipher::with_suite(*key, suite)
+    }
+}
+
+/// Runs the client side of the handshake over `conn`, sealing its half to the app's
+/// static key `app` with HPKE from the client's static `secret` and offering every suite
+/// in this host's order of preference. With `hybrid`, an ML-KEM-768 exchange is mixed
+/// into the session key next to X25519. Returns the session cipher for the connection's
+/// remaining messages, under the suite the app chose.
+pub async fn connect<S: AsyncRead + AsyncWrite + Unpin>(
+    conn: &mut S,
+    secret: &[u8; 32],
+    app: &[u8; 32],
+    hybrid: bool,
+) -> Result<CommittingCipher, Box<dyn Error>> {
+    let ephemeral = Ephemeral::generate();
+    let kem = hybrid.then(KemPair::generate);
+    let offered: Vec<u8> = Suite::preferred()
+        .iter()
+        .map(|suite| *suite as u8)
+        .collect();
+    let mut hello = [&ephemeral.public[..], &[offered.len() as u8], &offered].concat();
+    if let Some(kem) = &kem {
+        hello.extend_from_slice(&kem.public);
+    }
+    let (envelope, reply_cipher) = Envelope::seal_to(Kind::Handshake, secret, app, 0, &hello)?;
+    let reply = message::request(conn, &envelope).await?;
+
+    let reply = reply_cipher.decrypt(&reply, &reply_aad(&envelope, &ephemeral.public))?;
+    let kem_ciphertext_len = if hybrid { KEM_CIPHERTEXT_LEN } else { 0 };
+    if reply.len() != 33 + kem_ciphertext_len {
+        return Err("malformed handshake reply".into());
+    }
+    let app_ephemeral: [u8; 32] = reply[..32].try_into().unwrap();
+    let chosen = Suite::from_u8(reply[32])
+        .filter(|suite| offered.contains(&(*suite as u8)))
+        .ok_or("app chose a suite that wasn't offered")?;
+    let kem_shared = match kem {
+        Some(kem) => Some(kem.decapsulate(&reply[33..])?),
+        None => None,
+    };
+    let public = x25519(*secret, X25519_BASEPOINT_BYTES);
+    let transcript = transcript(&public, &hello, &reply);
+    let session = ephemeral.session(&app_ephemeral, kem_shared.as_deref(), &transcript, chosen);
+    Ok(session.sequenced(true))
+}
@@ -0,0 +1,191 @@
+use crate::pedersen;
+use curve25519_dalek::scalar::Scalar;
+use rand_core::{OsRng, RngCore};
+use std::error::Error;
+
+/// Prime the shares live in, the largest below 2^64. Values, and the sums recovered
+/// from shares, must stay below it.
+pub const P: u64 = u64::MAX - 58;
+
+pub fn add(a: u64, b: u64) -> u64 {
+    ((a as u128 + b as u128) % P as u128) as u64
+}
+
+fn sub(a: u64, b: u64) -> u64 {
+    add(a, P - b % P)
+}
+
+fn mul(a: u64, b: u64) -> u64 {
+    ((a as u128 * b as u128) % P as u128) as u64
+}
+
+/// Multiplicative inverse by Fermat's little theorem.
+fn inv(a: u64) -> u64 {
+    let (mut base, mut exp, mut acc) = (a, P - 2, 1);
+    while exp > 0 {
+        if exp & 1 == 1 {
+            acc = mul(acc, base);
+        }
+        base = mul(base, base);
+        exp >>= 1;
+    }
+    acc
+}
+
+/// Uniform field element, by rejection so there is no modulo bias.
+fn random() -> u64 {
+    loop {
+        let r = OsRng.next_u64();
+        if r < P {
+            return r;
+        }
+    }
+}
+
+/// Splits `secret` into `n` shares, any `threshold` of which recover it: the values at
+/// x = 1..=n of a random polynomial of degree `threshold - 1` whose constant term is the
+/// secret. Shares of different secrets add up to shares of their sum.
+pub fn split(secret: u64, threshold: usize, n: usize) -> Result<Vec<u64>, Box<dyn Error>> {
+    if threshold == 0 || threshold > n {
+        return Err("threshold must be between 1 and the number of shares".into());
+    }
+    if secret >= P {
+        return Err(format!("{} is too large to share", secret).into());
+    }
+    let mut coefficients = vec![secret];
+    coefficients.extend((1..threshold).map(|_| random()));
+    Ok((1..=n as u64)
+        .map(|x| {
+            // Horner's rule, from the highest coefficient down to the secret
+            coefficients
+                .iter()
+                .rev()
+                .fold(0, |acc, c| add(mul(acc, x), *c))
+        })
+        .collect())
+}
+
+/// Recovers the secret from `(x, share)` points by Lagrange interpolation at x = 0.
+pub fn combine(points: &[(u64, u64)]) -> Result<u64, Box<dyn Error>> {
+    let mut secret = 0;
+    for (i, &(xi, yi)) in points.iter().enumerate() {
+        let mut numerator = 1;
+        let mut denominator = 1;
+        for (j, &(xj, _)) in points.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            if xi == xj {
+                return Err("shares must come from distinct instances".into());
+            }
+            numerator = mul(numerator, xj);
+            denominator = mul(denominator, sub(xj, xi));
+        }
+        secret = add(secret, mul(yi, mul(numerator, inv(denominator))));
+    }
+    Ok(secret)
+}
+
+/// Splits `secret` like `split`, over the field of Ristretto scalars, so a Pedersen
+/// blinding factor can be shared alongside the value it blinds.
+pub fn split_scalar(
+    secret: &Scalar,
+    threshold: usize,
+    n: usize,
+) -> Result<Vec<Scalar>, Box<dyn Error>> {
+    if threshold == 0 || threshold > n {
+        return Err("threshold must be between 1 and the number of shares".into());
+    }
+    let mut coefficients = vec![*secret];
+    coefficients.extend((1..threshold).map(|_| pedersen::blinding()));
+    Ok((1..=n as u64)
+        .map(|x| {
+            let x = Scalar::from(x);
+            coefficients
+                .iter()
+                .rev()
+                .fold(Scalar::ZERO, |acc, c| acc * x + c)
+        })
+        .collect())
+}
+
+/// Recovers a scalar `split_scalar` shared from `(x, share)` points.
+pub fn combine_scalars(points: &[(u64, Scalar)]) -> Result<Scalar, Box<dyn Error>> {
+    let mut secret = Scalar::ZERO;
+    for (i, &(xi, yi)) in points.iter().enumerate() {
+        let mut numerator = Scalar::ONE;
+        let mut denominator = Scalar::ONE;
+        for (j, &(xj, _)) in points.iter().enumerate() {
+            if i == j {
+                continue;
+            }
+            if xi == xj {
+                return Err("shares must come from distinct instances".into());
+            }
+            numerator *= Scalar::from(xj);
+            denominator *= Scalar::from(xj) - Scalar::from(xi);
+        }
+        secret += yi * numerator * denominator.invert();
+    }
+    Ok(secret)
+}
+
+/// Sums share vectors position by position in the share field.
+pub fn sums(vectors: &[Vec<u64>]) -> Result<Vec<u64>, Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    let mut sums = vec![0u64; width];
+    for vector in vectors {
+        for (sum, value) in sums.iter_mut().zip(vector) {
+            *sum = add(*sum, *value);
+        }
+    }
+    Ok(sums)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `(x, share)` points for the shares at the given 0-based indices.
+    fn points(shares: &[u64], indices: &[usize]) -> Vec<(u64, u64)> {
+        indices.iter().map(|&i| (i as u64 + 1, shares[i])).collect()
+    }
+
+    #[test]
+    fn any_threshold_of_shares_recovers_the_secret() {
+        let secret = P - 1;
+        let shares = split(secret, 3, 5).unwrap();
+        for indices in [[0, 1, 2], [0, 2, 4], [4, 3, 1], [1, 2, 3]] {
+            assert_eq!(combine(&points(&shares, &indices)).unwrap(), secret);
+        }
+        assert_eq!(combine(&points(&shares, &[0, 1, 2, 3, 4])).unwrap(), secret);
+    }
+
+    #[test]
+    fn fewer_shares_than_the_threshold_reveal_nothing() {
+        let secret = 42;
+        let shares = split(secret, 3, 5).unwrap();
+        // Two points fit a line through any constant term
+        assert_ne!(combine(&points(&shares, &[0, 1])).unwrap(), secret);
+        assert_ne!(combine(&points(&shares, &[3])).unwrap(), secret);
+    }
+
+    #[test]
+    fn summed_shares_recover_the_sum() {
+        let a = split(1000, 2, 3).unwrap();
+        let b = split(234, 2, 3).unwrap();
+        let summed = sums(&[a, b]).unwrap();
+        assert_eq!(combine(&points(&summed, &[0, 2])).unwrap(), 1234);
+    }
+
+    #[test]
+    fn rejects_bad_parameters_and_points() {
+        assert!(split(1, 0, 3).is_err());
+        assert!(split(1, 4, 3).is_err());
+        assert!(split(P, 2, 3).is_err());
+        assert!(combine(&[(1, 5), (1, 7)]).is_err());
+    }
+}
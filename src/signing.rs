@@ -0,0 +1,203 @@
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use subtle::ConstantTimeEq;
+
+/// Signature schemes available for receipts and results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scheme {
+    Ed25519,
+    /// ECDSA over NIST P-256 with SHA-256
+    P256,
+    /// ECDSA over secp256k1 with Keccak-256 and a recovery byte, as checked by the EVM
+    /// `ecrecover` precompile
+    Secp256k1,
+}
+
+impl fmt::Display for Scheme {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Scheme::Ed25519 => "ed25519",
+            Scheme::P256 => "p256",
+            Scheme::Secp256k1 => "secp256k1",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Scheme {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ed25519" => Ok(Scheme::Ed25519),
+            "p256" => Ok(Scheme::P256),
+            "secp256k1" => Ok(Scheme::Secp256k1),
+            _ => Err(format!("unknown signature scheme: {}", s)),
+        }
+    }
+}
+
+/// Produces signatures under one scheme from a 32-byte secret.
+pub trait Signer {
+    fn scheme(&self) -> Scheme;
+
+    /// Public key in the scheme's usual encoding: 32 bytes for Ed25519, uncompressed
+    /// SEC1 (65 bytes) for the ECDSA schemes.
+    fn public_key(&self) -> Vec<u8>;
+
+    fn sign(&self, msg: &[u8]) -> Vec<u8>;
+}
+
+pub struct Ed25519Signer(ed25519_dalek::SigningKey);
+
+impl Signer for Ed25519Signer {
+    fn scheme(&self) -> Scheme {
+        Scheme::Ed25519
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.0.verifying_key().to_bytes().to_vec()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        use ed25519_dalek::Signer as _;
+        self.0.sign(msg).to_bytes().to_vec()
+    }
+}
+
+pub struct P256Signer(p256::ecdsa::SigningKey);
+
+impl Signer for P256Signer {
+    fn scheme(&self) -> Scheme {
+        Scheme::P256
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.0
+            .verifying_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        use p256::ecdsa::signature::Signer as _;
+        let signature: p256::ecdsa::Signature = self.0.sign(msg);
+        signature.to_bytes().to_vec()
+    }
+}
+
+pub struct Secp256k1Signer(k256::ecdsa::SigningKey);
+
+impl Signer for Secp256k1Signer {
+    fn scheme(&self) -> Scheme {
+        Scheme::Secp256k1
+    }
+
+    fn public_key(&self) -> Vec<u8> {
+        self.0
+            .verifying_key()
+            .to_encoded_point(false)
+            .as_bytes()
+            .to_vec()
+    }
+
+    /// Returns `r || s || v` with `v` in {27, 28}
+    fn sign(&self, msg: &[u8]) -> Vec<u8> {
+        let (signature, recovery_id) = self
+            .0
+            .sign_digest_recoverable(Keccak256::new_with_prefix(msg))
+            .expect("signing with a valid key cannot fail");
+        let mut out = signature.to_bytes().to_vec();
+        out.push(27 + recovery_id.to_byte());
+        out
+    }
+}
+
+/// Loads a signer for `scheme` from a 32-byte secret.
+pub fn signer(
+    scheme: Scheme,
+    secret: &[u8; 32],
+) -> Result<Box<dyn Signer + Send + Sync>, Box<dyn Error>> {
+    let signer: Box<dyn Signer + Send + Sync> = match scheme {
+        Scheme::Ed25519 => Box::new(Ed25519Signer(ed25519_dalek::SigningKey::from_bytes(secret))),
+        Scheme::P256 => Box::new(P256Signer(p256::ecdsa::SigningKey::from_slice(secret)?)),
+        Scheme::Secp256k1 => Box::new(Secp256k1Signer(k256::ecdsa::SigningKey::from_slice(
+            secret,
+        )?)),
+    };
+    Ok(signer)
+}
+
+/// Generates a fresh secret that is valid for every scheme.
+pub fn generate_secret() -> [u8; 32] {
+    loop {
+        let mut secret = [0u8; 32];
+        OsRng.fill_bytes(&mut secret);
+        // Rejects the negligible fraction of values outside both curve orders
+        if p256::ecdsa::SigningKey::from_slice(&secret).is_ok()
+            && k256::ecdsa::SigningKey::from_slice(&secret).is_ok()
+        {
+            return secret;
+        }
+    }
+}
+
+/// Checks `signature` over `msg` against a public key in the encoding returned by
+/// `Signer::public_key`.
+pub fn verify(
+    scheme: Scheme,
+    public_key: &[u8],
+    msg: &[u8],
+    signature: &[u8],
+) -> Result<(), Box<dyn Error>> {
+    match scheme {
+        Scheme::Ed25519 => {
+            use ed25519_dalek::Verifier as _;
+            let key = ed25519_dalek::VerifyingKey::from_bytes(public_key.try_into()?)?;
+            let signature = ed25519_dalek::Signature::from_slice(signature)?;
+            key.verify(msg, &signature)?;
+        }
+        Scheme::P256 => {
+            use p256::ecdsa::signature::Verifier as _;
+            let key = p256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)?;
+            let signature = p256::ecdsa::Signature::from_slice(signature)?;
+            key.verify(msg, &signature)?;
+        }
+        Scheme::Secp256k1 => {
+            if signature.len() != 65 || !(27..=28).contains(&signature[64]) {
+                return Err("secp256k1 signature must be 65 bytes r || s || v".into());
+            }
+            let key = k256::ecdsa::VerifyingKey::from_sec1_bytes(public_key)?;
+            let recovery_id = k256::ecdsa::RecoveryId::from_byte(signature[64] - 27)
+                .ok_or("invalid recovery id")?;
+            let sig = k256::ecdsa::Signature::from_slice(&signature[..64])?;
+            let recovered = k256::ecdsa::VerifyingKey::recover_from_digest(
+                Keccak256::new_with_prefix(msg),
+                &sig,
+                recovery_id,
+            )?;
+            if !bool::from(recovered.to_sec1_bytes().ct_eq(&key.to_sec1_bytes())) {
+                return Err("secp256k1 signature does not match public key".into());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Ethereum address for an uncompressed secp256k1 public key, so results can be checked
+/// against `ecrecover` on-chain.
+pub fn ethereum_address(public_key: &[u8]) -> Result<[u8; 20], Box<dyn Error>> {
+    if public_key.len() != 65 || public_key[0] != 0x04 {
+        return Err("expected an uncompressed secp256k1 public key".into());
+    }
+    let hash = Keccak256::digest(&public_key[1..]);
+    let mut address = [0u8; 20];
+    address.copy_from_slice(&hash[12..]);
+    Ok(address)
+}
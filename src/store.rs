@@ -0,0 +1,381 @@
+use crate::aead::CommittingCipher;
+use crate::http::{https_client, HttpsClient};
+use hmac::{Hmac, Mac};
+use hyper::{Body, Method, Request, StatusCode, Uri};
+use sha2::{Digest, Sha256};
+use std::error::Error;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Durable key/value storage for state that outlives the enclave, such as sealed
+/// snapshots and audit logs.
+///
+/// Keys are restricted to `[A-Za-z0-9._/-]` so they map directly onto file paths and
+/// object names without escaping.
+#[allow(async_fn_in_trait)]
+pub trait StateStore {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>>;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>>;
+}
+
+fn check_key(key: &str) -> Result<(), Box<dyn Error>> {
+    let valid = !key.is_empty()
+        && !key.starts_with('/')
+        && !key.split('/').any(|part| part == "..")
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '.' | '_' | '/' | '-'));
+    if !valid {
+        return Err(format!("invalid store key: {}", key).into());
+    }
+    Ok(())
+}
+
+/// Stores each key as a file under a local directory.
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        FileStore { dir: dir.into() }
+    }
+}
+
+impl StateStore for FileStore {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        check_key(key)?;
+        let path = self.dir.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write then rename so a crash never leaves a truncated blob behind
+        let tmp = path.with_extension("tmp");
+        tokio::fs::write(&tmp, value).await?;
+        tokio::fs::rename(&tmp, &path).await?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        check_key(key)?;
+        match tokio::fs::read(self.dir.join(key)).await {
+            Ok(value) => Ok(Some(value)),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Pushes blobs to a host-side relay with plain `PUT`/`GET <base>/<key>` requests.
+///
+/// The enclave has no network of its own, so the relay is usually reached through the
+/// host's vsock proxy.
+pub struct RelayStore {
+    base: String,
+    client: HttpsClient,
+}
+
+impl RelayStore {
+    pub fn new(base: &str) -> Self {
+        RelayStore {
+            base: base.trim_end_matches('/').to_owned(),
+            client: https_client(),
+        }
+    }
+
+    fn uri(&self, key: &str) -> Result<Uri, Box<dyn Error>> {
+        check_key(key)?;
+        Ok(format!("{}/{}", self.base, key).parse::<Uri>()?)
+    }
+}
+
+impl StateStore for RelayStore {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let req = Request::builder()
+            .method(Method::PUT)
+            .uri(self.uri(key)?)
+            .body(Body::from(value.to_vec()))?;
+        let res = self.client.request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("relay put {} failed: {}", key, res.status()).into());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let res = self.client.get(self.uri(key)?).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(hyper::body::to_bytes(res).await?.to_vec())),
+            status => Err(format!("relay get {} failed: {}", key, status).into()),
+        }
+    }
+}
+
+/// AWS credentials and region, taken from the standard `AWS_ACCESS_KEY_ID`,
+/// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN` and `AWS_REGION` environment variables.
+pub(crate) struct AwsCredentials {
+    pub region: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8], data: &[u8]) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("hmac accepts any key length");
+    mac.update(data);
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Formats a unix timestamp as the `(YYYYMMDD, YYYYMMDDTHHMMSSZ)` pair SigV4 expects.
+fn amz_date(secs: u64) -> (String, String) {
+    // Civil-from-days, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let date = format!("{:04}{:02}{:02}", year, month, day);
+    let rem = secs % 86400;
+    let time = format!(
+        "{}T{:02}{:02}{:02}Z",
+        date,
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60
+    );
+    (date, time)
+}
+
+impl AwsCredentials {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        Ok(AwsCredentials {
+            region: std::env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned()),
+            access_key: std::env::var("AWS_ACCESS_KEY_ID")?,
+            secret_key: std::env::var("AWS_SECRET_ACCESS_KEY")?,
+            session_token: std::env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+
+    /// Builds a SigV4-signed request for `service` to `https://<host><path>`. `extra`
+    /// headers are signed along with the host, date and payload hash.
+    pub fn signed_request(
+        &self,
+        service: &str,
+        method: Method,
+        host: &str,
+        path: &str,
+        extra: &[(&'static str, String)],
+        body: Vec<u8>,
+    ) -> Result<Request<Body>, Box<dyn Error>> {
+        let secs = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        let (date, timestamp) = amz_date(secs);
+        let payload_hash = hex::encode(Sha256::digest(&body));
+
+        let mut headers = vec![
+            ("host", host.to_owned()),
+            ("x-amz-content-sha256", payload_hash.clone()),
+            ("x-amz-date", timestamp.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.extend_from_slice(extra);
+        // Canonical headers are signed in name order
+        headers.sort_by_key(|(name, _)| *name);
+
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_request = format!(
+            "{}\n{}\n\n{}\n{}\n{}",
+            method, path, canonical_headers, signed_headers, payload_hash
+        );
+
+        let scope = format!("{}/{}/{}/aws4_request", date, self.region, service);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            timestamp,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+
+        let signing_key = [
+            self.region.as_bytes(),
+            service.as_bytes(),
+            b"aws4_request".as_slice(),
+        ]
+        .iter()
+        .fold(
+            hmac(
+                format!("AWS4{}", self.secret_key).as_bytes(),
+                date.as_bytes(),
+            ),
+            |key, part| hmac(&key, part),
+        );
+        let signature = hex::encode(hmac(&signing_key, string_to_sign.as_bytes()));
+
+        let mut req = Request::builder()
+            .method(method)
+            .uri(format!("https://{}{}", host, path));
+        for (name, value) in &headers {
+            req = req.header(*name, value);
+        }
+        let req = req
+            .header(
+                "authorization",
+                format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+                    self.access_key, scope, signed_headers, signature
+                ),
+            )
+            .body(Body::from(body))?;
+        Ok(req)
+    }
+}
+
+/// Stores blobs as S3 objects using SigV4-signed REST calls, with credentials from the
+/// environment (see `AwsCredentials`).
+pub struct S3Store {
+    bucket: String,
+    prefix: String,
+    credentials: AwsCredentials,
+    client: HttpsClient,
+}
+
+impl S3Store {
+    pub fn new(bucket: &str, prefix: &str) -> Result<Self, Box<dyn Error>> {
+        Ok(S3Store {
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_matches('/').to_owned(),
+            credentials: AwsCredentials::from_env()?,
+            client: https_client(),
+        })
+    }
+
+    fn signed_request(
+        &self,
+        method: Method,
+        key: &str,
+        body: Vec<u8>,
+    ) -> Result<Request<Body>, Box<dyn Error>> {
+        check_key(key)?;
+        let host = format!(
+            "{}.s3.{}.amazonaws.com",
+            self.bucket, self.credentials.region
+        );
+        let path = if self.prefix.is_empty() {
+            format!("/{}", key)
+        } else {
+            format!("/{}/{}", self.prefix, key)
+        };
+        self.credentials
+            .signed_request("s3", method, &host, &path, &[], body)
+    }
+}
+
+impl StateStore for S3Store {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let req = self.signed_request(Method::PUT, key, value.to_vec())?;
+        let res = self.client.request(req).await?;
+        if !res.status().is_success() {
+            return Err(format!("s3 put {} failed: {}", key, res.status()).into());
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        let req = self.signed_request(Method::GET, key, Vec::new())?;
+        let res = self.client.request(req).await?;
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => Ok(Some(hyper::body::to_bytes(res).await?.to_vec())),
+            status => Err(format!("s3 get {} failed: {}", key, status).into()),
+        }
+    }
+}
+
+/// Encrypts every blob before it reaches the wrapped store, using the key name as
+/// associated data so blobs cannot be swapped between keys.
+pub struct Sealed<S> {
+    inner: S,
+    cipher: CommittingCipher,
+}
+
+impl<S: StateStore> Sealed<S> {
+    pub fn new(inner: S, key: [u8; 32]) -> Self {
+        Sealed {
+            inner,
+            cipher: CommittingCipher::new(key),
+        }
+    }
+}
+
+impl<S: StateStore> StateStore for Sealed<S> {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        let sealed = self.cipher.encrypt(value, key.as_bytes())?;
+        self.inner.put(key, &sealed).await
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self.inner.get(key).await? {
+            Some(sealed) => Ok(Some(self.cipher.decrypt(&sealed, key.as_bytes())?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// One of the available backends, selected at runtime.
+pub enum Backend {
+    File(FileStore),
+    Relay(RelayStore),
+    S3(S3Store),
+}
+
+impl StateStore for Backend {
+    async fn put(&self, key: &str, value: &[u8]) -> Result<(), Box<dyn Error>> {
+        match self {
+            Backend::File(store) => store.put(key, value).await,
+            Backend::Relay(store) => store.put(key, value).await,
+            Backend::S3(store) => store.put(key, value).await,
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+        match self {
+            Backend::File(store) => store.get(key).await,
+            Backend::Relay(store) => store.get(key).await,
+            Backend::S3(store) => store.get(key).await,
+        }
+    }
+}
+
+/// Opens a sealed store from a spec of the form `file:<dir>`, `relay:<url>` or
+/// `s3:<bucket>[/<prefix>]`.
+pub fn open(spec: &str, key: [u8; 32]) -> Result<Sealed<Backend>, Box<dyn Error>> {
+    let backend = match spec.split_once(':') {
+        Some(("file", dir)) => Backend::File(FileStore::new(dir)),
+        Some(("relay", url)) => Backend::Relay(RelayStore::new(url)),
+        Some(("s3", location)) => {
+            let (bucket, prefix) = location.split_once('/').unwrap_or((location, ""));
+            Backend::S3(S3Store::new(bucket, prefix)?)
+        }
+        _ => return Err(format!("unknown store spec: {}", spec).into()),
+    };
+    Ok(Sealed::new(backend, key))
+}
@@ -0,0 +1,745 @@
+use crate::frame::{AppError, ERR_MALFORMED, ERR_SCHEMA, ERR_SIZE};
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+
+/// Dataset used when none is named
+pub const DEFAULT_DATASET: &str = "default";
+
+/// Longest dataset name, in bytes
+pub const MAX_DATASET_LEN: usize = 64;
+
+/// Prefixes `body` with the name of the dataset it belongs to, as a length byte
+/// followed by the UTF-8 name.
+pub fn with_dataset(dataset: &str, body: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    if dataset.is_empty() || dataset.len() > MAX_DATASET_LEN {
+        return Err(format!("dataset names must be 1 to {} bytes", MAX_DATASET_LEN).into());
+    }
+    let mut buf = vec![dataset.len() as u8];
+    buf.extend_from_slice(dataset.as_bytes());
+    buf.extend_from_slice(body);
+    Ok(buf)
+}
+
+/// Splits a message built by `with_dataset` into the dataset name and body.
+pub fn split_dataset(buf: &[u8]) -> Result<(String, &[u8]), Box<dyn Error>> {
+    let malformed = || AppError::malformed("dataset name");
+    let (&len, rest) = buf.split_first().ok_or_else(malformed)?;
+    let len = len as usize;
+    if len == 0 || len > MAX_DATASET_LEN || rest.len() < len {
+        return Err(malformed());
+    }
+    let (name, body) = rest.split_at(len);
+    let name = String::from_utf8(name.to_vec()).map_err(|_| malformed())?;
+    Ok((name, body))
+}
+
+/// Which values a query aggregates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mode {
+    /// Every value at once
+    Total,
+    /// The values at each position of the vectors, one result per position
+    Elementwise,
+}
+
+impl Mode {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Mode::Total => 0,
+            Mode::Elementwise => 1,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Mode, Box<dyn Error>> {
+        match byte {
+            0 => Ok(Mode::Total),
+            1 => Ok(Mode::Elementwise),
+            _ => Err(AppError::new(
+                ERR_MALFORMED,
+                format!("unknown query mode {}", byte),
+            )),
+        }
+    }
+}
+
+/// Aggregate operation a query applies.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Op {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    /// Sum with each position of the vectors multiplied by a per-position weight
+    Weighted,
+    /// Population variance
+    Variance,
+    /// Population standard deviation
+    Stddev,
+    Median,
+    /// Percentile at a rank the query gives
+    Percentile,
+    /// Dot product of the vectors of a contributor group of two loaders
+    Dot,
+    /// Count and sums of the vectors in each bucket, bucketed by their first value
+    Histogram,
+    /// Approximate count of distinct identifiers in merged HyperLogLog sketches
+    Distinct,
+}
+
+impl Op {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            Op::Sum => 0,
+            Op::Mean => 1,
+            Op::Min => 2,
+            Op::Max => 3,
+            Op::Count => 4,
+            Op::Weighted => 5,
+            Op::Variance => 6,
+            Op::Stddev => 7,
+            Op::Median => 8,
+            Op::Percentile => 9,
+            Op::Dot => 10,
+            Op::Histogram => 11,
+            Op::Distinct => 12,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Op, Box<dyn Error>> {
+        match byte {
+            0 => Ok(Op::Sum),
+            1 => Ok(Op::Mean),
+            2 => Ok(Op::Min),
+            3 => Ok(Op::Max),
+            4 => Ok(Op::Count),
+            5 => Ok(Op::Weighted),
+            6 => Ok(Op::Variance),
+            7 => Ok(Op::Stddev),
+            8 => Ok(Op::Median),
+            9 => Ok(Op::Percentile),
+            10 => Ok(Op::Dot),
+            11 => Ok(Op::Histogram),
+            12 => Ok(Op::Distinct),
+            _ => Err(AppError::new(
+                ERR_MALFORMED,
+                format!("unknown operation {}", byte),
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Op {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Op::Sum => "sum",
+            Op::Mean => "mean",
+            Op::Min => "min",
+            Op::Max => "max",
+            Op::Count => "count",
+            Op::Weighted => "weighted",
+            Op::Variance => "variance",
+            Op::Stddev => "stddev",
+            Op::Median => "median",
+            Op::Percentile => "percentile",
+            Op::Dot => "dot",
+            Op::Histogram => "histogram",
+            Op::Distinct => "distinct",
+        };
+        f.write_str(name)
+    }
+}
+
+impl FromStr for Op {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sum" => Ok(Op::Sum),
+            "mean" => Ok(Op::Mean),
+            "min" => Ok(Op::Min),
+            "max" => Ok(Op::Max),
+            "count" => Ok(Op::Count),
+            "weighted" => Ok(Op::Weighted),
+            "variance" => Ok(Op::Variance),
+            "stddev" => Ok(Op::Stddev),
+            "median" => Ok(Op::Median),
+            "percentile" => Ok(Op::Percentile),
+            "dot" => Ok(Op::Dot),
+            "histogram" => Ok(Op::Histogram),
+            "distinct" => Ok(Op::Distinct),
+            _ => Err(format!("unknown operation: {}", s)),
+        }
+    }
+}
+
+/// Mode byte flag: a contributor group follows the operation
+const GROUP: u8 = 0x80;
+
+/// What a requester asks of a dataset, sent after the dataset name as `mode | op |
+/// weights`. A percentile's rank follows the op as a byte from 0 to 100. With flag
+/// `0x80` set in the mode byte, the name of a contributor group follows the op (and
+/// rank) as a length byte and the UTF-8 name.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Query {
+    pub mode: Mode,
+    pub op: Op,
+    /// rank of a percentile, from 0 to 100
+    pub percentile: Option<u8>,
+    /// group of loaders, as the app defines it, whose contributions alone are aggregated
+    pub group: Option<String>,
+    /// per-position weights of a weighted sum, empty for any other op
+    pub weights: Vec<u64>,
+}
+
+impl Query {
+    pub fn encode(&self) -> Result<Vec<u8>, Box<dyn Error>> {
+        if (self.op == Op::Weighted) == self.weights.is_empty() {
+            return Err("weights are required by a weighted sum and only allowed with it".into());
+        }
+        let mut buf = vec![self.mode.to_byte(), self.op.to_byte()];
+        match self.percentile {
+            Some(rank) if self.op == Op::Percentile && rank <= 100 => buf.push(rank),
+            None if self.op != Op::Percentile => {}
+            _ => return Err("a percentile takes a rank from 0 to 100, other ops none".into()),
+        }
+        let weights = encode_weights(&self.weights);
+        match &self.group {
+            Some(group) => {
+                buf[0] |= GROUP;
+                buf.extend(
+                    with_dataset(group, &weights).map_err(|_| {
+                        format!("group names must be 1 to {} bytes", MAX_DATASET_LEN)
+                    })?,
+                );
+            }
+            None => buf.extend(weights),
+        }
+        Ok(buf)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Query, Box<dyn Error>> {
+        let malformed = || AppError::malformed("query");
+        let [mode, op, ref rest @ ..] = buf[..] else {
+            return Err(malformed());
+        };
+        let op = Op::from_byte(op)?;
+        let (percentile, rest) = match rest.split_first() {
+            Some((&rank, rest)) if op == Op::Percentile && rank <= 100 => (Some(rank), rest),
+            _ if op == Op::Percentile => return Err(malformed()),
+            _ => (None, rest),
+        };
+        let (group, weights) = if mode & GROUP != 0 {
+            let (group, weights) = split_dataset(rest).map_err(|_| malformed())?;
+            (Some(group), weights)
+        } else {
+            (None, rest)
+        };
+        let query = Query {
+            mode: Mode::from_byte(mode & !GROUP)?,
+            op,
+            percentile,
+            group,
+            weights: decode_weights(weights)?,
+        };
+        if (query.op == Op::Weighted) == query.weights.is_empty() {
+            return Err(malformed());
+        }
+        Ok(query)
+    }
+}
+
+/// Result of an aggregate operation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Value {
+    /// Wide enough for any u64 or i64 value
+    Int(i128),
+    Float(f64),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(v) => write!(f, "{}", v),
+            Value::Float(v) => write!(f, "{}", v),
+        }
+    }
+}
+
+/// Applies `op` to `values`, already read as `numeric`. Sums are taken wide and fail
+/// instead of wrapping if they leave the type's range, so a signed sum may pass through
+/// out-of-range partial sums as long as the result fits.
+pub fn aggregate<I: IntoIterator<Item = i128>>(
+    op: Op,
+    numeric: Numeric,
+    values: I,
+) -> Result<Value, Box<dyn Error>> {
+    let mut values = values.into_iter();
+    match op {
+        Op::Sum => numeric.check(values.sum()).map(Value::Int),
+        Op::Mean => {
+            let (sum, count) = values.fold((0i128, 0u64), |(sum, count), value| {
+                (sum + value, count + 1)
+            });
+            if count == 0 {
+                return Err("mean of no values".into());
+            }
+            Ok(Value::Float(sum as f64 / count as f64))
+        }
+        Op::Min => values
+            .min()
+            .map(Value::Int)
+            .ok_or_else(|| "min of no values".into()),
+        Op::Max => values
+            .max()
+            .map(Value::Int)
+            .ok_or_else(|| "max of no values".into()),
+        Op::Count => Ok(Value::Int(values.count() as i128)),
+        Op::Weighted => Err("weighted sums need per-position weights".into()),
+        Op::Percentile => Err("percentiles need a rank".into()),
+        Op::Dot => Err("dot products need a pair of loaders".into()),
+        Op::Histogram => Err("histograms need whole vectors".into()),
+        Op::Distinct => Err("distinct counts need sketches".into()),
+        Op::Median => {
+            let mut values: Vec<i128> = values.collect();
+            values.sort_unstable();
+            percentile(&values, 50)
+        }
+        Op::Variance | Op::Stddev => {
+            let (count, _, m2) = moments(values);
+            if count == 0 {
+                return Err(format!("{} of no values", op).into());
+            }
+            let variance = m2 / count as f64;
+            Ok(Value::Float(match op {
+                // In squared units, unscaled once here and once more when rendered
+                Op::Variance => numeric.unscale(variance),
+                _ => variance.sqrt(),
+            }))
+        }
+    }
+}
+
+/// Vectors sharing a first value: how many there are and the sums of their other
+/// positions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Bucket {
+    pub count: u64,
+    pub sums: Vec<i128>,
+}
+
+/// Buckets the vectors by their first value, read as `numeric`, counting them and
+/// summing their other positions per bucket. Buckets of fewer than `min_count` vectors
+/// are left out, so no small set of rows can be picked out, and only their number is
+/// returned with the rest.
+pub fn histogram(
+    numeric: Numeric,
+    vectors: &[Vec<u64>],
+    min_count: u64,
+) -> Result<(BTreeMap<i128, Bucket>, usize), Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    let mut buckets: BTreeMap<i128, Bucket> = BTreeMap::new();
+    for vector in vectors {
+        let Some((bucket, values)) = vector.split_first() else {
+            continue;
+        };
+        let bucket = buckets.entry(numeric.value(*bucket)).or_default();
+        bucket.count += 1;
+        bucket.sums.resize(values.len(), 0);
+        for (sum, value) in bucket.sums.iter_mut().zip(values) {
+            *sum += numeric.value(*value);
+        }
+    }
+    let before = buckets.len();
+    buckets.retain(|_, bucket| bucket.count >= min_count);
+    for bucket in buckets.values() {
+        for sum in &bucket.sums {
+            numeric.check(*sum)?;
+        }
+    }
+    Ok((buckets, before - buckets.len()))
+}
+
+/// The `rank`th percentile of `sorted`, interpolated linearly between the nearest
+/// ranks, so the median of an even count is the mean of the middle two.
+pub fn percentile(sorted: &[i128], rank: u8) -> Result<Value, Box<dyn Error>> {
+    if sorted.is_empty() {
+        return Err("percentile of no values".into());
+    }
+    let position = (sorted.len() - 1) as f64 * rank as f64 / 100.0;
+    let below = sorted[position.floor() as usize];
+    let above = sorted[position.ceil() as usize];
+    Ok(Value::Float(
+        below as f64 + (above - below) as f64 * position.fract(),
+    ))
+}
+
+/// Count, mean and sum of squared deviations from the mean of `values`, updated one
+/// value at a time (Welford), so no sum of squares has to fit in an integer and the
+/// subtraction doesn't cancel away the precision of large values.
+fn moments<I: Iterator<Item = i128>>(values: I) -> (u64, f64, f64) {
+    values.fold((0u64, 0f64, 0f64), |(count, mean, m2), value| {
+        let count = count + 1;
+        let delta = value as f64 - mean;
+        let mean = mean + delta / count as f64;
+        (count, mean, m2 + delta * (value as f64 - mean))
+    })
+}
+
+/// Most decimal places a fixed-point value may have, so `10^scale` fits in a u64
+pub const MAX_SCALE: u8 = 18;
+
+/// How the values of an upload are read. Fixed-point values travel as integers in
+/// units of `10^-scale`, so sums over them stay exact; signed values travel as the
+/// two's complement bits of an i64.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Numeric {
+    pub signed: bool,
+    /// decimal places, 0 for integers
+    pub scale: u8,
+}
+
+impl Numeric {
+    /// Unsigned integers, the type of uploads that declare nothing else
+    pub const INTEGER: Numeric = Numeric {
+        signed: false,
+        scale: 0,
+    };
+
+    pub fn new(signed: bool, scale: u8) -> Result<Numeric, Box<dyn Error>> {
+        if scale > MAX_SCALE {
+            return Err(format!("scale must be at most {}", MAX_SCALE).into());
+        }
+        Ok(Numeric { signed, scale })
+    }
+
+    /// Reads a decimal such as `12.5`, or `-12.5` if signed, exactly, as a count of
+    /// `10^-scale` units.
+    pub fn parse(self, s: &str) -> Result<u64, Box<dyn Error>> {
+        let scale = self.scale as usize;
+        let (negative, digits) = match s.strip_prefix('-') {
+            Some(rest) if self.signed => (true, rest),
+            _ => (false, s),
+        };
+        let (whole, fraction) = digits.split_once('.').unwrap_or((digits, ""));
+        let is_digits = |d: &str| d.bytes().all(|b| b.is_ascii_digit());
+        if !is_digits(whole) || !is_digits(fraction) || (whole.is_empty() && fraction.is_empty()) {
+            return Err(format!("invalid value {:?}", s).into());
+        }
+        if fraction.len() > scale {
+            return Err(format!("{} has more than {} decimal places", s, scale).into());
+        }
+        let units: i128 = format!("{}{:0<scale$}", whole, fraction)
+            .parse()
+            .map_err(|_| format!("{} is out of range", s))?;
+        let units = self
+            .check(if negative { -units } else { units })
+            .map_err(|_| format!("{} is out of range", s))?;
+        Ok(units as u64)
+    }
+
+    /// Reads stored bits as the value they hold.
+    pub fn value(self, bits: u64) -> i128 {
+        if self.signed {
+            bits as i64 as i128
+        } else {
+            bits as i128
+        }
+    }
+
+    /// Passes `value` through if it is in the type's range.
+    pub fn check(self, value: i128) -> Result<i128, Box<dyn Error>> {
+        let fits = if self.signed {
+            i64::try_from(value).is_ok()
+        } else {
+            u64::try_from(value).is_ok()
+        };
+        if !fits {
+            let bits = if self.signed { "i64" } else { "u64" };
+            return Err(format!("sum overflows {}", bits).into());
+        }
+        Ok(value)
+    }
+
+    /// Renders a count of `10^-scale` units, possibly negative, as a decimal.
+    pub fn format(self, units: i128) -> String {
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return units.to_string();
+        }
+        let one = 10u128.pow(scale as u32);
+        let sign = if units < 0 { "-" } else { "" };
+        let units = units.unsigned_abs();
+        format!("{}{}.{:0scale$}", sign, units / one, units % one)
+    }
+
+    /// Converts a value computed over units, such as a mean, back to the declared type.
+    pub fn unscale(self, value: f64) -> f64 {
+        value / 10f64.powi(self.scale as i32)
+    }
+}
+
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(if self.signed { "i64" } else { "u64" })?;
+        if self.scale > 0 {
+            write!(f, " at scale {}", self.scale)?;
+        }
+        Ok(())
+    }
+}
+
+/// Longest position label, in bytes
+pub const MAX_LABEL_LEN: usize = 64;
+
+/// Declared shape of an upload, which the app checks the decrypted values against.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Schema {
+    pub numeric: Numeric,
+    /// name of each vector position, empty if the positions aren't labelled
+    pub labels: Vec<String>,
+}
+
+fn check_label(label: &str) -> Result<(), Box<dyn Error>> {
+    if label.is_empty() || label.len() > MAX_LABEL_LEN {
+        return Err(AppError::new(
+            ERR_SCHEMA,
+            format!("labels must be 1 to {} bytes", MAX_LABEL_LEN),
+        ));
+    }
+    Ok(())
+}
+
+/// Encodes equal-length vectors behind a header declaring their schema: a flags byte
+/// (`1` fixed-point followed by the scale, `2` signed, `4` labelled, `8` expiring
+/// followed by the TTL in seconds as a u64, so `0` is plain u64), the vector length and
+/// the number of vectors (u32 each), a length byte and UTF-8 name per position if
+/// labelled, then every value (u64), all little-endian.
+pub fn encode(
+    schema: &Schema,
+    ttl: Option<u64>,
+    vectors: &[Vec<u64>],
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors must all have the same length".into());
+    }
+    let labelled = !schema.labels.is_empty();
+    if labelled && schema.labels.len() != width {
+        return Err("there must be one label per vector position".into());
+    }
+
+    let Numeric { signed, scale } = schema.numeric;
+    let flags = (scale > 0) as u8
+        | (signed as u8) << 1
+        | (labelled as u8) << 2
+        | (ttl.is_some() as u8) << 3;
+    let mut buf = vec![flags];
+    if scale > 0 {
+        buf.push(scale);
+    }
+    if let Some(ttl) = ttl {
+        if ttl == 0 {
+            return Err("a TTL must be at least one second".into());
+        }
+        buf.extend_from_slice(&ttl.to_le_bytes());
+    }
+    buf.extend_from_slice(&u32::try_from(width)?.to_le_bytes());
+    buf.extend_from_slice(&u32::try_from(vectors.len())?.to_le_bytes());
+    for label in &schema.labels {
+        check_label(label)?;
+        buf.push(label.len() as u8);
+        buf.extend_from_slice(label.as_bytes());
+    }
+    for value in vectors.iter().flatten() {
+        buf.extend_from_slice(&value.to_le_bytes());
+    }
+    Ok(buf)
+}
+
+/// Splits `len` bytes off the front of `buf`, failing with `ERR_SIZE` if it's shorter.
+fn take(buf: &[u8], len: usize) -> Result<(&[u8], &[u8]), Box<dyn Error>> {
+    if buf.len() < len {
+        return Err(AppError::new(ERR_SIZE, "upload is truncated"));
+    }
+    Ok(buf.split_at(len))
+}
+
+fn take_u32(buf: &[u8]) -> Result<(usize, &[u8]), Box<dyn Error>> {
+    let (n, rest) = take(buf, 4)?;
+    Ok((u32::from_le_bytes(n.try_into()?) as usize, rest))
+}
+
+/// Reads vectors encoded by `encode`, checking them against their declared schema, and
+/// returns them with the schema and TTL. Failures carry `ERR_SCHEMA` or `ERR_SIZE` for
+/// the loader.
+pub fn decode(buf: &[u8]) -> Result<(Schema, Option<u64>, Vec<Vec<u64>>), Box<dyn Error>> {
+    let schema_error = |message: String| AppError::new(ERR_SCHEMA, message);
+    let (flags, mut rest) = take(buf, 1)?;
+    let flags = flags[0];
+    if flags & !0b1111 != 0 {
+        return Err(schema_error(format!("unknown schema flags {:#04x}", flags)));
+    }
+    let mut scale = 0;
+    if flags & 1 != 0 {
+        let (s, r) = take(rest, 1)?;
+        if s[0] == 0 {
+            return Err(schema_error("fixed-point values need a scale".into()));
+        }
+        (scale, rest) = (s[0], r);
+    }
+    let mut ttl = None;
+    if flags & 8 != 0 {
+        let (t, r) = take(rest, 8)?;
+        let t = u64::from_le_bytes(t.try_into()?);
+        if t == 0 {
+            return Err(schema_error("a TTL must be at least one second".into()));
+        }
+        (ttl, rest) = (Some(t), r);
+    }
+    let numeric = Numeric::new(flags & 2 != 0, scale).map_err(|e| schema_error(e.to_string()))?;
+    let (width, rest) = take_u32(rest)?;
+    let (count, mut rest) = take_u32(rest)?;
+    if width == 0 {
+        return Err(schema_error("vectors must hold at least one value".into()));
+    }
+
+    let mut labels = Vec::new();
+    if flags & 4 != 0 {
+        let mut seen = HashSet::new();
+        for _ in 0..width {
+            let (len, r) = take(rest, 1)?;
+            let (label, r) = take(r, len[0] as usize)?;
+            let label = std::str::from_utf8(label)
+                .map_err(|_| schema_error("labels must be UTF-8".into()))?;
+            check_label(label)?;
+            if !seen.insert(label) {
+                return Err(schema_error(format!("duplicate label {:?}", label)));
+            }
+            labels.push(label.to_owned());
+            rest = r;
+        }
+    }
+
+    let expected = width.checked_mul(count).and_then(|n| n.checked_mul(8));
+    if expected != Some(rest.len()) {
+        return Err(AppError::new(
+            ERR_SIZE,
+            format!(
+                "upload declares {} vectors of {} values but holds {} bytes of values",
+                count,
+                width,
+                rest.len()
+            ),
+        ));
+    }
+    let values: Vec<u64> = rest
+        .chunks_exact(8)
+        .map(|v| u64::from_le_bytes(v.try_into().unwrap()))
+        .collect();
+    let schema = Schema { numeric, labels };
+    let vectors = values.chunks(width).map(<[u64]>::to_vec).collect();
+    Ok((schema, ttl, vectors))
+}
+
+/// Sums every value read as `numeric`, failing instead of wrapping on overflow.
+pub fn total(numeric: Numeric, vectors: &[Vec<u64>]) -> Result<i128, Box<dyn Error>> {
+    numeric.check(vectors.iter().flatten().map(|v| numeric.value(*v)).sum())
+}
+
+/// Sums the vectors position by position mod 2^64, for pairwise-masked uploads whose
+/// masks only cancel under wrapping arithmetic.
+pub fn wrapping_sums(vectors: &[Vec<u64>]) -> Result<Vec<u64>, Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    let mut sums = vec![0u64; width];
+    for vector in vectors {
+        for (sum, value) in sums.iter_mut().zip(vector) {
+            *sum = sum.wrapping_add(*value);
+        }
+    }
+    Ok(sums)
+}
+
+/// Encodes per-position weights as little-endian u64 values.
+pub fn encode_weights(weights: &[u64]) -> Vec<u8> {
+    weights.iter().flat_map(|w| w.to_le_bytes()).collect()
+}
+
+pub fn decode_weights(buf: &[u8]) -> Result<Vec<u64>, Box<dyn Error>> {
+    if buf.len() % 8 != 0 {
+        return Err(AppError::malformed("weights"));
+    }
+    Ok(buf
+        .chunks_exact(8)
+        .map(|w| u64::from_le_bytes(w.try_into().unwrap()))
+        .collect())
+}
+
+/// Multiplies each position of the vectors by its weight and sums the products per
+/// position, reading the values as `numeric` and failing instead of wrapping on
+/// overflow.
+pub fn weighted(
+    numeric: Numeric,
+    vectors: &[Vec<u64>],
+    weights: &[u64],
+) -> Result<Vec<i128>, Box<dyn Error>> {
+    if vectors.iter().any(|v| v.len() != weights.len()) {
+        return Err("weights must match the vector length".into());
+    }
+    let overflow = |i| format!("weighted sum at position {} overflows", i);
+    let mut sums = vec![0i128; weights.len()];
+    for vector in vectors {
+        for (i, (sum, (value, weight))) in
+            sums.iter_mut().zip(vector.iter().zip(weights)).enumerate()
+        {
+            *sum = numeric
+                .value(*value)
+                .checked_mul(*weight as i128)
+                .and_then(|product| sum.checked_add(product))
+                .ok_or_else(|| overflow(i))?;
+        }
+    }
+    for (i, sum) in sums.iter().enumerate() {
+        numeric.check(*sum).map_err(|_| overflow(i))?;
+    }
+    Ok(sums)
+}
+
+/// Applies `op` to the vectors position by position, reading the values as `numeric`.
+pub fn elementwise(
+    op: Op,
+    numeric: Numeric,
+    vectors: &[Vec<u64>],
+) -> Result<Vec<Value>, Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    // Uploads from different loaders may disagree on the length
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    (0..width)
+        .map(|i| {
+            aggregate(op, numeric, vectors.iter().map(|v| numeric.value(v[i])))
+                .map_err(|e| format!("{} at position {}", e, i).into())
+        })
+        .collect()
+}
+
+/// The values at each position of the vectors, read as `numeric`, one list per
+/// position.
+pub fn positions(numeric: Numeric, vectors: &[Vec<u64>]) -> Result<Vec<Vec<i128>>, Box<dyn Error>> {
+    let width = vectors.first().map_or(0, Vec::len);
+    if vectors.iter().any(|v| v.len() != width) {
+        return Err("vectors differ in length".into());
+    }
+    Ok((0..width)
+        .map(|i| vectors.iter().map(|v| numeric.value(v[i])).collect())
+        .collect())
+}
@@ -0,0 +1,81 @@
+use crate::message::{self, Envelope, Kind, Status};
+use crate::{noise, ratls};
+use std::error::Error;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use x25519_dalek::{x25519, X25519_BASEPOINT_BYTES};
+
+/// Byte stream a client talks to the app over, plain or through RA-TLS and Noise.
+pub trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// Connects to the app at `addr`, an `ip:port` or a `unix:<path>` socket. With
+/// `tls_image_id` the connection runs over RA-TLS to an enclave attested to run that
+/// image; with `noise` it then carries a Noise channel keyed by the client's `secret`
+/// and the app's public key.
+pub async fn dial(
+    addr: &str,
+    tls_image_id: Option<&str>,
+    noise: bool,
+    secret: &[u8; 32],
+    app: &[u8; 32],
+) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    let stream: Box<dyn Stream> = match addr.strip_prefix("unix:") {
+        Some(path) => connect_unix(path).await?,
+        None => Box::new(TcpStream::connect(addr).await?),
+    };
+    let stream: Box<dyn Stream> = match tls_image_id {
+        Some(image_id) => Box::new(ratls::connect(stream, image_id).await?),
+        None => stream,
+    };
+    if !noise {
+        return Ok(stream);
+    }
+    Ok(Box::new(noise::connect(stream, secret, app).await?))
+}
+
+/// Connects like `dial` to whichever of `addrs`, the instances of a replicated pair,
+/// leads at the highest term, so a primary fenced by a newer term is passed over while
+/// it still thinks it leads. A single address is dialled without asking.
+pub async fn dial_leader(
+    addrs: &[String],
+    tls_image_id: Option<&str>,
+    noise: bool,
+    secret: &[u8; 32],
+    app: &[u8; 32],
+) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    if let [addr] = addrs {
+        return dial(addr, tls_image_id, noise, secret, app).await;
+    }
+    let public = x25519(*secret, X25519_BASEPOINT_BYTES);
+    let mut leader: Option<(u64, Box<dyn Stream>)> = None;
+    for addr in addrs {
+        let probe = async {
+            let mut stream = dial(addr, tls_image_id, noise, secret, app).await?;
+            let envelope = Envelope::new(Kind::Status, &public, 0, Vec::new());
+            let resp = message::request(&mut stream, &envelope).await?;
+            let status: Status = serde_json::from_slice(&resp)?;
+            Ok::<_, Box<dyn Error>>((status, stream))
+        };
+        // Unreachable instances are skipped
+        if let Ok((status, stream)) = probe.await {
+            if status.leader && leader.as_ref().is_none_or(|(term, _)| status.term > *term) {
+                leader = Some((status.term, stream));
+            }
+        }
+    }
+    leader
+        .map(|(_, stream)| stream)
+        .ok_or_else(|| "none of the app instances leads".into())
+}
+
+#[cfg(unix)]
+async fn connect_unix(path: &str) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path: &str) -> Result<Box<dyn Stream>, Box<dyn Error>> {
+    Err("unix sockets are only supported on Unix".into())
+}
@@ -1,201 +1,427 @@
-use aws_nitro_enclaves_cose::{crypto::Openssl, crypto::SigningPublicKey, CoseSign1};
-use clap::Parser;
-use hex;
-use hyper::{client::Client, Uri};
-use openssl::asn1::Asn1Time;
-use openssl::error::ErrorStack;
-use openssl::x509::{X509VerifyResult, X509};
-use serde_cbor::{self, value, value::Value};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use futures::stream::{self, StreamExt};
+use hyper::body::HttpBody;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server, StatusCode, Uri};
+use openssl::x509::X509;
+use ppa::attestation::{
+    self, Document, Limits, Policy, Report, Trace, VerifyError, AWS_ROOT_CERT, FAILURE_CATEGORIES,
+};
+use ppa::hooks::{self, Hooks, DEFAULT_TEMPLATE};
+use ppa::http::{proxied_client, HttpsClient};
+use ppa::logging::{self, LogFormat};
+use ppa::onchain;
+use ppa::receipt::{AttestedResult, Receipt};
+use ppa::signing::{self, Scheme, Signer};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::convert::Infallible;
 use std::error::Error;
-use std::fs::File;
-use std::io::Write;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::{Cursor, Read, Write};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use subtle::ConstantTimeEq;
 use tokio;
+use tokio::io::AsyncReadExt;
+use tracing::{error, info, warn};
 
-fn get_all_certs(cert: X509, cabundle: Vec<Value>) -> Result<Vec<X509>, ErrorStack> {
-    let mut all_certs = Vec::new();
-    all_certs.push(cert);
-    for cert in cabundle {
-        let intermediate_certificate = match cert {
-            Value::Bytes(b) => b,
-            _ => unreachable!(),
-        };
-        let intermediate_certificate = X509::from_der(&intermediate_certificate)?;
-        all_certs.push(intermediate_certificate);
-    }
-    Ok(all_certs)
-}
-
-fn verify_cert_chain(
-    cert: X509,
-    cabundle: Vec<Value>,
-    root_cert_pem: Vec<u8>,
-    attestation_time: i64,
-) -> Result<(), Box<dyn Error>> {
-    let certs = get_all_certs(cert, cabundle)?;
-    // Use attestation timestamp for validation, not current system time
-    let attestation_asn1_time = Asn1Time::from_unix(attestation_time)?;
-    let mut i = 0;
-    while i < certs.len() - 1 {
-        let pubkey = certs[i + 1].public_key()?;
-        let x = certs[i].verify(&pubkey)?;
-        if !x {
-            return Err("signature verification failed".into());
-        }
-        let x = certs[i + 1].issued(&certs[i]);
-        if x != X509VerifyResult::OK {
-            return Err("certificate issuer and subject verification failed".into());
+/// How an attestation document is encoded on the wire.
+#[derive(Clone, Copy, ValueEnum)]
+enum InputEncoding {
+    Raw,
+    Hex,
+    Base64,
+}
+
+fn decode_input(buf: Vec<u8>, encoding: InputEncoding) -> Result<Vec<u8>, Box<dyn Error>> {
+    if let InputEncoding::Raw = encoding {
+        return Ok(buf);
+    }
+    let text = std::str::from_utf8(&buf)?.trim();
+    // Some servers return the encoded document as a JSON string
+    let text = text
+        .strip_prefix('"')
+        .and_then(|text| text.strip_suffix('"'))
+        .unwrap_or(text);
+    Ok(match encoding {
+        InputEncoding::Hex => hex::decode(text)?,
+        InputEncoding::Base64 => BASE64.decode(text)?,
+        InputEncoding::Raw => unreachable!(),
+    })
+}
+
+/// Reads an attestation document from an endpoint, or from stdin if `endpoint` is `-`.
+async fn get_attestation_doc(
+    client: &HttpsClient,
+    endpoint: &str,
+    headers: &[(String, String)],
+    encoding: InputEncoding,
+    max_size: usize,
+) -> Result<Vec<u8>, Box<dyn Error>> {
+    // Hex is the largest encoding, plus room for quotes and whitespace
+    let max_read = match encoding {
+        InputEncoding::Raw => max_size,
+        InputEncoding::Hex | InputEncoding::Base64 => max_size * 2 + 64,
+    };
+    let too_large = || format!("attestation document larger than {} bytes", max_read);
+
+    let mut buf = Vec::new();
+    if endpoint == "-" {
+        tokio::io::stdin()
+            .take(max_read as u64 + 1)
+            .read_to_end(&mut buf)
+            .await?;
+        if buf.len() > max_read {
+            return Err(too_large().into());
         }
-        if certs[i].not_after() < attestation_asn1_time || certs[i].not_before() > attestation_asn1_time {
-            return Err("certificate timestamp expired/not valid".into());
+        return decode_input(buf, encoding);
+    }
+
+    let mut req = Request::get(endpoint.parse::<Uri>()?);
+    for (name, value) in headers {
+        req = req.header(name, value);
+    }
+    let mut res = client.request(req.body(Body::empty())?).await?;
+
+    // Stop reading as soon as the body exceeds the limit instead of buffering it all
+    while let Some(chunk) = res.body_mut().data().await {
+        buf.extend_from_slice(&chunk?);
+        if buf.len() > max_read {
+            return Err(too_large().into());
         }
-        i += 1;
     }
-    let root_cert = X509::from_pem(&root_cert_pem)?;
-    if &root_cert != certs.last().unwrap() {
-        return Err("root certificate mismatch".into());
+    decode_input(buf, encoding)
+}
+
+fn unix_now() -> f64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs_f64()
+}
+
+#[derive(Default)]
+struct Metrics {
+    attempts: u64,
+    failures: BTreeMap<&'static str, u64>,
+    last_success: Option<f64>,
+    attestation_age: Option<f64>,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP verifier_attempts_total Attestation verification attempts.\n");
+        out.push_str("# TYPE verifier_attempts_total counter\n");
+        out.push_str(&format!("verifier_attempts_total {}\n", self.attempts));
+
+        out.push_str("# HELP verifier_failures_total Failed verifications by category.\n");
+        out.push_str("# TYPE verifier_failures_total counter\n");
+        for category in FAILURE_CATEGORIES {
+            let count = self.failures.get(category).copied().unwrap_or(0);
+            out.push_str(&format!(
+                "verifier_failures_total{{category=\"{}\"}} {}\n",
+                category, count
+            ));
+        }
+
+        if let Some(last_success) = self.last_success {
+            out.push_str("# HELP verifier_last_success_timestamp_seconds Time of the last successful verification.\n");
+            out.push_str("# TYPE verifier_last_success_timestamp_seconds gauge\n");
+            out.push_str(&format!(
+                "verifier_last_success_timestamp_seconds {}\n",
+                last_success
+            ));
+        }
+
+        if let Some(age) = self.attestation_age {
+            out.push_str("# HELP verifier_attestation_age_seconds Age of the last verified attestation document.\n");
+            out.push_str("# TYPE verifier_attestation_age_seconds gauge\n");
+            out.push_str(&format!("verifier_attestation_age_seconds {}\n", age));
+        }
+
+        out
     }
-    Ok(())
 }
 
-fn compute_image_id(pcr0: &[u8], pcr1: &[u8], pcr2: &[u8], pcr16: &[u8]) -> String {
-    let mut hasher = Sha256::new();
+async fn serve_metrics(addr: SocketAddr, metrics: Arc<Mutex<Metrics>>) -> Result<(), hyper::Error> {
+    let make_svc = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    let resp = if req.uri().path() == "/metrics" {
+                        Response::new(Body::from(metrics.lock().unwrap().render()))
+                    } else {
+                        Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty())
+                            .unwrap()
+                    };
+                    Ok::<_, Infallible>(resp)
+                }
+            }))
+        }
+    });
+    Server::bind(&addr).serve(make_svc).await
+}
 
-    // Bitflags: PCR 0, 1, 2, 16
-    let bitflags: u32 = (1 << 0) | (1 << 1) | (1 << 2) | (1 << 16);
-    hasher.update(&bitflags.to_be_bytes());
+async fn fetch_and_verify(
+    client: &HttpsClient,
+    endpoint: &str,
+    headers: &[(String, String)],
+    encoding: InputEncoding,
+    cert: &[u8],
+    policy: &Policy,
+    trace: &mut Trace,
+) -> Result<Report, VerifyError> {
+    let attestation_doc =
+        get_attestation_doc(client, endpoint, headers, encoding, policy.limits.max_size)
+            .await
+            .map_err(VerifyError::category("fetch"))?;
 
-    // PCR values (48 bytes each)
-    hasher.update(pcr0);
-    hasher.update(pcr1);
-    hasher.update(pcr2);
-    hasher.update(pcr16);
+    attestation::verify_traced(&attestation_doc, cert, policy, trace)
+}
 
-    hex::encode(hasher.finalize())
+/// Append-only JSON lines log of verification traces. Every line is signed and carries
+/// the SHA-256 of the line before it, so dropped or reordered entries are detectable.
+struct AuditLog {
+    file: File,
+    signer: Arc<dyn Signer + Send + Sync>,
+    prev: String,
 }
 
-fn extract_pcr(pcrs_map: &mut BTreeMap<Value, Value>, index: u64) -> Result<Vec<u8>, Box<dyn Error>> {
-    let pcr = pcrs_map
-        .remove(&value::to_value(index).unwrap())
-        .ok_or(Box::<dyn Error>::from(format!("pcr{} not found", index)))?;
-    match pcr {
-        Value::Bytes(b) => Ok(b),
-        _ => Err(format!("pcr{} is not bytes", index).into()),
+impl AuditLog {
+    fn open(path: &str, signer: Arc<dyn Signer + Send + Sync>) -> Result<Self, Box<dyn Error>> {
+        let existing = match std::fs::read_to_string(path) {
+            Ok(existing) => existing,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let prev = existing
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .last()
+            .map(|line| hex::encode(Sha256::digest(line)))
+            .unwrap_or_default();
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog { file, signer, prev })
     }
-}
 
-fn extract_pcr_optional(pcrs_map: &mut BTreeMap<Value, Value>, index: u64) -> Vec<u8> {
-    match pcrs_map.remove(&value::to_value(index).unwrap()) {
-        Some(Value::Bytes(b)) => b,
-        _ => vec![0u8; 48], // Default to zeros if not present
+    fn append(
+        &mut self,
+        endpoint: &str,
+        result: &Result<Report, VerifyError>,
+        trace: &Trace,
+    ) -> Result<(), Box<dyn Error>> {
+        let entry = serde_json::json!({
+            "time": unix_now(),
+            "endpoint": endpoint,
+            "prev_sha256": self.prev,
+            "ok": result.is_ok(),
+            "category": result.as_ref().err().map(|e| e.category),
+            "steps": trace.steps,
+        })
+        .to_string();
+        let signature = self.signer.sign(entry.as_bytes());
+
+        let line = serde_json::json!({
+            "entry": entry,
+            "scheme": self.signer.scheme(),
+            "public_key": hex::encode(self.signer.public_key()),
+            "signature": hex::encode(signature),
+        })
+        .to_string();
+        writeln!(self.file, "{}", line)?;
+        self.file.sync_data()?;
+
+        self.prev = hex::encode(Sha256::digest(&line));
+        Ok(())
     }
 }
 
-fn verify(
-    attestation_doc_cbor: Vec<u8>,
-    root_cert_pem: Vec<u8>,
-    expected_image_id: &str,
-) -> Result<Vec<u8>, Box<dyn Error>> {
-    let cosesign1 = CoseSign1::from_bytes(&attestation_doc_cbor)?;
-    let payload = cosesign1.get_payload::<Openssl>(None as Option<&dyn SigningPublicKey>)?;
-    let mut attestation_doc: BTreeMap<Value, Value> =
-        value::from_value(serde_cbor::from_slice::<Value>(&payload)?)?;
-
-    // Extract PCRs
-    let document_pcrs_arr = attestation_doc
-        .remove(&value::to_value("pcrs").unwrap())
-        .ok_or(Box::<dyn Error>::from(
-            "pcrs key not found in attestation doc",
-        ))?;
-    let mut document_pcrs_arr: BTreeMap<Value, Value> = value::from_value(document_pcrs_arr)?;
-
-    let pcr0 = extract_pcr(&mut document_pcrs_arr, 0)?;
-    let pcr1 = extract_pcr(&mut document_pcrs_arr, 1)?;
-    let pcr2 = extract_pcr(&mut document_pcrs_arr, 2)?;
-    let pcr16 = extract_pcr_optional(&mut document_pcrs_arr, 16);
-
-    // Compute and verify image_id
-    let computed_image_id = compute_image_id(&pcr0, &pcr1, &pcr2, &pcr16);
-    if computed_image_id != expected_image_id {
-        return Err(format!(
-            "image_id mismatch: expected {}, got {}",
-            expected_image_id, computed_image_id
-        )
-        .into());
+async fn run_once(
+    args: &VerifyArgs,
+    client: &HttpsClient,
+    cert: &[u8],
+    policy: &Policy,
+    audit: Option<&mut AuditLog>,
+    signer: Option<&(dyn Signer + Send + Sync)>,
+) -> Result<Report, VerifyError> {
+    let mut trace = Trace::default();
+    let result = fetch_and_verify(
+        client,
+        &args.endpoint,
+        &args.header,
+        args.input_encoding,
+        cert,
+        policy,
+        &mut trace,
+    )
+    .await;
+    if let Some(audit) = audit {
+        audit
+            .append(&args.endpoint, &result, &trace)
+            .map_err(VerifyError::category("output"))?;
     }
 
-    // Verify COSE signature
-    let enclave_certificate = attestation_doc
-        .remove(&value::to_value("certificate").unwrap())
-        .ok_or(Box::<dyn Error>::from(
-            "certificate key not found in attestation doc",
-        ))?;
-    let enclave_certificate = match enclave_certificate {
-        Value::Bytes(b) => b,
-        _ => unreachable!(),
-    };
-    let enclave_certificate = X509::from_der(&enclave_certificate)?;
-    let pub_key = enclave_certificate.public_key()?;
-    let verify_result = cosesign1.verify_signature::<Openssl>(&pub_key)?;
-
-    if !verify_result {
-        return Err("cose signature verification failed".into());
-    }
-
-    // Extract timestamp from attestation doc (in milliseconds)
-    let timestamp = attestation_doc
-        .remove(&value::to_value("timestamp").unwrap())
-        .ok_or(Box::<dyn Error>::from(
-            "timestamp not found in attestation doc",
-        ))?;
-    let timestamp: i64 = match timestamp {
-        Value::Integer(i) => i.try_into()?,
-        _ => return Err("timestamp is not an integer".into()),
-    };
+    let report = result?;
+    info!(
+        module_id = %report.module_id,
+        public_key = %hex::encode(&report.public_key),
+        "Verification successful"
+    );
 
-    // Verify certificate chain
-    let cabundle = attestation_doc
-        .remove(&value::to_value("cabundle").unwrap())
-        .ok_or(Box::<dyn Error>::from(
-            "cabundle key not found in attestation doc",
-        ))?;
-
-    let mut cabundle: Vec<Value> = value::from_value(cabundle)?;
-    cabundle.reverse();
-
-    // Pass timestamp in seconds (AWS Nitro uses milliseconds)
-    verify_cert_chain(enclave_certificate, cabundle, root_cert_pem, timestamp / 1000)?;
-
-    // Extract public key
-    let public_key = attestation_doc
-        .remove(&value::to_value("public_key").unwrap())
-        .ok_or(Box::<dyn Error>::from(
-            "public key not found in attestation doc",
-        ))?;
-    let public_key = match public_key {
-        Value::Bytes(b) => b,
-        _ => unreachable!(),
-    };
+    let mut file = File::create(&args.app).map_err(VerifyError::category("output"))?;
+    file.write_all(report.public_key.as_slice())
+        .map_err(VerifyError::category("output"))?;
 
-    Ok(public_key)
-}
+    if let (Some(path), Some(signer)) = (&args.receipt, signer) {
+        let receipt = Receipt::issue(signer, &report, unix_now() as u64);
+        let receipt = serde_json::to_vec(&receipt).map_err(VerifyError::category("output"))?;
+        std::fs::write(path, receipt).map_err(VerifyError::category("output"))?;
+    }
 
-#[tokio::main]
-async fn get_attestation_doc(endpoint: String) -> Result<Vec<u8>, Box<dyn Error>> {
-    let client = Client::new();
-    let res = client.get(endpoint.parse::<Uri>()?).await?;
-    let buf = hyper::body::to_bytes(res).await?;
-    Ok(buf.to_vec())
+    Ok(report)
 }
 
+/// Official AWS Nitro Enclaves root certificate bundle and its published SHA-256
+const AWS_ROOT_URL: &str = "https://aws-nitro-enclaves.amazonaws.com/AWS_NitroEnclaves_Root-G1.zip";
+const AWS_ROOT_SHA256: &str = "8cf60e2b2efca96c6a9e71e851d00c1b6991cc09eadbe64a6a1d1b1eb9faff7c";
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
-    /// Attestation endpoint http://<ip:port>/attestation/raw
+    #[command(subcommand)]
+    command: Command,
+
+    /// Console log format; levels are set with RUST_LOG. Results a command prints as
+    /// its output (JSON lines, diffs, calldata) always go to stdout as-is
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Verify an enclave's attestation and extract its public key
+    Verify(VerifyArgs),
+
+    /// Verify many enclaves concurrently, printing one JSON result per line
+    Batch(BatchArgs),
+
+    /// Poll enclaves and alert when their identity changes between polls
+    Monitor(MonitorArgs),
+
+    /// Show which fields of two attestation documents differ
+    Diff(DiffArgs),
+
+    /// Verify an attestation and print it as ABI-encoded calldata for an on-chain verifier
+    ExportOnchain(ExportOnchainArgs),
+
+    /// Download the AWS Nitro Enclaves root certificate and pin it locally
+    FetchRoot(FetchRootArgs),
+
+    /// Run the verify, keygen, load and request steps end to end and report each outcome
+    Demo(DemoArgs),
+
+    /// Check a verification receipt's signature and print its claims
+    CheckReceipt(CheckReceiptArgs),
+}
+
+#[derive(Args)]
+struct BatchArgs {
+    /// File listing `<endpoint> <image_id> [module_id]` per line, `#` starts a comment
+    #[arg(long)]
+    endpoints: String,
+
+    /// Maximum number of endpoints fetched and verified at once
+    #[arg(long, default_value_t = 16)]
+    concurrency: usize,
+
+    /// Proxy for the attestation fetches, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Path to a PEM root certificate, defaults to the embedded AWS root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Directory of PEM root certificates, the chain may end at any of them
+    #[arg(long, conflicts_with = "root")]
+    root_dir: Option<String>,
+
+    /// Seconds of clock skew tolerated when checking certificate validity periods
+    #[arg(long, default_value_t = 300)]
+    clock_skew: i64,
+}
+
+#[derive(Args)]
+struct MonitorArgs {
+    /// File listing one attestation endpoint per line, `#` starts a comment
+    #[arg(long)]
+    endpoints: String,
+
+    /// Seconds between polls
+    #[arg(long, default_value_t = 60)]
+    interval: u64,
+
+    /// File the last-seen identity of each endpoint is kept in
+    #[arg(long, default_value = "monitor.json")]
+    state: String,
+
+    /// Webhook URL notified when an endpoint fails, recovers or changes identity
+    #[arg(long)]
+    hook: Vec<String>,
+
+    /// File with the webhook JSON payload, `{{event}}`, `{{endpoint}}`, `{{detail}}` and
+    /// `{{time}}` are substituted
+    #[arg(long)]
+    hook_template: Option<String>,
+
+    /// Proxy for the attestation fetches, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Path to a PEM root certificate, defaults to the embedded AWS root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Directory of PEM root certificates, the chain may end at any of them
+    #[arg(long, conflicts_with = "root")]
+    root_dir: Option<String>,
+
+    /// Seconds of clock skew tolerated when checking certificate validity periods
+    #[arg(long, default_value_t = 300)]
+    clock_skew: i64,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Attestation endpoint http://<ip:port>/attestation/raw, or - to read the document
+    /// from stdin
     #[clap(short, long, value_parser)]
     endpoint: String,
 
+    /// Encoding of the attestation document
+    #[arg(long, value_enum, default_value_t = InputEncoding::Raw)]
+    input_encoding: InputEncoding,
+
     /// Path to output app public key file
     #[arg(short, long)]
     app: String,
@@ -203,19 +429,1102 @@ struct Cli {
     /// Expected image ID (hex-encoded)
     #[arg(short, long)]
     image_id: String,
+
+    /// Proxy for the attestation fetch, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Path to a PEM root certificate, defaults to the embedded AWS root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Directory of PEM root certificates, the chain may end at any of them
+    #[arg(long, conflicts_with = "root")]
+    root_dir: Option<String>,
+
+    /// Expected module ID, matched as a regex against the whole value
+    #[arg(long)]
+    expected_module_id: Option<String>,
+
+    /// Seconds of clock skew tolerated when checking certificate validity periods
+    #[arg(long, default_value_t = 300)]
+    clock_skew: i64,
+
+    /// Expected PCR value <INDEX>=<HEX>, repeatable; pinpoints which PCR differs when
+    /// the image ID doesn't match
+    #[arg(long, value_parser = parse_expected_pcr)]
+    expected_pcr: Vec<(u64, String)>,
+
+    /// Minimum vCPUs the enclave must report in its attestation user_data
+    #[arg(long)]
+    min_vcpus: Option<u64>,
+
+    /// Minimum memory in MiB the enclave must report in its attestation user_data
+    #[arg(long)]
+    min_memory_mib: Option<u64>,
+
+    /// Keep running and re-verify every <INTERVAL> seconds
+    #[arg(long)]
+    interval: Option<u64>,
+
+    /// Address to serve Prometheus metrics on in daemon mode <ip:port>
+    #[arg(long, requires = "interval")]
+    metrics: Option<SocketAddr>,
+
+    /// Webhook URL notified when verification fails or recovers in daemon mode
+    #[arg(long, requires = "interval")]
+    hook: Vec<String>,
+
+    /// File with the webhook JSON payload, `{{event}}`, `{{endpoint}}`, `{{detail}}` and
+    /// `{{time}}` are substituted
+    #[arg(long)]
+    hook_template: Option<String>,
+
+    /// Append a signed record of every verification step to this JSON lines file
+    #[arg(long, requires = "signing_key")]
+    trace: Option<String>,
+
+    /// Path to the verifier's Ed25519 signing key file
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    /// Write a signed receipt of the verified report to this file
+    #[arg(long, requires = "signing_key")]
+    receipt: Option<String>,
+}
+
+#[derive(Args)]
+struct DemoArgs {
+    /// Run against a locally spawned app with fresh keys instead of an enclave
+    #[arg(long)]
+    local: bool,
+
+    /// Attestation endpoint of the target enclave
+    #[arg(long, required_unless_present = "local", conflicts_with = "local")]
+    endpoint: Option<String>,
+
+    /// Expected image ID of the target enclave
+    #[arg(long, required_unless_present = "local", conflicts_with = "local")]
+    image_id: Option<String>,
+
+    /// Loader private key trusted by the target app
+    #[arg(long, required_unless_present = "local", conflicts_with = "local")]
+    loader_secret: Option<String>,
+
+    /// Requester private key trusted by the target app
+    #[arg(long, required_unless_present = "local", conflicts_with = "local")]
+    requester_secret: Option<String>,
+
+    /// App address <ip:port>
+    #[arg(long, default_value = "127.0.0.1:4000")]
+    app_addr: String,
+
+    /// Directory the app public key and generated keys are written to
+    #[arg(long, default_value = "demo")]
+    workdir: String,
+
+    /// Proxy for the attestation fetch, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Path to a PEM root certificate, defaults to the embedded AWS root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Directory of PEM root certificates, the chain may end at any of them
+    #[arg(long, conflicts_with = "root")]
+    root_dir: Option<String>,
+}
+
+#[derive(Args)]
+struct CheckReceiptArgs {
+    /// Path to the receipt file
+    #[arg(long)]
+    receipt: String,
+
+    /// Hex-encoded public key the receipt must be signed by
+    #[arg(long)]
+    signer: Option<String>,
+
+    /// The receipt is an app's signed query result rather than a verification receipt
+    #[arg(long)]
+    result: bool,
+
+    /// Require the result's attestation to show an enclave running this image ID
+    #[arg(long, requires = "result")]
+    image_id: Option<String>,
+
+    /// Path to a PEM root certificate for the result's attestation, defaults to the
+    /// embedded AWS root
+    #[arg(long, requires = "image_id")]
+    root: Option<String>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// First attestation document, a file path, an http(s) endpoint or - for stdin
+    a: String,
+
+    /// Second attestation document, a file path, an http(s) endpoint or - for stdin
+    b: String,
+
+    /// Encoding of both documents
+    #[arg(long, value_enum, default_value_t = InputEncoding::Raw)]
+    input_encoding: InputEncoding,
+
+    /// Proxy for endpoint fetches, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+}
+
+#[derive(Args)]
+struct ExportOnchainArgs {
+    /// Attestation endpoint http://<ip:port>/attestation/raw, or - to read the document
+    /// from stdin
+    #[arg(short, long)]
+    endpoint: String,
+
+    /// Expected image ID (hex-encoded)
+    #[arg(short, long)]
+    image_id: String,
+
+    /// Encoding of the attestation document
+    #[arg(long, value_enum, default_value_t = InputEncoding::Raw)]
+    input_encoding: InputEncoding,
+
+    /// Extra request header for attestation fetches <NAME: VALUE>, repeatable
+    #[arg(long, value_parser = parse_header)]
+    header: Vec<(String, String)>,
+
+    /// Proxy for the attestation fetch, defaults to HTTPS_PROXY/HTTP_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+
+    /// Path to a PEM root certificate, defaults to the embedded AWS root
+    #[arg(long)]
+    root: Option<String>,
+
+    /// Directory of PEM root certificates, the chain may end at any of them
+    #[arg(long, conflicts_with = "root")]
+    root_dir: Option<String>,
+
+    /// Seconds of clock skew tolerated when checking certificate validity periods
+    #[arg(long, default_value_t = 300)]
+    clock_skew: i64,
+
+    /// Write the hex calldata to this file instead of stdout
+    #[arg(short, long)]
+    out: Option<String>,
 }
 
-fn main() -> Result<(), Box<dyn Error>> {
-    let cli = Cli::parse();
+#[derive(Args)]
+struct FetchRootArgs {
+    /// Path to write the PEM root certificate to
+    #[arg(short, long, default_value = "aws.cert")]
+    out: String,
+
+    /// Proxy for the download, defaults to HTTPS_PROXY
+    #[arg(long)]
+    proxy: Option<String>,
+}
+
+async fn fetch_root(args: FetchRootArgs) -> Result<(), Box<dyn Error>> {
+    let client = proxied_client(args.proxy.as_deref())?;
+    let res = client.get(AWS_ROOT_URL.parse::<Uri>()?).await?;
+    if !res.status().is_success() {
+        return Err(format!("root certificate download failed: {}", res.status()).into());
+    }
+    let bundle = hyper::body::to_bytes(res).await?;
+
+    let digest = hex::encode(Sha256::digest(&bundle));
+    if !bool::from(digest.as_bytes().ct_eq(AWS_ROOT_SHA256.as_bytes())) {
+        return Err(format!(
+            "root certificate bundle fingerprint mismatch: expected {}, got {}",
+            AWS_ROOT_SHA256, digest
+        )
+        .into());
+    }
 
-    let attestation_doc = get_attestation_doc(cli.endpoint)?;
-    let cert = include_bytes!("../aws.cert").to_vec();
+    let mut archive = zip::ZipArchive::new(Cursor::new(bundle))?;
+    let mut pem = Vec::new();
+    archive.by_name("root.pem")?.read_to_end(&mut pem)?;
+    X509::from_pem(&pem)?;
 
-    let pub_key = verify(attestation_doc, cert, &cli.image_id)?;
-    println!("verification successful with pubkey: {:?}", pub_key);
+    let mut file = File::create(&args.out)?;
+    file.write_all(&pem)?;
 
-    let mut file = File::create(cli.app)?;
-    file.write_all(pub_key.as_slice())?;
+    info!("Root certificate pinned to: {}", args.out);
 
     Ok(())
 }
+
+fn describe_cert(der: &[u8]) -> String {
+    let fingerprint = hex::encode(Sha256::digest(der));
+    match X509::from_der(der) {
+        Ok(cert) => format!(
+            "sha256 {} (not_before {}, not_after {})",
+            fingerprint,
+            cert.not_before(),
+            cert.not_after()
+        ),
+        Err(_) => format!("sha256 {} (unparseable)", fingerprint),
+    }
+}
+
+/// Flattens a document into comparable, printable fields.
+fn document_fields(doc: &Document) -> BTreeMap<String, String> {
+    let mut fields = BTreeMap::new();
+    if let Some(module_id) = &doc.module_id {
+        fields.insert("module_id".to_owned(), module_id.clone());
+    }
+    if let Some(digest) = &doc.digest {
+        fields.insert("digest".to_owned(), digest.clone());
+    }
+    if let Some(timestamp) = doc.timestamp {
+        fields.insert("timestamp".to_owned(), timestamp.to_string());
+    }
+    for (index, pcr) in &doc.pcrs {
+        fields.insert(format!("pcr{}", index), hex::encode(pcr));
+    }
+    if let Some(cert) = &doc.certificate {
+        fields.insert("certificate".to_owned(), describe_cert(cert));
+    }
+    for (i, cert) in doc.cabundle.iter().enumerate() {
+        fields.insert(format!("cabundle[{}]", i), describe_cert(cert));
+    }
+    if let Some(public_key) = &doc.public_key {
+        fields.insert("public_key".to_owned(), hex::encode(public_key));
+    }
+    if let Some(user_data) = &doc.user_data {
+        let shown = match std::str::from_utf8(user_data) {
+            Ok(text) => text.to_owned(),
+            Err(_) => hex::encode(user_data),
+        };
+        fields.insert("user_data".to_owned(), shown);
+    }
+    if let Some(nonce) = &doc.nonce {
+        fields.insert("nonce".to_owned(), hex::encode(nonce));
+    }
+    fields
+}
+
+async fn load_document(
+    client: &HttpsClient,
+    source: &str,
+    headers: &[(String, String)],
+    encoding: InputEncoding,
+    limits: &Limits,
+) -> Result<Document, Box<dyn Error>> {
+    let is_url = source.starts_with("http://") || source.starts_with("https://");
+    let doc = if is_url || source == "-" {
+        get_attestation_doc(client, source, headers, encoding, limits.max_size).await?
+    } else {
+        decode_input(std::fs::read(source)?, encoding)?
+    };
+    attestation::decode(&doc, limits).map_err(|e| format!("{}: {}", source, e).into())
+}
+
+async fn export_onchain(args: ExportOnchainArgs) -> Result<(), Box<dyn Error>> {
+    let cert = load_root(args.root.as_deref(), args.root_dir.as_deref())?;
+    let client = proxied_client(args.proxy.as_deref())?;
+    let policy = Policy {
+        image_id: args.image_id.clone(),
+        module_id: None,
+        clock_skew: args.clock_skew,
+        expected_pcrs: BTreeMap::new(),
+        min_vcpus: None,
+        min_memory_mib: None,
+        limits: Limits::default(),
+    };
+
+    let doc = get_attestation_doc(
+        &client,
+        &args.endpoint,
+        &args.header,
+        args.input_encoding,
+        policy.limits.max_size,
+    )
+    .await?;
+    // Only verified documents are exported
+    attestation::verify(&doc, &cert, &policy)?;
+    let calldata = format!(
+        "0x{}",
+        hex::encode(onchain::attestation_calldata(&doc, &policy.limits)?)
+    );
+
+    match &args.out {
+        Some(out) => {
+            std::fs::write(out, &calldata)?;
+            info!("Calldata for {} written to: {}", onchain::FUNCTION, out);
+        }
+        None => println!("{}", calldata),
+    }
+    Ok(())
+}
+
+async fn diff_cmd(args: DiffArgs) -> Result<(), Box<dyn Error>> {
+    let client = proxied_client(args.proxy.as_deref())?;
+    let limits = Limits::default();
+    let a = document_fields(
+        &load_document(&client, &args.a, &args.header, args.input_encoding, &limits).await?,
+    );
+    let b = document_fields(
+        &load_document(&client, &args.b, &args.header, args.input_encoding, &limits).await?,
+    );
+
+    let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+    keys.sort();
+    keys.dedup();
+
+    let mut differing = 0;
+    for key in keys {
+        let (va, vb) = (a.get(key), b.get(key));
+        if va == vb {
+            continue;
+        }
+        differing += 1;
+        println!("{}:", key);
+        println!("  a: {}", va.map_or("<absent>", String::as_str));
+        println!("  b: {}", vb.map_or("<absent>", String::as_str));
+    }
+
+    if differing == 0 {
+        println!("documents are identical");
+    } else {
+        println!("{} field(s) differ", differing);
+    }
+    Ok(())
+}
+
+fn parse_header(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once(':')
+        .ok_or_else(|| format!("expected <NAME: VALUE>: {}", s))?;
+    let name = hyper::header::HeaderName::from_bytes(name.trim().as_bytes())
+        .map_err(|e| format!("bad header name {}: {}", name, e))?;
+    Ok((name.to_string(), value.trim().to_owned()))
+}
+
+fn parse_expected_pcr(s: &str) -> Result<(u64, String), String> {
+    let (index, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <INDEX>=<HEX>: {}", s))?;
+    let index = index
+        .parse()
+        .map_err(|e| format!("bad pcr index {}: {}", index, e))?;
+    hex::decode(value).map_err(|e| format!("bad pcr value: {}", e))?;
+    Ok((index, value.to_owned()))
+}
+
+fn load_root(path: Option<&str>, dir: Option<&str>) -> Result<Vec<u8>, Box<dyn Error>> {
+    let Some(dir) = dir else {
+        return Ok(match path {
+            Some(path) => std::fs::read(path)?,
+            None => AWS_ROOT_CERT.to_vec(),
+        });
+    };
+
+    let mut paths = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_pem = path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| matches!(ext, "pem" | "crt" | "cert"));
+        if is_pem {
+            paths.push(path);
+        }
+    }
+    paths.sort();
+
+    let mut bundle = Vec::new();
+    for path in &paths {
+        let pem = std::fs::read(path)?;
+        X509::stack_from_pem(&pem).map_err(|e| format!("{}: {}", path.display(), e))?;
+        bundle.extend_from_slice(&pem);
+        bundle.push(b'\n');
+    }
+    if paths.is_empty() {
+        return Err(format!("no .pem, .crt or .cert roots in {}", dir).into());
+    }
+    Ok(bundle)
+}
+
+fn load_signer(path: &str) -> Result<Box<dyn Signer + Send + Sync>, Box<dyn Error>> {
+    let mut file = File::open(path)?;
+    let mut secret = [0u8; 32];
+    file.read_exact(&mut secret)?;
+    signing::signer(Scheme::Ed25519, &secret)
+}
+
+async fn batch_cmd(args: BatchArgs) -> Result<(), Box<dyn Error>> {
+    let cert = load_root(args.root.as_deref(), args.root_dir.as_deref())?;
+    let client = proxied_client(args.proxy.as_deref())?;
+
+    let mut targets = Vec::new();
+    for line in std::fs::read_to_string(&args.endpoints)?.lines() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let mut fields = line.split_whitespace();
+        let (Some(endpoint), Some(image_id)) = (fields.next(), fields.next()) else {
+            return Err(format!("expected `<endpoint> <image_id> [module_id]`: {}", line).into());
+        };
+        let policy = Policy {
+            image_id: image_id.to_owned(),
+            module_id: fields.next().map(str::to_owned),
+            clock_skew: args.clock_skew,
+            expected_pcrs: BTreeMap::new(),
+            min_vcpus: None,
+            min_memory_mib: None,
+            limits: Limits::default(),
+        };
+        targets.push((endpoint.to_owned(), policy));
+    }
+    let total = targets.len();
+
+    let mut results = stream::iter(targets)
+        .map(|(endpoint, policy)| {
+            let client = &client;
+            let cert = &cert;
+            let headers = &args.header;
+            async move {
+                let result = fetch_and_verify(
+                    client,
+                    &endpoint,
+                    headers,
+                    InputEncoding::Raw,
+                    cert,
+                    &policy,
+                    &mut Trace::default(),
+                )
+                .await;
+                (endpoint, result)
+            }
+        })
+        .buffer_unordered(args.concurrency.max(1));
+
+    let mut failed = 0;
+    while let Some((endpoint, result)) = results.next().await {
+        let line = match result {
+            Ok(report) => serde_json::json!({
+                "endpoint": endpoint,
+                "ok": true,
+                "report": report,
+            }),
+            Err(e) => {
+                failed += 1;
+                serde_json::json!({
+                    "endpoint": endpoint,
+                    "ok": false,
+                    "category": e.category,
+                    "error": e.error.to_string(),
+                })
+            }
+        };
+        println!("{}", line);
+    }
+
+    if failed > 0 {
+        return Err(format!("{} of {} verifications failed", failed, total).into());
+    }
+    Ok(())
+}
+
+/// What identifies the enclave behind an endpoint. Any change means it was redeployed.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+struct Identity {
+    image_id: String,
+    #[serde(with = "hex")]
+    public_key: Vec<u8>,
+    module_id: String,
+}
+
+fn save_state(path: &str, seen: &BTreeMap<String, Identity>) -> Result<(), Box<dyn Error>> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, serde_json::to_vec_pretty(seen)?)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Webhook notifications for verification failures, recoveries and identity changes.
+struct Notifier {
+    hooks: Hooks,
+    template: String,
+    /// whether the last verification of each endpoint succeeded
+    healthy: HashMap<String, bool>,
+}
+
+impl Notifier {
+    fn new(urls: Vec<String>, template: Option<&str>) -> Result<Self, Box<dyn Error>> {
+        let template = match template {
+            Some(path) => std::fs::read_to_string(path)?,
+            None => DEFAULT_TEMPLATE.to_owned(),
+        };
+        Ok(Notifier {
+            hooks: Hooks::new(urls),
+            template,
+            healthy: HashMap::new(),
+        })
+    }
+
+    async fn send(&self, event: &str, endpoint: &str, detail: &str) {
+        let time = (unix_now() as u64).to_string();
+        let body = hooks::render(
+            &self.template,
+            &[
+                ("event", event),
+                ("endpoint", endpoint),
+                ("detail", detail),
+                ("time", &time),
+            ],
+        );
+        // Derived from the body so retries of one event share a key
+        let key = hex::encode(&Sha256::digest(&body)[..16]);
+        self.hooks.send(&key, body.as_bytes()).await;
+    }
+
+    /// Fires `failure` when an endpoint starts failing and `recovery` once it verifies
+    /// again.
+    async fn outcome(&mut self, endpoint: &str, error: Option<String>) {
+        let was_healthy = self.healthy.insert(endpoint.to_owned(), error.is_none());
+        match (was_healthy, error) {
+            (Some(true) | None, Some(e)) => self.send("failure", endpoint, &e).await,
+            (Some(false), None) => self.send("recovery", endpoint, "verified again").await,
+            _ => {}
+        }
+    }
+}
+
+async fn monitor_cmd(args: MonitorArgs) -> Result<(), Box<dyn Error>> {
+    let cert = load_root(args.root.as_deref(), args.root_dir.as_deref())?;
+    let client = proxied_client(args.proxy.as_deref())?;
+    let mut notifier = Notifier::new(args.hook.clone(), args.hook_template.as_deref())?;
+
+    let endpoints: Vec<String> = std::fs::read_to_string(&args.endpoints)?
+        .lines()
+        .map(|line| line.split('#').next().unwrap_or_default().trim())
+        .filter(|line| !line.is_empty())
+        .map(str::to_owned)
+        .collect();
+
+    let mut seen: BTreeMap<String, Identity> = match std::fs::read(&args.state) {
+        Ok(state) => serde_json::from_slice(&state)?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+        Err(e) => return Err(e.into()),
+    };
+
+    // Any image is accepted, the point is to notice when it changes
+    let policy = Policy {
+        image_id: String::new(),
+        module_id: None,
+        clock_skew: args.clock_skew,
+        expected_pcrs: BTreeMap::new(),
+        min_vcpus: None,
+        min_memory_mib: None,
+        limits: Limits::default(),
+    };
+
+    loop {
+        let results: Vec<_> = stream::iter(&endpoints)
+            .map(|endpoint| {
+                let client = &client;
+                let cert = &cert;
+                let policy = &policy;
+                let headers = &args.header;
+                async move {
+                    let result = fetch_and_verify(
+                        client,
+                        endpoint,
+                        headers,
+                        InputEncoding::Raw,
+                        cert,
+                        policy,
+                        &mut Trace::default(),
+                    )
+                    .await;
+                    (endpoint, result)
+                }
+            })
+            .buffer_unordered(16)
+            .collect()
+            .await;
+
+        let mut changes = 0;
+        for (endpoint, result) in results {
+            let report = match result {
+                Ok(report) => report,
+                Err(e) => {
+                    warn!(%endpoint, error = %e, "Verification failed");
+                    notifier.outcome(endpoint, Some(e.to_string())).await;
+                    continue;
+                }
+            };
+            notifier.outcome(endpoint, None).await;
+            let current = Identity {
+                image_id: report.image_id,
+                public_key: report.public_key,
+                module_id: report.module_id,
+            };
+
+            match seen.get(endpoint) {
+                // The previous identity stays recorded so the alert repeats until an
+                // operator removes it from the state file
+                Some(previous) if *previous != current => {
+                    error!(
+                        %endpoint,
+                        "ALERT: enclave identity changed from {:?} to {:?}",
+                        previous,
+                        current
+                    );
+                    let detail = format!(
+                        "image_id {} -> {}, public_key {} -> {}, module_id {} -> {}",
+                        previous.image_id,
+                        current.image_id,
+                        hex::encode(&previous.public_key),
+                        hex::encode(&current.public_key),
+                        previous.module_id,
+                        current.module_id
+                    );
+                    notifier.send("identity_change", endpoint, &detail).await;
+                    changes += 1;
+                }
+                Some(_) => {}
+                None => {
+                    info!(
+                        %endpoint,
+                        image_id = %current.image_id,
+                        module_id = %current.module_id,
+                        "Tracking enclave identity"
+                    );
+                    seen.insert(endpoint.clone(), current);
+                }
+            }
+        }
+        save_state(&args.state, &seen)?;
+
+        if changes > 0 {
+            return Err(format!("{} enclave identity change(s) detected", changes).into());
+        }
+
+        tokio::time::sleep(Duration::from_secs(args.interval)).await;
+    }
+}
+
+/// Data uploaded by the loader binary and the sum the requester must get back
+const DEMO_EXPECTED: &str = "Result: 55";
+
+#[derive(Serialize)]
+struct StepReport {
+    step: &'static str,
+    status: &'static str,
+    detail: String,
+    millis: u64,
+}
+
+/// Runs demo steps in order, skipping everything after the first failure.
+#[derive(Default)]
+struct Scenario {
+    steps: Vec<StepReport>,
+    failed: bool,
+}
+
+impl Scenario {
+    async fn step(
+        &mut self,
+        step: &'static str,
+        f: impl Future<Output = Result<String, Box<dyn Error>>>,
+    ) {
+        let started = Instant::now();
+        let (status, detail) = if self.failed {
+            ("skip", "earlier step failed".to_owned())
+        } else {
+            match f.await {
+                Ok(detail) => ("pass", detail),
+                Err(e) => {
+                    self.failed = true;
+                    ("fail", e.to_string())
+                }
+            }
+        };
+        let report = StepReport {
+            step,
+            status,
+            detail,
+            millis: started.elapsed().as_millis() as u64,
+        };
+        println!("{}", serde_json::to_string(&report).unwrap());
+        self.steps.push(report);
+    }
+}
+
+/// Path of another binary built alongside this one.
+fn sibling(name: &str) -> Result<PathBuf, Box<dyn Error>> {
+    Ok(
+        std::env::current_exe()?.with_file_name(format!(
+            "{}{}",
+            name,
+            std::env::consts::EXE_SUFFIX
+        )),
+    )
+}
+
+/// Runs a sibling binary to completion and returns the first stdout line containing
+/// `expected`.
+async fn run_bin(name: &str, args: &[&str], expected: &str) -> Result<String, Box<dyn Error>> {
+    let output = tokio::process::Command::new(sibling(name)?)
+        .args(args)
+        .output()
+        .await?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    if !output.status.success() {
+        return Err(format!(
+            "{} exited with {}: {}",
+            name,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        )
+        .into());
+    }
+    match stdout.lines().find(|line| line.contains(expected)) {
+        Some(line) => Ok(line.trim().to_owned()),
+        None => Err(format!(
+            "expected `{}` from {}, got: {}",
+            expected,
+            name,
+            stdout.trim()
+        )
+        .into()),
+    }
+}
+
+/// Starts the app on `addr` and waits until it accepts connections.
+async fn spawn_app(addr: &str, workdir: &str) -> Result<tokio::process::Child, Box<dyn Error>> {
+    let child = tokio::process::Command::new(sibling("app")?)
+        .args(["--ip-addr", addr])
+        .args(["--secret", &format!("{}/app.sec", workdir)])
+        .args(["--loader", &format!("{}/loader.pub", workdir)])
+        .args(["--requester", &format!("{}/requester.pub", workdir)])
+        .stdout(std::process::Stdio::null())
+        .kill_on_drop(true)
+        .spawn()?;
+
+    for _ in 0..50 {
+        // An unknown message type is a harmless probe
+        if let Ok(mut probe) = tokio::net::TcpStream::connect(addr).await {
+            ppa::frame::write(&mut probe, 0xff, &[]).await?;
+            return Ok(child);
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    Err(format!("app did not start listening on {}", addr).into())
+}
+
+async fn demo_cmd(args: DemoArgs) -> Result<(), Box<dyn Error>> {
+    std::fs::create_dir_all(&args.workdir)?;
+    let key = |name: &str| format!("{}/{}", args.workdir, name);
+    let app_pub = key("app.pub");
+    let mut scenario = Scenario::default();
+    let mut app = None;
+
+    let (loader_secret, requester_secret) = if args.local {
+        scenario
+            .step("keygen", async {
+                for role in ["app", "loader", "requester"] {
+                    let (secret, public) =
+                        (key(&format!("{}.sec", role)), key(&format!("{}.pub", role)));
+                    run_bin("keygen", &["--secret", &secret, "--public", &public], "").await?;
+                }
+                Ok(format!(
+                    "app, loader and requester keys in {}",
+                    args.workdir
+                ))
+            })
+            .await;
+        scenario
+            .step("verify", async {
+                Ok("local app, attestation skipped".to_owned())
+            })
+            .await;
+        scenario
+            .step("start_app", async {
+                app = Some(spawn_app(&args.app_addr, &args.workdir).await?);
+                Ok(format!("listening on {}", args.app_addr))
+            })
+            .await;
+        (key("loader.sec"), key("requester.sec"))
+    } else {
+        scenario
+            .step("keygen", async {
+                Ok("using supplied loader and requester keys".to_owned())
+            })
+            .await;
+        scenario
+            .step("verify", async {
+                let cert = load_root(args.root.as_deref(), args.root_dir.as_deref())?;
+                let client = proxied_client(args.proxy.as_deref())?;
+                let policy = Policy {
+                    image_id: args.image_id.clone().unwrap_or_default(),
+                    module_id: None,
+                    clock_skew: 300,
+                    expected_pcrs: BTreeMap::new(),
+                    min_vcpus: None,
+                    min_memory_mib: None,
+                    limits: Limits::default(),
+                };
+                let endpoint = args.endpoint.as_deref().unwrap_or_default();
+                let report = fetch_and_verify(
+                    &client,
+                    endpoint,
+                    &args.header,
+                    InputEncoding::Raw,
+                    &cert,
+                    &policy,
+                    &mut Trace::default(),
+                )
+                .await?;
+                std::fs::write(&app_pub, &report.public_key)?;
+                Ok(format!(
+                    "image_id {}, module_id {}",
+                    report.image_id, report.module_id
+                ))
+            })
+            .await;
+        (
+            args.loader_secret.clone().unwrap_or_default(),
+            args.requester_secret.clone().unwrap_or_default(),
+        )
+    };
+
+    scenario
+        .step(
+            "load",
+            run_bin(
+                "loader",
+                &[
+                    "--ip-addr",
+                    &args.app_addr,
+                    "--app",
+                    &app_pub,
+                    "--secret",
+                    &loader_secret,
+                ],
+                "Data write suceeded!",
+            ),
+        )
+        .await;
+    scenario
+        .step(
+            "request",
+            run_bin(
+                "requester",
+                &[
+                    "--ip-addr",
+                    &args.app_addr,
+                    "--app",
+                    &app_pub,
+                    "--secret",
+                    &requester_secret,
+                ],
+                DEMO_EXPECTED,
+            ),
+        )
+        .await;
+    drop(app);
+
+    let failed = scenario.steps.iter().filter(|s| s.status != "pass").count();
+    if scenario.failed {
+        return Err(format!(
+            "demo failed: {} of {} steps did not pass",
+            failed,
+            scenario.steps.len()
+        )
+        .into());
+    }
+    info!("Demo passed: {} steps", scenario.steps.len());
+    Ok(())
+}
+
+async fn verify_cmd(args: VerifyArgs) -> Result<(), Box<dyn Error>> {
+    let cert = load_root(args.root.as_deref(), args.root_dir.as_deref())?;
+    let policy = Policy {
+        image_id: args.image_id.clone(),
+        module_id: args.expected_module_id.clone(),
+        clock_skew: args.clock_skew,
+        expected_pcrs: args.expected_pcr.iter().cloned().collect(),
+        min_vcpus: args.min_vcpus,
+        min_memory_mib: args.min_memory_mib,
+        limits: Limits::default(),
+    };
+
+    let client = proxied_client(args.proxy.as_deref())?;
+
+    let signer: Option<Arc<dyn Signer + Send + Sync>> = match &args.signing_key {
+        Some(path) => {
+            let signer = load_signer(path)?;
+            info!("Verifier signing key: {}", hex::encode(signer.public_key()));
+            Some(signer.into())
+        }
+        None => None,
+    };
+
+    let mut audit = match (&args.trace, &signer) {
+        (Some(path), Some(signer)) => Some(AuditLog::open(path, signer.clone())?),
+        _ => None,
+    };
+
+    let Some(interval) = args.interval else {
+        run_once(
+            &args,
+            &client,
+            &cert,
+            &policy,
+            audit.as_mut(),
+            signer.as_deref(),
+        )
+        .await?;
+        return Ok(());
+    };
+
+    let mut notifier = Notifier::new(args.hook.clone(), args.hook_template.as_deref())?;
+    let metrics = Arc::new(Mutex::new(Metrics::default()));
+    if let Some(addr) = args.metrics {
+        info!("Serving metrics on: {}", addr);
+        tokio::spawn(serve_metrics(addr, metrics.clone()));
+    }
+
+    loop {
+        let result = run_once(
+            &args,
+            &client,
+            &cert,
+            &policy,
+            audit.as_mut(),
+            signer.as_deref(),
+        )
+        .await;
+
+        let mut m = metrics.lock().unwrap();
+        m.attempts += 1;
+        let error = match result {
+            Ok(report) => {
+                let now = unix_now();
+                m.last_success = Some(now);
+                m.attestation_age = Some(now - report.timestamp as f64 / 1000.0);
+                None
+            }
+            Err(e) => {
+                warn!(category = e.category, error = %e, "Verification failed");
+                *m.failures.entry(e.category).or_insert(0) += 1;
+                Some(e.to_string())
+            }
+        };
+        drop(m);
+
+        notifier.outcome(&args.endpoint, error).await;
+
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+    }
+}
+
+fn check_result(args: CheckReceiptArgs) -> Result<(), Box<dyn Error>> {
+    let result: AttestedResult = serde_json::from_slice(&std::fs::read(&args.receipt)?)?;
+    let receipt = &result.receipt;
+    let trusted = args.signer.as_deref().map(hex::decode).transpose()?;
+    let claims = receipt.verify_result(trusted.as_deref())?;
+    let scope = match claims.elementwise {
+        true => "per position",
+        false => "in total",
+    };
+    let op = match claims.percentile {
+        Some(rank) => format!("{} {}", claims.op, rank),
+        None => claims.op.clone(),
+    };
+    let dataset = match &claims.group {
+        Some(group) => format!("{} (contributor group {})", claims.dataset, group),
+        None => claims.dataset.clone(),
+    };
+    info!(
+        "Result receipt valid, signed by {}: {} of dataset {} {} is {} at {}",
+        hex::encode(&receipt.public_key),
+        op,
+        dataset,
+        scope,
+        claims.result,
+        claims.timestamp
+    );
+
+    if let Some(image_id) = args.image_id {
+        let cert = load_root(args.root.as_deref(), None)?;
+        let policy = Policy {
+            image_id,
+            module_id: None,
+            clock_skew: 300,
+            expected_pcrs: BTreeMap::new(),
+            min_vcpus: None,
+            min_memory_mib: None,
+            limits: Limits::default(),
+        };
+        let report = result.verify_attestation(&cert, &policy)?;
+        info!(
+            "Result attested by image_id {} module_id {} at {}",
+            report.image_id, report.module_id, report.timestamp
+        );
+    } else if trusted.is_none() {
+        warn!("Neither signer nor image pinned, pass --signer or --image-id");
+    }
+
+    Ok(())
+}
+
+fn check_receipt(args: CheckReceiptArgs) -> Result<(), Box<dyn Error>> {
+    if args.result {
+        return check_result(args);
+    }
+    let receipt: Receipt = serde_json::from_slice(&std::fs::read(&args.receipt)?)?;
+    let trusted = args.signer.as_deref().map(hex::decode).transpose()?;
+    let claims = receipt.verify(trusted.as_deref())?;
+
+    info!(
+        "Receipt valid, signed by {}: image_id {} with pubkey {} verified at {}",
+        hex::encode(&receipt.public_key),
+        claims.image_id,
+        hex::encode(&claims.public_key),
+        claims.verified_at
+    );
+    if trusted.is_none() {
+        warn!("Signer not pinned, pass --signer to require a known verifier");
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let cli = Cli::parse();
+    logging::init(cli.log_format);
+
+    match cli.command {
+        Command::Verify(args) => verify_cmd(args).await,
+        Command::Batch(args) => batch_cmd(args).await,
+        Command::Monitor(args) => monitor_cmd(args).await,
+        Command::Diff(args) => diff_cmd(args).await,
+        Command::ExportOnchain(args) => export_onchain(args).await,
+        Command::FetchRoot(args) => fetch_root(args).await,
+        Command::Demo(args) => demo_cmd(args).await,
+        Command::CheckReceipt(args) => check_receipt(args),
+    }
+}
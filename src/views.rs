@@ -0,0 +1,265 @@
+use crate::sum::{self, Op, Value};
+use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Deserializer};
+use std::error::Error;
+
+/// How much of a result a requester is allowed to see.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum View {
+    /// The exact result
+    #[default]
+    Exact,
+    /// The result with differential privacy noise added, then rounded
+    Noised {
+        /// Privacy budget spent per released value, smaller is noisier
+        epsilon: f64,
+        /// Largest change one record can make to the result
+        #[serde(default = "default_sensitivity")]
+        sensitivity: f64,
+        #[serde(default)]
+        mechanism: Mechanism,
+        /// Failure probability of the Gaussian mechanism
+        #[serde(default = "default_delta")]
+        delta: f64,
+        /// Round the noised result to a multiple of this
+        #[serde(default = "default_round_to")]
+        round_to: u64,
+    },
+}
+
+/// Distribution the noise of a noised view is drawn from.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Mechanism {
+    /// Laplace with scale `sensitivity / epsilon`, for pure epsilon-DP
+    #[default]
+    Laplace,
+    /// Gaussian with standard deviation `sensitivity * sqrt(2 ln(1.25 / delta)) / epsilon`,
+    /// for (epsilon, delta)-DP with epsilon below 1
+    Gaussian,
+}
+
+fn default_delta() -> f64 {
+    1e-6
+}
+
+fn default_sensitivity() -> f64 {
+    // A byte-sized record, raise it when loaders submit larger values
+    255.0
+}
+
+fn default_round_to() -> u64 {
+    1
+}
+
+impl View {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if let View::Noised {
+            epsilon,
+            sensitivity,
+            mechanism,
+            delta,
+            round_to,
+        } = self
+        {
+            if !epsilon.is_finite()
+                || *epsilon <= 0.0
+                || !sensitivity.is_finite()
+                || *sensitivity < 0.0
+                || *round_to == 0
+            {
+                return Err("noised view needs epsilon > 0, sensitivity >= 0, round_to > 0".into());
+            }
+            // The classic Gaussian bound only holds below epsilon 1
+            if *mechanism == Mechanism::Gaussian
+                && (*epsilon >= 1.0 || !(*delta > 0.0 && *delta < 1.0))
+            {
+                return Err("gaussian noise needs epsilon < 1 and 0 < delta < 1".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Privacy budget one released value costs, none for exact results.
+    pub fn epsilon(&self) -> Option<f64> {
+        match self {
+            View::Exact => None,
+            View::Noised { epsilon, .. } => Some(*epsilon),
+        }
+    }
+
+    pub fn apply(&self, result: i128) -> i128 {
+        match self {
+            View::Exact => result,
+            View::Noised {
+                epsilon,
+                sensitivity,
+                mechanism,
+                delta,
+                round_to,
+            } => {
+                let noise = match mechanism {
+                    Mechanism::Laplace => laplace(sensitivity / epsilon),
+                    Mechanism::Gaussian => {
+                        gaussian(sensitivity * (2.0 * (1.25 / delta).ln()).sqrt() / epsilon)
+                    }
+                };
+                let noised = result as f64 + noise;
+                let round_to = *round_to as f64;
+                ((noised / round_to).round() * round_to) as i128
+            }
+        }
+    }
+
+    /// The `rank`th percentile of `sorted`. A noised view draws it with the exponential
+    /// mechanism instead of adding noise, since one value can move a percentile
+    /// anywhere between its neighbours: each gap between neighbouring values, clamped
+    /// to `bounds`, is picked with probability proportional to its width times
+    /// `exp(-epsilon * d / 2)`, `d` being how many values the gap is off the rank, and
+    /// the result is drawn uniformly within it, then rounded to `round_to`.
+    pub fn percentile(
+        &self,
+        sorted: &[i128],
+        rank: u8,
+        bounds: Option<(i128, i128)>,
+    ) -> Result<Value, Box<dyn Error>> {
+        let View::Noised {
+            epsilon, round_to, ..
+        } = self
+        else {
+            return sum::percentile(sorted, rank);
+        };
+        let (min, max) = bounds.ok_or("noised percentiles need the dataset's value range")?;
+        if min >= max {
+            return Ok(Value::Float(min as f64));
+        }
+        let points: Vec<f64> = std::iter::once(min)
+            .chain(sorted.iter().map(|v| (*v).clamp(min, max)))
+            .chain(std::iter::once(max))
+            .map(|v| v as f64)
+            .collect();
+        // Log-weights, shifted by their maximum so the largest is exp(0)
+        let target = sorted.len() as f64 * rank as f64 / 100.0;
+        let scores: Vec<f64> = points
+            .windows(2)
+            .enumerate()
+            .map(|(i, gap)| (gap[1] - gap[0]).ln() - epsilon * (i as f64 - target).abs() / 2.0)
+            .collect();
+        let top = scores.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+        let weights: Vec<f64> = scores.iter().map(|s| (s - top).exp()).collect();
+        let mut pick = uniform() * weights.iter().sum::<f64>();
+        let gap = weights
+            .iter()
+            .position(|w| {
+                pick -= w;
+                pick <= 0.0
+            })
+            .unwrap_or(weights.len() - 1);
+        let value = points[gap] + uniform() * (points[gap + 1] - points[gap]);
+        let round_to = *round_to as f64;
+        Ok(Value::Float((value / round_to).round() * round_to))
+    }
+}
+
+/// Samples uniformly from (0, 1) with 53 random bits.
+fn uniform() -> f64 {
+    ((OsRng.next_u64() >> 11) as f64 + 0.5) / (1u64 << 53) as f64
+}
+
+/// Samples Laplace(0, scale) by inverting the CDF.
+fn laplace(scale: f64) -> f64 {
+    let u = uniform() - 0.5;
+    -scale * u.signum() * (1.0 - 2.0 * u.abs()).ln()
+}
+
+/// Samples N(0, sigma^2) with the Box-Muller transform.
+fn gaussian(sigma: f64) -> f64 {
+    let (u1, u2) = (uniform(), uniform());
+    sigma * (-2.0 * u1.ln()).sqrt() * (std::f64::consts::TAU * u2).cos()
+}
+
+/// What a requester may query and how much of each result it sees. Absent lists
+/// allow everything.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct Access {
+    #[serde(default)]
+    pub view: View,
+    /// operations the requester may run
+    #[serde(default, deserialize_with = "ops")]
+    pub ops: Option<Vec<Op>>,
+    /// datasets the requester may query
+    #[serde(default)]
+    pub datasets: Option<Vec<String>>,
+    /// total epsilon the requester may spend across queries, unlimited if absent
+    #[serde(default)]
+    pub budget: Option<f64>,
+}
+
+fn ops<'de, D: Deserializer<'de>>(d: D) -> Result<Option<Vec<Op>>, D::Error> {
+    let Some(names) = Option::<Vec<String>>::deserialize(d)? else {
+        return Ok(None);
+    };
+    names
+        .iter()
+        .map(|name| name.parse().map_err(serde::de::Error::custom))
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
+
+impl Access {
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        self.view.validate()?;
+        if let Some(budget) = self.budget {
+            if self.view.epsilon().is_none() || !budget.is_finite() || budget <= 0.0 {
+                return Err("budget needs a noised view and must be > 0".into());
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks a query against the grant, before anything is computed.
+    pub fn permits(&self, dataset: &str, op: Op) -> Result<(), Box<dyn Error>> {
+        if self.ops.as_ref().is_some_and(|ops| !ops.contains(&op)) {
+            return Err(format!("{} is not permitted for the requester", op).into());
+        }
+        self.permits_dataset(dataset)
+    }
+
+    /// Checks that the grant covers a dataset, whatever the operation.
+    pub fn permits_dataset(&self, dataset: &str) -> Result<(), Box<dyn Error>> {
+        if self
+            .datasets
+            .as_ref()
+            .is_some_and(|datasets| !datasets.iter().any(|d| d == dataset))
+        {
+            return Err(format!("dataset {} is not permitted for the requester", dataset).into());
+        }
+        Ok(())
+    }
+}
+
+/// A requester public key file and what it is granted.
+#[derive(Clone, Debug, Deserialize)]
+pub struct Grant {
+    pub key: String,
+    #[serde(flatten)]
+    pub access: Access,
+}
+
+/// Reads a JSON list of grants, e.g.
+/// `[{"key": "auditor.pub", "view": "exact"},
+///   {"key": "public.pub", "view": {"noised": {"epsilon": 0.5, "round_to": 10}}},
+///   {"key": "partner.pub", "ops": ["sum"], "datasets": ["trial-a"]},
+///   {"key": "analyst.pub", "view": {"noised": {"epsilon": 0.5, "mechanism": "gaussian"}},
+///    "budget": 5.0}]`.
+pub fn read_policy(path: &str) -> Result<Vec<Grant>, Box<dyn Error>> {
+    let grants: Vec<Grant> = serde_json::from_slice(&std::fs::read(path)?)?;
+    for grant in &grants {
+        grant
+            .access
+            .validate()
+            .map_err(|e| format!("{}: {}", grant.key, e))?;
+    }
+    Ok(grants)
+}